@@ -0,0 +1,47 @@
+//! Battery voltage alerts pushed by the adapter, split out of its responses by `Elm327`
+use serde::Serialize;
+
+use crate::clock;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    LowVoltage,
+    HighVoltage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub volts: f32,
+    /// Gateway uptime when the alert was received
+    pub uptime_ms: u64,
+    /// RFC 3339, once the clock has been set
+    pub time: Option<String>,
+}
+
+impl Alert {
+    /// Parse an unsolicited adapter line, e.g. `VOLTAGE LOW 11.6V`
+    pub fn parse(line: &str, uptime_ms: u64) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+
+        if tokens.next()? != "VOLTAGE" {
+            return None;
+        }
+
+        let kind = match tokens.next()? {
+            "LOW" => AlertKind::LowVoltage,
+            "HIGH" => AlertKind::HighVoltage,
+            _ => return None,
+        };
+
+        let volts = tokens.next()?.trim_end_matches('V').parse().ok()?;
+
+        Some(Alert {
+            kind,
+            volts,
+            uptime_ms,
+            time: clock::now(),
+        })
+    }
+}
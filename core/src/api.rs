@@ -0,0 +1,210 @@
+//! The adapter side of the HTTP endpoints without the server: what `/post`, `/snapshot`, `/scan`
+//! and `/bt/pair` make of a request and how they run it on the ELM worker. The firmware's
+//! handlers and the host tests (`core/tests/common`) both go through here, so the tests check the
+//! limits and steps the firmware has.
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    dtc::{self, EcuDtcs, ScanReport},
+    ecus::{self, Ecu},
+    elm327::Bus,
+    elm_worker::{ElmWorker, Priority},
+    pid, timeouts,
+};
+
+/// An oversized body is read and dropped up to this before the 413, a client still sending it
+/// may not see a response sent sooner
+pub const MAX_DRAIN_LEN: usize = 64 * 1024;
+/// Four multi-PID requests
+pub const MAX_SNAPSHOT_PIDS: usize = 4 * pid::MAX_PIDS_PER_REQUEST;
+
+/// `/post` once its body is read
+pub struct PostRequest {
+    pub command: Vec<u8>,
+    pub bus: Bus,
+    pub timeout_ms: Option<u32>,
+    pub priority: Priority,
+}
+
+impl PostRequest {
+    /// The body with `?bus=`, `X-Elm-Timeout` and `X-Priority`. An error is the client's, a 400.
+    pub fn parse(
+        command: Vec<u8>,
+        bus: Option<&str>,
+        timeout: Option<&str>,
+        priority: Option<&str>,
+    ) -> Result<Self> {
+        let bus = Bus::from_param(bus)?;
+        let timeout_ms = timeouts::from_header(timeout)?;
+        let priority = Priority::from_header(priority, Priority::for_request(&command));
+
+        Ok(Self {
+            command,
+            bus,
+            timeout_ms,
+            priority,
+        })
+    }
+
+    /// Send it on the worker. A retried request ID is answered from the worker's cache.
+    pub fn transact(
+        &self,
+        worker: &ElmWorker<'_>,
+        request_id: Option<String>,
+        ecu: Option<Ecu>,
+    ) -> Result<String> {
+        worker.transact(
+            self.priority,
+            self.command.clone(),
+            request_id,
+            self.timeout_ms,
+            self.bus,
+            ecu,
+        )
+    }
+}
+
+/// `/snapshot?pids=0C,0D,boost`
+pub struct SnapshotRequest {
+    /// The hex PIDs asked for
+    pub pids: Vec<u8>,
+    /// The rest, names the caller knows, e.g. virtual PIDs
+    pub names: Vec<String>,
+}
+
+impl SnapshotRequest {
+    /// `is_name` says which names are known, any other that isn't hex is a 400
+    pub fn parse(pids: Option<&str>, is_name: impl Fn(&str) -> bool) -> Result<Self> {
+        let mut request = Self {
+            pids: Vec::new(),
+            names: Vec::new(),
+        };
+
+        for p in pids
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+        {
+            match u8::from_str_radix(p, 16) {
+                Ok(p) => request.pids.push(p),
+                Err(_) if is_name(p) => request.names.push(p.to_owned()),
+                Err(_) => Err(anyhow!("Unknown pid ({p})"))?,
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// The PIDs to read, the ones asked for and `inputs` the names need. Checked against the
+    /// limit and the vehicle's supported PIDs, an error is a 400.
+    pub fn to_read(&self, inputs: Vec<u8>) -> Result<Vec<u8>> {
+        let mut pids = self.pids.clone();
+        pids.extend(inputs);
+        pids.sort_unstable();
+        pids.dedup();
+
+        if (self.pids.is_empty() && self.names.is_empty()) || pids.len() > MAX_SNAPSHOT_PIDS {
+            Err(anyhow!("pids must be 1 to {MAX_SNAPSHOT_PIDS} hex PIDs"))?;
+        }
+
+        pid::check_supported(&pids)?;
+
+        Ok(pids)
+    }
+
+    /// Read `pids` together on the worker, `stamp` taken just before, e.g. the time
+    pub fn read<T: Send + 'static>(
+        worker: &ElmWorker<'_>,
+        priority: Priority,
+        pids: Vec<u8>,
+        stamp: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<(T, BTreeMap<u8, f32>)> {
+        worker.run(priority, move |elm327| {
+            let stamp = stamp();
+            pid::request_many(elm327, &pids).map(|values| (stamp, values))
+        })
+    }
+}
+
+/// Find the ECUs on HS-CAN, for `/scan` when there's been no `/ecus/scan`
+pub fn find_ecus(worker: &ElmWorker<'_>) -> Result<Vec<Ecu>> {
+    worker.run(Priority::Bulk, |elm327| {
+        elm327.select_bus(Bus::Hs)?;
+        ecus::enumerate(elm327)
+    })
+}
+
+/// What a `DtcScan` step did
+pub enum ScanStep {
+    /// The ECUs that answered, there were none known
+    Found(Vec<Ecu>),
+    /// An ECU's DTCs were read, or it failed and is in the report as failed
+    Read {
+        ecu: String,
+        done: usize,
+        total: usize,
+    },
+    Finished,
+}
+
+/// `/scan`, every ECU's stored, pending and permanent DTCs. It goes a worker job at a time, so
+/// other requests get the adapter in between and whoever drives it can do other work too.
+pub struct DtcScan {
+    /// None until they're found, when none were known
+    ecus: Option<Vec<Ecu>>,
+    read: Vec<EcuDtcs>,
+}
+
+impl DtcScan {
+    /// With the ECUs from `/ecus/scan`, empty to find them first
+    pub fn new(ecus: Vec<Ecu>) -> Self {
+        Self {
+            ecus: (!ecus.is_empty()).then_some(ecus),
+            read: Vec::new(),
+        }
+    }
+
+    /// Find the ECUs if need be, otherwise read the next one. An error is finding them failing.
+    pub fn step(&mut self, worker: &ElmWorker<'_>) -> Result<ScanStep> {
+        let Some(ecus) = &self.ecus else {
+            let found = find_ecus(worker)?;
+            self.ecus = Some(found.clone());
+            return Ok(ScanStep::Found(found));
+        };
+
+        let Some(ecu) = ecus.get(self.read.len()) else {
+            return Ok(ScanStep::Finished);
+        };
+
+        let job_ecu = ecu.clone();
+        let result = worker.run(Priority::Bulk, move |elm327| {
+            elm327.select_bus(Bus::Hs)?;
+            dtc::read_ecu(elm327, &job_ecu)
+        });
+        self.read
+            .push(result.unwrap_or_else(|err| EcuDtcs::failed(ecu, &err)));
+
+        Ok(ScanStep::Read {
+            ecu: ecu.name.clone(),
+            done: self.read.len(),
+            total: ecus.len(),
+        })
+    }
+
+    /// ECUs read and how many there are, None until they're found
+    pub fn progress(&self) -> (usize, Option<usize>) {
+        (self.read.len(), self.ecus.as_ref().map(Vec::len))
+    }
+
+    pub fn into_report(self) -> ScanReport {
+        ScanReport::new(self.read)
+    }
+}
+
+/// Connect to the adapter again, `/bt/pair` after it forgets the bond
+pub fn reconnect(worker: &ElmWorker<'_>) -> Result<()> {
+    worker.run(Priority::Normal, |elm327| elm327.reconnect())
+}
@@ -0,0 +1,52 @@
+//! Uptime and wall clock time. How the clock gets set is up to the firmware, until then `now` is
+//! None.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Anything earlier is the RTC counting from 1970 after a reset
+pub const MIN_VALID_UNIX_S: u64 = 1_704_067_200;
+
+/// Milliseconds since boot, the monotonic clock ESP-IDF keeps from the boot
+pub fn uptime_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// The time now as RFC 3339 UTC, e.g. `2025-06-01T14:03:07.250Z`, None until it's been set
+pub fn now() -> Option<String> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+    (since_epoch.as_secs() >= MIN_VALID_UNIX_S).then(|| rfc3339(since_epoch))
+}
+
+pub fn rfc3339(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Year, month and day of a day count from 1970-01-01, Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
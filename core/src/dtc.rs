@@ -0,0 +1,227 @@
+//! Diagnostic trouble codes. `read_ecu` asks one ECU for its stored (mode 03), pending (mode 07)
+//! and permanent (mode 0A) codes, and `ScanReport` puts the ECUs' codes together with totals. The
+//! common generic codes get a short description from a built-in table. CAN only, as `ecus`.
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+
+use crate::ecus::{self, Ecu};
+use crate::elm327::{parse_messages, Elm327};
+
+/// Positive response modes are the request's plus this
+const RESPONSE_OFFSET: u8 = 0x40;
+const LETTERS: [char; 4] = ['P', 'C', 'B', 'U'];
+
+/// Generic (SAE) codes seen most often, sorted by code
+const DESCRIPTIONS: &[(&str, &str)] = &[
+    ("P0010", "Camshaft position actuator circuit (bank 1)"),
+    ("P0011", "Camshaft timing over-advanced (bank 1)"),
+    ("P0101", "Mass air flow sensor range/performance"),
+    ("P0102", "Mass air flow sensor circuit low"),
+    ("P0113", "Intake air temperature sensor circuit high"),
+    ("P0117", "Coolant temperature sensor circuit low"),
+    ("P0118", "Coolant temperature sensor circuit high"),
+    ("P0121", "Throttle position sensor range/performance"),
+    ("P0128", "Coolant below thermostat regulating temperature"),
+    ("P0130", "O2 sensor circuit (bank 1 sensor 1)"),
+    ("P0133", "O2 sensor slow response (bank 1 sensor 1)"),
+    ("P0135", "O2 sensor heater circuit (bank 1 sensor 1)"),
+    ("P0141", "O2 sensor heater circuit (bank 1 sensor 2)"),
+    ("P0171", "System too lean (bank 1)"),
+    ("P0172", "System too rich (bank 1)"),
+    ("P0174", "System too lean (bank 2)"),
+    ("P0175", "System too rich (bank 2)"),
+    ("P0217", "Engine overheating"),
+    ("P0234", "Turbo/supercharger overboost"),
+    ("P0299", "Turbo/supercharger underboost"),
+    ("P0300", "Random/multiple cylinder misfire"),
+    ("P0301", "Cylinder 1 misfire"),
+    ("P0302", "Cylinder 2 misfire"),
+    ("P0303", "Cylinder 3 misfire"),
+    ("P0304", "Cylinder 4 misfire"),
+    ("P0305", "Cylinder 5 misfire"),
+    ("P0306", "Cylinder 6 misfire"),
+    ("P0325", "Knock sensor circuit (bank 1)"),
+    ("P0335", "Crankshaft position sensor circuit"),
+    ("P0340", "Camshaft position sensor circuit"),
+    ("P0401", "EGR flow insufficient"),
+    ("P0402", "EGR flow excessive"),
+    ("P0411", "Secondary air injection incorrect flow"),
+    ("P0420", "Catalyst efficiency below threshold (bank 1)"),
+    ("P0430", "Catalyst efficiency below threshold (bank 2)"),
+    ("P0440", "EVAP system malfunction"),
+    ("P0442", "EVAP system small leak"),
+    ("P0446", "EVAP vent control circuit"),
+    ("P0455", "EVAP system large leak"),
+    ("P0456", "EVAP system very small leak"),
+    ("P0500", "Vehicle speed sensor"),
+    ("P0505", "Idle air control system"),
+    ("P0507", "Idle speed higher than expected"),
+    ("P0562", "System voltage low"),
+    ("P0563", "System voltage high"),
+    ("P0571", "Brake switch circuit"),
+    ("P0601", "Control module memory checksum error"),
+    ("P0700", "Transmission control system malfunction"),
+    ("P0715", "Input/turbine speed sensor circuit"),
+    ("P0741", "Torque converter clutch stuck off"),
+    ("U0100", "Lost communication with ECM/PCM"),
+    ("U0101", "Lost communication with TCM"),
+    ("U0121", "Lost communication with ABS module"),
+    ("U0140", "Lost communication with body control module"),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DtcKind {
+    /// Mode 03, confirmed and lighting the MIL
+    Stored,
+    /// Mode 07, seen this or the last drive cycle
+    Pending,
+    /// Mode 0A, only cleared by the ECU once the fault is gone
+    Permanent,
+}
+
+impl DtcKind {
+    fn mode(self) -> u8 {
+        match self {
+            DtcKind::Stored => 0x03,
+            DtcKind::Pending => 0x07,
+            DtcKind::Permanent => 0x0A,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct Dtc {
+    /// e.g. `P0301`
+    pub code: String,
+    /// None for codes not in the table, e.g. manufacturer codes
+    pub description: Option<&'static str>,
+}
+
+impl Dtc {
+    /// From its two bytes, the top two bits are the letter
+    pub fn from_bytes(a: u8, b: u8) -> Self {
+        let code = format!(
+            "{}{}{:X}{b:02X}",
+            LETTERS[(a >> 6) as usize],
+            (a >> 4) & 0x03,
+            a & 0x0F
+        );
+
+        Self {
+            description: describe(&code),
+            code,
+        }
+    }
+}
+
+/// The short description of a generic code
+pub fn describe(code: &str) -> Option<&'static str> {
+    DESCRIPTIONS
+        .binary_search_by(|(c, _)| (*c).cmp(code))
+        .ok()
+        .map(|i| DESCRIPTIONS[i].1)
+}
+
+/// One ECU's codes
+#[derive(Serialize, Clone, Debug)]
+pub struct EcuDtcs {
+    pub ecu: String,
+    pub stored: Vec<Dtc>,
+    pub pending: Vec<Dtc>,
+    pub permanent: Vec<Dtc>,
+    /// Why the ECU couldn't be read, its lists are empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl EcuDtcs {
+    pub fn failed(ecu: &Ecu, err: &anyhow::Error) -> Self {
+        Self {
+            ecu: ecu.name.clone(),
+            stored: Vec::new(),
+            pending: Vec::new(),
+            permanent: Vec::new(),
+            error: Some(format!("{err:#}")),
+        }
+    }
+}
+
+/// Every ECU's codes and the totals
+#[derive(Serialize, Clone, Debug)]
+pub struct ScanReport {
+    pub ecus: Vec<EcuDtcs>,
+    pub stored: usize,
+    pub pending: usize,
+    pub permanent: usize,
+    /// ECUs that couldn't be read
+    pub failed: usize,
+}
+
+impl ScanReport {
+    pub fn new(ecus: Vec<EcuDtcs>) -> Self {
+        let total = |codes: fn(&EcuDtcs) -> &Vec<Dtc>| ecus.iter().map(|e| codes(e).len()).sum();
+
+        Self {
+            stored: total(|e| &e.stored),
+            pending: total(|e| &e.pending),
+            permanent: total(|e| &e.permanent),
+            failed: ecus.iter().filter(|e| e.error.is_some()).count(),
+            ecus,
+        }
+    }
+}
+
+/// The codes of one kind from the ECUs that answer, `NO DATA` is none
+pub fn read(elm: &mut Elm327<'_>, kind: DtcKind) -> Result<Vec<Dtc>> {
+    let lines = elm.transact_lines(format!("{:02X}", kind.mode()).as_bytes())?;
+
+    let dtcs = parse_messages(&lines)
+        .into_iter()
+        .filter(|message| message.data.first() == Some(&(kind.mode() + RESPONSE_OFFSET)))
+        .flat_map(|message| parse(&message.data[1..]))
+        .collect();
+
+    Ok(dtcs)
+}
+
+/// The codes after the response mode, the count then two bytes each. `0000` is padding.
+fn parse(data: &[u8]) -> Vec<Dtc> {
+    let Some((&count, codes)) = data.split_first() else {
+        return Vec::new();
+    };
+
+    codes
+        .chunks_exact(2)
+        .take(count as usize)
+        .filter(|code| *code != [0, 0])
+        .map(|code| Dtc::from_bytes(code[0], code[1]))
+        .collect()
+}
+
+/// Stored, pending and permanent codes of `ecu`
+pub fn read_ecu(elm: &mut Elm327<'_>, ecu: &Ecu) -> Result<EcuDtcs> {
+    let [stored, pending, permanent] = ecus::with_ecu(elm, ecu, |elm| {
+        Ok([
+            read(elm, DtcKind::Stored)?,
+            read(elm, DtcKind::Pending)?,
+            read(elm, DtcKind::Permanent)?,
+        ])
+    })?;
+
+    info!(
+        "DTCs {} stored {} pending {} permanent {}",
+        ecu.name,
+        stored.len(),
+        pending.len(),
+        permanent.len()
+    );
+
+    Ok(EcuDtcs {
+        ecu: ecu.name.clone(),
+        stored,
+        pending,
+        permanent,
+        error: None,
+    })
+}
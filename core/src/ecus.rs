@@ -0,0 +1,128 @@
+//! The vehicle's ECUs. `enumerate` sends mode 01 PID 00 to the functional address and keeps each
+//! module that answers (7E8-7EF on 11 bit CAN, 18DAF1xx on 29 bit). A request can then go to one
+//! of them by name with `with_ecu`, which sets its request header (ATSH) and filters for its
+//! response address (ATCRA) around the request.
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::elm327::{parse_messages, Elm327};
+use crate::error::ElmError;
+
+/// Functional (broadcast) request addresses
+const FUNCTIONAL_11BIT: &str = "7DF";
+const FUNCTIONAL_29BIT: &str = "DB33F1";
+/// Mode 01 PID 00, every emissions ECU answers it
+const ENUMERATE_REQUEST: &[u8] = b"0100";
+const PID_00_RESPONSE: [u8; 2] = [0x41, 0x00];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Ecu {
+    /// `ecm`, `tcm` or `ecu_` and its address, e.g. `ecu_7ea`
+    pub name: String,
+    /// ATSH value, e.g. `7E1` or `DA18F1`
+    pub request: String,
+    /// ATCRA value, e.g. `7E9` or `18DAF118`
+    pub response: String,
+}
+
+impl Ecu {
+    /// The ECU that answered from `header`, None if it isn't a physical response address
+    pub fn from_response(header: &str) -> Option<Ecu> {
+        let (name, request) = match header.len() {
+            3 => {
+                let id = u16::from_str_radix(header, 16).ok()?;
+                if !(0x7E8..=0x7EF).contains(&id) {
+                    return None;
+                }
+
+                let name = match id {
+                    0x7E8 => "ecm".to_owned(),
+                    0x7E9 => "tcm".to_owned(),
+                    _ => format!("ecu_{id:03x}"),
+                };
+                (name, format!("{:03X}", id - 8))
+            }
+            8 if header.starts_with("18DAF1") => {
+                let addr = u8::from_str_radix(&header[6..], 16).ok()?;
+
+                let name = match addr {
+                    0x10 => "ecm".to_owned(),
+                    0x18 => "tcm".to_owned(),
+                    _ => format!("ecu_{addr:02x}"),
+                };
+                (name, format!("DA{addr:02X}F1"))
+            }
+            _ => return None,
+        };
+
+        Some(Ecu {
+            name,
+            request,
+            response: header.to_owned(),
+        })
+    }
+}
+
+/// Ask every ECU on the current bus for its mode 01 PIDs and return the ones that answered, in
+/// address order. The default header is restored afterwards.
+pub fn enumerate(elm: &mut Elm327<'_>) -> Result<Vec<Ecu>> {
+    let functional = if eleven_bit(elm)? {
+        FUNCTIONAL_11BIT
+    } else {
+        FUNCTIONAL_29BIT
+    };
+
+    elm.set_header(functional)?;
+    let lines = elm.transact_lines(ENUMERATE_REQUEST);
+    elm.restore_header()?;
+
+    let mut ecus: Vec<Ecu> = parse_messages(&lines?)
+        .into_iter()
+        .filter(|message| message.data.starts_with(&PID_00_RESPONSE))
+        .filter_map(|message| Ecu::from_response(&message.header))
+        .collect();
+    ecus.sort_by(|a, b| a.response.cmp(&b.response));
+    ecus.dedup();
+
+    info!(
+        "ECUs found {:?}",
+        ecus.iter().map(|ecu| &ecu.name).collect::<Vec<_>>()
+    );
+
+    Ok(ecus)
+}
+
+/// Run `f` with requests addressed to `ecu` and only its responses let through. The default
+/// header and filtering are restored afterwards, even if `f` failed.
+pub fn with_ecu<R>(
+    elm: &mut Elm327<'_>,
+    ecu: &Ecu,
+    f: impl FnOnce(&mut Elm327<'_>) -> Result<R>,
+) -> Result<R> {
+    elm.set_header(&ecu.request)?;
+
+    let result = elm
+        .set_receive_address(Some(&ecu.response))
+        .and_then(|_| f(elm));
+
+    elm.set_receive_address(None)?;
+    elm.restore_header()?;
+
+    result
+}
+
+/// Whether the protocol in use has 11 bit IDs, from ATDPN (e.g. `6` or `A6` for ISO 15765 11
+/// bit 500 kbaud)
+fn eleven_bit(elm: &mut Elm327<'_>) -> Result<bool> {
+    elm.write_request(b"ATDPN")?;
+    let protocol = elm.read_response()?;
+
+    match protocol.trim().trim_start_matches('A') {
+        "6" | "8" => Ok(true),
+        "7" | "9" => Ok(false),
+        other => Err(ElmError::InvalidRequest(format!(
+            "ECUs can only be listed on CAN, the protocol is ({other})"
+        )))?,
+    }
+}
@@ -0,0 +1,1501 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::string::FromUtf8Error;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// use crate::command::OBDResponse;
+use crate::alerts::Alert;
+use crate::clock::uptime_ms;
+use crate::error::{ElmError, ReadObdError};
+use crate::flow_control::FlowControlConfig;
+use crate::metrics;
+use crate::session::{Session, SessionMode};
+use crate::timeouts::{self, TimeoutConfig};
+use crate::transport::ElmTransport;
+use crate::uds::KeepAlive;
+
+/// Responses that leave the adapter in a state where following requests tend to wedge
+const WEDGED_RESPONSES: [&str; 5] = ["BUFFER FULL", "STOPPED", "RX ERROR", "LV RESET", "FB ERROR"];
+/// Lines that mean the request never made it across the bus
+const BUS_ERRORS: [&str; 4] = ["CAN ERROR", "BUS ERROR", "BUS BUSY", "BUFFER FULL"];
+/// Interim lines while the adapter looks for the vehicle's protocol, or wakes a slow init bus
+/// (ISO 9141, KWP). The answer follows once it's done, or `UNABLE TO CONNECT`.
+const SEARCHING: &str = "SEARCHING...";
+const BUS_INIT: &str = "BUS INIT:";
+const UNABLE_TO_CONNECT: &str = "UNABLE TO CONNECT";
+/// Baud rate changes (ELM327 ATBRD/ATBRT, STN STBR/STBRT/STSBR). The link runs at the old rate
+/// afterwards, and a firmware loader is reached the same way.
+const BAUD_COMMANDS: [&str; 4] = ["ATBRD", "ATBRT", "STBR", "STSBR"];
+/// Commands that bring a programming adapter back, by setting it up again
+const REINIT_COMMANDS: [&str; 2] = ["ATZ", "ATWS"];
+/// Stores the baud rate as the adapter's power on rate, where the gateway couldn't reach it
+const SAVE_BAUD_COMMAND: &str = "STWBR";
+
+/// So far, all service requests are for module 10
+const DEFAULT_HEADER: &str = "ATSH DA10F1";
+
+/// MS-CAN on an STN21xx wired for it, e.g. the OBDLink MX+ on pins 3 and 11. ISO 15765, 11 bit,
+/// 125 kbaud.
+const MS_CAN_PROTOCOL: &[u8] = b"STP 53";
+const MS_CAN_HEADER: &[u8] = b"ATSH 7E0";
+/// Request PGN (EA00) to all, from the gateway's address F9
+const J1939_HEADER: &[u8] = b"ATSH 18EAFFF9";
+
+/// STN battery voltage alerts below 11.8V and above 15.0V. The adapter then prints e.g.
+/// `VOLTAGE LOW 11.6V` whenever a threshold is crossed, whether or not a request is active.
+const VOLTAGE_ALERT_COMMAND: &[u8] = b"STVALRT 11.8,15.0";
+
+/// Longest STN monitoring session, it has to finish within the ELM worker reply timeout
+pub const MAX_MONITOR_DURATION: Duration = Duration::from_secs(7);
+/// Time between checks for monitored frames
+const MONITOR_POLL: Duration = Duration::from_millis(20);
+/// Longest a response takes to arrive in full, under the ELM worker reply timeout
+const MAX_RESPONSE_TIME: Duration = Duration::from_secs(8);
+/// Longest a response takes once the adapter is searching, a sleeping bus can take a while to
+/// answer. Also under the ELM worker reply timeout.
+const MAX_SEARCH_TIME: Duration = Duration::from_secs(13);
+/// Largest response kept, e.g. a long multi frame response
+const MAX_RESPONSE_LEN: usize = 4096;
+/// How long a response stopped at the deadline has to reach its prompt
+const STOP_TIME: Duration = Duration::from_secs(1);
+/// Time for the adapter to come out of low power before the setup
+const WAKE_DELAY: Duration = Duration::from_millis(500);
+/// STBRT, how long the adapter waits at a new baud rate for the gateway's CR before going back
+const BAUD_SWITCH_TIMEOUT: Duration = Duration::from_millis(250);
+/// Longest line read while switching baud rate, the adapter's ID
+const MAX_LINE_LEN: usize = 64;
+
+/// Checks requests before they're written, e.g. a command policy for requests from clients
+pub trait RequestGuard: Send + Sync {
+    fn check(&self, request: &[u8]) -> Result<(), ElmError>;
+}
+
+/// STN pass or block filter, hex CAN ID bits, e.g. `18FEF1` with mask `1FFFFF`
+#[derive(Deserialize, Clone, Debug)]
+pub struct StnFilter {
+    pub pattern: String,
+    /// All of the pattern bits when left out
+    #[serde(default)]
+    pub mask: Option<String>,
+}
+
+impl StnFilter {
+    fn command(&self, add: &str) -> Result<String> {
+        let mask = self
+            .mask
+            .clone()
+            .unwrap_or_else(|| "F".repeat(self.pattern.len()));
+
+        if !is_hex(&self.pattern) || !is_hex(&mask) || mask.len() != self.pattern.len() {
+            Err(ElmError::InvalidRequest(
+                "filter pattern and mask must be hex of the same length".to_owned(),
+            ))?;
+        }
+
+        Ok(format!("{add} {},{mask}", self.pattern))
+    }
+}
+
+/// Adapter response timing
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimingProfile {
+    /// The configured timeouts, see `timeouts`
+    Normal,
+    /// Cold ECUs and adapters answer noticeably slower, fixed ~1s timeout
+    Cold,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterFamily {
+    /// OBDLink and other STN chips, ELM327 plus the ST commands
+    Stn,
+    /// ELM327 or a clone
+    Elm327,
+    #[default]
+    Unknown,
+}
+
+/// CAN bus for a request, picked with `?bus=`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Bus {
+    /// HS-CAN, the protocol set up by `setup`
+    #[default]
+    Hs,
+    /// MS-CAN, STN adapters only
+    Ms,
+    /// SAE J1939 at 250 kbaud, heavy duty vehicles, see `j1939`
+    J1939,
+    /// SAE J1939 at 500 kbaud, STN adapters only
+    J1939Fast,
+}
+
+impl Bus {
+    /// From the `bus` query parameter, HS-CAN if there isn't one
+    pub fn from_param(value: Option<&str>) -> Result<Self, ElmError> {
+        match value {
+            None | Some("hs") => Ok(Bus::Hs),
+            Some("ms") => Ok(Bus::Ms),
+            Some("j1939") => Ok(Bus::J1939),
+            Some("j1939_500") => Ok(Bus::J1939Fast),
+            Some(bus) => Err(ElmError::InvalidRequest(format!(
+                "unknown bus ({bus}), hs, ms, j1939 or j1939_500"
+            ))),
+        }
+    }
+}
+
+/// What the adapter supports, so features needing ST commands can be skipped on clones
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Capabilities {
+    pub family: AdapterFamily,
+    /// ATI response, e.g. `ELM327 v1.4b`
+    pub version: String,
+    /// STI response for STN adapters, e.g. `STN2255 v5.6.19`
+    pub device: Option<String>,
+    pub st_commands: bool,
+    /// ATFCSM flow control setup
+    pub flow_control: bool,
+    /// Unsolicited battery voltage alerts enabled
+    pub voltage_alerts: bool,
+    /// UART rate switched to with STBR, None for the adapter's power on rate
+    pub baud_rate: Option<u32>,
+}
+
+pub struct Elm327<'d> {
+    port: Box<dyn ElmTransport + 'd>,
+    timing: TimingProfile,
+    /// Used for `TimingProfile::Normal`
+    timeouts: TimeoutConfig,
+    capabilities: Capabilities,
+    /// Alerts split out of the responses, waiting for `take_alerts`
+    alerts: Vec<Alert>,
+    /// Partial unsolicited line received while idle
+    idle_buf: Vec<u8>,
+    /// Replaces the built in protocol and formatting commands, see `init_script`
+    init_script: Vec<String>,
+    /// Checks every request written while set, see `guarded`
+    guard: Option<Arc<dyn RequestGuard>>,
+    /// In low power with the link closed, see `low_power`
+    asleep: bool,
+    /// The last response went over `MAX_RESPONSE_LEN` or was cut at the response deadline
+    truncated: bool,
+    /// The bus the adapter is on, see `select_bus`
+    bus: Bus,
+    /// Set by `set_header`, None for the bus's default
+    header: Option<String>,
+    /// HS-CAN protocol and header as `setup` left them, sent when switching back to it
+    hs_protocol: String,
+    hs_header: String,
+    /// Non default UDS sessions the worker sends tester present to
+    keep_alive: KeepAlive,
+    /// Records or replays the requests, see `session`
+    session: Option<Arc<Session>>,
+    /// The vehicle request written last while a session records or replays, the next response
+    /// read is its response
+    exchange: Option<Vec<u8>>,
+    /// When the request being answered started writing, for the latency histograms
+    written_at: Option<Instant>,
+    /// Why the adapter looks to be in a bootloader or programming state, see `programming`
+    programming: Option<String>,
+    /// UART rate `setup` switches STN adapters to, see `set_baud_rate`
+    baud_rate: Option<u32>,
+    /// Sent by `setup`, see `set_flow_control`
+    flow_control: FlowControlConfig,
+    /// When a request was last written, see `quiet_for`
+    last_write: Instant,
+    /// The ECU was seen to only answer one PID per request, see `pid::request_many`. Until the
+    /// next setup or reconnect, which may be another vehicle.
+    single_pid_only: bool,
+}
+
+impl<'d> Elm327<'d> {
+    pub fn new(port: impl ElmTransport + 'd) -> Self {
+        Elm327 {
+            port: Box::new(port),
+            timing: TimingProfile::Normal,
+            timeouts: TimeoutConfig::default(),
+            capabilities: Capabilities::default(),
+            alerts: Vec::new(),
+            idle_buf: Vec::new(),
+            init_script: Vec::new(),
+            guard: None,
+            asleep: false,
+            truncated: false,
+            bus: Bus::Hs,
+            header: None,
+            hs_protocol: "STP 34".to_owned(),
+            hs_header: DEFAULT_HEADER.to_owned(),
+            keep_alive: KeepAlive::default(),
+            session: None,
+            exchange: None,
+            written_at: None,
+            programming: None,
+            baud_rate: None,
+            flow_control: FlowControlConfig::default(),
+            last_write: Instant::now(),
+            single_pid_only: false,
+        }
+    }
+
+    /// Record the requests and responses to `session`, or answer from it while it replays
+    pub fn set_session(&mut self, session: Arc<Session>) {
+        self.session = Some(session);
+    }
+
+    /// Commands sent by `setup` after the reset in place of the built in ones, empty for the
+    /// built in ones. Used from the next setup.
+    pub fn set_init_script(&mut self, commands: Vec<String>) {
+        self.init_script = commands;
+    }
+
+    /// UART rate for STN adapters from the next setup, for more throughput when streaming with
+    /// `/monitor`. None stays at the power on rate.
+    pub fn set_baud_rate(&mut self, baud: Option<u32>) {
+        self.baud_rate = baud;
+    }
+
+    /// Flow control for long responses, used from the next setup
+    pub fn set_flow_control(&mut self, flow_control: FlowControlConfig) {
+        self.flow_control = flow_control;
+    }
+
+    /// Reset the adapter and set it up for the gateway. Clears the programming state, which comes
+    /// back if the reset fails.
+    pub fn setup(&mut self) -> Result<()> {
+        let programming = self.programming.take();
+
+        let mut result = self.init();
+
+        // The gateway restarted without the adapter losing power, e.g. a panic or an update, so
+        // it's still at the rate an earlier setup switched it to. The ATZ there takes it back.
+        if let (Err(err), Some(baud)) = (&result, self.baud_rate) {
+            if self.capabilities.baud_rate.is_none() {
+                warn!("Setup failed at the power on rate, trying ({baud}) baud {err:#}");
+
+                self.programming = None;
+                self.port.set_baud_rate(Some(baud))?;
+                self.capabilities.baud_rate = Some(baud);
+
+                result = self.init();
+                if result.is_err() && self.capabilities.baud_rate.take().is_some() {
+                    self.port.set_baud_rate(None)?;
+                }
+            }
+        }
+
+        if result.is_err() && self.programming.is_none() {
+            self.programming = programming;
+        }
+
+        result
+    }
+
+    /// Set up with the settings `change` makes, e.g. a new init script. If the adapter refuses
+    /// them the earlier settings go back and it's set up again with those, so a setting it
+    /// doesn't take is never kept.
+    pub fn setup_with(&mut self, change: impl FnOnce(&mut Self)) -> Result<()> {
+        let earlier = (
+            self.init_script.clone(),
+            self.timeouts,
+            self.baud_rate,
+            self.flow_control.clone(),
+        );
+
+        change(self);
+
+        let result = self.setup();
+        if result.is_err() {
+            (
+                self.init_script,
+                self.timeouts,
+                self.baud_rate,
+                self.flow_control,
+            ) = earlier;
+
+            if let Err(err) = self.setup() {
+                error!("Setup with the earlier settings failed {err:#}");
+            }
+        }
+
+        result
+    }
+
+    fn init(&mut self) -> Result<()> {
+        // Turn off any monitoring, and wait for response line
+        self.write_request(b"??")?;
+        self.read_response()?;
+
+        // Reset elm327. No banner means it isn't running the ELM firmware, e.g. a loader.
+        self.write_request(b"ATZ")?;
+        // The reset goes back to the power on rate
+        if self.capabilities.baud_rate.take().is_some() {
+            self.port.set_baud_rate(None)?;
+        }
+        let banner = match self.read_response() {
+            Ok(banner) if !matches!(banner.trim(), "" | "?") => Ok(banner),
+            Ok(banner) => Err(anyhow!("No banner ({})", banner.trim())),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = banner {
+            error!("ATZ failed, adapter may be in a programming state {err:#}");
+            self.programming = Some("ATZ failed".to_owned());
+            return Err(err.context(ElmError::Programming("ATZ failed".to_owned())));
+        }
+
+        // Turn off echo
+        self.write_request(b"ATE 0")?;
+        self.read_response()?;
+
+        // Back on the protocol the setup sends, the ECUs drop any sessions after the reset
+        self.bus = Bus::Hs;
+        self.header = None;
+        self.keep_alive.clear();
+        self.single_pid_only = false;
+
+        // Generic ELM clones reject the ST commands
+        self.capabilities = self.detect_capabilities()?;
+
+        // A failed switch leaves the link at the power on rate, slower but working
+        if let Some(baud) = self.baud_rate.filter(|_| self.capabilities.st_commands) {
+            if let Err(err) = self.switch_baud_rate(baud) {
+                warn!("Staying at the power on baud rate {err:#}");
+            }
+        }
+
+        if self.init_script.is_empty() {
+            self.default_init()?;
+        } else {
+            self.run_init_script()?;
+        }
+
+        // Battery alerts even when nothing is being polled
+        if self.capabilities.st_commands {
+            self.write_request(VOLTAGE_ALERT_COMMAND)?;
+            self.capabilities.voltage_alerts = self.read_response()?.trim() == "OK";
+        }
+
+        // Keep the timing across a reset, which goes back to the ELM327 defaults
+        if self.timing != TimingProfile::Normal || self.timeouts != TimeoutConfig::default() {
+            self.set_timing(self.timing)?;
+        }
+
+        // After the init script, which may set up flow control itself. A clone that claims v1.4
+        // but refuses the commands keeps its own, rather than failing every setup.
+        if self.flow_control != FlowControlConfig::default() {
+            if !self.capabilities.flow_control {
+                warn!("Adapter can't set up flow control, using its own");
+            } else if let Err(err) = self.send_flow_control(&self.flow_control.clone()) {
+                if !matches!(err.downcast_ref(), Some(ElmError::InvalidRequest(_))) {
+                    return Err(err);
+                }
+
+                warn!("Adapter refused the flow control, using its own {err:#}");
+                self.send_flow_control(&FlowControlConfig::default())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// STBR handshake: the adapter answers OK, switches and sends its ID at the new rate, and
+    /// stays there if the gateway answers with a CR within STBRT. Otherwise it goes back to the
+    /// old rate, and so does the link.
+    fn switch_baud_rate(&mut self, baud: u32) -> Result<()> {
+        let timeout = format!("STBRT {}", BAUD_SWITCH_TIMEOUT.as_millis());
+        self.write_unchecked(timeout.as_bytes())?;
+        self.read_response()?;
+
+        self.write_unchecked(format!("STBR {baud}").as_bytes())?;
+        let answer = self.read_line()?;
+        if answer != "OK" {
+            // The rest up to the prompt
+            self.read_response()?;
+            Err(ElmError::InvalidRequest(format!(
+                "STBR {baud} refused ({answer})"
+            )))?;
+        }
+
+        self.port.set_baud_rate(Some(baud))?;
+
+        let confirmed = match self.read_line() {
+            Ok(id) if !id.is_empty() && Self::wedged_reason(&id).is_none() => {
+                self.port.write_elm_request(b"")?;
+                matches!(self.read_response(), Ok(response) if response.trim() == "OK")
+            }
+            _ => false,
+        };
+
+        if !confirmed {
+            self.port.set_baud_rate(None)?;
+            thread::sleep(BAUD_SWITCH_TIMEOUT * 2);
+            let mut buf = [0u8; 20];
+            while self.port.try_read(&mut buf)? > 0 {}
+
+            Err(anyhow!("No answer at ({baud}) baud"))?;
+        }
+
+        info!("Adapter link at ({baud}) baud");
+        self.capabilities.baud_rate = Some(baud);
+
+        Ok(())
+    }
+
+    /// One line without waiting for a prompt, for the STBR handshake
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        let mut buf = [0u8; 1];
+
+        while line.len() < MAX_LINE_LEN {
+            self.port
+                .read_exact(&mut buf)
+                .map_err(ReadObdError::IOError)
+                .context("read line")?;
+
+            match buf[0] {
+                b'\r' if !line.is_empty() => break,
+                b'\r' | b'\n' => {}
+                b => line.push(b),
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&line).trim().to_owned())
+    }
+
+    /// Protocol and formatting for the RAM Promaster, when there's no init script
+    fn default_init(&mut self) -> Result<()> {
+        // RAM Promaster protocol - ISO 15765-4 CAN (29 bit ID, 500 Kbaud)
+        let protocol = match self.capabilities.st_commands {
+            true => "STP 34",
+            false => "ATSP 7",
+        };
+        self.write_request(protocol.as_bytes())?;
+        self.read_response()?;
+        self.hs_protocol = protocol.to_owned();
+
+        // Display headers
+        self.write_request(b"ATH 1")?;
+        self.read_response()?;
+
+        // Auto formatting
+        self.write_request(b"ATCAF 1")?;
+        self.read_response()?;
+
+        // Use spaces
+        self.write_request(b"ATS 1")?;
+        self.read_response()?;
+
+        // So far, all service requests are for module 10
+        self.hs_header = DEFAULT_HEADER.to_owned();
+        self.write_request(DEFAULT_HEADER.as_bytes())?;
+        self.read_response()?;
+
+        Ok(())
+    }
+
+    fn run_init_script(&mut self) -> Result<()> {
+        info!("Running the adapter init script");
+
+        let mut protocol = None;
+        let mut header = None;
+
+        for command in self.init_script.clone() {
+            self.write_request(command.trim().as_bytes())?;
+            let response = self.read_response()?;
+
+            if response.trim() == "?" {
+                Err(ElmError::InvalidRequest(format!(
+                    "init command ({command}) rejected"
+                )))?;
+            }
+
+            let normalised = normalise(command.as_bytes());
+            if is_protocol_command(&normalised) {
+                protocol = Some(command.trim().to_owned());
+            } else if normalised.starts_with("ATSH") {
+                header = Some(command.trim().to_owned());
+            }
+        }
+
+        // A script that leaves the protocol alone gets whatever the adapter is on, asked for
+        // so a switch back to HS-CAN goes to it rather than to the Promaster's
+        self.hs_protocol = match protocol {
+            Some(protocol) => protocol,
+            None => {
+                self.write_request(b"ATDPN")?;
+                format!("ATSP {}", self.read_response()?.trim())
+            }
+        };
+        // The adapter has no command for its power on header, so without one in the script
+        // it's the Promaster's
+        self.hs_header = header.unwrap_or_else(|| DEFAULT_HEADER.to_owned());
+
+        Ok(())
+    }
+
+    /// What the adapter supports, detected by `setup`
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Why the adapter looks to be in a bootloader or programming state, e.g. after a baud rate
+    /// change or a failed ATZ. Every write is refused until it's set up again, an `ATZ` or
+    /// `ATWS` request does that.
+    pub fn programming(&self) -> Option<&str> {
+        self.programming.as_deref()
+    }
+
+    /// The bus the adapter is on
+    pub fn bus(&self) -> Bus {
+        self.bus
+    }
+
+    /// The header from `set_header`, None for the bus's default
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    /// Time since a request was last written to the adapter, by anyone
+    pub fn quiet_for(&self) -> Duration {
+        self.last_write.elapsed()
+    }
+
+    /// A request that leaves the vehicle alone, so an adapter that sleeps after a while without
+    /// traffic (the OBDLink MX+) stays awake. STSLCS on STN adapters, ATI on the rest.
+    pub fn ping(&mut self) -> Result<()> {
+        let command = if self.capabilities.st_commands {
+            "STSLCS"
+        } else {
+            "ATI"
+        };
+
+        let response = self.transact(command.as_bytes())?;
+        if response.trim() == "?" {
+            Err(anyhow!("Ping refused ({command})"))?;
+        }
+        metrics::KEEP_AWAKE_PINGS.inc();
+
+        Ok(())
+    }
+
+    /// The UDS sessions open through this adapter, see `uds::keep_alive`
+    pub fn keep_alive(&mut self) -> &mut KeepAlive {
+        &mut self.keep_alive
+    }
+
+    /// Identify the adapter family from the ATI and STI responses
+    fn detect_capabilities(&mut self) -> Result<Capabilities> {
+        self.write_request(b"ATI")?;
+        let version = self.read_response()?.trim().to_owned();
+
+        // Only STN based adapters (OBDLink) know STI, others answer '?'
+        self.write_request(b"STI")?;
+        let device = self.read_response()?.trim().to_owned();
+        let stn = device.starts_with("STN");
+
+        let elm_version = version
+            .rsplit_once('v')
+            .and_then(|(_, v)| v.trim_end_matches(|c: char| c.is_alphabetic()).parse().ok())
+            .unwrap_or(0.0f32);
+
+        let capabilities = Capabilities {
+            family: if stn {
+                AdapterFamily::Stn
+            } else if version.starts_with("ELM327") {
+                AdapterFamily::Elm327
+            } else {
+                AdapterFamily::Unknown
+            },
+            version,
+            device: stn.then_some(device),
+            st_commands: stn,
+            // ATFCSM arrived in ELM327 v1.4
+            flow_control: stn || elm_version >= 1.4,
+            voltage_alerts: false,
+            baud_rate: None,
+        };
+
+        info!(
+            "Adapter ({}) {:?}, ST commands ({})",
+            capabilities.version, capabilities.family, capabilities.st_commands
+        );
+
+        Ok(capabilities)
+    }
+
+    /// Close the link to the adapter, requests fail until it reconnects
+    pub fn disconnect(&mut self) {
+        info!("Disconnecting from the adapter");
+        self.port.disconnect();
+        self.keep_alive.clear();
+    }
+
+    /// Connect to the adapter again, e.g. to pair after its bond was removed. The adapter
+    /// keeps its setup unless it lost power.
+    pub fn reconnect(&mut self) -> Result<()> {
+        info!("Reconnecting to the adapter ({})", self.port.name());
+        self.single_pid_only = false;
+        self.port.reconnect()
+    }
+
+    /// True once the ECU is seen to only answer one PID per request
+    pub fn single_pid_only(&self) -> bool {
+        self.single_pid_only
+    }
+
+    pub fn set_single_pid_only(&mut self) {
+        self.single_pid_only = true;
+    }
+
+    /// Put the adapter in low power (ATLP) and close the link, so an idle adapter doesn't drain
+    /// the battery. `wake` brings it back.
+    pub fn low_power(&mut self) -> Result<()> {
+        info!("Adapter idle, going to low power");
+
+        let result = self.transact(b"ATLP");
+        self.port.disconnect();
+        self.asleep = true;
+        self.keep_alive.clear();
+
+        match result?.trim() {
+            "OK" => Ok(()),
+            response => Err(anyhow!("Low power refused ({response})")),
+        }
+    }
+
+    /// True while `low_power` has the link closed
+    pub fn asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Reconnect and set the adapter up again after `low_power`. Stays asleep if it fails, so
+    /// the next request tries again.
+    pub fn wake(&mut self) -> Result<()> {
+        if !self.asleep {
+            return Ok(());
+        }
+
+        info!("Waking the adapter");
+
+        self.port.reconnect()?;
+        self.port.wait_connected()?;
+
+        // Any character wakes it, whatever it prints on the way up is dropped
+        self.port.write_elm_request(b"")?;
+        thread::sleep(WAKE_DELAY);
+        let mut buf = [0u8; 20];
+        while self.port.try_read(&mut buf)? > 0 {}
+
+        self.setup()?;
+        self.asleep = false;
+        metrics::ADAPTER_WAKES.inc();
+
+        Ok(())
+    }
+
+    /// Battery voltage at the OBD port (ATRV), e.g. `12.6V`
+    pub fn battery_voltage(&mut self) -> Result<f32> {
+        let response = self.transact(b"ATRV")?;
+
+        response
+            .trim()
+            .trim_end_matches('V')
+            .parse()
+            .map_err(|_| ElmError::NoData(format!("voltage ({response})")).into())
+    }
+
+    /// Alerts received since the last call, from responses or `poll_unsolicited`
+    pub fn take_alerts(&mut self) -> Vec<Alert> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    /// Pick up unsolicited messages sent while no request is active. Won't block.
+    pub fn poll_unsolicited(&mut self) -> Result<()> {
+        if !self.capabilities.voltage_alerts {
+            return Ok(());
+        }
+
+        let mut buf = [0u8; 20];
+        loop {
+            let bytes_read = self.port.try_read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            self.idle_buf
+                .extend(buf[..bytes_read].iter().filter(|b| **b != b'>'));
+        }
+
+        // Keep any partial line for the next poll
+        let Some(end) = self.idle_buf.iter().rposition(|b| *b == b'\r') else {
+            return Ok(());
+        };
+
+        let lines = self.idle_buf.drain(..=end).collect::<Vec<_>>();
+        let rest = self.demux(lines);
+
+        if rest.iter().any(|b| !b.is_ascii_whitespace()) {
+            debug!("Unsolicited ({})", String::from_utf8_lossy(&rest));
+        }
+
+        Ok(())
+    }
+
+    /// Split the alert lines out of a response, keeping them for `take_alerts`
+    fn demux(&mut self, response: Vec<u8>) -> Vec<u8> {
+        if !self.capabilities.voltage_alerts {
+            return response;
+        }
+
+        let mut rest = Vec::with_capacity(response.len());
+
+        for line in response.split_inclusive(|b| *b == b'\r') {
+            match std::str::from_utf8(line)
+                .ok()
+                .and_then(|l| Alert::parse(l.trim(), uptime_ms()))
+            {
+                Some(alert) => self.alerts.push(alert),
+                None => rest.extend_from_slice(line),
+            }
+        }
+
+        rest
+    }
+
+    pub fn timing(&self) -> TimingProfile {
+        self.timing
+    }
+
+    /// Change the adapter timeouts
+    pub fn set_timing(&mut self, timing: TimingProfile) -> Result<()> {
+        let timeouts = match timing {
+            TimingProfile::Normal => self.timeouts,
+            TimingProfile::Cold => TimeoutConfig::fixed(timeouts::MAX_TIMEOUT_MS),
+        };
+        self.send_timeouts(&timeouts)?;
+
+        self.timing = timing;
+
+        Ok(())
+    }
+
+    /// Timeouts for the normal profile, used from the next `set_timing` or setup
+    pub fn set_timeouts(&mut self, timeouts: TimeoutConfig) {
+        self.timeouts = timeouts;
+    }
+
+    /// Run `f` with a fixed adapter timeout, then go back to the current timing. None runs it
+    /// with the current timing, e.g. for a request without `X-Elm-Timeout`.
+    pub fn with_timeout<R>(
+        &mut self,
+        timeout_ms: Option<u32>,
+        f: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        let Some(timeout_ms) = timeout_ms else {
+            return f(self);
+        };
+
+        self.send_timeouts(&TimeoutConfig::fixed(timeout_ms))?;
+
+        let result = f(self);
+
+        if let Err(err) = self.set_timing(self.timing) {
+            error!("Failed to restore the timeouts {err}");
+        }
+
+        result
+    }
+
+    /// Run `f` with its own flow control, then go back to the configured one. None runs it with
+    /// the configured one, e.g. for a request without `X-Flow-Control`.
+    pub fn with_flow_control<R>(
+        &mut self,
+        flow_control: Option<FlowControlConfig>,
+        f: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        let Some(flow_control) = flow_control else {
+            return f(self);
+        };
+
+        if !self.capabilities.flow_control {
+            Err(ElmError::InvalidRequest(
+                "Flow control needs ELM327 v1.4 or an STN adapter".to_owned(),
+            ))?;
+        }
+
+        self.send_flow_control(&flow_control)?;
+
+        let result = f(self);
+
+        if let Err(err) = self.send_flow_control(&self.flow_control.clone()) {
+            error!("Failed to restore the flow control {err}");
+        }
+
+        result
+    }
+
+    fn send_flow_control(&mut self, flow_control: &FlowControlConfig) -> Result<()> {
+        for command in flow_control.commands() {
+            self.write_unchecked(command.as_bytes())?;
+            let response = self.read_response()?;
+            if response.trim() == "?" {
+                Err(ElmError::InvalidRequest(format!("{command} refused")))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_timeouts(&mut self, timeouts: &TimeoutConfig) -> Result<()> {
+        for command in timeouts.commands() {
+            self.write_unchecked(command.as_bytes())?;
+            self.read_response()?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a raw CAN payload with an explicit header and return every received frame
+    /// unfiltered, one per line.
+    ///
+    /// CAN auto formatting is turned off for the request so the payload must include the PCI
+    /// byte(s), e.g. `03 22 F1 90`. Formatting and the default header are restored afterwards.
+    pub fn raw_request(&mut self, header: &str, data: &str) -> Result<Vec<String>> {
+        if !is_hex(header) || !is_hex(data) {
+            Err(ElmError::InvalidRequest(
+                "header and data must be hex".to_owned(),
+            ))?;
+        }
+
+        self.write_request(b"ATCAF 0")?;
+        self.read_response()?;
+
+        let frames = self.raw_frames(header, data);
+        let truncated = self.truncated;
+
+        // Always restore, even if the raw request failed
+        self.write_request(b"ATCAF 1")?;
+        self.read_response()?;
+        self.restore_header()?;
+
+        // For the frames, not the restore
+        self.truncated = truncated;
+        frames
+    }
+
+    fn raw_frames(&mut self, header: &str, data: &str) -> Result<Vec<String>> {
+        self.set_header(header)?;
+
+        // The policy rules are for the service, not the PCI byte in front of it
+        if let Some(guard) = &self.guard {
+            guard.check(data.trim_start().get(2..).unwrap_or_default().as_bytes())?;
+        }
+
+        self.write_unchecked(data.as_bytes())?;
+        self.read_lines()
+    }
+
+    /// Listen to the bus through STN pass and block filters for `duration` and return the frames
+    /// received, up to `max_frames`. Catches broadcast messages (e.g. body CAN) a request never
+    /// gets an answer with. The filters are cleared afterwards, which puts the adapter back on
+    /// its automatic request/response filtering.
+    pub fn monitor(
+        &mut self,
+        pass: &[StnFilter],
+        block: &[StnFilter],
+        duration: Duration,
+        max_frames: usize,
+    ) -> Result<Vec<String>> {
+        if !self.capabilities.st_commands {
+            Err(ElmError::InvalidRequest(
+                "monitoring needs an STN adapter (OBDLink)".to_owned(),
+            ))?;
+        }
+
+        let commands = pass
+            .iter()
+            .map(|filter| filter.command("STFAP"))
+            .chain(block.iter().map(|filter| filter.command("STFAB")))
+            .collect::<Result<Vec<_>>>()?;
+
+        let frames = self.monitor_frames(&commands, duration.min(MAX_MONITOR_DURATION), max_frames);
+
+        let truncated = self.truncated;
+
+        // Always clear, even if monitoring failed
+        self.clear_filters()?;
+
+        // For the frames, not the clearing
+        self.truncated = truncated;
+        frames
+    }
+
+    fn monitor_frames(
+        &mut self,
+        filters: &[String],
+        duration: Duration,
+        max_frames: usize,
+    ) -> Result<Vec<String>> {
+        self.clear_filters()?;
+
+        for filter in filters {
+            self.write_request(filter.as_bytes())?;
+            if self.read_response()?.trim() == "?" {
+                Err(ElmError::InvalidRequest(format!(
+                    "filter ({filter}) rejected"
+                )))?;
+            }
+        }
+
+        self.write_request(b"STM")?;
+
+        let deadline = Instant::now() + duration;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+
+        while Instant::now() < deadline
+            && received.iter().filter(|b| **b == b'\r').count() < max_frames
+        {
+            match self.port.try_read(&mut buf)? {
+                0 => thread::sleep(MONITOR_POLL),
+                n => received.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        // Any character stops monitoring, what's left arrives before the prompt
+        self.port.write_elm_request(b"")?;
+        received.retain(|b| *b != b'>');
+        received.extend(self.read_raw()?.0);
+
+        let received = String::from_utf8_lossy(&self.demux(received)).into_owned();
+
+        Ok(received
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && *line != "STOPPED")
+            .take(max_frames)
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn clear_filters(&mut self) -> Result<()> {
+        for command in [b"STFCP", b"STFCB"] {
+            self.write_request(command)?;
+            self.read_response()?;
+        }
+
+        Ok(())
+    }
+
+    /// Address requests to another module, e.g. `DA10F1`
+    pub fn set_header(&mut self, header: &str) -> Result<()> {
+        if !is_hex(header) {
+            Err(ElmError::InvalidRequest("header must be hex".to_owned()))?;
+        }
+
+        self.write_request(format!("ATSH {header}").as_bytes())?;
+        self.read_response()?;
+        self.header = Some(header.to_owned());
+
+        Ok(())
+    }
+
+    /// Only let responses from `address` through (ATCRA), e.g. `7E9`. None goes back to the
+    /// adapter's own filtering.
+    pub fn set_receive_address(&mut self, address: Option<&str>) -> Result<()> {
+        let command = match address {
+            Some(address) if is_hex(address) => format!("ATCRA {address}"),
+            Some(_) => Err(ElmError::InvalidRequest(
+                "receive address must be hex".to_owned(),
+            ))?,
+            None => "ATCRA".to_owned(),
+        };
+
+        self.write_request(command.as_bytes())?;
+        self.read_response()?;
+
+        Ok(())
+    }
+
+    /// Go back to the default header of the current bus, the one `setup` left on HS-CAN (module
+    /// 10 without an init script) and a PGN request on J1939
+    pub fn restore_header(&mut self) -> Result<()> {
+        let header = match self.bus {
+            Bus::Hs => self.hs_header.clone().into_bytes(),
+            Bus::Ms => MS_CAN_HEADER.to_vec(),
+            Bus::J1939 | Bus::J1939Fast => J1939_HEADER.to_vec(),
+        };
+        self.write_request(&header)?;
+        self.read_response()?;
+        self.header = None;
+
+        Ok(())
+    }
+
+    /// Switch to `bus` and its default header, HS-CAN to the protocol `setup` left. Nothing is
+    /// sent if the adapter is already on it, so it stays on a bus for a run of requests to it
+    /// and only switches when one asks for the other.
+    pub fn select_bus(&mut self, bus: Bus) -> Result<()> {
+        if bus == self.bus {
+            return Ok(());
+        }
+
+        let hs_protocol = self.hs_protocol.clone();
+        let protocol: &[u8] = match (bus, self.capabilities.st_commands) {
+            (Bus::Hs, _) => hs_protocol.as_bytes(),
+            (Bus::Ms, true) => MS_CAN_PROTOCOL,
+            (Bus::J1939, true) => b"STP 41",
+            (Bus::J1939, false) => b"ATSP A",
+            (Bus::J1939Fast, true) => b"STP 42",
+            (Bus::Ms | Bus::J1939Fast, false) => Err(ElmError::InvalidRequest(format!(
+                "{bus:?} needs an STN adapter"
+            )))?,
+        };
+
+        info!("Switching to {bus:?} CAN");
+
+        // The gateway's own command, not checked by the policy
+        self.write_unchecked(protocol)?;
+        if self.read_response()?.trim() == "?" {
+            Err(ElmError::InvalidRequest(format!(
+                "adapter rejected the {bus:?} CAN protocol"
+            )))?;
+        }
+
+        self.bus = bus;
+        self.restore_header()
+    }
+
+    /// Write the request and read the response keeping each frame on its own line
+    pub fn transact_lines(&mut self, request: &[u8]) -> Result<Vec<String>> {
+        self.write_request(request)?;
+        let lines = self.read_lines()?;
+
+        if let Some(line) = lines
+            .iter()
+            .find(|line| BUS_ERRORS.iter().any(|error| line.contains(error)))
+        {
+            Err(ElmError::BusError(line.clone()))?;
+        }
+
+        Ok(lines)
+    }
+
+    /// Write the request and read the response, supervising the adapter.
+    ///
+    /// If the response shows the adapter has wedged (see `WEDGED_RESPONSES`) or is garbage, the
+    /// adapter is reset and set up again, and an `ElmError::AdapterReset` is returned so the
+    /// caller can retry the request.
+    pub fn transact(&mut self, request: &[u8]) -> Result<String> {
+        // Out of a programming state only through a full setup, so the formatting is back too.
+        // Answered with the banner ATZ would print.
+        if self.programming.is_some() && REINIT_COMMANDS.contains(&normalise(request).as_str()) {
+            info!("Setting the adapter up again after programming");
+            self.setup()?;
+            return Ok(self.capabilities.version.clone());
+        }
+
+        self.write_request(request)?;
+
+        let reason = match self.read_response() {
+            Ok(response) => match Self::wedged_reason(&response) {
+                Some(reason) => reason,
+                None => return Ok(response),
+            },
+            Err(err) if err.downcast_ref::<FromUtf8Error>().is_some() => "garbage".to_owned(),
+            Err(err) => return Err(err),
+        };
+
+        error!("Adapter wedged ({reason}), resetting");
+        metrics::ELM_RESETS.inc();
+
+        self.setup().context("adapter recovery")?;
+
+        Err(ElmError::AdapterReset(reason).into())
+    }
+
+    /// The session while it replays
+    fn replaying(&self) -> Option<&Arc<Session>> {
+        self.session
+            .as_ref()
+            .filter(|session| session.mode() == SessionMode::Replay)
+    }
+
+    fn wedged_reason(response: &str) -> Option<String> {
+        if let Some(wedged) = WEDGED_RESPONSES.iter().find(|w| response.contains(*w)) {
+            return Some((*wedged).to_owned());
+        }
+
+        if response.chars().any(|c| c.is_control() && c != '\t') {
+            return Some("garbage".to_owned());
+        }
+
+        None
+    }
+
+    /// Run `f` with every request it writes checked against `guard`, for work from HTTP
+    /// clients. None is for clients that sent the unlock token.
+    pub fn guarded<G: RequestGuard + 'static, R>(
+        &mut self,
+        guard: Option<Arc<G>>,
+        f: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        self.guard = guard.map(|guard| guard as Arc<dyn RequestGuard>);
+        let result = f(self);
+        self.guard = None;
+
+        result
+    }
+
+    /// Write the request to the OBDLink
+    pub fn write_request(&mut self, request: &[u8]) -> Result<()> {
+        if let Some(guard) = &self.guard {
+            guard.check(request)?;
+        }
+
+        let command = normalise(request);
+        if command.starts_with(SAVE_BAUD_COMMAND) {
+            Err(ElmError::InvalidRequest(format!(
+                "{SAVE_BAUD_COMMAND} would leave the adapter at a rate the link doesn't start at"
+            )))?;
+        }
+
+        self.write_unchecked(request)?;
+
+        // `setup` switches the rate itself, see `set_baud_rate`
+        if BAUD_COMMANDS.iter().any(|baud| command.starts_with(baud)) {
+            warn!("Baud rate change ({command}), writes refused until set up again");
+            self.programming = Some(format!("baud rate change ({command})"));
+        }
+
+        Ok(())
+    }
+
+    fn write_unchecked(&mut self, request: &[u8]) -> Result<()> {
+        if let Some(reason) = &self.programming {
+            Err(ElmError::Programming(reason.clone()))?;
+        }
+
+        debug!("Write string ({})", String::from_utf8_lossy(request));
+        metrics::ELM_REQUESTS.inc();
+
+        // Requests to the vehicle are recorded or replayed, adapter commands always go to the
+        // adapter so it stays set up
+        self.exchange = self
+            .session
+            .as_ref()
+            .filter(|session| session.mode() != SessionMode::Off)
+            .filter(|_| {
+                let command = normalise(request);
+                !command.starts_with("AT") && !command.starts_with("ST")
+            })
+            .map(|_| request.to_vec());
+        if self.exchange.is_some() && self.replaying().is_some() {
+            self.written_at = None;
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        self.port.write_elm_request(request)?;
+        metrics::ELM_WRITE_LATENCY.observe(start.elapsed());
+        self.written_at = Some(start);
+        self.last_write = start;
+
+        Ok(())
+    }
+
+    /// Read a complete OBDLink response. Will block until we get the total response, which
+    /// will not include the trailing '>' and '\r'. A response cut at the deadline fails with
+    /// `ElmError::ResponseTimeout`, a single value is no use in part.
+    pub fn read_response(&mut self) -> Result<String> {
+        let (mut response, cut) = self.read_raw()?;
+        if cut {
+            Err(ElmError::ResponseTimeout(
+                String::from_utf8_lossy(&response).trim().to_owned(),
+            ))?;
+        }
+        response.retain(|b| *b != b'\r' && *b != b'\n');
+
+        let response = String::from_utf8(response)?;
+
+        debug!("Response string ({response})");
+
+        // Send data to the ESPNOW handler via channel
+
+        Ok(response)
+    }
+
+    /// Read a complete OBDLink response keeping each line, e.g. one line per CAN frame. A
+    /// response cut at the deadline returns the frames received so far with `truncated` set.
+    pub fn read_lines(&mut self) -> Result<Vec<String>> {
+        let (response, cut) = self.read_raw()?;
+        let mut response = String::from_utf8(response)?;
+        if cut {
+            // The last frame stopped part way through
+            response.truncate(response.rfind(['\r', '\n']).unwrap_or(0));
+        }
+
+        debug!("Response lines ({response:?})");
+
+        Ok(response
+            .split(['\r', '\n'])
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Whether the last response was cut at `MAX_RESPONSE_LEN` or the response deadline, the
+    /// rest was dropped
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Read up to the '>' prompt, the prompt is not included. Each read waits up to the
+    /// transport's read timeout, the whole response has to arrive by the `MAX_RESPONSE_TIME`
+    /// deadline, or `MAX_SEARCH_TIME` once the adapter says it's searching. A response still
+    /// arriving at the deadline is stopped and returned as far as it got, with true.
+    fn read_raw(&mut self) -> Result<(Vec<u8>, bool)> {
+        let mut response: Vec<u8> = Vec::new();
+        let start = Instant::now();
+        let mut searching = false;
+        self.truncated = false;
+        let written_at = self.written_at.take();
+        let exchange = self.exchange.take();
+
+        if let Some((session, request)) = self.replaying().zip(exchange.as_ref()) {
+            return Ok((session.replay(request)?.join("\r").into_bytes(), false));
+        }
+
+        loop {
+            let limit = if searching {
+                MAX_SEARCH_TIME
+            } else {
+                MAX_RESPONSE_TIME
+            };
+            if start.elapsed() >= limit {
+                let partial = String::from_utf8_lossy(&response).trim().to_owned();
+                metrics::ELM_ERRORS.inc();
+                if searching {
+                    Err(ElmError::SearchTimeout(partial))?;
+                } else if partial.is_empty() {
+                    Err(ElmError::ResponseTimeout(partial))?;
+                }
+
+                warn!("Response still arriving at the deadline, stopping the adapter");
+                self.stop_response()?;
+                self.truncated = true;
+                let response = self.demux(response);
+                return Ok((Self::classify(response)?, true));
+            }
+
+            let mut buf = [0u8; 20];
+
+            let bytes_read = match self.port.read(&mut buf) {
+                Ok(n) => n,
+                // The search can be quiet for longer than the read timeout
+                Err(err) if searching && err.kind() == io::ErrorKind::TimedOut => 0,
+                Err(err) => {
+                    trace!("Read error {err:?}");
+                    metrics::ELM_ERRORS.inc();
+                    Err(ReadObdError::IOError(err)).context("read data")?
+                }
+            };
+
+            trace!("Response buffer ({:?})", &buf[..bytes_read]);
+
+            if bytes_read > 0 && response.is_empty() {
+                if let Some(written_at) = written_at {
+                    metrics::ELM_FIRST_BYTE_LATENCY.observe(written_at.elapsed());
+                }
+            }
+
+            // Read on to the prompt, so the rest isn't taken as the next response
+            if response.len() < MAX_RESPONSE_LEN {
+                response.extend(buf[..bytes_read].iter().filter(|b| **b != b'>'));
+            } else if !self.truncated {
+                error!("Response over ({MAX_RESPONSE_LEN}) bytes, dropping the rest");
+                self.truncated = true;
+            }
+
+            if !searching && (contains(&response, SEARCHING) || contains(&response, BUS_INIT)) {
+                debug!("Adapter searching for the protocol");
+                searching = true;
+            }
+
+            if bytes_read > 0 && buf[bytes_read - 1] == b'>' {
+                break;
+            }
+        }
+
+        if let Some(written_at) = written_at {
+            metrics::ELM_RESPONSE_LATENCY.observe(written_at.elapsed());
+        }
+
+        let response = self.demux(response);
+        let response = Self::classify(response)?;
+
+        if let Some((session, request)) = self.session.as_ref().zip(exchange) {
+            let lines = String::from_utf8_lossy(&response)
+                .split(['\r', '\n'])
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            session.record(&request, &lines);
+        }
+
+        Ok((response, false))
+    }
+
+    /// Any character stops the adapter sending, drop the rest up to the prompt so it isn't
+    /// taken as the next response
+    fn stop_response(&mut self) -> Result<()> {
+        self.port.write_elm_request(b"")?;
+
+        let deadline = Instant::now() + STOP_TIME;
+        let mut buf = [0u8; 64];
+
+        while Instant::now() < deadline {
+            match self.port.try_read(&mut buf)? {
+                0 => thread::sleep(MONITOR_POLL),
+                n if buf[..n].contains(&b'>') => return Ok(()),
+                _ => {}
+            }
+        }
+
+        Err(ElmError::ResponseTimeout("no prompt after stopping".to_owned()).into())
+    }
+
+    /// Drop the interim lines of a protocol search or bus init, and turn the outcomes where the
+    /// request never reached the vehicle into errors
+    fn classify(response: Vec<u8>) -> Result<Vec<u8>> {
+        let mut rest = Vec::with_capacity(response.len());
+
+        for line in response.split_inclusive(|b| *b == b'\r') {
+            let text = String::from_utf8_lossy(line);
+            let text = text.trim();
+
+            if text.contains(UNABLE_TO_CONNECT) {
+                Err(ElmError::UnableToConnect)?;
+            }
+
+            if text.starts_with(BUS_INIT) && text.contains("ERROR") {
+                Err(ElmError::BusInit(text.to_owned()))?;
+            }
+
+            if text == SEARCHING || (text.starts_with(BUS_INIT) && text.ends_with("OK")) {
+                continue;
+            }
+
+            rest.extend_from_slice(line);
+        }
+
+        Ok(rest)
+    }
+}
+
+fn contains(response: &[u8], text: &str) -> bool {
+    response
+        .windows(text.len())
+        .any(|window| window == text.as_bytes())
+}
+
+/// Upper case without whitespace, so "04" also matches "04 " and "ATPC" matches "at pc"
+pub fn normalise(request: &[u8]) -> String {
+    String::from_utf8_lossy(request)
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// `ATSP`/`ATTP` with a protocol or `STP` with its number, normalised. Not `STPC`, `STPX` and
+/// the like.
+fn is_protocol_command(command: &str) -> bool {
+    let number =
+        |protocol: &str| !protocol.is_empty() && protocol.chars().all(|c| c.is_ascii_digit());
+
+    match command.strip_prefix("STP") {
+        Some(protocol) => number(protocol),
+        None => ["ATSP", "ATTP"]
+            .iter()
+            .any(|prefix| command.strip_prefix(prefix).is_some_and(is_hex)),
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.trim().is_empty() && s.chars().all(|c| c.is_ascii_hexdigit() || c == ' ')
+}
+
+/// A complete message reassembled from the CAN frames of one module
+#[derive(Debug)]
+pub struct Message {
+    /// Responding module header, e.g. `18DAF110` or `7E8`
+    pub header: String,
+    pub data: Vec<u8>,
+}
+
+/// Reassemble ISO 15765 frames (headers on, spaces on, auto formatting) into messages.
+///
+/// Each line is `header PCI data...`, the header is a single 11 bit token (`7E8`) or four 29 bit
+/// bytes (`18 DA F1 10`). Frames are grouped by header so responses from several modules are
+/// kept apart. Lines that aren't frames, e.g. `SEARCHING...`, are skipped.
+pub fn parse_messages(lines: &[String]) -> Vec<Message> {
+    let mut messages: Vec<(Message, usize)> = Vec::new();
+
+    for line in lines {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        let header_len = match tokens.first() {
+            Some(t) if t.len() == 3 => 1,
+            Some(_) => 4,
+            None => continue,
+        };
+
+        if tokens.len() <= header_len {
+            continue;
+        }
+
+        let header = tokens[..header_len].concat();
+        let Some(bytes) = tokens[header_len..]
+            .iter()
+            .map(|t| u8::from_str_radix(t, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+        else {
+            continue;
+        };
+
+        match bytes[0] >> 4 {
+            // Single frame
+            0 => {
+                let len = (bytes[0] & 0x0F) as usize;
+                let data = bytes[1..].iter().take(len).copied().collect();
+                messages.push((Message { header, data }, len));
+            }
+            // First frame
+            1 if bytes.len() > 1 => {
+                let len = (((bytes[0] & 0x0F) as usize) << 8) | bytes[1] as usize;
+                let data = bytes[2..].iter().take(len).copied().collect();
+                messages.push((Message { header, data }, len));
+            }
+            // Consecutive frame, add to the last unfinished message from this module
+            2 => {
+                if let Some((message, len)) = messages
+                    .iter_mut()
+                    .rev()
+                    .find(|(m, len)| m.header == header && m.data.len() < *len)
+                {
+                    let remaining = *len - message.data.len();
+                    message
+                        .data
+                        .extend(bytes[1..].iter().take(remaining).copied());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    messages.into_iter().map(|(message, _)| message).collect()
+}
@@ -0,0 +1,372 @@
+//! ELM worker thread. HTTP handlers queue their adapter work here and wait for the reply with a
+//! timeout, rather than taking the adapter lock and blocking on the SPP read themselves, so a
+//! slow or stuck adapter never ties up a server worker and its small stack.
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+
+use crate::ecus::{self, Ecu};
+use crate::elm327::{Bus, Elm327};
+use crate::error::WorkerError;
+use crate::metrics;
+use crate::response_cache::ResponseCache;
+use crate::uds;
+
+/// `high`, `normal` or `bulk`, overrides the endpoint's default priority
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+/// Requests of one priority waiting for the adapter before new ones are turned away
+const QUEUE_LEN: usize = 4;
+/// Longest a handler waits, time spent queued included
+const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+const STACK_SIZE: usize = 8192;
+
+/// Which requests the worker takes first. A request only waits behind those of a higher
+/// priority, and for the one in progress.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    /// Dashboard PIDs
+    High,
+    Normal,
+    /// DTC scans, UDS reads, raw CAN
+    Bulk,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    /// From the `X-Priority` header, `default` if it's missing or not recognised
+    pub fn from_header(value: Option<&str>, default: Priority) -> Self {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            Some("high") => Priority::High,
+            Some("normal") => Priority::Normal,
+            Some("bulk") | Some("low") => Priority::Bulk,
+            _ => default,
+        }
+    }
+
+    /// Default for an ELM passthrough request by its mode. Mode 01 is the dashboard, DTC
+    /// reads and UDS services wait behind everything else.
+    pub fn for_request(request: &[u8]) -> Self {
+        let request = String::from_utf8_lossy(request);
+
+        match request.trim_start().get(..2) {
+            Some("01") => Priority::High,
+            Some("03" | "07" | "0A" | "19" | "22") => Priority::Bulk,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+type Work<'d> = Box<dyn FnOnce(&mut Elm327<'d>) + Send>;
+
+enum Job<'d> {
+    Run(Work<'d>),
+    /// ELM passthrough, answered from the response cache for a retried request ID
+    Transact {
+        request: Vec<u8>,
+        request_id: Option<String>,
+        /// Adapter timeout for this request, see `Elm327::with_timeout`
+        timeout_ms: Option<u32>,
+        bus: Bus,
+        /// Addressed to one ECU rather than the bus's default header, see `ecus::with_ecu`
+        ecu: Option<Ecu>,
+        reply: SyncSender<Result<String>>,
+    },
+}
+
+/// What the worker does next
+enum Next<'d> {
+    Job(Job<'d>),
+    /// Nothing was queued before the deadline
+    KeepAlive,
+    Stop,
+}
+
+/// Waiting jobs by priority
+struct Queue<'d> {
+    jobs: Mutex<QueueState<'d>>,
+    ready: Condvar,
+}
+
+struct QueueState<'d> {
+    classes: [VecDeque<Job<'d>>; Priority::COUNT],
+    stopped: bool,
+    /// When the last job was queued, for the idle manager
+    last_push: Instant,
+}
+
+impl<'d> Queue<'d> {
+    fn push(&self, priority: Priority, job: Job<'d>) -> Result<(), WorkerError> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        if jobs.stopped {
+            Err(WorkerError::Stopped)?;
+        }
+
+        let class = &mut jobs.classes[priority as usize];
+        if class.len() >= QUEUE_LEN {
+            Err(WorkerError::Busy)?;
+        }
+
+        class.push_back(job);
+        jobs.last_push = Instant::now();
+        self.ready.notify_one();
+
+        Ok(())
+    }
+
+    /// The next job, highest priority first, waiting for one until `deadline` if there is one.
+    /// Once stopped the waiting jobs are dropped and their handlers see the worker stopped.
+    fn pop(&self, deadline: Option<Instant>) -> Next<'d> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        loop {
+            if jobs.stopped {
+                return Next::Stop;
+            }
+
+            if let Some(job) = jobs.classes.iter_mut().find_map(VecDeque::pop_front) {
+                return Next::Job(job);
+            }
+
+            jobs = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Next::KeepAlive;
+                    }
+
+                    self.ready.wait_timeout(jobs, timeout).unwrap().0
+                }
+                None => self.ready.wait(jobs).unwrap(),
+            };
+        }
+    }
+
+    fn stop(&self) {
+        self.jobs.lock().unwrap().stopped = true;
+        self.ready.notify_all();
+    }
+}
+
+pub struct ElmWorker<'d> {
+    queue: Arc<Queue<'d>>,
+    thread: Option<JoinHandle<()>>,
+    cache: Arc<ResponseCache>,
+}
+
+impl<'d> ElmWorker<'d> {
+    /// Start the worker thread on the shared adapter. The main loop keeps using the adapter
+    /// directly, the lock is shared with it.
+    ///
+    /// # Safety
+    ///
+    /// The thread borrows `elm327`. The worker must be dropped before it, which stops and joins
+    /// the thread, and never leaked.
+    pub unsafe fn start(elm327: &Mutex<Elm327<'d>>) -> Result<Self> {
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(QueueState {
+                classes: Default::default(),
+                stopped: false,
+                last_push: Instant::now(),
+            }),
+            ready: Condvar::new(),
+        });
+        let cache = Arc::new(ResponseCache::new());
+
+        let worker_queue = Arc::clone(&queue);
+        let worker_cache = Arc::clone(&cache);
+        let thread = thread::Builder::new()
+            .name("elm_worker".to_owned())
+            .stack_size(STACK_SIZE)
+            .spawn_unchecked(move || work(elm327, &worker_queue, &worker_cache))?;
+
+        Ok(Self {
+            queue,
+            thread: Some(thread),
+            cache,
+        })
+    }
+
+    /// Run `f` with the adapter on the worker thread and wait for its result
+    pub fn run<R>(
+        &self,
+        priority: Priority,
+        f: impl FnOnce(&mut Elm327<'d>) -> Result<R> + Send + 'static,
+    ) -> Result<R>
+    where
+        R: Send + 'static,
+    {
+        let (reply, result) = mpsc::sync_channel(1);
+        let start = Instant::now();
+
+        self.queue.push(
+            priority,
+            Job::Run(Box::new(move |elm327| {
+                let _ = reply.send(f(elm327));
+            })),
+        )?;
+
+        wait(&result, start)
+    }
+
+    /// Queue `f` without waiting for it, for callers that can't be held up. It has to report its
+    /// own result.
+    pub fn spawn(
+        &self,
+        priority: Priority,
+        f: impl FnOnce(&mut Elm327<'d>) + Send + 'static,
+    ) -> Result<()> {
+        self.queue.push(priority, Job::Run(Box::new(f)))?;
+
+        Ok(())
+    }
+
+    /// Send an ELM request. A `request_id` already answered gets the cached response instead,
+    /// checked on the worker so a retry queued behind the original still sees it. The adapter
+    /// switches to `bus` first if it's on the other one, and addresses `ecu` if there is one.
+    pub fn transact(
+        &self,
+        priority: Priority,
+        request: Vec<u8>,
+        request_id: Option<String>,
+        timeout_ms: Option<u32>,
+        bus: Bus,
+        ecu: Option<Ecu>,
+    ) -> Result<String> {
+        let (reply, result) = mpsc::sync_channel(1);
+        let start = Instant::now();
+
+        self.queue.push(
+            priority,
+            Job::Transact {
+                request,
+                request_id,
+                timeout_ms,
+                bus,
+                ecu,
+                reply,
+            },
+        )?;
+
+        wait(&result, start)
+    }
+
+    /// Time since a job was last queued, waiting jobs included
+    pub fn idle_for(&self) -> Duration {
+        let jobs = self.queue.jobs.lock().unwrap();
+
+        if jobs.classes.iter().any(|class| !class.is_empty()) {
+            Duration::ZERO
+        } else {
+            jobs.last_push.elapsed()
+        }
+    }
+
+    /// Cache a response fetched for another request, e.g. one shared by the coalescer
+    pub fn cache_response(&self, request_id: &str, response: &str) {
+        self.cache.insert(request_id, response);
+    }
+}
+
+impl Drop for ElmWorker<'_> {
+    /// Stop the thread and wait for it, a job in progress finishes first
+    fn drop(&mut self) {
+        self.queue.stop();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The job's result, `start` is when it was queued
+fn wait<R>(result: &Receiver<Result<R>>, start: Instant) -> Result<R> {
+    let reply = result.recv_timeout(REPLY_TIMEOUT);
+    metrics::WORKER_LATENCY.observe(start.elapsed());
+
+    reply.map_err(|err| match err {
+        RecvTimeoutError::Timeout => {
+            metrics::WORKER_TIMEOUTS.inc();
+            WorkerError::Timeout
+        }
+        // Dropped unanswered as the worker stopped
+        RecvTimeoutError::Disconnected => WorkerError::Stopped,
+    })?
+}
+
+fn work<'d>(elm327: &Mutex<Elm327<'d>>, queue: &Queue<'d>, cache: &ResponseCache) {
+    info!("ELM worker started");
+
+    // When an open UDS session next needs tester present
+    let mut keep_alive_due = None;
+
+    loop {
+        let job = match queue.pop(keep_alive_due) {
+            Next::Job(job) => Some(job),
+            Next::KeepAlive => None,
+            Next::Stop => break,
+        };
+
+        let mut elm327 = elm327.lock().unwrap();
+
+        // Idle in low power, a failed wake leaves the job to fail as not connected. The sessions
+        // were closed as it went to sleep, so there's nothing to keep alive.
+        if job.is_some() {
+            if let Err(err) = elm327.wake() {
+                error!("Failed to wake the adapter {err:#}");
+            }
+        }
+
+        match job {
+            None => {}
+            Some(Job::Run(work)) => work(&mut elm327),
+            Some(Job::Transact {
+                request,
+                request_id,
+                timeout_ms,
+                bus,
+                ecu,
+                reply,
+            }) => {
+                let transact = |elm327: &mut Elm327<'_>| {
+                    elm327.with_timeout(timeout_ms, |elm327| elm327.transact(&request))
+                };
+
+                let response = match request_id.as_deref().and_then(|id| cache.get(id)) {
+                    Some(cached) => Ok(cached),
+                    None => elm327.select_bus(bus).and_then(|_| match &ecu {
+                        Some(ecu) => ecus::with_ecu(&mut elm327, ecu, transact),
+                        None => transact(&mut elm327),
+                    }),
+                };
+
+                if let (Ok(response), Some(id)) = (&response, &request_id) {
+                    cache.insert(id, response);
+                }
+
+                let _ = reply.send(response);
+            }
+        }
+
+        // Between requests too, a steady run of them could otherwise starve the sessions
+        if keep_alive_due.is_some_and(|due| due <= Instant::now()) {
+            if let Err(err) = uds::keep_alive(&mut elm327) {
+                error!("UDS keep alive failed {err:#}");
+            }
+        }
+        keep_alive_due = elm327.keep_alive().next_due();
+    }
+
+    info!("ELM worker stopped");
+}
@@ -0,0 +1,234 @@
+//! Errors from the adapter, the worker and the protocols on top of them, and the codes they're
+//! sent to HTTP clients as
+use std::io;
+
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReadObdError {
+    #[error("Device IO Error")]
+    IOError(#[from] std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ElmError {
+    /// The adapter wedged and was reset, the request was not serviced and can be sent again
+    #[error("Adapter reset after ({0}), retry the request")]
+    AdapterReset(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("No data for {0}")]
+    NoData(String),
+
+    /// CAN ERROR, BUS BUSY and the like, see `elm327::BUS_ERRORS`
+    #[error("Bus error ({0})")]
+    BusError(String),
+
+    #[error("({0}) is blocked by the command policy, send the unlock token")]
+    Blocked(String),
+
+    #[error("PID ({0:02X}) is not supported by the vehicle, see /pids/supported")]
+    UnsupportedPid(u8),
+
+    /// The adapter kept sending without a prompt, the partial response so far
+    #[error("Response still arriving without a prompt, partial response ({0})")]
+    ResponseTimeout(String),
+
+    /// The adapter found no protocol the vehicle answers, usually the ignition is off
+    #[error("Unable to connect to the vehicle, is the ignition on?")]
+    UnableToConnect,
+
+    /// A slow init bus (ISO 9141, KWP) didn't wake, the adapter's line
+    #[error("Bus init failed ({0})")]
+    BusInit(String),
+
+    /// The adapter was still searching for the protocol at the deadline, the partial response
+    #[error("Protocol search didn't finish, partial response ({0})")]
+    SearchTimeout(String),
+
+    /// The adapter may be in a bootloader or at another baud rate, nothing is written until it's
+    /// set up again. Why it's thought to be.
+    #[error("Adapter in a programming state after ({0}), send ATZ to set it up again")]
+    Programming(String),
+}
+
+#[derive(Error, Debug)]
+pub enum WorkerError {
+    #[error("Adapter busy, retry the request")]
+    Busy,
+
+    #[error("No adapter response in time")]
+    Timeout,
+
+    #[error("ELM worker stopped")]
+    Stopped,
+}
+
+#[derive(Error, Debug)]
+pub enum UdsError {
+    #[error("Negative response to service ({sid:02X}), {} ({nrc:02X})", crate::uds::nrc_name(*.nrc))]
+    Negative { sid: u8, nrc: u8 },
+
+    #[error("No UDS response")]
+    NoResponse,
+
+    #[error("Malformed UDS response, {0}")]
+    Malformed(&'static str),
+}
+
+/// Machine readable code for adapter errors sent to HTTP clients, so they can tell what's worth
+/// retrying
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// No answer from the adapter within the SPP read timeout
+    ElmTimeout,
+    /// The response didn't finish by the response deadline
+    ResponseTimeout,
+    /// The adapter was still searching for the protocol at the deadline
+    SearchTimeout,
+    /// No protocol the vehicle answers, usually the ignition is off
+    UnableToConnect,
+    /// A slow init bus didn't wake
+    BusInitFailed,
+    /// No answer from the vehicle
+    NoData,
+    CanError,
+    /// The SPP write buffer or the adapter's own buffer is full
+    BufferFull,
+    AdapterReset,
+    /// No SPP connection, requests wait for the reconnect
+    AdapterDisconnected,
+    /// An SPP write failed, the link is going down
+    BtLinkLost,
+    Busy,
+    WorkerTimeout,
+    WorkerStopped,
+    Blocked,
+    /// The adapter may be in a bootloader, see `ElmError::Programming`
+    AdapterProgramming,
+    InvalidRequest,
+    UnsupportedPid,
+    UdsNegative,
+    BadResponse,
+    Internal,
+}
+
+impl ErrorCode {
+    /// The first cause in the chain with a code
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|cause| {
+                if let Some(err) = cause.downcast_ref::<WorkerError>() {
+                    return Some(match err {
+                        WorkerError::Busy => ErrorCode::Busy,
+                        WorkerError::Timeout => ErrorCode::WorkerTimeout,
+                        WorkerError::Stopped => ErrorCode::WorkerStopped,
+                    });
+                }
+
+                if let Some(err) = cause.downcast_ref::<ElmError>() {
+                    return Some(match err {
+                        ElmError::AdapterReset(_) => ErrorCode::AdapterReset,
+                        ElmError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+                        ElmError::NoData(_) => ErrorCode::NoData,
+                        ElmError::BusError(line) if line.contains("BUFFER FULL") => {
+                            ErrorCode::BufferFull
+                        }
+                        ElmError::BusError(_) => ErrorCode::CanError,
+                        ElmError::Blocked(_) => ErrorCode::Blocked,
+                        ElmError::UnsupportedPid(_) => ErrorCode::UnsupportedPid,
+                        ElmError::ResponseTimeout(_) => ErrorCode::ResponseTimeout,
+                        ElmError::UnableToConnect => ErrorCode::UnableToConnect,
+                        ElmError::BusInit(_) => ErrorCode::BusInitFailed,
+                        ElmError::SearchTimeout(_) => ErrorCode::SearchTimeout,
+                        ElmError::Programming(_) => ErrorCode::AdapterProgramming,
+                    });
+                }
+
+                if let Some(err) = cause.downcast_ref::<UdsError>() {
+                    return Some(match err {
+                        UdsError::Negative { .. } => ErrorCode::UdsNegative,
+                        UdsError::NoResponse => ErrorCode::NoData,
+                        UdsError::Malformed(_) => ErrorCode::BadResponse,
+                    });
+                }
+
+                match cause.downcast_ref::<io::Error>()?.kind() {
+                    io::ErrorKind::TimedOut => Some(ErrorCode::ElmTimeout),
+                    io::ErrorKind::NotConnected => Some(ErrorCode::AdapterDisconnected),
+                    io::ErrorKind::ConnectionReset => Some(ErrorCode::BtLinkLost),
+                    io::ErrorKind::WouldBlock => Some(ErrorCode::BufferFull),
+                    _ => None,
+                }
+            })
+            .unwrap_or(ErrorCode::Internal)
+    }
+
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::UnsupportedPid => 400,
+            ErrorCode::Blocked => 403,
+            ErrorCode::AdapterProgramming => 409,
+            ErrorCode::Internal => 500,
+            ErrorCode::NoData
+            | ErrorCode::CanError
+            | ErrorCode::UnableToConnect
+            | ErrorCode::BusInitFailed
+            | ErrorCode::UdsNegative
+            | ErrorCode::BadResponse => 502,
+            ErrorCode::BufferFull
+            | ErrorCode::AdapterReset
+            | ErrorCode::AdapterDisconnected
+            | ErrorCode::BtLinkLost
+            | ErrorCode::Busy
+            | ErrorCode::WorkerStopped => 503,
+            ErrorCode::ElmTimeout
+            | ErrorCode::ResponseTimeout
+            | ErrorCode::SearchTimeout
+            | ErrorCode::WorkerTimeout => 504,
+        }
+    }
+
+    /// Sending the same request again may work. NO DATA, UNABLE TO CONNECT and negative responses
+    /// come back the same until something changes on the vehicle.
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::ElmTimeout
+                | ErrorCode::ResponseTimeout
+                | ErrorCode::SearchTimeout
+                | ErrorCode::CanError
+                | ErrorCode::BusInitFailed
+                | ErrorCode::BufferFull
+                | ErrorCode::AdapterReset
+                | ErrorCode::AdapterDisconnected
+                | ErrorCode::BtLinkLost
+                | ErrorCode::Busy
+                | ErrorCode::WorkerTimeout
+        )
+    }
+}
+
+/// The body of an adapter error response, {"code": "ELM_TIMEOUT", "detail": "...", "retryable": true}
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub detail: String,
+    pub retryable: bool,
+}
+
+impl ErrorBody {
+    pub fn of(err: &anyhow::Error) -> Self {
+        let code = ErrorCode::of(err);
+
+        Self {
+            code,
+            detail: format!("{err:#}"),
+            retryable: code.retryable(),
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Arithmetic over decoded mode 01 PIDs for virtual channels, e.g. `map - baro` for boost.
+//!
+//! Numbers, PIDs by channel name (`rpm`, `map`) or hex (`pid_0B`), `+ - * /`, parentheses and
+//! `min`, `max` and `abs`. Parsed once when the config is set, evaluated against each read.
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::pid;
+
+/// Longer expressions are rejected, they're meant to be short formulas
+pub const MAX_EXPR_LEN: usize = 128;
+/// Parentheses and calls nested deeper than this are rejected, the parser recurses on a small
+/// stack
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Func {
+    Min,
+    Max,
+    Abs,
+}
+
+/// A parsed expression
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Num(f32),
+    Pid(u8),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self> {
+        if text.len() > MAX_EXPR_LEN {
+            Err(anyhow!("Expression over ({MAX_EXPR_LEN}) characters"))?;
+        }
+
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.sum()?;
+
+        parser.skip_space();
+        if parser.pos < text.len() {
+            Err(anyhow!(
+                "Unexpected ({}) at {}",
+                &text[parser.pos..],
+                parser.pos
+            ))?;
+        }
+
+        Ok(expr)
+    }
+
+    /// The PIDs the expression reads, each once
+    pub fn pids(&self) -> Vec<u8> {
+        let mut pids = Vec::new();
+        self.collect_pids(&mut pids);
+        pids.sort_unstable();
+        pids.dedup();
+
+        pids
+    }
+
+    fn collect_pids(&self, pids: &mut Vec<u8>) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Pid(pid) => pids.push(*pid),
+            Expr::Neg(expr) => expr.collect_pids(pids),
+            Expr::Bin(_, lhs, rhs) => {
+                lhs.collect_pids(pids);
+                rhs.collect_pids(pids);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_pids(pids)),
+        }
+    }
+
+    /// None if a PID it reads has no value, or the result isn't a number, e.g. a divide by zero
+    pub fn eval(&self, values: &BTreeMap<u8, f32>) -> Option<f32> {
+        let value = match self {
+            Expr::Num(value) => *value,
+            Expr::Pid(pid) => *values.get(pid)?,
+            Expr::Neg(expr) => -expr.eval(values)?,
+            Expr::Bin(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(values)?, rhs.eval(values)?);
+                match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                }
+            }
+            Expr::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(values))
+                    .collect::<Option<Vec<f32>>>()?;
+                match func {
+                    Func::Min => args.into_iter().reduce(f32::min)?,
+                    Func::Max => args.into_iter().reduce(f32::max)?,
+                    Func::Abs => args.first()?.abs(),
+                }
+            }
+        };
+
+        value.is_finite().then_some(value)
+    }
+}
+
+struct Parser<'t> {
+    text: &'t [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    /// `product (+|- product)*`
+    fn sum(&mut self) -> Result<Expr> {
+        let mut expr = self.product()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(b'+') => Op::Add,
+                Some(b'-') => Op::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    /// `unary (*|/ unary)*`
+    fn product(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(b'*') => Op::Mul,
+                Some(b'/') => Op::Div,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.nested(Self::unary)?)));
+        }
+
+        self.primary()
+    }
+
+    /// A number, a PID, a call or a parenthesised expression
+    fn primary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let expr = self.nested(Self::sum)?;
+                self.expect(b')')?;
+                Ok(expr)
+            }
+            Some(b) if b.is_ascii_digit() || b == b'.' => self.number(),
+            Some(b) if b.is_ascii_alphabetic() => self.name(),
+            Some(b) => Err(anyhow!("Unexpected ({}) at {}", b as char, self.pos)),
+            None => Err(anyhow!("Expression ends early")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Expr> {
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_digit() || *b == b'.')
+        {
+            self.pos += 1;
+        }
+
+        let text = std::str::from_utf8(&self.text[start..self.pos])?;
+        let value = text
+            .parse()
+            .map_err(|_| anyhow!("Bad number ({text}) at {start}"))?;
+
+        Ok(Expr::Num(value))
+    }
+
+    fn name(&mut self) -> Result<Expr> {
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_')
+        {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.text[start..self.pos])?;
+
+        let func = match name {
+            "min" => Some(Func::Min),
+            "max" => Some(Func::Max),
+            "abs" => Some(Func::Abs),
+            _ => None,
+        };
+
+        if let Some(func) = func {
+            self.expect(b'(')?;
+            let mut args = vec![self.nested(Self::sum)?];
+            while self.peek() == Some(b',') {
+                self.pos += 1;
+                args.push(self.nested(Self::sum)?);
+            }
+            self.expect(b')')?;
+
+            if matches!(func, Func::Abs) && args.len() != 1 {
+                Err(anyhow!("abs takes one argument"))?;
+            }
+
+            return Ok(Expr::Call(func, args));
+        }
+
+        resolve(name)
+            .map(Expr::Pid)
+            .ok_or_else(|| anyhow!("Unknown channel ({name})"))
+    }
+
+    /// Parse with `f` one level deeper
+    fn nested(&mut self, f: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth >= MAX_DEPTH {
+            Err(anyhow!("Expression nested over ({MAX_DEPTH}) deep"))?;
+        }
+
+        self.depth += 1;
+        let expr = f(self);
+        self.depth -= 1;
+
+        expr
+    }
+
+    fn expect(&mut self, b: u8) -> Result<()> {
+        if self.peek() != Some(b) {
+            Err(anyhow!("Expected ({}) at {}", b as char, self.pos))?;
+        }
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    /// The next byte that isn't a space
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+}
+
+/// A channel name from `pid::name`, or `pid_0B` for one of those PIDs
+fn resolve(name: &str) -> Option<u8> {
+    // Only PIDs `pid::decode` handles have a value
+    if let Some(hex) = name.strip_prefix("pid_") {
+        return u8::from_str_radix(hex, 16)
+            .ok()
+            .filter(|p| pid::name(*p).is_some());
+    }
+
+    (0..=0xFFu8).find(|p| pid::name(*p) == Some(name))
+}
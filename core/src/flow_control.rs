@@ -0,0 +1,88 @@
+//! Multi-frame flow control (ISO 15765-2). After the first frame of a long response the adapter
+//! sends a flow control frame, by default `30 00 00` addressed back to the ECU that answered.
+//! Some ECUs need a longer separation time, a smaller block size or the frame on an ID of their
+//! own, or a long UDS response stalls after the first frame. Set up with ATFCSH, ATFCSD and ATFCSM.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// `<block size>,<separation time>[,<header>]` for this request only, e.g. `0,20` or `8,5,7E0`
+pub const FLOW_CONTROL_HEADER: &str = "X-Flow-Control";
+
+/// The adapter's own flow control is `FlowControlConfig::default()`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(default)]
+pub struct FlowControlConfig {
+    /// ATFCSH, the ID the frame is sent on, 3 or 8 hex digits. None for the adapter's.
+    pub header: Option<String>,
+    /// Consecutive frames the ECU sends before waiting for the next flow control frame, 0 for
+    /// all of them
+    pub block_size: u8,
+    /// STmin, 0 to 127 milliseconds, or 241 to 249 (F1 to F9) for 100 to 900µs
+    pub separation_time: u8,
+}
+
+impl FlowControlConfig {
+    /// The commands that set it up, ATFCSM 0 goes back to the adapter's own
+    pub fn commands(&self) -> Vec<String> {
+        if *self == Self::default() {
+            return vec!["ATFCSM 0".to_owned()];
+        }
+
+        let data = format!(
+            "ATFCSD 30 {:02X} {:02X}",
+            self.block_size, self.separation_time
+        );
+
+        match &self.header {
+            Some(header) => vec![format!("ATFCSH {header}"), data, "ATFCSM 1".to_owned()],
+            None => vec![data, "ATFCSM 2".to_owned()],
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if let Some(header) = &self.header {
+            if !matches!(header.len(), 3 | 8) || !header.chars().all(|c| c.is_ascii_hexdigit()) {
+                Err(anyhow!("header must be 3 or 8 hex digits"))?;
+            }
+        }
+
+        if !matches!(self.separation_time, 0x00..=0x7F | 0xF1..=0xF9) {
+            Err(anyhow!("separation_time must be 0 to 127 or 241 to 249"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The flow control from the `X-Flow-Control` header, None without one
+pub fn from_header(value: Option<&str>) -> Result<Option<FlowControlConfig>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    let invalid =
+        || anyhow!("{FLOW_CONTROL_HEADER} must be <block size>,<separation time>[,<header>]");
+
+    let mut parts = value.split(',').map(str::trim);
+    let block_size = parts
+        .next()
+        .and_then(|bs| bs.parse().ok())
+        .ok_or_else(invalid)?;
+    let separation_time = parts
+        .next()
+        .and_then(|st| st.parse().ok())
+        .ok_or_else(invalid)?;
+    let header = parts.next().map(str::to_ascii_uppercase);
+    if parts.next().is_some() {
+        Err(invalid())?;
+    }
+
+    let config = FlowControlConfig {
+        header,
+        block_size,
+        separation_time,
+    };
+    config.check()?;
+
+    Ok(Some(config))
+}
@@ -0,0 +1,258 @@
+//! SAE J1939 (heavy duty) PGN requests on top of the ELM327, for diesel engines that don't speak
+//! OBD. A PGN is requested from everyone with the request PGN (EA00), answers longer than one
+//! frame come back as a broadcast (TP.BAM) and are reassembled here.
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+
+use crate::elm327::{Bus, Elm327};
+use crate::error::ElmError;
+
+/// Request PGN (EA00) to all from the gateway's address F9, also the bus's default header
+const REQUEST_HEADER: &str = "18EAFFF9";
+/// Transport protocol connection management, carries the BAM announce
+const PF_TP_CM: u8 = 0xEC;
+/// Transport protocol data transfer, the numbered packets of a BAM
+const PF_TP_DT: u8 = 0xEB;
+const TP_CM_BAM: u8 = 0x20;
+/// Largest PGN, 18 bits
+pub const MAX_PGN: u32 = 0x3FFFF;
+/// BAM packets can be up to 200ms apart, the adapter keeps listening this long after each frame
+const RESPONSE_TIMEOUT_MS: u32 = 500;
+
+/// A suspect parameter number, where it sits in its PGN and how to scale it
+struct SpnDef {
+    spn: u32,
+    pgn: u32,
+    name: &'static str,
+    unit: &'static str,
+    /// First byte, from 0
+    start: usize,
+    len: usize,
+    scale: f32,
+    offset: f32,
+}
+
+const SPNS: [SpnDef; 6] = [
+    SpnDef {
+        spn: 190,
+        pgn: 61444,
+        name: "engine_speed",
+        unit: "rpm",
+        start: 3,
+        len: 2,
+        scale: 0.125,
+        offset: 0.0,
+    },
+    SpnDef {
+        spn: 110,
+        pgn: 65262,
+        name: "coolant_temp",
+        unit: "°C",
+        start: 0,
+        len: 1,
+        scale: 1.0,
+        offset: -40.0,
+    },
+    SpnDef {
+        spn: 247,
+        pgn: 65253,
+        name: "engine_hours",
+        unit: "h",
+        start: 0,
+        len: 4,
+        scale: 0.05,
+        offset: 0.0,
+    },
+    SpnDef {
+        spn: 84,
+        pgn: 65265,
+        name: "vehicle_speed",
+        unit: "km/h",
+        start: 1,
+        len: 2,
+        scale: 1.0 / 256.0,
+        offset: 0.0,
+    },
+    SpnDef {
+        spn: 183,
+        pgn: 65266,
+        name: "fuel_rate",
+        unit: "L/h",
+        start: 0,
+        len: 2,
+        scale: 0.05,
+        offset: 0.0,
+    },
+    SpnDef {
+        spn: 250,
+        pgn: 65257,
+        name: "total_fuel_used",
+        unit: "L",
+        start: 4,
+        len: 4,
+        scale: 0.5,
+        offset: 0.0,
+    },
+];
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PgnResponse {
+    pub pgn: u32,
+    /// Source address of the ECU that answered, e.g. `00` for the engine
+    pub source: String,
+    pub data: String,
+    /// The known SPNs in the PGN, those the ECU reports as not available are left out
+    pub spns: Vec<SpnValue>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SpnValue {
+    pub spn: u32,
+    pub name: &'static str,
+    pub value: f32,
+    pub unit: &'static str,
+}
+
+/// A frame split into its 29 bit identifier parts
+struct Frame {
+    pgn: u32,
+    source: u8,
+    data: Vec<u8>,
+}
+
+/// Request a PGN on `bus`, one of the J1939 buses, and return the first answer
+pub fn request_pgn(elm: &mut Elm327<'_>, bus: Bus, pgn: u32) -> Result<PgnResponse> {
+    if !matches!(bus, Bus::J1939 | Bus::J1939Fast) {
+        Err(ElmError::InvalidRequest(
+            "bus must be j1939 or j1939_500".to_owned(),
+        ))?;
+    }
+    if pgn > MAX_PGN {
+        Err(ElmError::InvalidRequest(format!(
+            "PGN must be 0 to {MAX_PGN}"
+        )))?;
+    }
+
+    elm.select_bus(bus)?;
+
+    // The request PGN carries the PGN asked for, least significant byte first
+    let [lo, mid, hi, _] = pgn.to_le_bytes();
+    let lines = elm.with_timeout(Some(RESPONSE_TIMEOUT_MS), |elm| {
+        elm.raw_request(REQUEST_HEADER, &format!("{lo:02X} {mid:02X} {hi:02X}"))
+    })?;
+
+    let frames: Vec<Frame> = lines.iter().filter_map(|line| parse_frame(line)).collect();
+
+    let (source, data) =
+        reassemble(pgn, &frames).ok_or_else(|| ElmError::NoData(format!("PGN {pgn}")))?;
+
+    debug!("PGN {pgn} from {source:02X} ({} bytes)", data.len());
+
+    Ok(PgnResponse {
+        pgn,
+        source: format!("{source:02X}"),
+        data: data
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+        spns: decode_spns(pgn, &data),
+    })
+}
+
+/// `18 FE E5 00 xx ..`, the 4 header bytes then the data
+fn parse_frame(line: &str) -> Option<Frame> {
+    let bytes = line
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()?;
+
+    let (header, data) = bytes.split_first_chunk::<4>()?;
+    let [priority_dp, pf, ps, source] = *header;
+
+    // PDU1 (PF below F0) has a destination address in PS rather than part of the PGN
+    let dp = (priority_dp as u32 & 0x03) << 16;
+    let pgn = match pf {
+        0xF0.. => dp | ((pf as u32) << 8) | ps as u32,
+        _ => dp | ((pf as u32) << 8),
+    };
+
+    Some(Frame {
+        pgn,
+        source,
+        data: data.to_vec(),
+    })
+}
+
+/// The data of `pgn` from the first ECU that sent it, in one frame or a complete BAM
+fn reassemble(pgn: u32, frames: &[Frame]) -> Option<(u8, Vec<u8>)> {
+    if let Some(frame) = frames.iter().find(|frame| frame.pgn == pgn) {
+        return Some((frame.source, frame.data.clone()));
+    }
+
+    // BAM announce, 20 size_lo size_hi packets FF pgn_lo pgn_mid pgn_hi
+    let announce = frames.iter().find(|frame| {
+        (frame.pgn >> 8) as u8 == PF_TP_CM
+            && frame.data.len() == 8
+            && frame.data[0] == TP_CM_BAM
+            && u32::from_le_bytes([frame.data[5], frame.data[6], frame.data[7], 0]) == pgn
+    })?;
+
+    let size = u16::from_le_bytes([announce.data[1], announce.data[2]]) as usize;
+    let packets = announce.data[3];
+
+    // Packets numbered from 1, 7 data bytes each
+    let mut received: BTreeMap<u8, &[u8]> = BTreeMap::new();
+    for frame in frames {
+        if (frame.pgn >> 8) as u8 == PF_TP_DT && frame.source == announce.source {
+            if let Some((seq, data)) = frame.data.split_first() {
+                received.entry(*seq).or_insert(data);
+            }
+        }
+    }
+
+    if !(1..=packets).all(|seq| received.contains_key(&seq)) {
+        warn!(
+            "PGN {pgn} BAM incomplete, {} of {packets} packets",
+            received.len()
+        );
+        return None;
+    }
+
+    let mut data: Vec<u8> = (1..=packets)
+        .flat_map(|seq| received[&seq].iter().copied())
+        .collect();
+    data.truncate(size);
+
+    Some((announce.source, data))
+}
+
+fn decode_spns(pgn: u32, data: &[u8]) -> Vec<SpnValue> {
+    SPNS.iter()
+        .filter(|def| def.pgn == pgn)
+        .filter_map(|def| {
+            let bytes = data.get(def.start..def.start + def.len)?;
+
+            // All ones is not available, FE.. an error indicator
+            if bytes.last().is_some_and(|b| *b >= 0xFE) {
+                return None;
+            }
+
+            let raw = bytes
+                .iter()
+                .rev()
+                .fold(0u32, |raw, b| (raw << 8) | *b as u32);
+
+            Some(SpnValue {
+                spn: def.spn,
+                name: def.name,
+                value: raw as f32 * def.scale + def.offset,
+                unit: def.unit,
+            })
+        })
+        .collect()
+}
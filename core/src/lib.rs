@@ -0,0 +1,44 @@
+//! The gateway's adapter side without the ESP32: the ELM327 protocol handling (`elm327`), PID
+//! decoding (`pid`), the request worker that schedules adapter work by priority (`elm_worker`)
+//! and the transport traits an adapter link implements (`transport`). `api` is what the adapter
+//! endpoints do with a request, for the firmware's HTTP server and the host tests alike.
+//!
+//! It needs std for the threads and IO but nothing from ESP-IDF, so it builds for the host too.
+//! Everything runs against an `ElmTransport`, e.g. `mock_elm::MockElm` off target with the
+//! `mock-elm` feature:
+//!
+//! ```no_run
+//! # #[cfg(feature = "mock-elm")]
+//! # {
+//! use bt_obd_gw_core::{elm327::Elm327, mock_elm::MockElm, pid};
+//!
+//! let mut elm = Elm327::new(MockElm::new(Default::default()));
+//! elm.setup()?;
+//! let rpm = pid::request(&mut elm, 0x0C)?;
+//! # }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The decoding (`pid::decode`, `elm327::parse_messages`, `monitors::decode`, `j1939`, `uds`) only works on strings
+//! and bytes, the parts that need the adapter take an `Elm327`.
+pub mod alerts;
+pub mod api;
+pub mod clock;
+pub mod dtc;
+pub mod ecus;
+pub mod elm327;
+pub mod elm_worker;
+pub mod error;
+pub mod expr;
+pub mod flow_control;
+pub mod j1939;
+pub mod metrics;
+#[cfg(feature = "mock-elm")]
+pub mod mock_elm;
+pub mod monitors;
+pub mod pid;
+pub mod response_cache;
+pub mod session;
+pub mod timeouts;
+pub mod transport;
+pub mod uds;
@@ -0,0 +1,126 @@
+//! Adapter and worker counters and latencies, rendered with the gateway's own by the binary
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+/// Requests written to the adapter
+pub static ELM_REQUESTS: Counter = Counter::new();
+/// Adapter responses that didn't arrive, a read error or the response deadline
+pub static ELM_ERRORS: Counter = Counter::new();
+/// Adapter resets after a wedged or garbage response
+pub static ELM_RESETS: Counter = Counter::new();
+/// Reads the adapter didn't answer within the transport's read timeout
+pub static ELM_READ_TIMEOUTS: Counter = Counter::new();
+/// Requests the ELM worker didn't answer in time
+pub static WORKER_TIMEOUTS: Counter = Counter::new();
+/// Adapter woken from idle low power for a request
+pub static ADAPTER_WAKES: Counter = Counter::new();
+/// Requests sent to keep an idle adapter from sleeping
+pub static KEEP_AWAKE_PINGS: Counter = Counter::new();
+
+/// Writing a request to the transport, e.g. the SPP write waiting for congestion to clear
+pub static ELM_WRITE_LATENCY: Histogram = Histogram::new();
+/// From the request written to the first byte of the response, the adapter and vehicle's turn
+pub static ELM_FIRST_BYTE_LATENCY: Histogram = Histogram::new();
+/// From the request written to the prompt, the whole response in
+pub static ELM_RESPONSE_LATENCY: Histogram = Histogram::new();
+/// A handler waiting on the worker, queued behind other clients' jobs included
+pub static WORKER_LATENCY: Histogram = Histogram::new();
+
+/// Upper bounds of the latency buckets in milliseconds, one more bucket takes the slower ones
+pub const LATENCY_BUCKETS_MS: [u32; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Default)]
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Latencies counted into `LATENCY_BUCKETS_MS`
+pub struct Histogram {
+    buckets: [AtomicU32; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU32,
+}
+
+/// Percentiles are the upper bound of the bucket they fall in, None without samples or past the
+/// last bound
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct LatencySummary {
+    pub count: u32,
+    pub mean_ms: Option<u32>,
+    pub p50_ms: Option<u32>,
+    pub p90_ms: Option<u32>,
+    pub p99_ms: Option<u32>,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU32::new(0) }; LATENCY_BUCKETS_MS.len() + 1],
+            sum_ms: AtomicU32::new(0),
+        }
+    }
+
+    pub fn observe(&self, latency: Duration) {
+        let ms = latency.as_millis().min(u32::MAX as u128) as u32;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    /// Count in each bucket, the last is past `LATENCY_BUCKETS_MS`
+    pub fn counts(&self) -> [u32; LATENCY_BUCKETS_MS.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    pub fn sum_ms(&self) -> u32 {
+        self.sum_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let counts = self.counts();
+        let count = counts.iter().sum::<u32>();
+
+        // Bound of the bucket the nth fastest sample is in
+        let percentile = |p: u32| {
+            let rank = (count as u64 * p as u64).div_ceil(100).max(1);
+            let mut seen = 0u64;
+            counts.iter().enumerate().find_map(|(i, n)| {
+                seen += *n as u64;
+                (seen >= rank).then(|| LATENCY_BUCKETS_MS.get(i).copied())
+            })?
+        };
+
+        LatencySummary {
+            count,
+            mean_ms: (count > 0).then(|| self.sum_ms() / count),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
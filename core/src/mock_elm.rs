@@ -0,0 +1,357 @@
+//! Simulated ELM327 in place of a real adapter, built with the `mock-elm` feature, for working on
+//! the HTTP side and the parsing without an adapter or a car. It answers the AT and ST commands
+//! the gateway sends, mode 01 from a PID table, DTCs (modes 03, 07 and 0A) and UDS session control
+//! and tester present, anything else gets `NO DATA`. It only needs std, so it also builds for the
+//! host.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+};
+
+use anyhow::Result;
+use log::*;
+
+use crate::transport::{ConnectionStatus, ElmTransport};
+
+const MODE_CURRENT_DATA: u8 = 0x01;
+const MODE_RESPONSE: u8 = 0x41;
+const MODE_STORED_DTCS: u8 = 0x03;
+const MODE_PENDING_DTCS: u8 = 0x07;
+const MODE_PERMANENT_DTCS: u8 = 0x0A;
+const SID_SESSION_CONTROL: u8 = 0x10;
+const SID_TESTER_PRESENT: u8 = 0x3E;
+/// Positive response SIDs are the request's plus this
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+/// Data bytes in a single CAN frame after the PCI byte
+const SINGLE_FRAME_LEN: usize = 7;
+
+/// What the simulated adapter and vehicle answer
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    /// Mode 01 data bytes after the mode and PID. The supported PID bitmaps (00, 20..) are made
+    /// from the table.
+    pub pids: BTreeMap<u8, Vec<u8>>,
+    /// DTCs by mode (03, 07 or 0A) as their two bytes, e.g. `0x0301` for P0301
+    pub dtcs: BTreeMap<u8, Vec<u16>>,
+    /// `ATRV`
+    pub volts: f32,
+    /// Answer `STI` as an OBDLink, otherwise as a v1.5 clone
+    pub stn: bool,
+}
+
+impl Default for MockConfig {
+    /// A warm engine idling with the car parked
+    fn default() -> Self {
+        Self {
+            pids: BTreeMap::from([
+                // Monitor status, MIL off and no DTCs
+                (0x01, vec![0x00, 0x07, 0xE5, 0x00]),
+                // Load 25%
+                (0x04, vec![0x40]),
+                // Coolant 90°C
+                (0x05, vec![0x82]),
+                // 800 rpm
+                (0x0C, vec![0x0C, 0x80]),
+                // Stopped
+                (0x0D, vec![0x00]),
+                // Intake air 25°C
+                (0x0F, vec![0x41]),
+                // Throttle 12.5%
+                (0x11, vec![0x20]),
+                // Fuel level 50%
+                (0x2F, vec![0x80]),
+                // Module voltage 14.0V
+                (0x42, vec![0x36, 0xB0]),
+                // Ambient 20°C
+                (0x46, vec![0x3C]),
+            ]),
+            dtcs: BTreeMap::new(),
+            volts: 14.1,
+            stn: true,
+        }
+    }
+}
+
+pub struct MockElm {
+    config: MockConfig,
+    /// The request being written, up to the '\r'
+    request: Vec<u8>,
+    /// The response waiting to be read, prompt included
+    response: VecDeque<u8>,
+    headers: bool,
+    spaces: bool,
+    /// 11 bit headers (`7E8`), e.g. after `STP 53`, otherwise 29 bit
+    eleven_bit: bool,
+    /// Sent its ID after `STBR`, waiting for the CR that keeps the new rate
+    switching_baud: bool,
+}
+
+impl MockElm {
+    pub fn new(config: MockConfig) -> Self {
+        Self {
+            config,
+            request: Vec::new(),
+            response: VecDeque::new(),
+            headers: false,
+            spaces: true,
+            eleven_bit: false,
+            switching_baud: false,
+        }
+    }
+
+    /// Queue the answer to a request, each line ends in '\r' and the prompt follows
+    fn answer(&mut self, request: &str) {
+        let command: String = request
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        debug!("Mock request ({command})");
+
+        // OK at the old rate then the ID at the new one, without a prompt. The rate isn't
+        // simulated.
+        if command.starts_with("STBR") && !command.starts_with("STBRT") && self.config.stn {
+            self.response.extend(b"OK\rSTN2255 v5.10.3\r");
+            self.switching_baud = true;
+            return;
+        }
+
+        let lines = match command.as_str() {
+            "" if std::mem::take(&mut self.switching_baud) => vec!["OK".to_owned()],
+            "ATZ" | "ATWS" => {
+                self.headers = false;
+                self.spaces = true;
+                self.eleven_bit = false;
+                vec!["".to_owned(), "ELM327 v1.5".to_owned()]
+            }
+            "ATI" => vec!["ELM327 v1.5".to_owned()],
+            "STI" if self.config.stn => vec!["STN2255 v5.10.3".to_owned()],
+            "ATRV" => vec![format!("{:.1}V", self.config.volts)],
+            "ATH0" | "ATH1" => {
+                self.headers = command == "ATH1";
+                vec!["OK".to_owned()]
+            }
+            "ATS0" | "ATS1" => {
+                self.spaces = command == "ATS1";
+                vec!["OK".to_owned()]
+            }
+            "STP53" | "ATSP6" => {
+                self.eleven_bit = true;
+                vec!["OK".to_owned()]
+            }
+            "STP34" | "ATSP7" | "STP41" | "STP42" | "ATSPA" => {
+                self.eleven_bit = false;
+                vec!["OK".to_owned()]
+            }
+            "ATDPN" => vec![if self.eleven_bit { "A6" } else { "A7" }.to_owned()],
+            _ if command.starts_with("AT") && command != "AT" => vec!["OK".to_owned()],
+            _ if command.starts_with("ST") && self.config.stn => vec!["OK".to_owned()],
+            _ => match parse_hex(&command) {
+                Some(bytes) => self.obd(&bytes),
+                None => vec!["?".to_owned()],
+            },
+        };
+
+        for line in lines {
+            self.response.extend(line.bytes());
+            self.response.push_back(b'\r');
+        }
+        self.response.extend(b"\r>");
+    }
+
+    /// A mode 01 request for up to 6 PIDs, a DTC read or a UDS session request, as one message in
+    /// one or more frames
+    fn obd(&self, request: &[u8]) -> Vec<String> {
+        let pids = match request {
+            [MODE_CURRENT_DATA, pids @ ..] => pids,
+            // Every session is granted, with P2 50ms and P2* 5s
+            [SID_SESSION_CONTROL, session] => {
+                return self.frames(&[
+                    SID_SESSION_CONTROL + POSITIVE_RESPONSE_OFFSET,
+                    *session,
+                    0x00,
+                    0x32,
+                    0x01,
+                    0xF4,
+                ])
+            }
+            [SID_TESTER_PRESENT, 0x00] => {
+                return self.frames(&[SID_TESTER_PRESENT + POSITIVE_RESPONSE_OFFSET, 0x00])
+            }
+            [mode @ (MODE_STORED_DTCS | MODE_PENDING_DTCS | MODE_PERMANENT_DTCS)] => {
+                let dtcs = self.config.dtcs.get(mode).cloned().unwrap_or_default();
+
+                let mut data = vec![mode + POSITIVE_RESPONSE_OFFSET, dtcs.len() as u8];
+                data.extend(dtcs.iter().flat_map(|dtc| dtc.to_be_bytes()));
+
+                return self.frames(&data);
+            }
+            _ => return vec!["NO DATA".to_owned()],
+        };
+
+        let mut data = vec![MODE_RESPONSE];
+        for &pid in pids {
+            if let Some(value) = self.pid(pid) {
+                data.push(pid);
+                data.extend(value);
+            }
+        }
+
+        if data.len() == 1 {
+            return vec!["NO DATA".to_owned()];
+        }
+
+        self.frames(&data)
+    }
+
+    /// The PID's data bytes, the supported bitmaps from the table
+    fn pid(&self, pid: u8) -> Option<Vec<u8>> {
+        if !pid.is_multiple_of(0x20) {
+            return self.config.pids.get(&pid).cloned();
+        }
+
+        // Bit 31 is the PID after the base, bit 0 says the next range is supported
+        let bitmap = self
+            .config
+            .pids
+            .keys()
+            .filter(|p| **p > pid)
+            .fold(0u32, |bitmap, &p| match p - pid {
+                offset @ 1..=0x20 => bitmap | (1 << (0x20 - offset)),
+                _ => bitmap | 1,
+            });
+
+        (pid == 0 || bitmap != 0).then(|| bitmap.to_be_bytes().to_vec())
+    }
+
+    /// ISO 15765 frames of a message from the engine, the PCI bytes are shown with headers on
+    fn frames(&self, data: &[u8]) -> Vec<String> {
+        let frames: Vec<Vec<u8>> = if data.len() <= SINGLE_FRAME_LEN {
+            vec![[&[data.len() as u8], data].concat()]
+        } else {
+            // First frame with the length, then consecutive frames numbered from 1
+            let (first, rest) = data.split_at(6);
+            std::iter::once([&[0x10, data.len() as u8], first].concat())
+                .chain(
+                    rest.chunks(SINGLE_FRAME_LEN)
+                        .enumerate()
+                        .map(|(i, chunk)| [&[0x20 | ((i + 1) % 0x10) as u8], chunk].concat()),
+                )
+                .collect()
+        };
+
+        let header = if self.eleven_bit {
+            "7E8"
+        } else {
+            "18 DA F1 10"
+        };
+        let separator = if self.spaces { " " } else { "" };
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("{b:02X}"))
+                .collect::<Vec<_>>()
+                .join(separator)
+        };
+
+        frames
+            .iter()
+            .map(|frame| {
+                if self.headers {
+                    format!(
+                        "{}{separator}{}",
+                        header.replace(' ', separator),
+                        hex(frame)
+                    )
+                } else {
+                    hex(&frame[1..])
+                }
+            })
+            .collect()
+    }
+}
+
+impl Read for MockElm {
+    /// The queued response, `TimedOut` with none as a real adapter that didn't answer
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.response.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No response from the mock adapter",
+            ));
+        }
+
+        self.try_read(buf)
+    }
+}
+
+impl Write for MockElm {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            if b == b'\r' {
+                let request = String::from_utf8_lossy(&self.request).into_owned();
+                self.request.clear();
+                self.answer(&request);
+            } else {
+                self.request.push(b);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ConnectionStatus for MockElm {
+    fn wait_connected(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {}
+
+    /// Drop anything not read, as after a dropped link
+    fn reconnect(&mut self) -> Result<()> {
+        self.request.clear();
+        self.response.clear();
+
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "mock".to_owned()
+    }
+}
+
+impl ElmTransport for MockElm {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.response.len());
+        for (b, r) in buf.iter_mut().zip(self.response.drain(..len)) {
+            *b = r;
+        }
+
+        Ok(len)
+    }
+
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
+        self.write_all(request)?;
+        self.write_all(b"\r")?;
+
+        Ok(())
+    }
+}
+
+/// `010C0D` to bytes, None unless it's all hex pairs
+fn parse_hex(command: &str) -> Option<Vec<u8>> {
+    if command.is_empty() || !command.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..command.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(command.get(i..i + 2)?, 16).ok())
+        .collect()
+}
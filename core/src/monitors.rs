@@ -0,0 +1,327 @@
+//! Mode 06 on-board monitoring test results, CAN (ISO 15765-4) format. Each monitor (OBDMID)
+//! reports its tests (TIDs) as a raw value and limits with a unit and scaling ID, decoded here
+//! with the SAE J1979 scalings. The O2 sensor tests that mode 05 has on older protocols are
+//! OBDMIDs 01 to 10 on CAN.
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+
+use crate::elm327::{parse_messages, Bus, Elm327};
+use crate::error::ElmError;
+
+const MODE_TEST_RESULTS: u8 = 0x06;
+const MODE_RESPONSE: u8 = MODE_TEST_RESULTS + 0x40;
+/// OBDMID, TID, UASID, then the test value, min and max limits, 2 bytes each
+const RECORD_LEN: usize = 9;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TestResult {
+    /// Responding module header, e.g. `7E8`
+    pub module: String,
+    pub mid: String,
+    /// Monitor name, e.g. `o2_sensor_b1s1`, None for one J1979 doesn't define
+    pub monitor: Option<&'static str>,
+    pub tid: String,
+    /// Standard test name, None for manufacturer tests (TID 80 and up)
+    pub test: Option<&'static str>,
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+    pub unit: &'static str,
+    pub passed: bool,
+}
+
+/// Read the test results of `mid`, or of every supported OBDMID with None
+pub fn request(elm: &mut Elm327<'_>, mid: Option<u8>) -> Result<Vec<TestResult>> {
+    let Some(mid) = mid else {
+        let mut results = Vec::new();
+        for mid in supported(elm)? {
+            match request_mid(elm, mid) {
+                Ok(mid_results) => results.extend(mid_results),
+                // One monitor not answering doesn't lose the others
+                Err(err) => warn!("OBDMID {mid:02X} failed {err:#}"),
+            }
+        }
+
+        return Ok(results);
+    };
+
+    if mid % 0x20 == 0 {
+        Err(ElmError::InvalidRequest(
+            "OBDMIDs 00, 20, 40.. are the supported bitmaps".to_owned(),
+        ))?;
+    }
+
+    request_mid(elm, mid)
+}
+
+/// The supported OBDMIDs from the bitmaps (OBDMIDs 00, 20, 40..), following each range's "next
+/// range supported" bit
+fn supported(elm: &mut Elm327<'_>) -> Result<Vec<u8>> {
+    let mut mids = Vec::new();
+
+    for base in (0x00..=0xE0u8).step_by(0x20) {
+        let bitmap = responses(elm, base)?
+            .iter()
+            .find_map(|(_, data)| data.get(2..6).filter(|_| data[1] == base))
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| ElmError::NoData(format!("OBDMID {base:02X} bitmap")))?;
+
+        // The last bit of each range is the next range, not a monitor
+        mids.extend(
+            (1..32u8)
+                .filter(|i| bitmap & (1 << (32 - i)) != 0)
+                .map(|i| base + i),
+        );
+
+        if bitmap & 1 == 0 {
+            break;
+        }
+    }
+
+    debug!("{} supported OBDMIDs", mids.len());
+
+    Ok(mids)
+}
+
+fn request_mid(elm: &mut Elm327<'_>, mid: u8) -> Result<Vec<TestResult>> {
+    let results: Vec<TestResult> = responses(elm, mid)?
+        .into_iter()
+        .flat_map(|(module, data)| {
+            data[1..]
+                .chunks_exact(RECORD_LEN)
+                .filter(|record| record[0] == mid)
+                .filter_map(|record| decode(&module, record))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    if results.is_empty() {
+        Err(ElmError::NoData(format!("OBDMID {mid:02X}")))?;
+    }
+
+    Ok(results)
+}
+
+/// The mode 06 responses to `mid` with the header of the module that sent each
+fn responses(elm: &mut Elm327<'_>, mid: u8) -> Result<Vec<(String, Vec<u8>)>> {
+    // OBD is on HS-CAN, a request for MS-CAN leaves the adapter there
+    elm.select_bus(Bus::Hs)?;
+    let lines = elm.transact_lines(format!("{MODE_TEST_RESULTS:02X} {mid:02X}").as_bytes())?;
+
+    Ok(parse_messages(&lines)
+        .into_iter()
+        .filter(|m| m.data.len() > 1 && m.data[0] == MODE_RESPONSE)
+        .map(|m| (m.header, m.data))
+        .collect())
+}
+
+/// One 9 byte record, None for a unit and scaling ID this doesn't know
+pub fn decode(module: &str, record: &[u8]) -> Option<TestResult> {
+    let [mid, tid, uas, v1, v2, min1, min2, max1, max2] = *record else {
+        return None;
+    };
+
+    let (unit, scale, offset) = unit_scaling(uas)?;
+    // UASIDs 81 and up are the signed versions of 01 and up
+    let scaled = |hi: u8, lo: u8| {
+        let raw = match uas {
+            0x80.. => i16::from_be_bytes([hi, lo]) as f32,
+            _ => u16::from_be_bytes([hi, lo]) as f32,
+        };
+        raw * scale + offset
+    };
+
+    let (value, min, max) = (scaled(v1, v2), scaled(min1, min2), scaled(max1, max2));
+
+    Some(TestResult {
+        module: module.to_owned(),
+        mid: format!("{mid:02X}"),
+        monitor: monitor_name(mid),
+        tid: format!("{tid:02X}"),
+        test: test_name(mid, tid),
+        value,
+        min,
+        max,
+        unit,
+        passed: (min..=max).contains(&value),
+    })
+}
+
+/// Unit, scale and offset of the SAE J1979 unit and scaling IDs
+pub fn unit_scaling(uas: u8) -> Option<(&'static str, f32, f32)> {
+    let scaling = match uas {
+        0x01 | 0x81 => ("", 1.0, 0.0),
+        0x02 | 0x82 => ("", 0.1, 0.0),
+        0x03 | 0x83 => ("", 0.01, 0.0),
+        0x04 | 0x84 => ("", 0.001, 0.0),
+        0x05 | 0x85 => ("", 1.0 / 32768.0, 0.0),
+        0x06 | 0x86 => ("", 0.000305, 0.0),
+        0x07 => ("rpm", 0.25, 0.0),
+        0x08 => ("km/h", 0.01, 0.0),
+        0x09 => ("km/h", 1.0, 0.0),
+        0x0A | 0x8A => ("mV", 0.122, 0.0),
+        0x0B | 0x8B => ("V", 0.001, 0.0),
+        0x0C | 0x8C => ("V", 0.01, 0.0),
+        0x0D | 0x8D => ("mA", 1.0 / 256.0, 0.0),
+        0x0E | 0x8E => ("A", 0.001, 0.0),
+        0x0F => ("A", 0.01, 0.0),
+        0x10 | 0x90 => ("ms", 1.0, 0.0),
+        0x11 => ("ms", 100.0, 0.0),
+        0x12 => ("s", 1.0, 0.0),
+        0x13 => ("mOhm", 1.0, 0.0),
+        0x14 => ("Ohm", 1.0, 0.0),
+        0x15 => ("kOhm", 1.0, 0.0),
+        0x16 => ("C", 0.1, -40.0),
+        0x96 => ("C", 0.1, 0.0),
+        0x17 => ("kPa", 0.01, 0.0),
+        0x18 => ("kPa", 0.0117, 0.0),
+        0x19 => ("kPa", 0.079, 0.0),
+        0x1A => ("kPa", 1.0, 0.0),
+        0x1B => ("kPa", 10.0, 0.0),
+        0x1C | 0x9C => ("deg", 0.01, 0.0),
+        0x1D | 0x9D => ("deg", 0.5, 0.0),
+        0x1E => ("ratio", 1.0 / 32768.0, 0.0),
+        0x1F => ("ratio", 0.05, 0.0),
+        0x20 => ("ratio", 1.0 / 256.0, 0.0),
+        0x21 => ("mHz", 1.0, 0.0),
+        0x22 => ("Hz", 1.0, 0.0),
+        0x23 => ("kHz", 1.0, 0.0),
+        0x24 => ("counts", 1.0, 0.0),
+        0x25 => ("km", 1.0, 0.0),
+        0x26 => ("mV/ms", 0.1, 0.0),
+        0x27 => ("g/s", 0.01, 0.0),
+        0x28 | 0xA8 => ("g/s", 1.0, 0.0),
+        0x29 | 0xA9 => ("Pa/s", 0.25, 0.0),
+        0x2A => ("kg/h", 0.001, 0.0),
+        0x2B => ("switches", 1.0, 0.0),
+        0x2C => ("g/cyl", 0.01, 0.0),
+        0x2D | 0xAD => ("mg/stroke", 0.01, 0.0),
+        0x2E => ("", 1.0, 0.0),
+        0x2F | 0xAF => ("%", 0.01, 0.0),
+        0x30 => ("%", 0.001526, 0.0),
+        0x31 => ("L", 0.001, 0.0),
+        0x34 => ("min", 1.0, 0.0),
+        0x35 => ("ms", 10.0, 0.0),
+        0xFC => ("kPa", 0.01, 0.0),
+        0xFD => ("kPa", 0.001, 0.0),
+        0xFE => ("Pa", 0.25, 0.0),
+        _ => return None,
+    };
+
+    Some(scaling)
+}
+
+/// Short OBDMID name, `b1s1` is bank 1 sensor 1
+pub fn monitor_name(mid: u8) -> Option<&'static str> {
+    const O2_SENSORS: [&str; 16] = [
+        "o2_sensor_b1s1",
+        "o2_sensor_b1s2",
+        "o2_sensor_b1s3",
+        "o2_sensor_b1s4",
+        "o2_sensor_b2s1",
+        "o2_sensor_b2s2",
+        "o2_sensor_b2s3",
+        "o2_sensor_b2s4",
+        "o2_sensor_b3s1",
+        "o2_sensor_b3s2",
+        "o2_sensor_b3s3",
+        "o2_sensor_b3s4",
+        "o2_sensor_b4s1",
+        "o2_sensor_b4s2",
+        "o2_sensor_b4s3",
+        "o2_sensor_b4s4",
+    ];
+    const O2_HEATERS: [&str; 16] = [
+        "o2_heater_b1s1",
+        "o2_heater_b1s2",
+        "o2_heater_b1s3",
+        "o2_heater_b1s4",
+        "o2_heater_b2s1",
+        "o2_heater_b2s2",
+        "o2_heater_b2s3",
+        "o2_heater_b2s4",
+        "o2_heater_b3s1",
+        "o2_heater_b3s2",
+        "o2_heater_b3s3",
+        "o2_heater_b3s4",
+        "o2_heater_b4s1",
+        "o2_heater_b4s2",
+        "o2_heater_b4s3",
+        "o2_heater_b4s4",
+    ];
+    const MISFIRE_CYLINDERS: [&str; 12] = [
+        "misfire_cyl1",
+        "misfire_cyl2",
+        "misfire_cyl3",
+        "misfire_cyl4",
+        "misfire_cyl5",
+        "misfire_cyl6",
+        "misfire_cyl7",
+        "misfire_cyl8",
+        "misfire_cyl9",
+        "misfire_cyl10",
+        "misfire_cyl11",
+        "misfire_cyl12",
+    ];
+
+    let name = match mid {
+        0x01..=0x10 => O2_SENSORS[(mid - 0x01) as usize],
+        0x21 => "catalyst_b1",
+        0x22 => "catalyst_b2",
+        0x23 => "catalyst_b3",
+        0x24 => "catalyst_b4",
+        0x31 => "egr_b1",
+        0x32 => "egr_b2",
+        0x33 => "egr_b3",
+        0x34 => "egr_b4",
+        0x35 => "vvt_b1",
+        0x36 => "vvt_b2",
+        0x37 => "vvt_b3",
+        0x38 => "vvt_b4",
+        0x39 => "evap_cap_off",
+        0x3A => "evap_0090",
+        0x3B => "evap_0040",
+        0x3C => "evap_0020",
+        0x3D => "purge_flow",
+        0x41..=0x50 => O2_HEATERS[(mid - 0x41) as usize],
+        0x61 => "heated_catalyst_b1",
+        0x62 => "heated_catalyst_b2",
+        0x63 => "heated_catalyst_b3",
+        0x64 => "heated_catalyst_b4",
+        0x71 => "secondary_air_1",
+        0x72 => "secondary_air_2",
+        0x73 => "secondary_air_3",
+        0x74 => "secondary_air_4",
+        0x81 => "fuel_system_b1",
+        0x82 => "fuel_system_b2",
+        0x83 => "fuel_system_b3",
+        0x84 => "fuel_system_b4",
+        0xA1 => "misfire_general",
+        0xA2..=0xAD => MISFIRE_CYLINDERS[(mid - 0xA2) as usize],
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Standard TID name, the O2 sensor tests (01 to 0A) and the misfire counts
+pub fn test_name(mid: u8, tid: u8) -> Option<&'static str> {
+    let name = match (mid, tid) {
+        (0x01..=0x10, 0x01) => "rich_to_lean_threshold",
+        (0x01..=0x10, 0x02) => "lean_to_rich_threshold",
+        (0x01..=0x10, 0x03) => "low_switch_voltage",
+        (0x01..=0x10, 0x04) => "high_switch_voltage",
+        (0x01..=0x10, 0x05) => "rich_to_lean_time",
+        (0x01..=0x10, 0x06) => "lean_to_rich_time",
+        (0x01..=0x10, 0x07) => "min_voltage",
+        (0x01..=0x10, 0x08) => "max_voltage",
+        (0x01..=0x10, 0x09) => "transition_time",
+        (0x01..=0x10, 0x0A) => "sensor_period",
+        (0xA1..=0xAD, 0x0B) => "misfire_ewma",
+        (0xA1..=0xAD, 0x0C) => "misfire_count",
+        _ => return None,
+    };
+
+    Some(name)
+}
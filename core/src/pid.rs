@@ -0,0 +1,301 @@
+//! Mode 01 (current data) PID requests and decoding
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::Result;
+use log::*;
+
+use crate::elm327::{parse_messages, Bus, Elm327};
+use crate::error::ElmError;
+
+const MODE_CURRENT_DATA: u8 = 0x01;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const MODE_RESPONSE: u8 = MODE_CURRENT_DATA + POSITIVE_RESPONSE_OFFSET;
+
+/// Supported PID bitmaps from PIDs 00, 20, 40.. (bit 31 is base + 1), None until discovered
+static SUPPORTED: Mutex<Option<[u32; 8]>> = Mutex::new(None);
+
+/// Last decoded value of each PID read, for /metrics
+static LAST_VALUES: Mutex<BTreeMap<u8, f32>> = Mutex::new(BTreeMap::new());
+
+pub const MONITOR_STATUS: u8 = 0x01;
+pub const COOLANT_TEMP: u8 = 0x05;
+pub const SPEED: u8 = 0x0D;
+pub const MONITOR_STATUS_THIS_CYCLE: u8 = 0x41;
+pub const AMBIENT_TEMP: u8 = 0x46;
+pub const ENGINE_RUN_TIME: u8 = 0x7F;
+pub const ODOMETER: u8 = 0xA6;
+
+/// Most PIDs the ELM will put in one CAN mode 01 request
+pub const MAX_PIDS_PER_REQUEST: usize = 6;
+
+/// Request a mode 01 PID and return the decoded value
+pub fn request(elm: &mut Elm327<'_>, pid: u8) -> Result<f32> {
+    let data = request_data(elm, pid)?;
+
+    let value = decode(pid, &data)
+        .ok_or_else(|| ElmError::NoData(format!("pid {pid:02X} not decodable")))?;
+    LAST_VALUES.lock().unwrap().insert(pid, value);
+
+    Ok(value)
+}
+
+/// Request a mode 01 PID and return the raw data bytes after the mode and PID, for bitmapped
+/// PIDs that don't decode to a value
+pub fn request_data(elm: &mut Elm327<'_>, pid: u8) -> Result<Vec<u8>> {
+    // OBD is on HS-CAN, a request for MS-CAN leaves the adapter there
+    elm.select_bus(Bus::Hs)?;
+    let lines = elm.transact_lines(format!("{MODE_CURRENT_DATA:02X} {pid:02X}").as_bytes())?;
+
+    let mut data = parse_messages(&lines)
+        .into_iter()
+        .map(|m| m.data)
+        .find(|data| data.len() > 2 && data[0] == MODE_RESPONSE && data[1] == pid)
+        .ok_or_else(|| ElmError::NoData(format!("pid {pid:02X}")))?;
+
+    data.drain(..2);
+
+    Ok(data)
+}
+
+/// Read the supported PID bitmaps (PIDs 00, 20, 40...), following each range's "next range
+/// supported" bit, and keep them for `is_supported`
+pub fn discover_supported(elm: &mut Elm327<'_>) -> Result<Vec<u8>> {
+    let mut bitmaps = [0u32; 8];
+
+    for (i, base) in (0x00..=0xE0u8).step_by(0x20).enumerate() {
+        let data = request_data(elm, base)?;
+        let Some(bitmap) = data.first_chunk::<4>().map(|b| u32::from_be_bytes(*b)) else {
+            return Err(ElmError::NoData(format!("pid {base:02X} bitmap")).into());
+        };
+
+        bitmaps[i] = bitmap;
+
+        // The last PID of the range says if the next range is supported
+        if bitmap & 1 == 0 {
+            break;
+        }
+    }
+
+    *SUPPORTED.lock().unwrap() = Some(bitmaps);
+
+    let pids = supported().unwrap_or_default();
+    info!("{} supported PIDs", pids.len());
+
+    Ok(pids)
+}
+
+/// The supported PIDs, None if discovery hasn't succeeded
+pub fn supported() -> Option<Vec<u8>> {
+    let bitmaps = (*SUPPORTED.lock().unwrap())?;
+
+    Some((1..=0xFFu8).filter(|pid| is_set(&bitmaps, *pid)).collect())
+}
+
+/// Last value read for each PID since boot, whoever asked for it
+pub fn last_values() -> BTreeMap<u8, f32> {
+    LAST_VALUES.lock().unwrap().clone()
+}
+
+/// True if the vehicle supports the PID, or support isn't known
+pub fn is_supported(pid: u8) -> bool {
+    match *SUPPORTED.lock().unwrap() {
+        Some(bitmaps) => pid == 0 || is_set(&bitmaps, pid),
+        None => true,
+    }
+}
+
+/// Error naming the first PID the vehicle doesn't support
+pub fn check_supported(pids: &[u8]) -> Result<(), ElmError> {
+    match pids.iter().find(|p| !is_supported(**p)) {
+        Some(pid) => Err(ElmError::UnsupportedPid(*pid)),
+        None => Ok(()),
+    }
+}
+
+fn is_set(bitmaps: &[u32; 8], pid: u8) -> bool {
+    let index = (pid - 1) as usize;
+    bitmaps[index / 32] & (1 << (31 - index % 32)) != 0
+}
+
+/// Request several PIDs in one SAE J1979 mode 01 message, up to `MAX_PIDS_PER_REQUEST` per
+/// adapter request. PIDs with no response are left out.
+///
+/// Falls back to one request per PID if the ECU doesn't answer multi-PID requests (e.g. non-CAN
+/// protocols), and once that's been seen to work sticks with single requests until the adapter
+/// is set up again or reconnects.
+pub fn request_many(elm: &mut Elm327<'_>, pids: &[u8]) -> Result<BTreeMap<u8, f32>> {
+    let mut values = BTreeMap::new();
+
+    for chunk in pids.chunks(MAX_PIDS_PER_REQUEST) {
+        if chunk.len() > 1 && !elm.single_pid_only() {
+            let found = request_chunk(elm, chunk, &mut values)?;
+            if found {
+                continue;
+            }
+        }
+
+        let mut found = false;
+        for pid in chunk {
+            if let Ok(value) = request(elm, *pid) {
+                values.insert(*pid, value);
+                found = true;
+            }
+        }
+
+        if found && chunk.len() > 1 && !elm.single_pid_only() {
+            info!("ECU doesn't answer multi-PID requests, using single PID requests");
+            elm.set_single_pid_only();
+        }
+    }
+
+    Ok(values)
+}
+
+/// One multi-PID request, true if any of the PIDs answered
+fn request_chunk(
+    elm: &mut Elm327<'_>,
+    chunk: &[u8],
+    values: &mut BTreeMap<u8, f32>,
+) -> Result<bool> {
+    let mut command = format!("{MODE_CURRENT_DATA:02X}");
+    for pid in chunk {
+        command.push_str(&format!(" {pid:02X}"));
+    }
+
+    elm.select_bus(Bus::Hs)?;
+    let lines = elm.transact_lines(command.as_bytes())?;
+
+    let mut found = false;
+    for message in parse_messages(&lines) {
+        for (pid, value) in decode_many(&message.data) {
+            if chunk.contains(&pid) {
+                LAST_VALUES.lock().unwrap().insert(pid, value);
+                values.insert(pid, value);
+                found = true;
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Decode a mode 01 response carrying several PIDs, `41 pid data.. pid data..`. Stops at the
+/// first PID whose length isn't known.
+fn decode_many(data: &[u8]) -> Vec<(u8, f32)> {
+    let mut values = Vec::new();
+
+    let Some((&MODE_RESPONSE, mut rest)) = data.split_first() else {
+        return values;
+    };
+
+    while let Some((&pid, tail)) = rest.split_first() {
+        let Some(len) = data_len(pid).filter(|len| *len <= tail.len()) else {
+            break;
+        };
+
+        if let Some(value) = decode(pid, &tail[..len]) {
+            values.push((pid, value));
+        }
+
+        rest = &tail[len..];
+    }
+
+    values
+}
+
+/// Number of data bytes for the decodable PIDs
+fn data_len(pid: u8) -> Option<usize> {
+    match pid {
+        0x0C | 0x10 | 0x1F | 0x21 | 0x31 | 0x42 | 0x4D | 0x4E | 0x5E => Some(2),
+        0x9B | 0xA6 => Some(4),
+        _ if decode(pid, &[0]).is_some() => Some(1),
+        _ => None,
+    }
+}
+
+/// Decode the data bytes (after the mode and PID) of a mode 01 response
+pub fn decode(pid: u8, data: &[u8]) -> Option<f32> {
+    let a = *data.first()? as f32;
+    let ab = || Some((*data.first()? as f32) * 256.0 + *data.get(1)? as f32);
+    let abcd = || Some(u32::from_be_bytes(*data.first_chunk::<4>()?) as f32);
+
+    let value = match pid {
+        0x04 | 0x11 | 0x2F | 0x45 | 0x47 | 0x49 | 0x4C | 0x5A => a * 100.0 / 255.0,
+        0x05 | 0x0F | 0x46 | 0x5C => a - 40.0,
+        0x0A => a * 3.0,
+        0x0B | 0x0D | 0x33 => a,
+        0x0C => ab()? / 4.0,
+        0x0E => a / 2.0 - 64.0,
+        0x10 => ab()? / 100.0,
+        0x1F | 0x21 | 0x31 | 0x4D | 0x4E => ab()?,
+        0x42 => ab()? / 1000.0,
+        0x5E => ab()? / 20.0,
+        // DEF sensor data, the tank level is the last byte
+        0x9B => *data.get(3)? as f32 * 100.0 / 255.0,
+        0xA6 => abcd()? / 10.0,
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// Unit and the range the formula can give, for the PIDs `decode` handles
+pub fn unit_range(pid: u8) -> Option<(&'static str, f32, f32)> {
+    let range = match pid {
+        0x04 | 0x11 | 0x2F | 0x45 | 0x47 | 0x49 | 0x4C | 0x5A => ("%", 0.0, 100.0),
+        0x05 | 0x0F | 0x46 | 0x5C => ("C", -40.0, 215.0),
+        0x0A => ("kPa", 0.0, 765.0),
+        0x0B | 0x33 => ("kPa", 0.0, 255.0),
+        0x0C => ("rpm", 0.0, 16383.75),
+        0x0D => ("km/h", 0.0, 255.0),
+        0x0E => ("deg", -64.0, 63.5),
+        0x10 => ("g/s", 0.0, 655.35),
+        0x1F => ("s", 0.0, 65535.0),
+        0x21 | 0x31 => ("km", 0.0, 65535.0),
+        0x42 => ("V", 0.0, 65.535),
+        0x4D | 0x4E => ("min", 0.0, 65535.0),
+        0x5E => ("L/h", 0.0, 3276.75),
+        0x9B => ("%", 0.0, 100.0),
+        0xA6 => ("km", 0.0, 429496729.5),
+        _ => return None,
+    };
+
+    Some(range)
+}
+
+/// Short channel name, used for log headers
+pub fn name(pid: u8) -> Option<&'static str> {
+    let name = match pid {
+        0x04 => "engine_load",
+        0x05 => "coolant_temp",
+        0x0A => "fuel_pressure",
+        0x0B => "map",
+        0x0C => "rpm",
+        0x0D => "speed",
+        0x0E => "timing_advance",
+        0x0F => "intake_temp",
+        0x10 => "maf",
+        0x11 => "throttle",
+        0x1F => "run_time",
+        0x21 => "mil_distance",
+        0x2F => "fuel_level",
+        0x31 => "clear_distance",
+        0x33 => "baro",
+        0x42 => "voltage",
+        0x45 => "rel_throttle",
+        0x46 => "ambient_temp",
+        0x47 => "abs_throttle_b",
+        0x49 => "accel_pedal_d",
+        0x4C => "throttle_actuator",
+        0x4D => "mil_time",
+        0x4E => "clear_time",
+        0x5A => "rel_accel_pedal",
+        0x5C => "oil_temp",
+        0x5E => "fuel_rate",
+        0x9B => "def_level",
+        0xA6 => "odometer",
+        _ => return None,
+    };
+
+    Some(name)
+}
@@ -0,0 +1,62 @@
+use std::sync::Mutex;
+
+use circular_buffer::CircularBuffer;
+use log::*;
+
+/// Optional client supplied ID used to recognise a retried request
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const CACHE_SIZE: usize = 8;
+const MAX_ID_LEN: usize = 32;
+
+type RequestId = heapless::String<MAX_ID_LEN>;
+
+/// The last few responses keyed by request ID.
+///
+/// When a client times out and retries with the same ID the cached response is returned instead
+/// of sending the command to the ELM again, so a retried mode 04 clear isn't run twice.
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<CircularBuffer<CACHE_SIZE, (RequestId, String)>>,
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(CircularBuffer::new()),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+
+        let response = entries
+            .iter()
+            .rev()
+            .find(|(entry_id, _)| entry_id.as_str() == id)
+            .map(|(_, response)| response.clone());
+
+        if response.is_some() {
+            debug!("Request id ({id}) retried, using cached response");
+        }
+
+        response
+    }
+
+    /// Cache a response, the oldest response is dropped once the cache is full. IDs longer than
+    /// `MAX_ID_LEN` are not cached, and an ID already cached keeps its response.
+    pub fn insert(&self, id: &str, response: &str) {
+        let Ok(id) = RequestId::try_from(id) else {
+            warn!("Request id too long to cache, max ({MAX_ID_LEN})");
+            return;
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.iter().any(|(entry_id, _)| *entry_id == id) {
+            return;
+        }
+
+        entries.push_back((id, response.to_owned()));
+    }
+}
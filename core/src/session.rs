@@ -0,0 +1,203 @@
+//! Session recording and replay, for developing a client at a desk against real responses. In
+//! record mode every vehicle request the adapter answers is appended to the session file as a
+//! JSON line, in replay mode they're answered from that file and never reach the adapter.
+//! `Elm327` records and replays each request it writes, whichever path wrote it, while AT and ST
+//! commands always go to the adapter. The mode isn't kept across a reboot.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::uptime_ms;
+use crate::elm327::normalise;
+use crate::error::ElmError;
+
+/// Replay loads the whole recording, so it has to fit in the heap
+const MAX_RECORDING_LEN: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionMode {
+    #[default]
+    Off,
+    /// Starts a new recording
+    Record,
+    Replay,
+}
+
+/// A request and its response lines, one per line of the recording
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    ms: u64,
+    request: String,
+    lines: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionStatus {
+    pub mode: SessionMode,
+    /// Exchanges in the recording, recorded so far or loaded for replay
+    pub exchanges: usize,
+    pub bytes: u64,
+}
+
+struct State {
+    mode: SessionMode,
+    file: Option<File>,
+    exchanges: usize,
+    bytes: u64,
+    /// The recorded responses by request, replayed in turn and then from the first again
+    replay: BTreeMap<String, (Vec<Vec<String>>, usize)>,
+}
+
+pub struct Session {
+    /// The recording, replaced by each new one
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Session {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            state: Mutex::new(State {
+                mode: SessionMode::Off,
+                file: None,
+                exchanges: 0,
+                bytes: 0,
+                replay: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        let state = self.state.lock().unwrap();
+
+        SessionStatus {
+            mode: state.mode,
+            exchanges: state.exchanges,
+            bytes: state.bytes,
+        }
+    }
+
+    pub fn mode(&self) -> SessionMode {
+        self.state.lock().unwrap().mode
+    }
+
+    pub fn set_mode(&self, mode: SessionMode) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.file = None;
+        state.replay.clear();
+        state.exchanges = 0;
+        state.bytes = 0;
+        // Until the new mode is ready, a recording that won't load leaves it off
+        state.mode = SessionMode::Off;
+
+        match mode {
+            SessionMode::Off => {}
+            SessionMode::Record => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                state.file = Some(File::create(&self.path)?);
+            }
+            SessionMode::Replay => {
+                let file = File::open(&self.path)?;
+                state.bytes = file.metadata()?.len();
+                if state.bytes > MAX_RECORDING_LEN {
+                    Err(ElmError::InvalidRequest(format!(
+                        "recording over {MAX_RECORDING_LEN} bytes"
+                    )))?;
+                }
+
+                // A torn last line is skipped, it won't parse
+                for line in BufReader::new(file).lines() {
+                    let Ok(exchange) = serde_json::from_str::<Exchange>(&line?) else {
+                        continue;
+                    };
+
+                    state
+                        .replay
+                        .entry(exchange.request)
+                        .or_default()
+                        .0
+                        .push(exchange.lines);
+                    state.exchanges += 1;
+                }
+            }
+        }
+
+        state.mode = mode;
+        info!("Session {mode:?}, {} exchanges", state.exchanges);
+
+        Ok(())
+    }
+
+    /// The next recorded response lines for `request`, a request that wasn't recorded is no data.
+    /// Matched without spaces or case, `01 0c` replays a recorded `010C`.
+    pub fn replay(&self, request: &[u8]) -> Result<Vec<String>> {
+        let mut state = self.state.lock().unwrap();
+        let request = normalise(request);
+
+        let (responses, next) = state
+            .replay
+            .get_mut(&request)
+            .ok_or_else(|| ElmError::NoData(format!("{request}, not in the recording")))?;
+
+        let lines = responses[*next].clone();
+        *next = (*next + 1) % responses.len();
+
+        Ok(lines)
+    }
+
+    /// Append an exchange while recording, recording stops at `MAX_RECORDING_LEN`
+    pub fn record(&self, request: &[u8], lines: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        if state.mode != SessionMode::Record {
+            return;
+        }
+
+        let exchange = Exchange {
+            ms: uptime_ms(),
+            request: normalise(request),
+            lines: lines.to_vec(),
+        };
+        let Ok(mut line) = serde_json::to_vec(&exchange) else {
+            return;
+        };
+        line.push(b'\n');
+
+        if state.bytes + line.len() as u64 > MAX_RECORDING_LEN {
+            warn!("Session recording full at {} exchanges", state.exchanges);
+            state.mode = SessionMode::Off;
+            state.file = None;
+            return;
+        }
+
+        let written = state
+            .file
+            .as_mut()
+            .map(|file| file.write_all(&line).and_then(|_| file.flush()));
+
+        match written {
+            Some(Ok(())) => {
+                state.exchanges += 1;
+                state.bytes += line.len() as u64;
+            }
+            Some(Err(err)) => {
+                error!("Session recording failed {err}");
+                state.mode = SessionMode::Off;
+                state.file = None;
+            }
+            None => {}
+        }
+    }
+}
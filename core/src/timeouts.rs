@@ -0,0 +1,76 @@
+//! Adapter timeouts, the adaptive timing mode (ATAT) and the response timeout (ATST)
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Milliseconds, the adapter timeout for this request only
+pub const TIMEOUT_HEADER: &str = "X-Elm-Timeout";
+
+/// ATST counts 4.096ms steps up to FF
+pub const MAX_TIMEOUT_MS: u32 = 1044;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeoutConfig {
+    /// ATAT mode, 0 off, 1 normal, 2 aggressive
+    pub adaptive: u8,
+    /// ATST, how long the adapter waits for the vehicle, or the starting point for adaptive
+    /// timing
+    pub timeout_ms: u32,
+}
+
+impl Default for TimeoutConfig {
+    /// The ELM327 defaults, ATAT 1 and ATST 32
+    fn default() -> Self {
+        Self {
+            adaptive: 1,
+            timeout_ms: 205,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// A fixed timeout, adaptive timing would shorten it again
+    pub fn fixed(timeout_ms: u32) -> Self {
+        Self {
+            adaptive: 0,
+            timeout_ms,
+        }
+    }
+
+    /// ATAT then ATST
+    pub fn commands(&self) -> [String; 2] {
+        // Rounded up, so the adapter never waits less than asked
+        let st = (self.timeout_ms * 1000).div_ceil(4096).clamp(1, 0xFF);
+
+        [format!("ATAT {}", self.adaptive), format!("ATST {st:02X}")]
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.adaptive > 2 {
+            Err(anyhow!("adaptive must be 0, 1 or 2"))?;
+        }
+        check_timeout(self.timeout_ms)
+    }
+}
+
+pub fn check_timeout(timeout_ms: u32) -> Result<()> {
+    if !(1..=MAX_TIMEOUT_MS).contains(&timeout_ms) {
+        Err(anyhow!("Timeout must be 1 to {MAX_TIMEOUT_MS}ms"))?;
+    }
+
+    Ok(())
+}
+
+/// The timeout from the `X-Elm-Timeout` header, None without one
+pub fn from_header(value: Option<&str>) -> Result<Option<u32>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    let timeout_ms = value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("{TIMEOUT_HEADER} must be milliseconds"))?;
+    check_timeout(timeout_ms)?;
+
+    Ok(Some(timeout_ms))
+}
@@ -0,0 +1,37 @@
+//! The link to the adapter. `Elm327` talks to any `ElmTransport`, BT SPP (`spp_handler`), a wired
+//! UART (`uart_handler`) or TCP to a WiFi adapter (`tcp_handler`), so another transport is an impl
+//! of these traits rather than a change to the ELM327 code.
+use std::io::{self, Read, Write};
+
+use anyhow::Result;
+
+/// Whether the link is up, and bringing it up or down
+pub trait ConnectionStatus {
+    /// Wait for the link to come up after `reconnect`
+    fn wait_connected(&mut self) -> Result<()>;
+
+    /// Close the link, e.g. before sleeping
+    fn disconnect(&mut self);
+
+    /// Open the link again. Requests wait for it to be up.
+    fn reconnect(&mut self) -> Result<()>;
+
+    /// The adapter for the logs, e.g. its BT address
+    fn name(&self) -> String;
+}
+
+/// A byte stream to the adapter. Reads block up to the transport's read timeout and fail with
+/// `TimedOut`, see `ErrorCode::of` for the other error kinds.
+pub trait ElmTransport: Read + Write + ConnectionStatus + Send {
+    /// Read whatever has been received without waiting, e.g. unsolicited adapter messages
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write a request, the trailing '\r' is added
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()>;
+
+    /// Follow the adapter to another UART rate after `STBR`, None for the rate the link started
+    /// at. Only a wired link has a rate of its own, others can size their buffers for it.
+    fn set_baud_rate(&mut self, _baud: Option<u32>) -> Result<()> {
+        Ok(())
+    }
+}
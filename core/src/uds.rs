@@ -0,0 +1,229 @@
+//! UDS (ISO 14229) services on top of the ELM327
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::*;
+
+use crate::elm327::{parse_messages, Bus, Elm327};
+use crate::error::UdsError;
+
+pub const SID_SESSION_CONTROL: u8 = 0x10;
+pub const SID_READ_DATA_BY_ID: u8 = 0x22;
+pub const SID_TESTER_PRESENT: u8 = 0x3E;
+
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+pub const SESSION_DEFAULT: u8 = 0x01;
+pub const SESSION_EXTENDED: u8 = 0x03;
+
+/// Tester present interval, inside the 5s S3 timer after which an ECU drops a non default session
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+/// Sessions kept open at once, one per ECU
+const MAX_OPEN_SESSIONS: usize = 4;
+
+/// Send a UDS request and return the positive response, including the response SID.
+///
+/// Response pending (NRC 0x78) responses are skipped, the ELM keeps listening until its timeout
+/// so the final response arrives with the same prompt.
+pub fn request(elm: &mut Elm327<'_>, request: &[u8]) -> Result<Vec<u8>> {
+    let sid = *request
+        .first()
+        .ok_or(UdsError::Malformed("empty request"))?;
+
+    let hex: Vec<String> = request.iter().map(|b| format!("{b:02X}")).collect();
+    let lines = elm.transact_lines(hex.join(" ").as_bytes())?;
+
+    let response = parse_messages(&lines)
+        .into_iter()
+        .map(|m| m.data)
+        .rfind(|data| {
+            !(data.len() >= 3 && data[0] == NEGATIVE_RESPONSE && data[2] == NRC_RESPONSE_PENDING)
+        })
+        .ok_or(UdsError::NoResponse)?;
+
+    match response.as_slice() {
+        [NEGATIVE_RESPONSE, _, nrc, ..] => {
+            debug!("UDS negative response sid {sid:02X}, nrc {nrc:02X}");
+            Err(UdsError::Negative { sid, nrc: *nrc }.into())
+        }
+        [rsid, ..] if *rsid == sid.wrapping_add(POSITIVE_RESPONSE_OFFSET) => Ok(response),
+        _ => Err(UdsError::Malformed("unexpected response sid").into()),
+    }
+}
+
+/// ReadDataByIdentifier (0x22), returns the data record without the echoed DID
+pub fn read_did(elm: &mut Elm327<'_>, did: u16) -> Result<Vec<u8>> {
+    let [did_hi, did_lo] = did.to_be_bytes();
+    let response = request(elm, &[SID_READ_DATA_BY_ID, did_hi, did_lo])?;
+
+    if response.len() < 3 || response[1..3] != [did_hi, did_lo] {
+        Err(UdsError::Malformed("DID mismatch"))?;
+    }
+
+    Ok(response[3..].to_vec())
+}
+
+/// DiagnosticSessionControl (0x10), returns the session parameter record (P2 timings). A non
+/// default session is kept open with tester present until the default one is asked for.
+pub fn session_control(elm: &mut Elm327<'_>, session: u8) -> Result<Vec<u8>> {
+    let response = request(elm, &[SID_SESSION_CONTROL, session])?;
+
+    let ecu = Ecu::current(elm);
+    if session == SESSION_DEFAULT {
+        elm.keep_alive().close(&ecu);
+    } else {
+        elm.keep_alive().open(ecu, session);
+    }
+
+    Ok(response.get(2..).unwrap_or_default().to_vec())
+}
+
+/// TesterPresent (0x3E), keeps a non default session open
+pub fn tester_present(elm: &mut Elm327<'_>) -> Result<()> {
+    request(elm, &[SID_TESTER_PRESENT, 0x00])?;
+
+    let ecu = Ecu::current(elm);
+    elm.keep_alive().sent(&ecu);
+
+    Ok(())
+}
+
+/// Send tester present to each open session that's due, called by the ELM worker between
+/// requests. A session whose ECU doesn't answer or refuses it has ended and is dropped, as is one
+/// that can't be addressed, e.g. with the link down, so it isn't due again straight away. The
+/// default header is restored afterwards.
+pub fn keep_alive(elm: &mut Elm327<'_>) -> Result<()> {
+    let due = elm.keep_alive().due();
+
+    for ecu in due {
+        if let Err(err) = tester_present_to(elm, &ecu) {
+            warn!("UDS session on {ecu:?} ended, {err:#}");
+            elm.keep_alive().close(&ecu);
+        }
+    }
+
+    Ok(())
+}
+
+fn tester_present_to(elm: &mut Elm327<'_>, ecu: &Ecu) -> Result<()> {
+    elm.select_bus(ecu.bus)?;
+    match &ecu.header {
+        Some(header) => elm.set_header(header)?,
+        None => elm.restore_header()?,
+    }
+
+    let result = tester_present(elm);
+    elm.restore_header()?;
+
+    result
+}
+
+/// Where a session is open, the bus and the header it was opened with
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Ecu {
+    bus: Bus,
+    /// None for the bus's default header
+    header: Option<String>,
+}
+
+impl Ecu {
+    fn current(elm: &Elm327<'_>) -> Self {
+        Self {
+            bus: elm.bus(),
+            header: elm.header().map(str::to_owned),
+        }
+    }
+}
+
+struct OpenSession {
+    ecu: Ecu,
+    /// Tester present or the session request, whichever was last
+    last_sent: Instant,
+}
+
+/// The non default sessions open on one adapter, kept by its `Elm327` as the ECUs drop them when
+/// the adapter goes quiet
+#[derive(Default)]
+pub struct KeepAlive {
+    sessions: Vec<OpenSession>,
+}
+
+impl KeepAlive {
+    fn open(&mut self, ecu: Ecu, session: u8) {
+        self.close(&ecu);
+
+        if self.sessions.len() >= MAX_OPEN_SESSIONS {
+            warn!("{MAX_OPEN_SESSIONS} UDS sessions open, not keeping {ecu:?} alive");
+            return;
+        }
+
+        info!("UDS session {session:02X} open on {ecu:?}, keeping it alive");
+        self.sessions.push(OpenSession {
+            ecu,
+            last_sent: Instant::now(),
+        });
+    }
+
+    fn close(&mut self, ecu: &Ecu) {
+        self.sessions.retain(|open| open.ecu != *ecu);
+    }
+
+    fn sent(&mut self, ecu: &Ecu) {
+        if let Some(open) = self.sessions.iter_mut().find(|open| open.ecu == *ecu) {
+            open.last_sent = Instant::now();
+        }
+    }
+
+    fn due(&self) -> Vec<Ecu> {
+        self.sessions
+            .iter()
+            .filter(|open| open.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL)
+            .map(|open| open.ecu.clone())
+            .collect()
+    }
+
+    /// When the next tester present is due, None without an open session
+    pub fn next_due(&self) -> Option<Instant> {
+        self.sessions
+            .iter()
+            .map(|open| open.last_sent + KEEP_ALIVE_INTERVAL)
+            .min()
+    }
+
+    /// The adapter was reset or went to sleep, the ECUs will drop every session
+    pub fn clear(&mut self) {
+        if !self.sessions.is_empty() {
+            info!("UDS sessions closed");
+            self.sessions.clear();
+        }
+    }
+}
+
+/// Name of a negative response code
+pub fn nrc_name(nrc: u8) -> &'static str {
+    match nrc {
+        0x10 => "generalReject",
+        0x11 => "serviceNotSupported",
+        0x12 => "subFunctionNotSupported",
+        0x13 => "incorrectMessageLengthOrInvalidFormat",
+        0x14 => "responseTooLong",
+        0x21 => "busyRepeatRequest",
+        0x22 => "conditionsNotCorrect",
+        0x24 => "requestSequenceError",
+        0x25 => "noResponseFromSubnetComponent",
+        0x26 => "failurePreventsExecutionOfRequestedAction",
+        0x31 => "requestOutOfRange",
+        0x33 => "securityAccessDenied",
+        0x35 => "invalidKey",
+        0x36 => "exceededNumberOfAttempts",
+        0x37 => "requiredTimeDelayNotExpired",
+        0x70 => "uploadDownloadNotAccepted",
+        0x72 => "generalProgrammingFailure",
+        0x78 => "requestCorrectlyReceivedResponsePending",
+        0x7E => "subFunctionNotSupportedInActiveSession",
+        0x7F => "serviceNotSupportedInActiveSession",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,441 @@
+//! In-memory stand-in for the gateway's HTTP server. A raw HTTP/1.1 request goes in, it's routed
+//! through `api` as the firmware's handlers are (body limit, headers, the ELM worker and the
+//! error codes) to a `MockElm` behind a `TestLink`, and the response comes out. Only the HTTP is
+//! here, the request handling is the firmware's. The link can be made to go quiet or drop, for
+//! the timeout and reconnect paths.
+#![allow(dead_code)]
+
+use std::{
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use bt_obd_gw_core::{
+    api::{self, DtcScan, PostRequest, ScanStep, SnapshotRequest, MAX_DRAIN_LEN},
+    dtc::ScanReport,
+    elm327::Elm327,
+    elm_worker::{ElmWorker, Priority, PRIORITY_HEADER},
+    error::ErrorBody,
+    mock_elm::{MockConfig, MockElm},
+    session::Session,
+    timeouts,
+    transport::{ConnectionStatus, ElmTransport},
+};
+use serde_json::Value;
+
+/// The firmware's default `max_body_len`, see `http_limits`
+pub const MAX_BODY_LEN: usize = 1024;
+
+/// Switches for the link, shared with the test
+#[derive(Clone, Default)]
+pub struct LinkControl {
+    /// Requests are written but never answered, as an adapter that hung
+    silent: Arc<AtomicBool>,
+    /// The link is down until `reconnect`
+    down: Arc<AtomicBool>,
+    reconnects: Arc<AtomicU32>,
+}
+
+impl LinkControl {
+    pub fn set_silent(&self, silent: bool) {
+        self.silent.store(silent, Ordering::Relaxed);
+    }
+
+    pub fn drop_link(&self) {
+        self.down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+}
+
+/// `MockElm` behind a link that fails the way SPP does
+pub struct TestLink {
+    mock: MockElm,
+    control: LinkControl,
+}
+
+impl Read for TestLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.control.silent.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No response"));
+        }
+
+        self.mock.read(buf)
+    }
+}
+
+impl Write for TestLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.control.down.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Link down"));
+        }
+        if self.control.silent.load(Ordering::Relaxed) {
+            return Ok(buf.len());
+        }
+
+        self.mock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ConnectionStatus for TestLink {
+    fn wait_connected(&mut self) -> Result<()> {
+        if self.control.down.load(Ordering::Relaxed) {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "Link down"))?;
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.control.down.store(true, Ordering::Relaxed);
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.control.down.store(false, Ordering::Relaxed);
+        self.control.reconnects.fetch_add(1, Ordering::Relaxed);
+
+        self.mock.reconnect()
+    }
+
+    fn name(&self) -> String {
+        "test".to_owned()
+    }
+}
+
+impl ElmTransport for TestLink {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.control.silent.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+
+        self.mock.try_read(buf)
+    }
+
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
+        self.write_all(request)?;
+        self.write_all(b"\r")?;
+
+        Ok(())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).expect("JSON body")
+    }
+
+    fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    /// As `http::adapter_error_response`
+    fn adapter_error(err: &anyhow::Error) -> Self {
+        let body = ErrorBody::of(err);
+
+        let mut headers = vec![("Content-Type".to_owned(), "application/json".to_owned())];
+        if body.retryable {
+            headers.push(("Retry-After".to_owned(), "1".to_owned()));
+        }
+
+        Self {
+            status: body.code.status(),
+            headers,
+            body: serde_json::to_string(&body).unwrap(),
+        }
+    }
+}
+
+struct Request<'r> {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: BufReader<Cursor<&'r [u8]>>,
+    content_len: usize,
+}
+
+impl Request<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn query_param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.uri.split_once('?')?;
+
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+
+    fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or_default()
+    }
+
+    /// As `http::read_body`, None if it's over `max_len`
+    fn read_body(&mut self, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+        if self.content_len > max_len {
+            let drain = self.content_len.min(MAX_DRAIN_LEN) as u64;
+            io::copy(&mut (&mut self.body).take(drain), &mut io::sink())?;
+
+            return Ok(None);
+        }
+
+        let mut buf = Vec::with_capacity(self.content_len);
+        (&mut self.body)
+            .take(self.content_len as u64)
+            .read_to_end(&mut buf)?;
+
+        Ok(Some(buf))
+    }
+}
+
+/// The gateway's request side on a mock adapter
+pub struct Gateway {
+    worker: Option<ElmWorker<'static>>,
+    /// The last `/scan`
+    scan: Mutex<Option<ScanReport>>,
+    pub link: LinkControl,
+}
+
+impl Gateway {
+    pub fn start() -> Self {
+        Self::with_config(MockConfig::default())
+    }
+
+    pub fn with_config(config: MockConfig) -> Self {
+        Self::with_init_script(config, &[])
+    }
+
+    /// Set up with `script` in place of the built in commands
+    pub fn with_init_script(config: MockConfig, script: &[&str]) -> Self {
+        Self::build(config, script, None)
+    }
+
+    /// Recording to or replaying from `session`, see `Session::set_mode`
+    pub fn with_session(session: Arc<Session>) -> Self {
+        Self::build(MockConfig::default(), &[], Some(session))
+    }
+
+    fn build(config: MockConfig, script: &[&str], session: Option<Arc<Session>>) -> Self {
+        let link = LinkControl::default();
+
+        let mut elm = Elm327::new(TestLink {
+            mock: MockElm::new(config),
+            control: link.clone(),
+        });
+        elm.set_init_script(script.iter().map(|command| command.to_string()).collect());
+        elm.setup().expect("Mock adapter setup");
+        if let Some(session) = session {
+            elm.set_session(session);
+        }
+
+        // The worker borrows the adapter for as long as it runs
+        let elm: &'static Mutex<Elm327<'static>> = Box::leak(Box::new(Mutex::new(elm)));
+        let worker = unsafe { ElmWorker::start(elm) }.expect("ELM worker");
+
+        Self {
+            worker: Some(worker),
+            scan: Mutex::new(None),
+            link,
+        }
+    }
+
+    fn worker(&self) -> &ElmWorker<'static> {
+        self.worker.as_ref().unwrap()
+    }
+
+    /// Answer a raw HTTP request
+    pub fn send(&self, raw: &[u8]) -> Response {
+        match parse(raw) {
+            Ok(req) => self.route(req),
+            Err(err) => Response::new(400, err.to_string()),
+        }
+    }
+
+    fn route(&self, req: Request<'_>) -> Response {
+        match (req.method.as_str(), req.path()) {
+            ("POST", "/post") => self.post(req),
+            ("GET", "/snapshot") => self.snapshot(req),
+            ("POST", "/scan") => self.scan(),
+            ("GET", "/scan") => self.scan_status(),
+            ("POST", "/bt/pair") => self.reconnect(),
+            _ => Response::new(404, "Not found"),
+        }
+    }
+
+    /// ELM327 passthrough, as the firmware's `/post` without the cache and coalescer
+    fn post(&self, mut req: Request<'_>) -> Response {
+        let buf = match req.read_body(MAX_BODY_LEN) {
+            Ok(Some(buf)) => buf,
+            Ok(None) => return Response::new(413, "Request too big"),
+            Err(err) => return Response::new(500, err.to_string()),
+        };
+
+        let post = match PostRequest::parse(
+            buf,
+            req.query_param("bus"),
+            req.header(timeouts::TIMEOUT_HEADER),
+            req.header(PRIORITY_HEADER),
+        ) {
+            Ok(post) => post,
+            Err(err) => return Response::new(400, err.to_string()),
+        };
+
+        match post.transact(self.worker(), None, None) {
+            Ok(response) => Response::new(200, response),
+            Err(err) => Response::adapter_error(&err),
+        }
+    }
+
+    /// Several PIDs in one request, `/snapshot?pids=0C,0D`, as the firmware's without virtual
+    /// PIDs or the timestamp
+    fn snapshot(&self, req: Request<'_>) -> Response {
+        let snapshot = match SnapshotRequest::parse(req.query_param("pids"), |_| false) {
+            Ok(snapshot) => snapshot,
+            Err(err) => return Response::new(400, err.to_string()),
+        };
+
+        let pids = match snapshot.to_read(Vec::new()) {
+            Ok(pids) => pids,
+            Err(err) => return Response::new(400, err.to_string()),
+        };
+
+        let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::High);
+
+        match SnapshotRequest::read(self.worker(), priority, pids, || ()) {
+            Ok(((), values)) => {
+                let values: serde_json::Map<String, Value> = values
+                    .into_iter()
+                    .map(|(pid, value)| (format!("{pid:02X}"), value.into()))
+                    .collect();
+
+                Response::new(200, serde_json::json!({ "values": values }).to_string())
+            }
+            Err(err) => Response::adapter_error(&err),
+        }
+    }
+
+    /// Every ECU's DTCs, as the firmware's background `/scan` but run straight through, with
+    /// the ECUs found each time and no progress events
+    fn scan(&self) -> Response {
+        let mut scan = DtcScan::new(Vec::new());
+        loop {
+            match scan.step(self.worker()) {
+                Ok(ScanStep::Finished) => break,
+                Ok(_) => {}
+                Err(err) => return Response::adapter_error(&err),
+            }
+        }
+
+        *self.scan.lock().unwrap() = Some(scan.into_report());
+
+        Response::new(202, "")
+    }
+
+    /// As `GET /scan`, the report with its state
+    fn scan_status(&self) -> Response {
+        let status = match &*self.scan.lock().unwrap() {
+            Some(report) => {
+                let mut status = serde_json::to_value(report).unwrap();
+                status["state"] = "done".into();
+                status
+            }
+            None => serde_json::json!({ "state": "idle" }),
+        };
+
+        Response::new(200, status.to_string())
+    }
+
+    /// Connect to the adapter again, as `/bt/pair` without the bond
+    fn reconnect(&self) -> Response {
+        match api::reconnect(self.worker()) {
+            Ok(()) => Response::new(202, ""),
+            Err(err) => Response::adapter_error(&err),
+        }
+    }
+}
+
+fn parse(raw: &[u8]) -> Result<Request<'_>> {
+    let mut reader = BufReader::new(Cursor::new(raw));
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(uri), Some("HTTP/1.1")) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!("Bad request line ({})", line.trim()));
+    };
+    let (method, uri) = (method.to_owned(), uri.to_owned());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Bad header ({header})"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let content_len = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, v)| v.parse())
+        .transpose()?
+        .unwrap_or(0);
+
+    Ok(Request {
+        method,
+        uri,
+        headers,
+        body: reader,
+        content_len,
+    })
+}
+
+/// A raw HTTP/1.1 request with a body
+pub fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{method} {uri} HTTP/1.1\r\nHost: gateway\r\n");
+    for (name, value) in headers {
+        raw.push_str(&format!("{name}: {value}\r\n"));
+    }
+    raw.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+    [raw.as_bytes(), body].concat()
+}
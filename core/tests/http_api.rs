@@ -0,0 +1,319 @@
+//! The HTTP API end to end on the host: raw requests through the in-memory server in `common` to
+//! the ELM worker and a mock adapter.
+mod common;
+
+use std::{collections::BTreeMap, sync::Arc, thread};
+
+use bt_obd_gw_core::{
+    api::MAX_SNAPSHOT_PIDS,
+    mock_elm::MockConfig,
+    session::{Session, SessionMode},
+};
+use common::{request, Gateway, MAX_BODY_LEN};
+
+/// Hex digits only, the adapter's spacing varies with ATS
+fn hex(response: &str) -> String {
+    response.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[test]
+fn post_answers_a_pid() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+
+    assert_eq!(resp.status, 200);
+    assert!(hex(&resp.body).contains("410C0C80"), "{}", resp.body);
+}
+
+#[test]
+fn post_answers_several_pids_in_one_request() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C0D05"));
+
+    assert_eq!(resp.status, 200);
+    let body = hex(&resp.body);
+    for answer in ["0C0C80", "0D00", "0582"] {
+        assert!(body.contains(answer), "{answer} missing from {}", resp.body);
+    }
+}
+
+#[test]
+fn snapshot_decodes_a_batch() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("GET", "/snapshot?pids=0C,0D,05", &[], b""));
+
+    assert_eq!(resp.status, 200);
+    let values = resp.json()["values"].clone();
+    assert_eq!(values["0C"], 800.0);
+    assert_eq!(values["0D"], 0.0);
+    assert_eq!(values["05"], 90.0);
+}
+
+#[test]
+fn snapshot_leaves_out_pids_with_no_answer() {
+    let gateway = Gateway::with_config(MockConfig {
+        pids: BTreeMap::from([(0x0C, vec![0x0C, 0x80])]),
+        ..Default::default()
+    });
+
+    let resp = gateway.send(&request("GET", "/snapshot?pids=0C,05", &[], b""));
+
+    assert_eq!(resp.status, 200);
+    let values = resp.json()["values"].clone();
+    assert_eq!(values["0C"], 800.0);
+    assert!(values.get("05").is_none());
+}
+
+#[test]
+fn snapshot_reads_more_pids_than_one_request() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request(
+        "GET",
+        "/snapshot?pids=04,05,0C,0D,0F,11,2F",
+        &[],
+        b"",
+    ));
+
+    assert_eq!(resp.status, 200);
+    let values = resp.json()["values"].clone();
+    assert_eq!(values["0C"], 800.0);
+    assert_eq!(values["05"], 90.0);
+}
+
+#[test]
+fn snapshot_refuses_too_many_pids() {
+    let gateway = Gateway::start();
+
+    let pids: Vec<String> = (1..=MAX_SNAPSHOT_PIDS as u8 + 1)
+        .map(|p| format!("{p:02X}"))
+        .collect();
+    let uri = format!("/snapshot?pids={}", pids.join(","));
+
+    let resp = gateway.send(&request("GET", &uri, &[], b""));
+
+    assert_eq!(resp.status, 400);
+}
+
+#[test]
+fn scan_reports_dtcs_with_descriptions() {
+    let gateway = Gateway::with_config(MockConfig {
+        dtcs: BTreeMap::from([(0x03, vec![0x0301, 0x0420]), (0x07, vec![0x1234])]),
+        ..Default::default()
+    });
+
+    let resp = gateway.send(&request("POST", "/scan", &[], b""));
+    assert_eq!(resp.status, 202);
+
+    let resp = gateway.send(&request("GET", "/scan", &[], b""));
+
+    assert_eq!(resp.status, 200);
+    let report = resp.json();
+    assert_eq!(report["state"], "done");
+    assert_eq!(report["stored"], 2);
+    assert_eq!(report["pending"], 1);
+    assert_eq!(report["permanent"], 0);
+    assert_eq!(report["failed"], 0);
+
+    let ecm = &report["ecus"][0];
+    assert_eq!(ecm["ecu"], "ecm");
+    assert_eq!(ecm["stored"][0]["code"], "P0301");
+    assert_eq!(ecm["stored"][0]["description"], "Cylinder 1 misfire");
+    assert_eq!(ecm["stored"][1]["code"], "P0420");
+    // Manufacturer codes aren't in the table
+    assert_eq!(ecm["pending"][0]["code"], "P1234");
+    assert!(ecm["pending"][0]["description"].is_null());
+}
+
+#[test]
+fn concurrent_requests_are_answered_or_turned_away() {
+    let gateway = Gateway::start();
+
+    thread::scope(|s| {
+        let requests: Vec<_> = (0..16)
+            .map(|i| {
+                let gateway = &gateway;
+                s.spawn(move || {
+                    let pid = if i % 2 == 0 { "010C" } else { "0105" };
+                    (
+                        pid,
+                        gateway.send(&request("POST", "/post", &[], pid.as_bytes())),
+                    )
+                })
+            })
+            .collect();
+
+        for handle in requests {
+            let (pid, resp) = handle.join().unwrap();
+            match resp.status {
+                200 => assert!(hex(&resp.body).contains(&format!("41{}", &pid[2..]))),
+                503 => assert_eq!(resp.json()["code"], "BUSY"),
+                status => panic!("Unexpected status {status} {}", resp.body),
+            }
+        }
+    });
+}
+
+#[test]
+fn oversized_body_is_refused() {
+    let gateway = Gateway::start();
+
+    let body = vec![b'0'; MAX_BODY_LEN + 1];
+    let resp = gateway.send(&request("POST", "/post", &[], &body));
+
+    assert_eq!(resp.status, 413);
+
+    // The worker never saw it
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 200);
+}
+
+#[test]
+fn body_at_the_limit_is_accepted() {
+    let gateway = Gateway::start();
+
+    let body = vec![b' '; MAX_BODY_LEN];
+    let resp = gateway.send(&request("POST", "/post", &[], &body));
+
+    assert_ne!(resp.status, 413);
+}
+
+#[test]
+fn silent_adapter_times_out_as_retryable() {
+    let gateway = Gateway::start();
+    gateway.link.set_silent(true);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+
+    assert_eq!(resp.status, 504);
+    assert_eq!(resp.header("Retry-After"), Some("1"));
+    let body = resp.json();
+    assert_eq!(body["code"], "ELM_TIMEOUT");
+    assert_eq!(body["retryable"], true);
+
+    gateway.link.set_silent(false);
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 200);
+}
+
+#[test]
+fn request_timeout_header() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request(
+        "POST",
+        "/post",
+        &[("X-Elm-Timeout", "500")],
+        b"010C",
+    ));
+    assert_eq!(resp.status, 200);
+    assert!(hex(&resp.body).contains("410C0C80"), "{}", resp.body);
+
+    for bad in ["0", "5000", "soon"] {
+        let resp = gateway.send(&request(
+            "POST",
+            "/post",
+            &[("X-Elm-Timeout", bad)],
+            b"010C",
+        ));
+        assert_eq!(resp.status, 400, "X-Elm-Timeout {bad}");
+    }
+}
+
+#[test]
+fn unknown_bus_is_refused() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("POST", "/post?bus=lin", &[], b"010C"));
+
+    assert_eq!(resp.status, 400);
+}
+
+#[test]
+fn switching_back_to_hs_keeps_the_init_script_protocol() {
+    let gateway = Gateway::with_init_script(MockConfig::default(), &["ATSP 6", "ATH 1", "ATS 1"]);
+
+    let resp = gateway.send(&request("POST", "/post?bus=j1939", &[], b"ATDPN"));
+    assert_eq!(resp.body.trim(), "A7");
+
+    let resp = gateway.send(&request("POST", "/post?bus=hs", &[], b"ATDPN"));
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.trim(), "A6");
+}
+
+#[test]
+fn replay_answers_recorded_requests_without_the_adapter() {
+    let path = std::env::temp_dir().join(format!("replay-{}.rec", std::process::id()));
+    let session = Arc::new(Session::new(&path));
+    let gateway = Gateway::with_session(Arc::clone(&session));
+
+    session.set_mode(SessionMode::Record).unwrap();
+    let recorded = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(recorded.status, 200);
+    gateway.send(&request("POST", "/post", &[], b"ATDPN"));
+    assert_eq!(session.status().exchanges, 1, "AT commands aren't recorded");
+
+    session.set_mode(SessionMode::Replay).unwrap();
+    gateway.link.set_silent(true);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"01 0c"));
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, recorded.body);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"0105"));
+    assert_eq!(resp.json()["code"], "NO_DATA");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn dropped_link_fails_until_reconnected() {
+    let gateway = Gateway::start();
+    gateway.link.drop_link();
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 503);
+    assert_eq!(resp.json()["code"], "ADAPTER_DISCONNECTED");
+    assert_eq!(resp.header("Retry-After"), Some("1"));
+
+    let resp = gateway.send(&request("POST", "/bt/pair", &[], b""));
+    assert_eq!(resp.status, 202);
+    assert_eq!(gateway.link.reconnects(), 1);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 200);
+    assert!(hex(&resp.body).contains("410C0C80"), "{}", resp.body);
+}
+
+#[test]
+fn writes_are_refused_after_a_baud_change_until_reset() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"ATBRD 23"));
+    assert_eq!(resp.status, 200);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 409);
+    assert_eq!(resp.json()["code"], "ADAPTER_PROGRAMMING");
+    assert_eq!(resp.json()["retryable"], false);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"ATZ"));
+    assert_eq!(resp.status, 200);
+    assert!(resp.body.contains("ELM327"), "{}", resp.body);
+
+    let resp = gateway.send(&request("POST", "/post", &[], b"010C"));
+    assert_eq!(resp.status, 200);
+    assert!(hex(&resp.body).contains("410C0C80"), "{}", resp.body);
+}
+
+#[test]
+fn unknown_route_is_not_found() {
+    let gateway = Gateway::start();
+
+    let resp = gateway.send(&request("GET", "/nothing", &[], b""));
+
+    assert_eq!(resp.status, 404);
+}
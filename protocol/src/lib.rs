@@ -0,0 +1,534 @@
+//! ESPNOW wire formats shared by the gateway and the LCD peers
+//!
+//! Every message starts with a message type byte, `| msg type | payload... |`:
+//!
+//! - `MSG_ANNOUNCE` gateway is ready, its address then the HTTP port and features as tagged
+//!   fields, see `Announce`
+//! - `MSG_TELEMETRY` postcard encoded `Telemetry`
+//! - `MSG_STATUS` a `Status` code byte
+//! - `MSG_COMMAND` a `Command` from a peer, `| MSG_COMMAND | command | token |`
+//! - `MSG_REPLY` the gateway reply to a command, `| MSG_REPLY | command | token | payload... |`
+//! - `MSG_REMINDER` a maintenance item came due, `| MSG_REMINDER | overdue | item name... |`
+//! - `MSG_ANNOUNCE_ACK` from a peer that got the announce, `| MSG_ANNOUNCE_ACK | ipv4 (4) |`
+//! - `MSG_HEARTBEAT` gateway is still up, `| MSG_HEARTBEAT | ipv4 (4) | uptime s (4) |`
+//! - `MSG_ALERT` a threshold alert triggered or cleared, `| MSG_ALERT | active | value f32 (4) |
+//!   alert name... |`
+//!
+//! The gateway repeats the announce until a peer acks it with the same address, then sends a
+//! heartbeat instead.
+//!
+//! The command token is chosen by the sender and echoed in the reply so it can match them up.
+//!
+//! When a relay forwards a message it wraps it:
+//!
+//! `| MSG_RELAY | hops | origin mac (6) | frame id (2) | original message... |`
+//!
+//! The frame id is a hash of the origin and original message so every relay that hears the same
+//! message gives it the same id, which lets relays and the LCD drop duplicates.
+#![no_std]
+
+use core::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+/// ESP_NOW_MAX_DATA_LEN
+pub const MAX_DATA_LEN: usize = 250;
+
+pub const MSG_ANNOUNCE: u8 = 0x01;
+pub const MSG_TELEMETRY: u8 = 0x02;
+pub const MSG_STATUS: u8 = 0x03;
+pub const MSG_COMMAND: u8 = 0x04;
+pub const MSG_REPLY: u8 = 0x05;
+pub const MSG_REMINDER: u8 = 0x06;
+pub const MSG_ANNOUNCE_ACK: u8 = 0x07;
+pub const MSG_HEARTBEAT: u8 = 0x08;
+pub const MSG_ALERT: u8 = 0x09;
+pub const MSG_RELAY: u8 = 0x7F;
+
+/// Relays stop forwarding once a message has taken this many hops
+pub const MAX_HOPS: u8 = 3;
+
+/// Most samples carried by one telemetry message
+pub const MAX_SAMPLES: usize = 20;
+
+const RELAY_HEADER_LEN: usize = 10;
+
+/// See `Announce`
+pub const ANNOUNCE_VERSION: u8 = 2;
+const DEFAULT_HTTP_PORT: u16 = 80;
+const TAG_HTTP_PORT: u8 = 0x02;
+const TAG_FEATURES: u8 = 0x03;
+const TAG_DEVICE_ID: u8 = 0x04;
+
+pub type MacAddr = [u8; 6];
+pub type EspNowData = heapless::Vec<u8, MAX_DATA_LEN>;
+
+//----------
+// Announce
+//----------
+
+/// Features flags in the announce, bits of a u16
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Features(pub u16);
+
+impl Features {
+    /// Live values on a websocket, not served by this gateway yet
+    pub const WEBSOCKET: Features = Features(1 << 0);
+    /// HTTP requests need the token or a challenge response
+    pub const AUTH_REQUIRED: Features = Features(1 << 1);
+    /// HTTPS on the announced port
+    pub const TLS: Features = Features(1 << 2);
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: Features, on: bool) {
+        if on {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+/// The ready announce the LCD waits for, the gateway's address and what it serves.
+///
+/// `| MSG_ANNOUNCE | ipv4 (4) | version | tag | len | value... | tag | len | value... |`
+///
+/// The address is where it was in the bare version 1 announce, `| MSG_ANNOUNCE | ipv4 (4) |`, so
+/// an LCD that only reads that (`parse_announce`) still finds the gateway. The fields after it
+/// are tag, length, value so either side can add one without breaking the other: a parser skips
+/// tags it doesn't know and takes the defaults for ones that are missing. The version only goes
+/// up when a field changes meaning.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Announce {
+    pub version: u8,
+    pub ip: Ipv4Addr,
+    /// 80 if not sent
+    pub http_port: u16,
+    pub features: Features,
+    /// The gateway's ESPNOW (STA) MAC
+    pub device_id: Option<MacAddr>,
+}
+
+impl Announce {
+    pub fn new(ip: Ipv4Addr) -> Self {
+        Self {
+            version: ANNOUNCE_VERSION,
+            ip,
+            http_port: DEFAULT_HTTP_PORT,
+            features: Features::default(),
+            device_id: None,
+        }
+    }
+
+    pub fn encode(&self) -> EspNowData {
+        let mut data = EspNowData::new();
+        let _ = data.push(MSG_ANNOUNCE);
+        let _ = data.extend_from_slice(&self.ip.octets());
+        let _ = data.push(self.version);
+
+        let mut field = |tag: u8, value: &[u8]| {
+            let _ = data.extend_from_slice(&[tag, value.len() as u8]);
+            let _ = data.extend_from_slice(value);
+        };
+        field(TAG_HTTP_PORT, &self.http_port.to_be_bytes());
+        field(TAG_FEATURES, &self.features.0.to_be_bytes());
+        if let Some(device_id) = &self.device_id {
+            field(TAG_DEVICE_ID, device_id);
+        }
+
+        data
+    }
+
+    /// None if it's not an announce or a field is cut short
+    pub fn parse(data: &[u8]) -> Option<Announce> {
+        let (ip, version, mut fields) = match data {
+            [MSG_ANNOUNCE, a, b, c, d] => return Some(Self::v1(Ipv4Addr::new(*a, *b, *c, *d))),
+            [MSG_ANNOUNCE, a, b, c, d, version, fields @ ..] => {
+                (Ipv4Addr::new(*a, *b, *c, *d), *version, fields)
+            }
+            _ => return None,
+        };
+
+        let mut announce = Self::new(ip);
+        announce.version = version;
+
+        while let [tag, len, rest @ ..] = fields {
+            let (value, rest) = rest.split_at_checked(*len as usize)?;
+            fields = rest;
+
+            match (*tag, value) {
+                (TAG_HTTP_PORT, &[a, b]) => announce.http_port = u16::from_be_bytes([a, b]),
+                (TAG_FEATURES, &[a, b]) => announce.features = Features(u16::from_be_bytes([a, b])),
+                (TAG_DEVICE_ID, value) => announce.device_id = value.try_into().ok(),
+                // A newer gateway's field, or a known one with a size from a newer version
+                _ => {}
+            }
+        }
+
+        if !fields.is_empty() {
+            return None;
+        }
+
+        Some(announce)
+    }
+
+    fn v1(ip: Ipv4Addr) -> Self {
+        Self {
+            version: 1,
+            ..Self::new(ip)
+        }
+    }
+}
+
+/// The gateway IP from an announce of any version, for an LCD that needs nothing else
+pub fn parse_announce(data: &[u8]) -> Option<Ipv4Addr> {
+    match data {
+        [MSG_ANNOUNCE, a, b, c, d, ..] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+        _ => None,
+    }
+}
+
+/// Sent back by the LCD for an announce, with the address it got
+pub fn announce_ack(ip: Ipv4Addr) -> EspNowData {
+    let mut data = EspNowData::new();
+    let _ = data.push(MSG_ANNOUNCE_ACK);
+    let _ = data.extend_from_slice(&ip.octets());
+
+    data
+}
+
+/// The acked gateway IP from an announce ack
+pub fn parse_announce_ack(data: &[u8]) -> Option<Ipv4Addr> {
+    match data {
+        [MSG_ANNOUNCE_ACK, a, b, c, d, ..] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+        _ => None,
+    }
+}
+
+/// Gateway liveness once the announce is acked, carries the IP so a restarted LCD picks it up
+pub fn heartbeat(ip: Ipv4Addr, uptime_s: u32) -> EspNowData {
+    let mut data = EspNowData::new();
+    let _ = data.push(MSG_HEARTBEAT);
+    let _ = data.extend_from_slice(&ip.octets());
+    let _ = data.extend_from_slice(&uptime_s.to_be_bytes());
+
+    data
+}
+
+/// Gateway IP and uptime from a heartbeat
+pub fn parse_heartbeat(data: &[u8]) -> Option<(Ipv4Addr, u32)> {
+    match data {
+        [MSG_HEARTBEAT, a, b, c, d, uptime @ ..] => Some((
+            Ipv4Addr::new(*a, *b, *c, *d),
+            u32::from_be_bytes(uptime.get(..4)?.try_into().ok()?),
+        )),
+        _ => None,
+    }
+}
+
+//--------
+// Status
+//--------
+
+/// Gateway state, follows the startup stages of the status LED
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Status {
+    BtConnecting = 1,
+    ElmReady = 2,
+    WifiConnected = 3,
+    BtDiscoveryFailed = 4,
+    /// Ignition off, going into deep sleep
+    Sleeping = 5,
+    /// Supply lost, the gateway is about to go dark
+    PoweringDown = 6,
+    /// The gateway lost the LCD's AP and is trying to get back on it, ESPNOW still works
+    WifiReconnecting = 7,
+    Error = 0xFF,
+}
+
+impl Status {
+    pub fn encode(self) -> [u8; 2] {
+        [MSG_STATUS, self as u8]
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Status> {
+        match data {
+            [MSG_STATUS, 1, ..] => Some(Status::BtConnecting),
+            [MSG_STATUS, 2, ..] => Some(Status::ElmReady),
+            [MSG_STATUS, 3, ..] => Some(Status::WifiConnected),
+            [MSG_STATUS, 4, ..] => Some(Status::BtDiscoveryFailed),
+            [MSG_STATUS, 5, ..] => Some(Status::Sleeping),
+            [MSG_STATUS, 6, ..] => Some(Status::PoweringDown),
+            [MSG_STATUS, 7, ..] => Some(Status::WifiReconnecting),
+            [MSG_STATUS, 0xFF, ..] => Some(Status::Error),
+            _ => None,
+        }
+    }
+}
+
+//----------
+// Commands
+//----------
+
+/// Lightweight commands a peer can send over ESPNOW, ELM requests stay on HTTP
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Command {
+    /// Empty reply
+    Ping = 1,
+    /// Reply is a `StatusReply`
+    Status = 2,
+    /// Empty reply, sent before the gateway restarts
+    Reboot = 3,
+    /// Empty reply, the status LED cycles through its colors
+    LedTest = 4,
+}
+
+impl Command {
+    pub fn encode(self, token: u8) -> [u8; 3] {
+        [MSG_COMMAND, self as u8, token]
+    }
+
+    /// The command and its token
+    pub fn parse(data: &[u8]) -> Option<(Command, u8)> {
+        let command = match data {
+            [MSG_COMMAND, 1, ..] => Command::Ping,
+            [MSG_COMMAND, 2, ..] => Command::Status,
+            [MSG_COMMAND, 3, ..] => Command::Reboot,
+            [MSG_COMMAND, 4, ..] => Command::LedTest,
+            _ => return None,
+        };
+
+        Some((command, *data.get(2)?))
+    }
+
+    /// Reply header followed by the payload
+    pub fn reply(self, token: u8, payload: &[u8]) -> Option<EspNowData> {
+        let mut data = EspNowData::new();
+
+        data.extend_from_slice(&[MSG_REPLY, self as u8, token])
+            .ok()?;
+        data.extend_from_slice(payload).ok()?;
+
+        Some(data)
+    }
+}
+
+/// Payload of the `Command::Status` reply, `| status | uptime s (4) | free heap (4) |`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StatusReply {
+    pub status: Status,
+    pub uptime_s: u32,
+    pub free_heap: u32,
+}
+
+impl StatusReply {
+    pub fn encode(&self) -> [u8; 9] {
+        let mut data = [0u8; 9];
+
+        data[0] = self.status as u8;
+        data[1..5].copy_from_slice(&self.uptime_s.to_be_bytes());
+        data[5..9].copy_from_slice(&self.free_heap.to_be_bytes());
+
+        data
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<StatusReply> {
+        let payload: &[u8; 9] = payload.get(..9)?.try_into().ok()?;
+
+        Some(StatusReply {
+            status: Status::parse(&[MSG_STATUS, payload[0]])?,
+            uptime_s: u32::from_be_bytes(payload[1..5].try_into().ok()?),
+            free_heap: u32::from_be_bytes(payload[5..9].try_into().ok()?),
+        })
+    }
+}
+
+//----------
+// Reminder
+//----------
+
+/// Maintenance reminder, the name is cut short if it doesn't fit
+pub fn reminder(overdue: bool, name: &str) -> EspNowData {
+    let mut data = EspNowData::new();
+    let _ = data.push(MSG_REMINDER);
+    let _ = data.push(overdue as u8);
+
+    let mut len = name.len().min(MAX_DATA_LEN - 2);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    let _ = data.extend_from_slice(&name.as_bytes()[..len]);
+
+    data
+}
+
+/// Overdue flag and item name from a reminder message
+pub fn parse_reminder(data: &[u8]) -> Option<(bool, &str)> {
+    match data {
+        [MSG_REMINDER, overdue, name @ ..] => {
+            Some((*overdue != 0, core::str::from_utf8(name).ok()?))
+        }
+        _ => None,
+    }
+}
+
+//-------
+// Alert
+//-------
+
+/// Threshold alert, `active` false once it has cleared. The name is cut short if it doesn't fit.
+pub fn alert(active: bool, value: f32, name: &str) -> EspNowData {
+    let mut data = EspNowData::new();
+    let _ = data.push(MSG_ALERT);
+    let _ = data.push(active as u8);
+    let _ = data.extend_from_slice(&value.to_be_bytes());
+
+    let mut len = name.len().min(MAX_DATA_LEN - 6);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    let _ = data.extend_from_slice(&name.as_bytes()[..len]);
+
+    data
+}
+
+/// Active flag, value and alert name from an alert message
+pub fn parse_alert(data: &[u8]) -> Option<(bool, f32, &str)> {
+    match data {
+        [MSG_ALERT, active, a, b, c, d, name @ ..] => Some((
+            *active != 0,
+            f32::from_be_bytes([*a, *b, *c, *d]),
+            core::str::from_utf8(name).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+//-----------
+// Telemetry
+//-----------
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Sample {
+    /// Mode 01 PID, or a DID for mode 22 channels
+    pub pid: u16,
+    pub value: f32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct Telemetry {
+    pub seq: u16,
+    pub samples: heapless::Vec<Sample, MAX_SAMPLES>,
+}
+
+impl Telemetry {
+    pub fn encode(&self) -> Option<EspNowData> {
+        let mut buf = [0u8; MAX_DATA_LEN];
+        buf[0] = MSG_TELEMETRY;
+
+        let len = postcard::to_slice(self, &mut buf[1..]).ok()?.len();
+
+        EspNowData::from_slice(&buf[..len + 1]).ok()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Telemetry> {
+        match data {
+            [MSG_TELEMETRY, payload @ ..] => postcard::from_bytes(payload).ok(),
+            _ => None,
+        }
+    }
+}
+
+//-------
+// Relay
+//-------
+
+pub struct RelayFrame<'a> {
+    pub hops: u8,
+    pub origin: MacAddr,
+    pub id: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RelayFrame<'a> {
+    /// Wrap a message heard directly from `origin`
+    pub fn wrap(origin: MacAddr, payload: &'a [u8]) -> Self {
+        Self {
+            hops: 0,
+            origin,
+            id: frame_id(&origin, payload),
+            payload,
+        }
+    }
+
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() <= RELAY_HEADER_LEN || data[0] != MSG_RELAY {
+            return None;
+        }
+
+        Some(Self {
+            hops: data[1],
+            origin: data[2..8].try_into().ok()?,
+            id: u16::from_be_bytes([data[8], data[9]]),
+            payload: &data[RELAY_HEADER_LEN..],
+        })
+    }
+
+    /// Encode for forwarding, counting this hop. None if the message is too big to wrap.
+    pub fn encode_next_hop(&self) -> Option<EspNowData> {
+        let mut data = EspNowData::new();
+
+        data.push(MSG_RELAY).ok()?;
+        data.push(self.hops + 1).ok()?;
+        data.extend_from_slice(&self.origin).ok()?;
+        data.extend_from_slice(&self.id.to_be_bytes()).ok()?;
+        data.extend_from_slice(self.payload).ok()?;
+
+        Some(data)
+    }
+}
+
+/// FNV-1a folded to 16 bits
+fn frame_id(origin: &MacAddr, payload: &[u8]) -> u16 {
+    let hash = origin.iter().chain(payload).fold(0x811c9dc5u32, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    });
+
+    ((hash >> 16) ^ hash) as u16
+}
+
+/// Remembers recently seen relay frames. Times are milliseconds from any monotonic clock.
+pub struct Dedup {
+    seen: heapless::Deque<(MacAddr, u16, u64), 16>,
+    window_ms: u64,
+}
+
+impl Dedup {
+    pub fn new(window_ms: u64) -> Self {
+        Self {
+            seen: heapless::Deque::new(),
+            window_ms,
+        }
+    }
+
+    /// True the first time a frame is seen within the dedup window
+    pub fn is_new(&mut self, origin: &MacAddr, id: u16, now_ms: u64) -> bool {
+        let seen = self.seen.iter().any(|(seen_origin, seen_id, at)| {
+            seen_origin == origin && *seen_id == id && now_ms.saturating_sub(*at) < self.window_ms
+        });
+
+        if !seen {
+            if self.seen.is_full() {
+                self.seen.pop_front();
+            }
+            let _ = self.seen.push_back((*origin, id, now_ms));
+        }
+
+        !seen
+    }
+}
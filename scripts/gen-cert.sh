@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+
+# Self-signed EC P-256 certificate for the gateway HTTPS server, upload with
+#   curl -X PUT --data-binary @cert.pem http://obd-gw.local/config/tls-cert
+#   curl -X PUT --data-binary @key.pem http://obd-gw.local/config/tls-key
+
+set -e
+
+HOSTNAME="${1:-obd-gw}"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -keyout key.pem -out cert.pem -days 3650 \
+    -subj "/CN=${HOSTNAME}.local" \
+    -addext "subjectAltName=DNS:${HOSTNAME}.local"
@@ -0,0 +1,34 @@
+//! Battery voltage alerts pushed by the adapter. They can arrive in the middle of a response or
+//! while the gateway is idle, the ELM demultiplexes them from the responses and the main loop
+//! collects them here.
+use std::sync::Mutex;
+
+use circular_buffer::CircularBuffer;
+use log::*;
+
+pub use bt_obd_gw_core::alerts::Alert;
+
+const MAX_ALERTS: usize = 16;
+
+/// The most recent alerts, oldest dropped first
+pub struct Alerts {
+    recent: Mutex<CircularBuffer<MAX_ALERTS, Alert>>,
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        Self {
+            recent: Mutex::new(CircularBuffer::new()),
+        }
+    }
+
+    pub fn push(&self, alert: Alert) {
+        warn!("Battery alert {:?} ({:.1}V)", alert.kind, alert.volts);
+
+        self.recent.lock().unwrap().push_back(alert);
+    }
+
+    pub fn recent(&self) -> Vec<Alert> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,110 @@
+//! Tells the LCD the gateway address and what it serves (see `bt_obd_gw_protocol::Announce`). A
+//! single announce is lost if the LCD isn't listening yet, so it's repeated until the LCD acks
+//! it, then a slower heartbeat lets the LCD notice the gateway going away.
+use std::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bt_obd_gw_protocol::{Announce, Features, MacAddr};
+use esp_idf_svc::{
+    espnow::EspNow,
+    sys::{esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac},
+};
+use log::*;
+
+use crate::peers::Peers;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// The LCD can treat three missed heartbeats as the gateway gone
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Last address acked by a peer, set from the ESPNOW receive callback. 0 is never announced.
+static ACKED: AtomicU32 = AtomicU32::new(0);
+
+/// A peer acked the announce of `ip`
+pub fn acked(ip: Ipv4Addr) {
+    ACKED.store(ip.to_bits(), Ordering::Relaxed);
+}
+
+pub struct Announcer {
+    announce: Announce,
+    started: Instant,
+    last_send: Option<Instant>,
+    /// Heartbeat rather than announce
+    acked: bool,
+}
+
+impl Announcer {
+    /// `tls` for HTTPS on 443, otherwise HTTP on 80
+    pub fn new(ip: Ipv4Addr, tls: bool, started: Instant) -> Result<Self> {
+        let mut announce = Announce::new(ip);
+        announce.http_port = if tls { 443 } else { 80 };
+        announce.features.set(Features::TLS, tls);
+        announce.device_id = Some(device_id()?);
+
+        Ok(Self {
+            announce,
+            started,
+            last_send: None,
+            acked: false,
+        })
+    }
+
+    /// Announce again until acked, for a new address or a peer that may have restarted
+    pub fn reannounce(&mut self, ip: Ipv4Addr) {
+        self.announce.ip = ip;
+        self.last_send = None;
+        self.acked = false;
+        ACKED.store(0, Ordering::Relaxed);
+    }
+
+    pub fn poll_due(&self) -> bool {
+        let interval = if self.acked {
+            HEARTBEAT_INTERVAL
+        } else {
+            ANNOUNCE_INTERVAL
+        };
+
+        self.last_send.is_none_or(|t| t.elapsed() >= interval)
+    }
+
+    /// Send the announce, or the heartbeat once a peer has acked it. A change to `auth_required`
+    /// is announced again, so the LCD knows to authenticate.
+    pub fn poll(&mut self, espnow: &EspNow, peers: &Peers, auth_required: bool) -> Result<()> {
+        if auth_required != self.announce.features.contains(Features::AUTH_REQUIRED) {
+            self.announce
+                .features
+                .set(Features::AUTH_REQUIRED, auth_required);
+            self.reannounce(self.announce.ip);
+        }
+
+        self.last_send = Some(Instant::now());
+
+        let ip = self.announce.ip;
+        if !self.acked && ACKED.load(Ordering::Relaxed) == ip.to_bits() {
+            info!("Announce of {ip} acked, sending heartbeats");
+            self.acked = true;
+        }
+
+        let data = if self.acked {
+            bt_obd_gw_protocol::heartbeat(ip, self.started.elapsed().as_secs() as u32)
+        } else {
+            self.announce.encode()
+        };
+
+        peers.send(espnow, &data)?;
+
+        Ok(())
+    }
+}
+
+/// The STA MAC, the address ESPNOW messages come from
+fn device_id() -> Result<MacAddr> {
+    let mut mac = MacAddr::default();
+    esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+
+    Ok(mac)
+}
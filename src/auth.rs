@@ -0,0 +1,317 @@
+//! HTTP authentication backends. The deployment picks one with `GatewayConfig::http_auth`, and
+//! `http::Router` checks it in front of every handler.
+use std::{
+    fmt::Write as _,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::esp_fill_random,
+};
+use hmac::{Hmac, Mac};
+use log::*;
+use sha2::Sha256;
+
+use crate::config::{NVS_AUTH_KEY, NVS_AUTH_TOKEN};
+use crate::error::AuthError;
+use crate::signing::decode_hex;
+
+/// `Bearer <token>`, `Basic` with the token as the password and any user name, or
+/// `Hmac <nonce>:<mac>` for the challenge backend
+pub const AUTH_HEADER: &str = "Authorization";
+
+const MAX_TOKEN_LEN: usize = 64;
+const MAX_KEY_LEN: usize = 64;
+const NONCE_LEN: usize = 16;
+/// A nonce has to be answered within this
+const NONCE_LIFETIME: Duration = Duration::from_secs(30);
+const MAX_NONCES: usize = 8;
+
+/// Which backend guards the HTTP endpoints
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HttpAuth {
+    /// Everything is accepted, for an LCD only network
+    None,
+    /// Shared token from NVS, see `TokenAuth`
+    Token,
+    /// HMAC challenge-response with a key shared with the LCD, see `ChallengeAuth`
+    Challenge,
+}
+
+pub trait AuthBackend: Send + Sync {
+    /// Check the Authorization header value
+    fn check(&self, authorization: Option<&str>) -> Result<(), AuthError>;
+
+    /// WWW-Authenticate value sent with a 401
+    fn challenge(&self) -> String;
+
+    /// A fresh nonce for `GET /auth/challenge`, None if the backend doesn't use them
+    fn nonce(&self) -> Option<String> {
+        None
+    }
+
+    /// Store a new token or key from `PUT /config/auth-token`, empty turns auth off
+    fn set_secret(&self, secret: &str) -> Result<()>;
+}
+
+/// The backend for `mode`, its secret (if any) loaded from NVS
+pub fn backend(mode: HttpAuth, nvs: EspNvs<NvsDefault>) -> Result<Box<dyn AuthBackend>> {
+    Ok(match mode {
+        HttpAuth::None => {
+            info!("HTTP auth off");
+            Box::new(NoAuth)
+        }
+        HttpAuth::Token => Box::new(TokenAuth::new(nvs)?),
+        HttpAuth::Challenge => Box::new(ChallengeAuth::new(nvs)?),
+    })
+}
+
+//---------
+// No auth
+//---------
+
+pub struct NoAuth;
+
+impl AuthBackend for NoAuth {
+    fn check(&self, _authorization: Option<&str>) -> Result<(), AuthError> {
+        Ok(())
+    }
+
+    fn challenge(&self) -> String {
+        String::new()
+    }
+
+    fn set_secret(&self, _secret: &str) -> Result<()> {
+        Err(anyhow!("HTTP auth is off in this build"))
+    }
+}
+
+//-------
+// Token
+//-------
+
+/// Optional shared token required on every HTTP request.
+///
+/// Without it anyone on the WIFI network can send ELM commands, including mode 04 DTC clears.
+/// Auth is enabled once a token has been stored in NVS, with no token every request is accepted.
+pub struct TokenAuth {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    token: Mutex<Option<String>>,
+}
+
+impl TokenAuth {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let mut buf = [0u8; MAX_TOKEN_LEN + 1];
+
+        let token = nvs
+            .get_str(NVS_AUTH_TOKEN, &mut buf)?
+            .filter(|token| !token.is_empty())
+            .map(str::to_owned);
+
+        if token.is_some() {
+            info!("HTTP token auth enabled");
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            token: Mutex::new(token),
+        })
+    }
+}
+
+impl AuthBackend for TokenAuth {
+    /// Always passes when no token is set
+    fn check(&self, authorization: Option<&str>) -> Result<(), AuthError> {
+        let token = self.token.lock().unwrap();
+
+        let Some(token) = token.as_ref() else {
+            return Ok(());
+        };
+
+        let authorization = authorization.ok_or(AuthError::Missing)?.trim();
+
+        let given = if let Some(bearer) = authorization.strip_prefix("Bearer ") {
+            bearer.trim().to_owned()
+        } else if let Some(basic) = authorization.strip_prefix("Basic ") {
+            let decoded = STANDARD
+                .decode(basic.trim())
+                .map_err(|_| AuthError::Invalid)?;
+            let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Invalid)?;
+            let (_, password) = decoded.split_once(':').ok_or(AuthError::Invalid)?;
+            password.to_owned()
+        } else {
+            Err(AuthError::Invalid)?
+        };
+
+        if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AuthError::Invalid)
+        }
+    }
+
+    fn challenge(&self) -> String {
+        "Basic realm=\"obd-gw\"".to_owned()
+    }
+
+    fn set_secret(&self, token: &str) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if token.is_empty() {
+            nvs.remove(NVS_AUTH_TOKEN)?;
+            *self.token.lock().unwrap() = None;
+            info!("HTTP token auth disabled");
+        } else {
+            if token.len() > MAX_TOKEN_LEN {
+                Err(anyhow!("Token too long, max ({MAX_TOKEN_LEN})"))?;
+            }
+            if token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                Err(anyhow!("Token can't contain whitespace"))?;
+            }
+
+            nvs.set_str(NVS_AUTH_TOKEN, token)?;
+            *self.token.lock().unwrap() = Some(token.to_owned());
+            info!("HTTP auth token updated");
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------
+// Challenge-response
+//--------------------
+
+/// HMAC-SHA256 challenge-response with a key shared with the LCD, so the key itself never
+/// crosses the network.
+///
+/// The client gets a nonce from `GET /auth/challenge` (or the 401) and sends
+/// `Authorization: Hmac <nonce>:<hex HMAC of the nonce>`. Each nonce is good for one request
+/// within `NONCE_LIFETIME`. With no key stored every request is accepted.
+pub struct ChallengeAuth {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    key: Mutex<Option<Vec<u8>>>,
+    /// Outstanding nonces, hex, and when they were issued
+    nonces: Mutex<Vec<(String, Instant)>>,
+}
+
+impl ChallengeAuth {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let mut buf = [0u8; MAX_KEY_LEN];
+
+        let key = nvs
+            .get_raw(NVS_AUTH_KEY, &mut buf)?
+            .filter(|key| !key.is_empty())
+            .map(<[u8]>::to_vec);
+
+        if key.is_some() {
+            info!("HTTP challenge auth enabled");
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            key: Mutex::new(key),
+            nonces: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Remove the nonce if it's outstanding and not expired
+    fn take_nonce(&self, nonce: &str) -> bool {
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|(_, issued)| issued.elapsed() < NONCE_LIFETIME);
+
+        match nonces.iter().position(|(n, _)| n == nonce) {
+            Some(i) => {
+                nonces.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl AuthBackend for ChallengeAuth {
+    /// Always passes when no key is set
+    fn check(&self, authorization: Option<&str>) -> Result<(), AuthError> {
+        let key = self.key.lock().unwrap();
+
+        let Some(key) = key.as_ref() else {
+            return Ok(());
+        };
+
+        let authorization = authorization.ok_or(AuthError::Missing)?.trim();
+
+        let (nonce, mac) = authorization
+            .strip_prefix("Hmac ")
+            .and_then(|hmac| hmac.trim().split_once(':'))
+            .ok_or(AuthError::Invalid)?;
+        let mac = decode_hex(mac).ok_or(AuthError::Invalid)?;
+
+        if !self.take_nonce(nonce) {
+            Err(AuthError::Expired)?;
+        }
+
+        let mut expected = Hmac::<Sha256>::new_from_slice(key).map_err(|_| AuthError::Invalid)?;
+        expected.update(nonce.as_bytes());
+        expected.verify_slice(&mac).map_err(|_| AuthError::Invalid)
+    }
+
+    fn challenge(&self) -> String {
+        format!(
+            "Hmac realm=\"obd-gw\", nonce=\"{}\"",
+            self.nonce().unwrap_or_default()
+        )
+    }
+
+    fn nonce(&self) -> Option<String> {
+        let mut bytes = [0u8; NONCE_LEN];
+        unsafe { esp_fill_random(bytes.as_mut_ptr().cast(), bytes.len()) };
+
+        let nonce = bytes.iter().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        });
+
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|(_, issued)| issued.elapsed() < NONCE_LIFETIME);
+        if nonces.len() >= MAX_NONCES {
+            nonces.remove(0);
+        }
+        nonces.push((nonce.clone(), Instant::now()));
+
+        Some(nonce)
+    }
+
+    /// The key is hex
+    fn set_secret(&self, key: &str) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if key.is_empty() {
+            nvs.remove(NVS_AUTH_KEY)?;
+            *self.key.lock().unwrap() = None;
+            info!("HTTP challenge auth disabled");
+        } else {
+            let key = decode_hex(key).ok_or_else(|| anyhow!("Key must be hex"))?;
+            if key.len() > MAX_KEY_LEN {
+                Err(anyhow!("Key too long, max ({MAX_KEY_LEN}) bytes"))?;
+            }
+
+            nvs.set_raw(NVS_AUTH_KEY, &key)?;
+            *self.key.lock().unwrap() = Some(key);
+            self.nonces.lock().unwrap().clear();
+            info!("HTTP challenge key updated");
+        }
+
+        Ok(())
+    }
+}
+
+/// Compare without an early exit, so the time taken doesn't give away how much of the token
+/// matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,14 +1,287 @@
-use std::borrow::Borrow;
+use std::{
+    borrow::Borrow,
+    ffi::c_int,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use anyhow::{anyhow, Result};
 use esp_idf_svc::{
     bt::{
         gap::{DeviceProp, EspGap, GapEvent},
-        BtClassicEnabled, BtDriver,
+        BdAddr, BtClassicEnabled, BtDriver, BtStatus,
+    },
+    sys::{
+        esp, esp_bt_gap_get_bond_device_list, esp_bt_gap_read_rssi_delta,
+        esp_bt_gap_remove_bond_device, esp_bt_gap_ssp_confirm_reply, esp_bt_gap_start_discovery,
+        esp_bt_inq_mode_t_ESP_BT_INQ_MODE_GENERAL_INQUIRY,
     },
-    sys::{esp, esp_bt_gap_ssp_confirm_reply},
 };
-
 use log::*;
+use serde::Serialize;
+
+/// Most bonds listed, the controller keeps far fewer
+const MAX_BONDS: usize = 16;
+/// Inquiry length unit
+const INQUIRY_UNIT_MS: u64 = 1280;
+pub const MAX_SCAN_SECS: u64 = 30;
+const MAX_FOUND: usize = 32;
+/// How often the adapter link's RSSI is read
+const RSSI_INTERVAL: Duration = Duration::from_secs(10);
+/// Older readings are dropped, the link is probably down
+const RSSI_MAX_AGE: Duration = Duration::from_secs(30);
+/// Readings in a row past the limit before the link is weak, or good again
+const RSSI_READINGS: u8 = 3;
+/// How far back above the limit a weak link has to come to be good again, in dB
+const RSSI_HYSTERESIS: i8 = 3;
+
+/// The last scan, None before the first
+static SCAN: Mutex<Option<Scan>> = Mutex::new(None);
+/// The adapter link's last RSSI reading and when, see `LinkMonitor`
+static LINK_RSSI: Mutex<Option<(i8, Instant)>> = Mutex::new(None);
+static LINK_WEAK: AtomicBool = AtomicBool::new(false);
+
+struct Scan {
+    /// When the inquiry is over, the controller stops it on its own
+    ends: Instant,
+    found: Vec<FoundDevice>,
+}
+
+/// `GET /bt/scan`, the scan running or the last one
+#[derive(Serialize)]
+pub struct ScanStatus {
+    pub scanning: bool,
+    /// Strongest first
+    pub devices: Vec<FoundDevice>,
+}
+
+/// A device answering the inquiry, for `/bt/scan`
+#[derive(Serialize, Clone, Debug)]
+pub struct FoundDevice {
+    pub address: String,
+    pub name: Option<String>,
+    /// Class of device, e.g. 0x1F00 for an uncategorized device like the OBDLink
+    pub cod: Option<u32>,
+    pub rssi: Option<i8>,
+}
+
+/// A bonded device for `GET /bt/bonds`
+#[derive(Serialize)]
+pub struct Bond {
+    pub address: String,
+    /// The configured OBD adapter
+    pub adapter: bool,
+}
+
+/// Devices the controller has link keys for
+pub fn bonded_devices() -> Result<Vec<BdAddr>> {
+    let mut list = [[0u8; 6]; MAX_BONDS];
+    let mut count = MAX_BONDS as c_int;
+
+    esp!(unsafe { esp_bt_gap_get_bond_device_list(&mut count, list.as_mut_ptr()) })?;
+
+    Ok(list[..count.clamp(0, MAX_BONDS as c_int) as usize]
+        .iter()
+        .map(|addr| BdAddr::from_bytes(*addr))
+        .collect())
+}
+
+/// Forget the link key, the device has to pair again on the next connect
+pub fn remove_bond(addr: &BdAddr) -> Result<()> {
+    let mut addr = addr.addr();
+
+    esp!(unsafe { esp_bt_gap_remove_bond_device(addr.as_mut_ptr()) })?;
+    info!("Removed bond {}", BdAddr::from_bytes(addr));
+
+    Ok(())
+}
+
+pub fn scanning() -> bool {
+    SCAN.lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|scan| Instant::now() < scan.ends)
+}
+
+/// Start a GAP inquiry for about `duration`, the devices that answer are in `scan_status` as they
+/// come in. The last scan's devices are dropped.
+pub fn start_scan(duration: Duration) -> Result<()> {
+    let units = duration
+        .as_millis()
+        .div_ceil(INQUIRY_UNIT_MS as u128)
+        .clamp(1, 0x30) as u8;
+
+    {
+        let mut scan = SCAN.lock().unwrap();
+        if scan.as_ref().is_some_and(|scan| Instant::now() < scan.ends) {
+            Err(anyhow!("Scan already running"))?;
+        }
+
+        // Before the inquiry starts, the first answers can come straight away
+        *scan = Some(Scan {
+            ends: Instant::now() + Duration::from_millis(units as u64 * INQUIRY_UNIT_MS),
+            found: Vec::new(),
+        });
+    }
+
+    info!("GAP: Scanning for {}ms", units as u64 * INQUIRY_UNIT_MS);
+
+    if let Err(err) = esp!(unsafe {
+        esp_bt_gap_start_discovery(esp_bt_inq_mode_t_ESP_BT_INQ_MODE_GENERAL_INQUIRY, units, 0)
+    }) {
+        *SCAN.lock().unwrap() = None;
+        Err(err)?;
+    }
+
+    Ok(())
+}
+
+pub fn scan_status() -> ScanStatus {
+    let scan = SCAN.lock().unwrap();
+
+    let mut devices = scan
+        .as_ref()
+        .map(|scan| scan.found.clone())
+        .unwrap_or_default();
+    devices.sort_by_key(|device| std::cmp::Reverse(device.rssi.unwrap_or(i8::MIN)));
+
+    ScanStatus {
+        scanning: scan.as_ref().is_some_and(|scan| Instant::now() < scan.ends),
+        devices,
+    }
+}
+
+/// Add a discovered device to the scan in progress, merging repeat answers
+fn record_found(device: FoundDevice) {
+    let mut scan = SCAN.lock().unwrap();
+    let Some(Scan { found, .. }) = scan.as_mut().filter(|scan| Instant::now() < scan.ends) else {
+        return;
+    };
+
+    match found.iter_mut().find(|d| d.address == device.address) {
+        Some(known) => {
+            known.name = device.name.or(known.name.take());
+            known.cod = device.cod.or(known.cod);
+            known.rssi = device.rssi.or(known.rssi);
+        }
+        None if found.len() < MAX_FOUND => found.push(device),
+        None => (),
+    }
+}
+
+/// The adapter link for `/status`
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct LinkQuality {
+    /// dB outside the controller's golden receive power range, 0 inside it and negative below
+    /// it. None without a recent reading.
+    pub rssi_delta: Option<i8>,
+    /// Below the limit for the last few readings
+    pub weak: bool,
+}
+
+pub fn link_quality() -> LinkQuality {
+    LinkQuality {
+        rssi_delta: link_rssi(),
+        weak: LINK_WEAK.load(Ordering::Relaxed),
+    }
+}
+
+/// The last RSSI reading, None if it's stale
+fn link_rssi() -> Option<i8> {
+    LINK_RSSI
+        .lock()
+        .unwrap()
+        .filter(|(_, at)| at.elapsed() < RSSI_MAX_AGE)
+        .map(|(rssi, _)| rssi)
+}
+
+/// The link turned weak or good again
+#[derive(Clone, Copy, Debug)]
+pub struct LinkChange {
+    pub weak: bool,
+    pub rssi_delta: i8,
+}
+
+/// Reads the adapter link's RSSI every 10s and warns when it stays below `weak_below`.
+/// Marginal range shows up as corrupted or missing responses long before the link drops.
+pub struct LinkMonitor {
+    addr: BdAddr,
+    weak_below: i8,
+    last_poll: Option<Instant>,
+    /// Readings in a row past the limit
+    streak: u8,
+}
+
+impl LinkMonitor {
+    pub fn new(addr: BdAddr, weak_below: i8) -> Self {
+        Self {
+            addr,
+            weak_below,
+            last_poll: None,
+            streak: 0,
+        }
+    }
+
+    pub fn poll_due(&self) -> bool {
+        self.last_poll
+            .is_none_or(|at| at.elapsed() >= RSSI_INTERVAL)
+    }
+
+    /// Check the last reading and ask for the next one, which arrives as a GAP event. Returns the
+    /// change once the link has been weak, or good again, for a few readings.
+    pub fn poll(&mut self) -> Result<Option<LinkChange>> {
+        self.last_poll = Some(Instant::now());
+
+        let change = link_rssi().and_then(|rssi| self.check(rssi));
+
+        let mut addr = self.addr.addr();
+        esp!(unsafe { esp_bt_gap_read_rssi_delta(addr.as_mut_ptr()) })?;
+
+        Ok(change)
+    }
+
+    fn check(&mut self, rssi_delta: i8) -> Option<LinkChange> {
+        let weak = LINK_WEAK.load(Ordering::Relaxed);
+
+        let past = if weak {
+            rssi_delta >= self.weak_below.saturating_add(RSSI_HYSTERESIS)
+        } else {
+            rssi_delta < self.weak_below
+        };
+
+        self.streak = if past { self.streak + 1 } else { 0 };
+        if self.streak < RSSI_READINGS {
+            return None;
+        }
+        self.streak = 0;
+
+        match weak {
+            false => warn!("Weak BT signal to the adapter, RSSI delta {rssi_delta}dB"),
+            true => info!("BT signal to the adapter good again, RSSI delta {rssi_delta}dB"),
+        }
+        LINK_WEAK.store(!weak, Ordering::Relaxed);
+
+        Some(LinkChange {
+            weak: !weak,
+            rssi_delta,
+        })
+    }
+}
+
+/// `00:04:3E:83:FC:98`, `-` separators are fine too
+pub fn parse_addr(addr: &str) -> Option<BdAddr> {
+    let mut bytes = [0u8; 6];
+    let mut parts = addr.trim().split([':', '-']);
+
+    for byte in bytes.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(BdAddr::from_bytes(bytes))
+}
 
 /// BT GAP callback handler
 pub fn handle_gap<'d, M, T>(gap: &EspGap<'d, M, T>, event: GapEvent<'_>)
@@ -20,19 +293,40 @@ where
         GapEvent::DeviceDiscovered { bd_addr, props } => {
             info!("GAP: Found device: {bd_addr:?}");
 
+            let mut device = FoundDevice {
+                address: bd_addr.to_string(),
+                name: None,
+                cod: None,
+                rssi: None,
+            };
+
             for prop in props {
                 info!("Prop: {:?}", prop.prop());
 
-                if let DeviceProp::Eir(eir) = prop.prop() {
-                    // let eir: Eir = eir as _;
-                    info!(
-                        "  Short Local Name: {}, Local Name: {}",
-                        eir.short_local_name::<M, T>().unwrap_or("-"),
-                        eir.local_name::<M, T>().unwrap_or("-")
-                    );
+                match prop.prop() {
+                    DeviceProp::Eir(eir) => {
+                        // let eir: Eir = eir as _;
+                        info!(
+                            "  Short Local Name: {}, Local Name: {}",
+                            eir.short_local_name::<M, T>().unwrap_or("-"),
+                            eir.local_name::<M, T>().unwrap_or("-")
+                        );
+
+                        if device.name.is_none() {
+                            device.name = eir
+                                .local_name::<M, T>()
+                                .or(eir.short_local_name::<M, T>())
+                                .map(str::to_owned);
+                        }
+                    }
+                    DeviceProp::BdName(name) => device.name = Some(name.to_owned()),
+                    DeviceProp::Cod(cod) => device.cod = Some(cod.raw()),
+                    DeviceProp::Rssi(rssi) => device.rssi = Some(rssi),
                 }
             }
 
+            record_found(device);
+
             //let _ = gap.stop_discovery();
         }
         GapEvent::SspPasskeyRequest { bd_addr } => {
@@ -66,6 +360,18 @@ where
                     .unwrap();
             }
         }
+        GapEvent::ReadRssiDeltaResponse {
+            bd_addr,
+            status,
+            rssi_delta,
+        } => {
+            if matches!(status, BtStatus::Success) {
+                trace!("GAP: RSSI delta {bd_addr} {rssi_delta}dB");
+                *LINK_RSSI.lock().unwrap() = Some((rssi_delta, Instant::now()));
+            } else {
+                debug!("GAP: RSSI read failed {bd_addr} {status:?}");
+            }
+        }
         _ => (),
     }
 }
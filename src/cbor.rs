@@ -0,0 +1,67 @@
+//! CBOR for the data endpoints, asked for with `Accept: application/cbor`. It's smaller than the
+//! JSON and the LCD can read it without a text parser. Maps have small integer keys rather than
+//! names:
+//!
+//! Snapshot `{0: timestamp_ms, 1: time or null, 2: {pid or name: value}}`, mode 01 PIDs are
+//! integer keys and virtual PIDs text, values are f32.
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use minicbor::Encoder;
+
+pub const CONTENT_TYPE: &str = "application/cbor";
+
+pub const SNAPSHOT_TIMESTAMP_MS: u8 = 0;
+pub const SNAPSHOT_TIME: u8 = 1;
+pub const SNAPSHOT_VALUES: u8 = 2;
+
+/// Whether the client would rather have CBOR than JSON. The first type in `Accept` that can be
+/// sent wins, one with `q=0` is skipped.
+pub fn accepted(accept: Option<&str>) -> bool {
+    for media in accept.unwrap_or_default().split(',') {
+        let mut params = media.split(';').map(str::trim);
+        let kind = params.next().unwrap_or_default();
+
+        if params.any(|p| matches!(p, "q=0" | "q=0.0" | "q=0.00" | "q=0.000")) {
+            continue;
+        }
+
+        if kind.eq_ignore_ascii_case(CONTENT_TYPE) {
+            return true;
+        }
+        if kind.eq_ignore_ascii_case("application/json") || kind == "*/*" {
+            return false;
+        }
+    }
+
+    false
+}
+
+/// `/snapshot` values keyed by hex PID or virtual PID name
+pub fn snapshot(
+    timestamp_ms: u64,
+    time: Option<&str>,
+    values: &BTreeMap<String, f32>,
+) -> Result<Vec<u8>> {
+    let mut e = Encoder::new(Vec::new());
+
+    e.map(3)?.u8(SNAPSHOT_TIMESTAMP_MS)?.u64(timestamp_ms)?;
+
+    e.u8(SNAPSHOT_TIME)?;
+    match time {
+        Some(time) => e.str(time)?,
+        None => e.null()?,
+    };
+
+    e.u8(SNAPSHOT_VALUES)?.map(values.len() as u64)?;
+    for (key, value) in values {
+        // Hex keys are always PIDs, a virtual PID named like one couldn't be asked for
+        match u8::from_str_radix(key, 16) {
+            Ok(pid) if key.len() == 2 => e.u8(pid)?,
+            _ => e.str(key)?,
+        };
+        e.f32(*value)?;
+    }
+
+    Ok(e.into_writer())
+}
@@ -0,0 +1,123 @@
+//! Channel metadata for `GET /channels`, so dashboards and the LCD can build their UI from what
+//! the gateway can read rather than a hardcoded list.
+use serde::Serialize;
+
+use crate::logger::LogConfig;
+use crate::maintenance::{self, MaintenanceConfig};
+use crate::pid;
+use crate::virtual_pids::VirtualPid;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelSource {
+    /// Mode 01 PID, read with /snapshot
+    Pid,
+    /// Mode 22 DID, read with /uds
+    Did,
+    /// Worked out by the gateway, reported by the endpoint that uses it
+    Derived,
+    /// A virtual PID, read with /snapshot by name
+    Virtual,
+}
+
+#[derive(Serialize)]
+pub struct Channel {
+    /// PID or DID in hex, or the derived or virtual value name
+    pub id: String,
+    pub name: String,
+    pub unit: String,
+    pub min: f32,
+    pub max: f32,
+    /// How often the gateway reads it on its own, None if only on request
+    pub update_ms: Option<u32>,
+    pub source: ChannelSource,
+    /// From the supported PID bitmaps, None if not known
+    pub supported: Option<bool>,
+}
+
+/// Every channel the gateway can read. The rates come from the logger and maintenance configs.
+pub fn list(
+    logger: &LogConfig,
+    maintenance: &MaintenanceConfig,
+    virtual_pids: &[VirtualPid],
+) -> Vec<Channel> {
+    let discovered = pid::supported();
+    let maintenance_ms =
+        (!maintenance.items.is_empty()).then(|| maintenance::READ_INTERVAL.as_millis() as u32);
+
+    let mut channels: Vec<Channel> = (0..=0xFFu8)
+        .filter_map(|p| {
+            let name = pid::name(p)?;
+            let (unit, min, max) = pid::unit_range(p)?;
+            let id = format!("{p:02X}");
+
+            let logged = logger
+                .pids
+                .iter()
+                .any(|logged| logged.eq_ignore_ascii_case(&id));
+            let update_ms = if logged {
+                Some(logger.interval_ms)
+            } else if p == pid::ODOMETER && maintenance.odometer_did.is_none() {
+                maintenance_ms
+            } else {
+                None
+            };
+
+            Some(Channel {
+                id,
+                name: name.to_owned(),
+                unit: unit.to_owned(),
+                min,
+                max,
+                update_ms,
+                source: ChannelSource::Pid,
+                supported: discovered.as_ref().map(|pids| pids.contains(&p)),
+            })
+        })
+        .collect();
+
+    if let Some(did) = &maintenance.odometer_did {
+        channels.push(Channel {
+            id: did.to_uppercase(),
+            name: "odometer".to_owned(),
+            unit: "km".to_owned(),
+            min: 0.0,
+            max: u32::MAX as f32,
+            update_ms: maintenance_ms,
+            source: ChannelSource::Did,
+            supported: None,
+        });
+    }
+
+    // Total engine run time from PID 7F, in /maintenance
+    channels.push(Channel {
+        id: "engine_hours".to_owned(),
+        name: "engine_hours".to_owned(),
+        unit: "h".to_owned(),
+        min: 0.0,
+        max: (u32::MAX / 3600) as f32,
+        update_ms: maintenance_ms,
+        source: ChannelSource::Derived,
+        supported: discovered
+            .as_ref()
+            .map(|pids| pids.contains(&pid::ENGINE_RUN_TIME)),
+    });
+
+    for def in virtual_pids {
+        channels.push(Channel {
+            id: def.name.clone(),
+            name: def.name.clone(),
+            unit: def.unit.clone(),
+            min: def.min,
+            max: def.max,
+            update_ms: logger
+                .pids
+                .contains(&def.name)
+                .then_some(logger.interval_ms),
+            source: ChannelSource::Virtual,
+            supported: None,
+        });
+    }
+
+    channels
+}
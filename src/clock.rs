@@ -0,0 +1,74 @@
+//! Wall clock time, from SNTP once WIFI is up or set by a client with `POST /time` where there's
+//! no internet, e.g. on the LCD's AP. Until then there's only the uptime, and `now` is None.
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    sntp::{EspSntp, SntpConf},
+    sys::{settimeofday, timeval},
+};
+use log::*;
+use serde::Serialize;
+
+pub use bt_obd_gw_core::clock::now;
+use bt_obd_gw_core::clock::{rfc3339, MIN_VALID_UNIX_S};
+
+static SOURCE: AtomicU8 = AtomicU8::new(TimeSource::None as u8);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+    None,
+    Sntp,
+    /// `POST /time`
+    Client,
+}
+
+/// Keep the handle, dropping it stops SNTP
+pub fn start_sntp() -> Result<EspSntp<'static>> {
+    let sntp = EspSntp::new_with_callback(&SntpConf::default(), |synced| {
+        SOURCE.store(TimeSource::Sntp as u8, Ordering::Relaxed);
+        info!("Time synced by SNTP ({})", rfc3339(synced));
+    })?;
+
+    Ok(sntp)
+}
+
+pub fn source() -> TimeSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        s if s == TimeSource::Sntp as u8 => TimeSource::Sntp,
+        s if s == TimeSource::Client as u8 => TimeSource::Client,
+        _ => TimeSource::None,
+    }
+}
+
+/// Set the time from a client, ignored once SNTP has synced as that's the better source
+pub fn set(unix_ms: u64) -> Result<()> {
+    if unix_ms / 1000 < MIN_VALID_UNIX_S {
+        Err(anyhow!("Time is before 2024"))?;
+    }
+
+    if source() == TimeSource::Sntp {
+        debug!("Time set ignored, SNTP has synced");
+        return Ok(());
+    }
+
+    let tv = timeval {
+        tv_sec: (unix_ms / 1000) as _,
+        tv_usec: ((unix_ms % 1000) * 1000) as _,
+    };
+    if unsafe { settimeofday(&tv, std::ptr::null()) } != 0 {
+        Err(anyhow!("settimeofday failed"))?;
+    }
+
+    SOURCE.store(TimeSource::Client as u8, Ordering::Relaxed);
+    info!(
+        "Time set by client ({})",
+        rfc3339(Duration::from_millis(unix_ms))
+    );
+
+    Ok(())
+}
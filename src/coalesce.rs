@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+
+use crate::elm327::normalise;
+use crate::error::WorkerError;
+
+/// Longest a completed response is shared with later callers
+const MAX_REUSE: Duration = Duration::from_millis(250);
+/// Longest a caller waits for another caller's response. Past the ELM worker reply timeout, so
+/// the caller running the request has its response or an error by then.
+const MAX_WAIT: Duration = Duration::from_secs(20);
+
+enum Slot {
+    /// A caller is running the request on the adapter
+    Pending,
+    /// Response and how long it stays fresh
+    Done { response: String, expires: Instant },
+}
+
+/// Runs identical concurrent requests once on the adapter.
+///
+/// The first caller for a request runs it and any caller asking for the same request meanwhile
+/// waits and gets the same response, e.g. the LCD and a phone dashboard polling the same PID. A
+/// completed response is also reused for as long as it took to fetch (capped at `MAX_REUSE`), a
+/// caller arriving in that time would have waited at least as long for its own request anyway.
+/// Failed requests aren't shared, waiting callers run the request themselves.
+///
+/// The slots lock is held to look up and update the slots, never while a request runs, so
+/// callers for other requests go straight through to the ELM worker.
+pub struct Coalescer {
+    slots: Mutex<BTreeMap<String, Slot>>,
+    done: Condvar,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self {
+            slots: Mutex::new(BTreeMap::new()),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Key for coalescing an ELM request, None for requests that must always run. AT and ST
+    /// commands change adapter state so are never shared.
+    pub fn key(request: &[u8]) -> Option<String> {
+        let key = normalise(request);
+
+        if key.is_empty() || key.starts_with("AT") || key.starts_with("ST") {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
+    /// Run `f` for the request, or wait for and share the response of a caller already running it.
+    /// `done` gets a fresh response while the slot is still locked, e.g. to cache it, so a later
+    /// caller finds it in either the slot or the cache.
+    pub fn run(
+        &self,
+        key: &str,
+        f: impl FnOnce() -> Result<String>,
+        done: impl FnOnce(&str),
+    ) -> Result<String> {
+        let mut slots = self.slots.lock().unwrap();
+        let waiting = Instant::now();
+
+        loop {
+            let now = Instant::now();
+            slots.retain(|_, slot| !matches!(slot, Slot::Done { expires, .. } if *expires <= now));
+
+            match slots.get(key) {
+                Some(Slot::Pending) => {
+                    let Some(wait) = MAX_WAIT.checked_sub(waiting.elapsed()) else {
+                        warn!("Gave up waiting for a coalesced request ({key})");
+                        return Err(WorkerError::Timeout.into());
+                    };
+                    slots = self.done.wait_timeout(slots, wait).unwrap().0;
+                }
+                Some(Slot::Done { response, .. }) => {
+                    debug!("Coalesced request ({key})");
+                    return Ok(response.clone());
+                }
+                None => break,
+            }
+        }
+
+        slots.insert(key.to_owned(), Slot::Pending);
+        drop(slots);
+
+        let started = Instant::now();
+        let result = f();
+
+        let mut slots = self.slots.lock().unwrap();
+        match &result {
+            Ok(response) => {
+                done(response);
+
+                let expires = Instant::now() + started.elapsed().min(MAX_REUSE);
+                slots.insert(
+                    key.to_owned(),
+                    Slot::Done {
+                        response: response.clone(),
+                        expires,
+                    },
+                );
+            }
+            Err(_) => {
+                slots.remove(key);
+            }
+        }
+        drop(slots);
+
+        self.done.notify_all();
+
+        result
+    }
+}
@@ -0,0 +1,130 @@
+//! BT and WiFi share the one radio, ESP-IDF's software coexistence hands it between them. Which
+//! side it favours changes how often SPP stalls (the stack reporting the link congested) against
+//! how often WiFi frames go unacked. `PUT /debug/coex` switches the preference at runtime, and
+//! `GET /debug/coex` has the counters since the switch, to compare one against another under the
+//! same load. It isn't stored, the gateway starts balanced.
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::Result;
+use esp_idf_svc::sys::{
+    esp, esp_coex_prefer_t, esp_coex_prefer_t_ESP_COEX_PREFER_BALANCE,
+    esp_coex_prefer_t_ESP_COEX_PREFER_BT, esp_coex_prefer_t_ESP_COEX_PREFER_WIFI,
+    esp_coex_preference_set,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::espnow_cmd;
+use crate::network::WifiSupervisor;
+use crate::spp_handler::{self, SppStats};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CoexPreference {
+    #[default]
+    Balanced,
+    Bt,
+    Wifi,
+}
+
+impl CoexPreference {
+    fn raw(self) -> esp_coex_prefer_t {
+        match self {
+            Self::Balanced => esp_coex_prefer_t_ESP_COEX_PREFER_BALANCE,
+            Self::Bt => esp_coex_prefer_t_ESP_COEX_PREFER_BT,
+            Self::Wifi => esp_coex_prefer_t_ESP_COEX_PREFER_WIFI,
+        }
+    }
+}
+
+/// `PUT /debug/coex`
+#[derive(Deserialize)]
+pub struct CoexRequest {
+    pub preference: CoexPreference,
+}
+
+/// The counters when the preference was switched
+struct Baseline {
+    preference: CoexPreference,
+    since: Instant,
+    spp: SppStats,
+    wifi_disconnects: u32,
+    espnow: (u32, u32),
+}
+
+/// None until the first switch, counted from boot
+static BASELINE: Mutex<Option<Baseline>> = Mutex::new(None);
+
+#[derive(Serialize)]
+pub struct CoexCounters {
+    pub spp_congestions: u32,
+    pub spp_congested_ms: u32,
+    pub spp_write_waits: u32,
+    pub wifi_disconnects: u32,
+    pub espnow_sends: u32,
+    /// Unicast frames the peer didn't ack after the MAC's retries
+    pub espnow_send_failures: u32,
+}
+
+#[derive(Serialize)]
+pub struct CoexStatus {
+    pub preference: CoexPreference,
+    /// Time since the switch, None if it's the preference from boot
+    pub for_ms: Option<u64>,
+    /// Since the switch
+    pub counters: CoexCounters,
+}
+
+pub fn set(preference: CoexPreference, wifi: &WifiSupervisor) -> Result<()> {
+    esp!(unsafe { esp_coex_preference_set(preference.raw()) })?;
+
+    *BASELINE.lock().unwrap() = Some(Baseline {
+        preference,
+        since: Instant::now(),
+        spp: spp_handler::stats(),
+        wifi_disconnects: wifi.status().disconnects,
+        espnow: espnow_cmd::send_stats(),
+    });
+
+    info!("Coexistence preference {preference:?}");
+
+    Ok(())
+}
+
+pub fn status(wifi: &WifiSupervisor) -> CoexStatus {
+    let baseline = BASELINE.lock().unwrap();
+
+    let spp = spp_handler::stats();
+    let wifi_disconnects = wifi.status().disconnects;
+    let (sends, failures) = espnow_cmd::send_stats();
+
+    let counters = match baseline.as_ref() {
+        Some(from) => CoexCounters {
+            spp_congestions: spp.congestions.wrapping_sub(from.spp.congestions),
+            spp_congested_ms: spp.congested_ms.wrapping_sub(from.spp.congested_ms),
+            spp_write_waits: spp.write_waits.wrapping_sub(from.spp.write_waits),
+            wifi_disconnects: wifi_disconnects.wrapping_sub(from.wifi_disconnects),
+            espnow_sends: sends.wrapping_sub(from.espnow.0),
+            espnow_send_failures: failures.wrapping_sub(from.espnow.1),
+        },
+        None => CoexCounters {
+            spp_congestions: spp.congestions,
+            spp_congested_ms: spp.congested_ms,
+            spp_write_waits: spp.write_waits,
+            wifi_disconnects,
+            espnow_sends: sends,
+            espnow_send_failures: failures,
+        },
+    };
+
+    CoexStatus {
+        preference: baseline
+            .as_ref()
+            .map(|from| from.preference)
+            .unwrap_or_default(),
+        for_ms: baseline
+            .as_ref()
+            .map(|from| from.since.elapsed().as_millis() as u64),
+        counters,
+    }
+}
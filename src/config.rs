@@ -0,0 +1,375 @@
+//! The gateway's NVS keys and settings in one place. Every key in the `elm_ns` namespace is listed
+//! here, as they share it and NVS keys are at most 15 characters. The feature toggles are kept
+//! by `Features`, one key each.
+//!
+//! Settings are stored as postcard blobs with `SCHEMA_VERSION` in front, see `load` and `encode`.
+//! Blobs from before the version byte are the JSON the stores used to write, and are read as
+//! schema 0 until they're next stored. A change to a stored type bumps `SCHEMA_VERSION`, with a
+//! migration in `decode` from the blob it replaces. Records the gateway keeps for itself (crash
+//! report, self-test, lifetime counts) stay JSON.
+//!
+//! `GET /config` returns every setting as one `Settings` document, and `PUT /config` stores the
+//! sections it has, all or none of them.
+use anyhow::{anyhow, Context, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(feature = "bt")]
+use crate::discovery::DiscoveryConfig;
+use crate::elm_cache::CacheConfig;
+use crate::flow_control::FlowControlConfig;
+use crate::http::Services;
+use crate::http_limits::HttpOverrides;
+use crate::keep_awake::KeepAwakeConfig;
+use crate::link_speed::LinkSpeedConfig;
+use crate::log_level::LogLevelConfig;
+use crate::logger::LogConfig;
+use crate::maintenance::MaintenanceConfig;
+use crate::network::NetConfig;
+use crate::policy::PolicyConfig;
+use crate::smoothing::SmoothingConfig;
+use crate::tasks::TaskConfig;
+use crate::thresholds::ThresholdRule;
+use crate::timeouts::TimeoutConfig;
+use crate::virtual_pids::VirtualPid;
+
+/// Version of the stored settings and of the `/config` document
+pub const SCHEMA_VERSION: u8 = 1;
+/// Room for every section at its largest
+pub const MAX_DOCUMENT_LEN: usize = 8192;
+
+// Settings
+pub const NVS_LOG_LEVELS: &str = "log_levels";
+pub const NVS_TASKS: &str = "tasks";
+pub const NVS_DISCOVERY_POLICY: &str = "disc_policy";
+pub const NVS_MAINT_CONFIG: &str = "maint_cfg";
+pub const NVS_CACHE_TTLS: &str = "cache_ttls";
+pub const NVS_INIT_SCRIPT: &str = "init_script";
+pub const NVS_TIMEOUTS: &str = "elm_timeouts";
+pub const NVS_LINK_SPEED: &str = "link_speed";
+pub const NVS_FLOW_CONTROL: &str = "flow_control";
+pub const NVS_KEEP_AWAKE: &str = "keep_awake";
+pub const NVS_POLICY: &str = "policy";
+pub const NVS_VIRTUAL_PIDS: &str = "virt_pids";
+/// Logged mode 01 PIDs, one byte each
+pub const NVS_LOG_PIDS: &str = "log_pids";
+pub const NVS_LOG_INTERVAL: &str = "log_ivl";
+/// Names of the logged virtual PIDs
+pub const NVS_LOG_VIRTUAL: &str = "log_virt";
+pub const NVS_SMOOTHING: &str = "smoothing";
+pub const NVS_THRESHOLDS: &str = "thresholds";
+pub const NVS_NET_CONFIG: &str = "net_cfg";
+pub const NVS_HTTP_LIMITS: &str = "http_limits";
+
+// Secrets, never in `/config`
+pub const NVS_SIGNING_KEY: &str = "sign_key";
+pub const NVS_AUTH_TOKEN: &str = "auth_token";
+pub const NVS_AUTH_KEY: &str = "auth_key";
+pub const NVS_UNLOCK_TOKEN: &str = "unlock_token";
+pub const NVS_TLS_CERT: &str = "tls_cert";
+pub const NVS_TLS_KEY: &str = "tls_key";
+
+// Records, JSON
+/// Failed attempts since the adapter was last reached
+pub const NVS_DISC_FAIL_COUNT: &str = "dsc_fail_cnt";
+/// The OBD adapter's SPP server channel, see `DiscoveryPolicy::known_scn`
+pub const NVS_ADAPTER_SCN: &str = "adapter_scn";
+pub const NVS_ECUS: &str = "ecus";
+/// Registered in a pairing window, see `peers`
+pub const NVS_ESPNOW_PEERS: &str = "espnow_peers";
+pub const NVS_LAST_CRASH: &str = "last_crash";
+pub const NVS_SELFTEST: &str = "selftest";
+pub const NVS_SELFTEST_PROBE: &str = "selftest_nvs";
+pub const NVS_LIFETIME: &str = "lifetime";
+pub const NVS_FUEL_TRIP: &str = "fuel_trip";
+/// Last reading, so /maintenance has something before the first read after a boot. Kept with
+/// `persist`, it changes every read.
+pub const NVS_MAINT_READING: &str = "maint_read";
+
+/// A setting as stored, the schema version then postcard
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut blob = vec![SCHEMA_VERSION];
+    blob.extend(postcard::to_allocvec(value)?);
+
+    Ok(blob)
+}
+
+/// A stored setting, None if there isn't one or it can't be read. One that can't be read is
+/// logged and left for the store to replace with its default.
+pub fn load<T: DeserializeOwned>(
+    nvs: &EspNvs<NvsDefault>,
+    key: &str,
+    max_len: usize,
+) -> Result<Option<T>> {
+    let mut buf = vec![0u8; max_len];
+
+    let Some(blob) = nvs.get_raw(key, &mut buf)? else {
+        return Ok(None);
+    };
+
+    match decode(blob) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => {
+            warn!("Setting ({key}) unreadable, using the default {err:#}");
+            Ok(None)
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(blob: &[u8]) -> Result<T> {
+    match blob.split_first() {
+        Some((&SCHEMA_VERSION, value)) => Ok(postcard::from_bytes(value)?),
+        // JSON never starts with a control character
+        Some((&version, _)) if version < b' ' => {
+            Err(anyhow!("Schema ({version}) from newer firmware"))
+        }
+        _ => Ok(serde_json::from_slice(blob).context("Schema 0")?),
+    }
+}
+
+/// Every setting, sections left out of a `PUT /config` are kept
+#[derive(Serialize, Deserialize, Default)]
+pub struct Settings {
+    /// `SCHEMA_VERSION` when left out
+    #[serde(default)]
+    pub schema: Option<u8>,
+    #[serde(default)]
+    pub log_levels: Option<LogLevelConfig>,
+    #[serde(default)]
+    pub tasks: Option<TaskConfig>,
+    #[cfg(feature = "bt")]
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceConfig>,
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub init_script: Option<Vec<String>>,
+    #[serde(default)]
+    pub timeouts: Option<TimeoutConfig>,
+    #[serde(default)]
+    pub link_speed: Option<LinkSpeedConfig>,
+    #[serde(default)]
+    pub flow_control: Option<FlowControlConfig>,
+    #[serde(default)]
+    pub keep_awake: Option<KeepAwakeConfig>,
+    #[serde(default)]
+    pub policy: Option<PolicyConfig>,
+    #[serde(default)]
+    pub virtual_pids: Option<Vec<VirtualPid>>,
+    #[serde(default)]
+    pub logger: Option<LogConfig>,
+    #[serde(default)]
+    pub smoothing: Option<SmoothingConfig>,
+    #[serde(default)]
+    pub thresholds: Option<Vec<ThresholdRule>>,
+    #[serde(default)]
+    pub network: Option<NetConfig>,
+    #[serde(default)]
+    pub http: Option<HttpOverrides>,
+    /// ESPNOW peers, `AA:BB:CC:DD:EE:FF`
+    #[serde(default)]
+    pub peers: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// All of the settings in use
+    pub fn current(services: &Services) -> Self {
+        Self {
+            schema: Some(SCHEMA_VERSION),
+            log_levels: Some(services.log_levels.config()),
+            tasks: Some(services.tasks.config()),
+            #[cfg(feature = "bt")]
+            discovery: Some(services.discovery.config()),
+            maintenance: Some(services.maintenance.config()),
+            cache: Some(services.elm_cache.config()),
+            init_script: Some(services.init_script.commands()),
+            timeouts: Some(services.timeouts.config()),
+            link_speed: Some(services.link_speed.config()),
+            flow_control: Some(services.flow_control.config()),
+            keep_awake: Some(services.keep_awake.config()),
+            policy: Some(services.policy.config()),
+            virtual_pids: Some(services.virtual_pids.config()),
+            logger: Some(services.logger.config()),
+            smoothing: Some(services.smoothing.config()),
+            thresholds: Some(services.thresholds.config()),
+            network: Some(services.net_settings.config()),
+            http: Some(services.http_settings.overrides()),
+            peers: Some(services.peers.config()),
+        }
+    }
+
+    /// As `current` with the WIFI passwords left out, as `GET /config/network`
+    pub fn redacted(services: &Services) -> Self {
+        let mut settings = Self::current(services);
+        if let Some(network) = settings.network.as_mut() {
+            network.redact();
+        }
+
+        settings
+    }
+
+    /// Whether the adapter needs setting up again for these
+    pub fn changes_adapter(&self) -> bool {
+        self.init_script.is_some()
+            || self.timeouts.is_some()
+            || self.link_speed.is_some()
+            || self.flow_control.is_some()
+    }
+
+    /// Store every section there is. If one is refused, the ones stored before it go back to
+    /// what they were. An empty WIFI password, as `redacted` gives, keeps the stored one. Returns
+    /// the sections it replaced, for `restore`.
+    pub fn apply(mut self, services: &Services) -> Result<Self> {
+        match self.schema {
+            Some(schema) if schema > SCHEMA_VERSION => Err(anyhow!(
+                "Schema ({schema}) is newer than the gateway's ({SCHEMA_VERSION})"
+            ))?,
+            _ => {}
+        }
+
+        if let Some(network) = self.network.as_mut() {
+            network.keep_passwords(services.net_settings.config());
+        }
+
+        let mut before = Self::current(services);
+        before.keep_sections_of(&self);
+
+        if let Err(err) = self.store(services) {
+            warn!("Settings refused, restoring the earlier ones");
+            before.restore(services);
+            return Err(err);
+        }
+
+        Ok(before)
+    }
+
+    /// Put back the sections `apply` replaced, e.g. when the adapter refuses the new ones
+    pub fn restore(self, services: &Services) {
+        if let Err(err) = self.store(services) {
+            error!("Settings restore failed {err:#}");
+        }
+    }
+
+    /// Leave out the sections `other` doesn't have
+    fn keep_sections_of(&mut self, other: &Self) {
+        fn keep<T>(section: &mut Option<T>, other: &Option<impl Sized>) {
+            if other.is_none() {
+                *section = None;
+            }
+        }
+
+        keep(&mut self.log_levels, &other.log_levels);
+        keep(&mut self.tasks, &other.tasks);
+        #[cfg(feature = "bt")]
+        keep(&mut self.discovery, &other.discovery);
+        keep(&mut self.maintenance, &other.maintenance);
+        keep(&mut self.cache, &other.cache);
+        keep(&mut self.init_script, &other.init_script);
+        keep(&mut self.timeouts, &other.timeouts);
+        keep(&mut self.link_speed, &other.link_speed);
+        keep(&mut self.flow_control, &other.flow_control);
+        keep(&mut self.keep_awake, &other.keep_awake);
+        keep(&mut self.policy, &other.policy);
+        keep(&mut self.virtual_pids, &other.virtual_pids);
+        keep(&mut self.logger, &other.logger);
+        keep(&mut self.smoothing, &other.smoothing);
+        keep(&mut self.thresholds, &other.thresholds);
+        keep(&mut self.network, &other.network);
+        keep(&mut self.http, &other.http);
+        keep(&mut self.peers, &other.peers);
+    }
+
+    /// Virtual PIDs go ahead of the logger and thresholds, which can name them
+    fn store(self, services: &Services) -> Result<()> {
+        if let Some(config) = self.log_levels {
+            services
+                .log_levels
+                .set_config(config)
+                .context("log_levels")?;
+        }
+        if let Some(config) = self.tasks {
+            services.tasks.set_config(config).context("tasks")?;
+        }
+        #[cfg(feature = "bt")]
+        if let Some(config) = self.discovery {
+            services.discovery.set_config(config).context("discovery")?;
+        }
+        if let Some(config) = self.maintenance {
+            services
+                .maintenance
+                .set_config(config)
+                .context("maintenance")?;
+        }
+        if let Some(config) = self.cache {
+            services.elm_cache.set_config(config).context("cache")?;
+        }
+        if let Some(commands) = self.init_script {
+            services.init_script.set(commands).context("init_script")?;
+        }
+        if let Some(config) = self.timeouts {
+            services.timeouts.set_config(config).context("timeouts")?;
+        }
+        if let Some(config) = self.link_speed {
+            services
+                .link_speed
+                .set_config(config)
+                .context("link_speed")?;
+        }
+        if let Some(config) = self.flow_control {
+            services
+                .flow_control
+                .set_config(config)
+                .context("flow_control")?;
+        }
+        if let Some(config) = self.keep_awake {
+            services
+                .keep_awake
+                .set_config(config)
+                .context("keep_awake")?;
+        }
+        if let Some(config) = self.policy {
+            services.policy.set_config(config).context("policy")?;
+        }
+        if let Some(config) = self.virtual_pids {
+            services
+                .virtual_pids
+                .set_config(config)
+                .context("virtual_pids")?;
+        }
+        if let Some(config) = self.logger {
+            services
+                .logger
+                .set_config(&config, services.virtual_pids)
+                .context("logger")?;
+        }
+        if let Some(config) = self.smoothing {
+            services.smoothing.set_config(config).context("smoothing")?;
+        }
+        if let Some(rules) = self.thresholds {
+            services
+                .thresholds
+                .set_config(rules, services.virtual_pids)
+                .context("thresholds")?;
+        }
+        if let Some(config) = self.network {
+            services
+                .net_settings
+                .set_config(config)
+                .context("network")?;
+        }
+        if let Some(overrides) = self.http {
+            services
+                .http_settings
+                .set_overrides(overrides)
+                .context("http")?;
+        }
+        if let Some(peers) = self.peers {
+            services.peers.set_config(peers).context("peers")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! Line based console on the USB serial port for bench debugging without WiFi or the LCD. Lines
+//! are read on their own thread and run by `handle` in the main loop, replies are printed back.
+//! ELM requests go to the worker without holding up the main loop and print their response when
+//! it comes. It takes physical access, so config changes aren't signed. The command policy, when
+//! there is one, still applies to ELM requests and can't be changed from here.
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use esp_idf_svc::hal::reset;
+use log::*;
+
+use crate::elm327::Bus;
+use crate::elm_cache::CacheConfig;
+use crate::elm_worker::Priority;
+use crate::features::Feature;
+use crate::http::{self, Services};
+use crate::logger::LogConfig;
+use crate::timeouts::TimeoutConfig;
+
+const STACK_SIZE: usize = 4096;
+/// The VFS console doesn't block, so an empty read waits this long before trying again
+const READ_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_LINE_LEN: usize = 512;
+
+const HELP: &str = "\
+elm <request>          send a request to the adapter, e.g. elm 010C
+status                 gateway status
+config <name>          show a config, one of timeouts, cache, features, logger
+config <name> <json>   set a config, the same JSON as PUT /config/<name>
+reboot                 restart the gateway
+help                   this text";
+
+/// Start the reader thread, complete lines are queued for `handle`
+pub fn start() -> Result<Receiver<String>> {
+    let (line_tx, line_rx) = mpsc::sync_channel(4);
+
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut stdin = io::stdin();
+            let mut line = Vec::new();
+            let mut buf = [0u8; 64];
+
+            loop {
+                let len = match stdin.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+                    Err(err) => {
+                        error!("Console read failed {err}");
+                        0
+                    }
+                };
+
+                if len == 0 {
+                    thread::sleep(READ_INTERVAL);
+                    continue;
+                }
+
+                for &b in &buf[..len] {
+                    match b {
+                        b'\r' | b'\n' => {
+                            if line.is_empty() {
+                                continue;
+                            }
+                            let text = String::from_utf8_lossy(&line).trim().to_string();
+                            line.clear();
+
+                            if line_tx.try_send(text).is_err() {
+                                warn!("Console busy, dropping command");
+                            }
+                        }
+                        // Too long to be a command, dropped at the end of the line
+                        _ if line.len() >= MAX_LINE_LEN => {}
+                        _ => line.push(b),
+                    }
+                }
+            }
+        })
+        .context("Failed to start console thread")?;
+
+    Ok(line_rx)
+}
+
+/// Run a console command and print the reply
+pub fn handle(services: &Services, line: &str) {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+    let result = match command {
+        "elm" => elm(services, args.trim()),
+        "status" => serde_json::to_string(&http::status(services)).map_err(anyhow::Error::from),
+        "config" => config(services, args.trim()),
+        "reboot" => {
+            info!("Rebooting on console command");
+            if let Err(err) = services.persist.flush() {
+                error!("Flush before reboot failed {err}");
+            }
+            thread::sleep(Duration::from_millis(100));
+            reset::restart();
+        }
+        "help" => Ok(HELP.to_string()),
+        _ => Err(anyhow!("Unknown command ({command}), try help")),
+    };
+
+    match result {
+        Ok(reply) => println!("{reply}"),
+        Err(err) => println!("ERROR {err}"),
+    }
+}
+
+fn elm(services: &Services, request: &str) -> Result<String> {
+    if request.is_empty() {
+        Err(anyhow!("elm needs a request"))?;
+    }
+
+    // There's no unlock token on the console, so the policy applies unless it's off
+    let guard = (!services.policy.unlocked(None)).then(|| services.policy.clone());
+    let request = request.as_bytes().to_vec();
+
+    // On the worker without waiting, the main loop keeps running while the adapter answers
+    services.elm_worker.spawn(Priority::Normal, move |elm327| {
+        let response = elm327
+            .select_bus(Bus::Hs)
+            .and_then(|_| elm327.guarded(guard, |elm327| elm327.transact(&request)));
+
+        match response {
+            Ok(response) => println!("{response}"),
+            Err(err) => println!("ERROR {err}"),
+        }
+    })?;
+
+    Ok("Queued".to_string())
+}
+
+fn config(services: &Services, args: &str) -> Result<String> {
+    let (name, json) = args.split_once(' ').unwrap_or((args, ""));
+    let json = json.trim();
+
+    if json.is_empty() {
+        return Ok(match name {
+            "timeouts" => serde_json::to_string(&services.timeouts.config())?,
+            "cache" => serde_json::to_string(&services.elm_cache.config())?,
+            "features" => serde_json::to_string(&services.features.states())?,
+            "logger" => serde_json::to_string(&services.logger.config())?,
+            _ => Err(anyhow!("Unknown config ({name})"))?,
+        });
+    }
+
+    match name {
+        "timeouts" => {
+            services
+                .timeouts
+                .set_config(serde_json::from_str::<TimeoutConfig>(json)?)?;
+
+            let config = services.timeouts.config();
+            services.elm_worker.run(Priority::Normal, move |elm327| {
+                elm327.set_timeouts(config);
+                elm327.set_timing(elm327.timing())
+            })?;
+        }
+        "cache" => services
+            .elm_cache
+            .set_config(serde_json::from_str::<CacheConfig>(json)?)?,
+        "features" => {
+            let toggles: BTreeMap<String, bool> = serde_json::from_str(json)?;
+
+            for (name, enabled) in toggles {
+                let feature =
+                    Feature::from_name(&name).ok_or_else(|| anyhow!("Unknown feature ({name})"))?;
+                services.features.set_enabled(feature, enabled)?;
+            }
+        }
+        "logger" => services.logger.set_config(
+            &serde_json::from_str::<LogConfig>(json)?,
+            services.virtual_pids,
+        )?,
+        _ => Err(anyhow!("Unknown config ({name})"))?,
+    }
+
+    Ok("OK".to_string())
+}
@@ -0,0 +1,245 @@
+//! Crash reporter. The panic hook writes the message and a backtrace into RTC RAM, which
+//! survives the restart that follows, and the next boot moves it into NVS with the reset reason
+//! for `GET /last-crash`. Watchdog and brownout resets don't go through the hook and are recorded
+//! from the reset reason alone.
+use std::{
+    fmt::{self, Write as _},
+    mem::MaybeUninit,
+    panic,
+    ptr::addr_of_mut,
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::{
+        esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT,
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP, esp_reset_reason_t_ESP_RST_EXT,
+        esp_reset_reason_t_ESP_RST_INT_WDT, esp_reset_reason_t_ESP_RST_PANIC,
+        esp_reset_reason_t_ESP_RST_POWERON, esp_reset_reason_t_ESP_RST_SW,
+        esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT,
+    },
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NVS_LAST_CRASH;
+use crate::http::uptime_ms;
+
+const MAX_REPORT_LEN: usize = 512;
+/// Marks a record written by the hook since the last boot, anything else is RTC RAM noise
+const RECORD_MAGIC: u32 = 0xC4A5_4ED1;
+const MAX_MESSAGE_LEN: usize = 160;
+const MAX_FRAMES: usize = 12;
+
+/// Written by the panic hook, read and cleared by the next boot
+#[repr(C)]
+struct PanicRecord {
+    magic: u32,
+    uptime_ms: u64,
+    message_len: u32,
+    message: [u8; MAX_MESSAGE_LEN],
+    frame_count: u32,
+    frames: [u32; MAX_FRAMES],
+}
+
+// Not initialised at boot, so a software reset leaves it as the hook wrote it
+#[link_section = ".rtc_noinit"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CrashReport {
+    pub reset_reason: String,
+    /// Rust panic message and location, None for a watchdog or brownout reset
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Program counters, for `xtensa-esp32-elf-addr2line -e <elf>`
+    #[serde(default)]
+    pub backtrace: Vec<String>,
+    #[serde(default)]
+    pub uptime_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CrashStatus {
+    /// Why this boot happened
+    pub reset_reason: &'static str,
+    pub last_crash: Option<CrashReport>,
+}
+
+pub struct CrashLog {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    reset_reason: &'static str,
+    last_crash: Mutex<Option<CrashReport>>,
+}
+
+impl CrashLog {
+    /// Pick up a crash from the last boot, replacing the one stored in NVS
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let reset_reason = reset_reason();
+        info!("Reset reason {reset_reason}");
+
+        let mut buf = vec![0u8; MAX_REPORT_LEN];
+        let mut last_crash: Option<CrashReport> = nvs
+            .get_raw(NVS_LAST_CRASH, &mut buf)?
+            .and_then(|report| serde_json::from_slice(report).ok());
+
+        let record = take_record();
+        if record.is_some() || is_crash(reset_reason) {
+            let (message, backtrace, uptime_ms) = record.unwrap_or_default();
+            let report = CrashReport {
+                reset_reason: reset_reason.to_owned(),
+                message,
+                backtrace,
+                uptime_ms,
+            };
+            warn!("Last boot crashed {report:?}");
+
+            nvs.set_raw(NVS_LAST_CRASH, &serde_json::to_vec(&report)?)?;
+            last_crash = Some(report);
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            reset_reason,
+            last_crash: Mutex::new(last_crash),
+        })
+    }
+
+    pub fn status(&self) -> CrashStatus {
+        CrashStatus {
+            reset_reason: self.reset_reason,
+            last_crash: self.last_crash.lock().unwrap().clone(),
+        }
+    }
+
+    /// Forget the stored crash, e.g. once it's been looked at
+    pub fn clear(&self) -> Result<()> {
+        self.nvs.lock().unwrap().remove(NVS_LAST_CRASH)?;
+        *self.last_crash.lock().unwrap() = None;
+
+        Ok(())
+    }
+}
+
+/// Install the hook that records panics, before anything that can panic. The default hook still
+/// runs after it, so the panic is logged as before.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        // No allocation, the panic may be an allocation failure
+        let record = unsafe { addr_of_mut!(PANIC_RECORD) }.cast::<PanicRecord>();
+        let mut message = MessageBuf {
+            buf: [0; MAX_MESSAGE_LEN],
+            len: 0,
+        };
+        let _ = write!(message, "{info}");
+
+        unsafe {
+            (*record).magic = 0;
+            (*record).uptime_ms = uptime_ms();
+            (*record).message = message.buf;
+            (*record).message_len = message.len as u32;
+            (*record).frame_count = backtrace(&mut (*record).frames) as u32;
+            (*record).magic = RECORD_MAGIC;
+        }
+
+        default_hook(info);
+    }));
+}
+
+/// The panic record left by the last boot, cleared so it's only reported once
+fn take_record() -> Option<(Option<String>, Vec<String>, Option<u64>)> {
+    let record = unsafe { addr_of_mut!(PANIC_RECORD) }.cast::<PanicRecord>();
+
+    unsafe {
+        if (*record).magic != RECORD_MAGIC {
+            return None;
+        }
+        (*record).magic = 0;
+
+        let len = ((*record).message_len as usize).min(MAX_MESSAGE_LEN);
+        let message = String::from_utf8_lossy(&(*record).message[..len]).into_owned();
+
+        let count = ((*record).frame_count as usize).min(MAX_FRAMES);
+        let backtrace = (*record).frames[..count]
+            .iter()
+            .map(|pc| format!("0x{pc:08x}"))
+            .collect();
+
+        Some((Some(message), backtrace, Some((*record).uptime_ms)))
+    }
+}
+
+/// Walk the stack from the hook, returning how many frames were written
+#[cfg(target_arch = "xtensa")]
+fn backtrace(frames: &mut [u32; MAX_FRAMES]) -> usize {
+    use esp_idf_svc::sys::{
+        esp_backtrace_frame_t, esp_backtrace_get_next_frame, esp_backtrace_get_start,
+    };
+
+    let mut count = 0;
+
+    unsafe {
+        let mut frame: esp_backtrace_frame_t = std::mem::zeroed();
+        esp_backtrace_get_start(&mut frame.pc, &mut frame.sp, &mut frame.next_pc);
+
+        while count < MAX_FRAMES {
+            // The top bits hold the register window, and the PC is the return address
+            frames[count] = ((frame.pc & 0x3FFF_FFFF) | 0x4000_0000).wrapping_sub(3);
+            count += 1;
+
+            if frame.next_pc == 0 || !esp_backtrace_get_next_frame(&mut frame) {
+                break;
+            }
+        }
+    }
+
+    count
+}
+
+#[cfg(not(target_arch = "xtensa"))]
+fn backtrace(_frames: &mut [u32; MAX_FRAMES]) -> usize {
+    0
+}
+
+fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
+
+fn is_crash(reset_reason: &str) -> bool {
+    matches!(
+        reset_reason,
+        "panic" | "interrupt_watchdog" | "task_watchdog" | "watchdog" | "brownout"
+    )
+}
+
+/// Fixed size `fmt::Write` target, the rest of a long message is dropped
+struct MessageBuf {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
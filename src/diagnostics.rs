@@ -0,0 +1,81 @@
+//! Diagnostics only mode for a fatal error once WIFI is up. The HTTP server stays reachable with
+//! status, trip logs, firmware updates and restart so recovery doesn't need physical access.
+use std::{thread, time::Duration};
+
+use anyhow::{Context, Result};
+use esp_idf_svc::{hal::reset, http::Method, sys::esp_get_free_heap_size};
+use log::*;
+use serde::Serialize;
+
+use crate::auth::AuthBackend;
+use crate::gateway::GatewayConfig;
+use crate::http::{self, json_response, Router};
+use crate::ota;
+use crate::tls::ServerCert;
+
+#[derive(Serialize)]
+struct DiagnosticsStatus<'a> {
+    mode: &'static str,
+    error: &'a str,
+    uptime_ms: u64,
+    free_heap: u32,
+}
+
+/// Serve the diagnostics endpoints in place of the gateway. Never returns unless the server
+/// can't start.
+pub fn run(
+    err: anyhow::Error,
+    config: &GatewayConfig,
+    auth: &dyn AuthBackend,
+    cert: Option<ServerCert>,
+) -> Result<()> {
+    error!("Fatal error, diagnostics only: {err:?}");
+
+    let reason = format!("{err:#}");
+
+    // The build's limits, an override in NVS may be what failed
+    let (mut server, _) = http::start_server(&config.http, cert)?;
+    let mut router = Router::new(&mut server, auth);
+
+    unsafe {
+        router
+            .handler("/status", Method::Get, |req| {
+                json_response(
+                    req,
+                    &DiagnosticsStatus {
+                        mode: "diagnostics",
+                        error: &reason,
+                        uptime_ms: http::uptime_ms(),
+                        free_heap: esp_get_free_heap_size(),
+                    },
+                )
+            })
+            .context("Register status handler")
+            .and(Ok(()))?
+    }
+
+    http::register_log_handlers(&mut router)?;
+    ota::register_handler(&mut router)?;
+
+    unsafe {
+        router
+            .handler("/restart", Method::Post, |req| {
+                req.into_ok_response()?;
+
+                info!("Restart requested");
+                thread::sleep(Duration::from_millis(100));
+                reset::restart();
+            })
+            .context("Register restart handler")
+            .and(Ok(()))?
+    }
+
+    // An update can still be sent from here, so there's no need to roll back
+    ota::mark_valid();
+
+    info!("Diagnostics server running");
+
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,198 @@
+//! What to do when the OBD adapter can't be reached at startup. Discovery sometimes fails for no
+//! reason and a fresh boot finds it, so the gateway restarts after a backoff, counting the
+//! attempts in NVS so it doesn't restart forever. Past `max_retries` the final action decides:
+//! keep restarting at the last backoff, stay up showing the fault, or deep sleep until the
+//! ignition or the sleep timer wakes it.
+//!
+//! The SPP server channel (SCN) discovery found is kept too, and the next startup connects on it
+//! straight away, only discovering again if that fails.
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    bt::BdAddr,
+    nvs::{EspNvs, NvsDefault},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_ADAPTER_SCN, NVS_DISCOVERY_POLICY, NVS_DISC_FAIL_COUNT};
+
+pub const MAX_CONFIG_LEN: usize = 256;
+const MAX_SCN_LEN: usize = 64;
+const MAX_BACKOFF_STEPS: usize = 8;
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FinalAction {
+    /// Restart at the last backoff for as long as it takes
+    KeepTrying,
+    /// Stay up blinking the self-test fault
+    IdleWithError,
+    DeepSleep,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// Restarts before the final action
+    pub max_retries: u8,
+    /// Wait before each restart, the last one repeats
+    pub backoff_s: Vec<u32>,
+    pub final_action: FinalAction,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff_s: vec![5, 30, 120],
+            final_action: FinalAction::IdleWithError,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    fn check(&self) -> Result<()> {
+        if self.backoff_s.is_empty() || self.backoff_s.len() > MAX_BACKOFF_STEPS {
+            Err(anyhow!("backoff_s needs 1 to {MAX_BACKOFF_STEPS} steps"))?;
+        }
+
+        if self
+            .backoff_s
+            .iter()
+            .any(|s| *s as u64 > MAX_BACKOFF.as_secs())
+        {
+            Err(anyhow!("backoff_s steps over ({}s)", MAX_BACKOFF.as_secs()))?;
+        }
+
+        Ok(())
+    }
+
+    fn backoff(&self, attempt: u8) -> Duration {
+        let step = (attempt as usize).min(self.backoff_s.len() - 1);
+
+        Duration::from_secs(self.backoff_s[step] as u64)
+    }
+}
+
+/// The last known good SCN, for the adapter at `address`
+#[derive(Serialize, Deserialize)]
+struct KnownScn {
+    address: String,
+    scn: u8,
+}
+
+/// The outcome of a failed attempt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiscoveryAction {
+    /// Restart after waiting this long
+    Restart(Duration),
+    IdleWithError,
+    DeepSleep,
+}
+
+pub struct DiscoveryPolicy {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<DiscoveryConfig>,
+}
+
+impl DiscoveryPolicy {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_DISCOVERY_POLICY, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> DiscoveryConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: DiscoveryConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_DISCOVERY_POLICY, &config::encode(&config)?)?;
+
+        info!("Discovery policy updated {config:?}");
+        *self.config.lock().unwrap() = config;
+
+        Ok(())
+    }
+
+    /// Count a failed attempt and pick what to do about it
+    pub fn failed(&self) -> DiscoveryAction {
+        let config = self.config.lock().unwrap();
+        let mut nvs = self.nvs.lock().unwrap();
+
+        let failures = nvs.get_u8(NVS_DISC_FAIL_COUNT).ok().flatten().unwrap_or(0);
+        info!("Discovery failures {failures}");
+
+        if failures < config.max_retries {
+            let _ = nvs.set_u8(NVS_DISC_FAIL_COUNT, failures + 1);
+            return DiscoveryAction::Restart(config.backoff(failures));
+        }
+
+        match config.final_action {
+            FinalAction::KeepTrying => DiscoveryAction::Restart(config.backoff(failures)),
+            FinalAction::IdleWithError => DiscoveryAction::IdleWithError,
+            // Waking is a fresh start, with all the retries again
+            FinalAction::DeepSleep => {
+                let _ = nvs.set_u8(NVS_DISC_FAIL_COUNT, 0);
+                DiscoveryAction::DeepSleep
+            }
+        }
+    }
+
+    /// The adapter was reached, the count starts again
+    pub fn connected(&self) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if nvs.get_u8(NVS_DISC_FAIL_COUNT)?.is_some_and(|n| n > 0) {
+            info!("Resetting discovery fail count");
+            nvs.set_u8(NVS_DISC_FAIL_COUNT, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// The SCN discovery last found for `addr`, None if it was for another adapter
+    pub fn known_scn(&self, addr: &BdAddr) -> Option<u8> {
+        let mut buf = [0u8; MAX_SCN_LEN];
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .get_raw(NVS_ADAPTER_SCN, &mut buf)
+            .ok()
+            .flatten()
+            .and_then(|known| serde_json::from_slice::<KnownScn>(known).ok())
+            .filter(|known| known.address == addr.to_string())
+            .map(|known| known.scn)
+    }
+
+    /// Keep the SCN discovery found, if it's changed
+    pub fn found_scn(&self, addr: &BdAddr, scn: u8) -> Result<()> {
+        if self.known_scn(addr) == Some(scn) {
+            return Ok(());
+        }
+
+        let known = KnownScn {
+            address: addr.to_string(),
+            scn,
+        };
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_ADAPTER_SCN, &serde_json::to_vec(&known)?)?;
+
+        info!("Adapter SCN ({scn}) kept for the next startup");
+
+        Ok(())
+    }
+}
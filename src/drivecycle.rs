@@ -0,0 +1,306 @@
+//! Drive cycle (I/M readiness) progress. The monitor status PIDs are polled across the trip along
+//! with the driving conditions the monitors need, so the gateway can say which monitors are still
+//! incomplete and what they're waiting for.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+
+use crate::elm327::Elm327;
+use crate::pid;
+
+const MONITOR_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const SPEED_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Speeds most non-continuous monitors want for steady cruise
+const CRUISE_KMH: std::ops::RangeInclusive<f32> = 60.0..=110.0;
+
+struct MonitorDef {
+    name: &'static str,
+    hint: &'static str,
+}
+
+const fn monitor(name: &'static str, hint: &'static str) -> Option<MonitorDef> {
+    Some(MonitorDef { name, hint })
+}
+
+/// Byte B bits 0-2 supported, 4-6 incomplete
+const CONTINUOUS: [Option<MonitorDef>; 3] = [
+    monitor("misfire", "runs continuously while driving"),
+    monitor("fuel_system", "needs a few minutes of warm driving"),
+    monitor("components", "runs continuously while driving"),
+];
+
+/// Bytes C supported, D incomplete, for spark ignition engines
+const SPARK: [Option<MonitorDef>; 8] = [
+    monitor(
+        "catalyst",
+        "needs steady cruise for several minutes once warm",
+    ),
+    monitor(
+        "heated_catalyst",
+        "needs steady cruise for several minutes once warm",
+    ),
+    monitor(
+        "evap",
+        "needs a cold start after an overnight soak with 15-85% fuel, then steady cruise",
+    ),
+    monitor("secondary_air", "needs a cold start"),
+    None,
+    monitor(
+        "o2_sensor",
+        "needs steady cruise then a decel to idle once warm",
+    ),
+    monitor(
+        "o2_heater",
+        "needs a cold start and a few minutes of driving",
+    ),
+    monitor("egr_vvt", "needs decelerations from cruise without braking"),
+];
+
+/// Bytes C supported, D incomplete, for compression ignition engines
+const DIESEL: [Option<MonitorDef>; 8] = [
+    monitor("nmhc_catalyst", "needs extended highway driving"),
+    monitor("nox_scr", "needs extended highway driving"),
+    None,
+    monitor("boost_pressure", "needs accelerations under load"),
+    None,
+    monitor("exhaust_gas_sensor", "needs steady cruise once warm"),
+    monitor(
+        "pm_filter",
+        "needs a regeneration, usually extended highway driving",
+    ),
+    monitor("egr_vvt", "needs decelerations from cruise without braking"),
+];
+
+#[derive(Serialize)]
+pub struct MonitorReport {
+    pub name: &'static str,
+    /// Complete since the DTCs were last cleared
+    pub complete: bool,
+    /// Complete this drive cycle, if the ECU reports it (PID 41)
+    pub this_cycle: Option<bool>,
+    /// Was incomplete when the trip started and has completed since
+    pub completed_this_trip: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+/// PID 01 decoded, for `GET /readiness`
+#[derive(Serialize)]
+pub struct Readiness {
+    pub mil: bool,
+    pub dtc_count: u8,
+    /// `spark` or `compression`, which set of non-continuous monitors applies
+    pub engine: &'static str,
+    /// All supported monitors complete
+    pub ready: bool,
+    /// The supported monitors
+    pub monitors: Vec<MonitorReadiness>,
+}
+
+#[derive(Serialize)]
+pub struct MonitorReadiness {
+    pub name: &'static str,
+    pub ready: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct TripConditions {
+    pub duration_s: u64,
+    /// Time spent in steady cruise speeds
+    pub cruise_s: u64,
+    /// Time stopped
+    pub idle_s: u64,
+}
+
+#[derive(Serialize)]
+pub struct DriveCycleReport {
+    /// All supported monitors complete
+    pub ready: bool,
+    pub mil: bool,
+    pub dtc_count: u8,
+    pub monitors: Vec<MonitorReport>,
+    pub trip: TripConditions,
+}
+
+struct State {
+    started: Instant,
+    last_monitor_poll: Option<Instant>,
+    last_speed_poll: Option<Instant>,
+    /// PID 01 data
+    since_clear: Option<Vec<u8>>,
+    /// PID 41 data
+    this_cycle: Option<Vec<u8>>,
+    /// Monitors incomplete at the first poll of the trip
+    incomplete_at_start: Option<Vec<&'static str>>,
+    cruise: Duration,
+    idle: Duration,
+}
+
+/// Tracks the I/M monitors across a trip. Polled from the main loop like the trip logger.
+pub struct DriveCycle {
+    state: Mutex<State>,
+}
+
+impl DriveCycle {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                started: Instant::now(),
+                last_monitor_poll: None,
+                last_speed_poll: None,
+                since_clear: None,
+                this_cycle: None,
+                incomplete_at_start: None,
+                cruise: Duration::ZERO,
+                idle: Duration::ZERO,
+            }),
+        }
+    }
+
+    pub fn poll_due(&self) -> bool {
+        let state = self.state.lock().unwrap();
+
+        [state.last_monitor_poll, state.last_speed_poll]
+            .into_iter()
+            .zip([MONITOR_POLL_INTERVAL, SPEED_POLL_INTERVAL])
+            .any(|(last, interval)| last.is_none_or(|t| t.elapsed() >= interval))
+    }
+
+    /// Read the monitor status and speed if due
+    pub fn poll(&self, elm: &mut Elm327<'_>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state
+            .last_speed_poll
+            .is_none_or(|t| t.elapsed() >= SPEED_POLL_INTERVAL)
+        {
+            state.last_speed_poll = Some(Instant::now());
+
+            let speed = pid::request(elm, pid::SPEED)?;
+            if speed == 0.0 {
+                state.idle += SPEED_POLL_INTERVAL;
+            } else if CRUISE_KMH.contains(&speed) {
+                state.cruise += SPEED_POLL_INTERVAL;
+            }
+        }
+
+        if state
+            .last_monitor_poll
+            .is_none_or(|t| t.elapsed() >= MONITOR_POLL_INTERVAL)
+        {
+            state.last_monitor_poll = Some(Instant::now());
+
+            let since_clear = pid::request_data(elm, pid::MONITOR_STATUS)?;
+
+            // Not every ECU supports the this drive cycle status
+            state.this_cycle = pid::request_data(elm, pid::MONITOR_STATUS_THIS_CYCLE).ok();
+
+            if state.incomplete_at_start.is_none() {
+                let incomplete = monitors(&since_clear)
+                    .into_iter()
+                    .filter(|(_, complete)| !complete)
+                    .map(|(def, _)| def.name)
+                    .collect::<Vec<_>>();
+
+                debug!("Incomplete monitors at trip start {incomplete:?}");
+                state.incomplete_at_start = Some(incomplete);
+            }
+
+            state.since_clear = Some(since_clear);
+        }
+
+        Ok(())
+    }
+
+    /// None until the monitor status has been read
+    pub fn report(&self) -> Option<DriveCycleReport> {
+        let state = self.state.lock().unwrap();
+
+        let since_clear = state.since_clear.as_ref()?;
+        let this_cycle = state.this_cycle.as_deref().map(monitors);
+        let incomplete_at_start = state.incomplete_at_start.as_deref().unwrap_or_default();
+
+        let monitors = monitors(since_clear)
+            .into_iter()
+            .map(|(def, complete)| MonitorReport {
+                name: def.name,
+                complete,
+                this_cycle: this_cycle.as_ref().and_then(|this_cycle| {
+                    this_cycle
+                        .iter()
+                        .find(|(d, _)| d.name == def.name)
+                        .map(|(_, complete)| *complete)
+                }),
+                completed_this_trip: complete && incomplete_at_start.contains(&def.name),
+                hint: (!complete).then_some(def.hint),
+            })
+            .collect::<Vec<_>>();
+
+        Some(DriveCycleReport {
+            ready: monitors.iter().all(|m| m.complete),
+            mil: since_clear.first().is_some_and(|a| a & 0x80 != 0),
+            dtc_count: since_clear.first().map_or(0, |a| a & 0x7F),
+            monitors,
+            trip: TripConditions {
+                duration_s: state.started.elapsed().as_secs(),
+                cruise_s: state.cruise.as_secs(),
+                idle_s: state.idle.as_secs(),
+            },
+        })
+    }
+}
+
+/// Decode PID 01 data, `A B C D`
+pub fn readiness(data: &[u8]) -> Readiness {
+    let monitors = monitors(data)
+        .into_iter()
+        .map(|(def, ready)| MonitorReadiness {
+            name: def.name,
+            ready,
+        })
+        .collect::<Vec<_>>();
+
+    Readiness {
+        mil: data.first().is_some_and(|a| a & 0x80 != 0),
+        dtc_count: data.first().map_or(0, |a| a & 0x7F),
+        engine: match data.get(1) {
+            Some(b) if b & 0x08 != 0 => "compression",
+            _ => "spark",
+        },
+        ready: monitors.iter().all(|m| m.ready),
+        monitors,
+    }
+}
+
+/// Supported monitors and whether each is complete from PID 01/41 data, `A B C D`
+fn monitors(data: &[u8]) -> Vec<(&'static MonitorDef, bool)> {
+    let [_, b, c, d, ..] = *data else {
+        return Vec::new();
+    };
+
+    let mut monitors = Vec::new();
+
+    for (bit, def) in CONTINUOUS.iter().enumerate() {
+        if let Some(def) = def {
+            if b & (1 << bit) != 0 {
+                monitors.push((def, b & (1 << (bit + 4)) == 0));
+            }
+        }
+    }
+
+    let non_continuous = if b & 0x08 == 0 { &SPARK } else { &DIESEL };
+    for (bit, def) in non_continuous.iter().enumerate() {
+        if let Some(def) = def {
+            if c & (1 << bit) != 0 {
+                monitors.push((def, d & (1 << bit) == 0));
+            }
+        }
+    }
+
+    monitors
+}
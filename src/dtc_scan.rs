@@ -0,0 +1,150 @@
+//! `/scan` in the background. `POST /scan` starts it and the main loop takes it a step at a time,
+//! finding the ECUs if there's no list yet and then reading one ECU per pass, so neither the
+//! HTTP server nor the main loop is held for the whole scan. `GET /scan` reads how far it got
+//! and the report once it's done. The main loop's polls are held off while it runs.
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Serialize;
+
+use crate::api::{DtcScan, ScanStep};
+use crate::dtc::ScanReport;
+use crate::ecus::{Ecu, Ecus};
+use crate::elm_worker::ElmWorker;
+use crate::events::{self, ScanProgress};
+use crate::poll_pause::{self, PauseHold};
+
+enum State {
+    Idle,
+    Running {
+        /// Out while the main loop runs a step
+        scan: Option<DtcScan>,
+        done: usize,
+        total: Option<usize>,
+        _hold: PauseHold,
+    },
+    Done(ScanReport),
+    Failed(String),
+}
+
+/// `GET /scan`, the report's fields once it's done
+#[derive(Serialize)]
+pub struct ScanStatus {
+    /// `idle`, `running`, `done` or `failed`
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done: Option<usize>,
+    /// None until the ECUs are known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub report: Option<ScanReport>,
+}
+
+pub struct DtcScanner {
+    state: Mutex<State>,
+}
+
+impl DtcScanner {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::Idle),
+        }
+    }
+
+    /// Start a scan of `ecus`, empty to find them first. One at a time.
+    pub fn start(&self, ecus: Vec<Ecu>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if matches!(*state, State::Running { .. }) {
+            Err(anyhow!("A scan is already running"))?;
+        }
+
+        let scan = DtcScan::new(ecus);
+        let (done, total) = scan.progress();
+        *state = State::Running {
+            scan: Some(scan),
+            done,
+            total,
+            _hold: poll_pause::hold(),
+        };
+
+        info!("DTC scan started");
+
+        Ok(())
+    }
+
+    pub fn running(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Running { .. })
+    }
+
+    /// Run the next step, a worker job, without holding the state so `GET /scan` isn't kept
+    /// waiting. The ECUs found are kept for `?ecu=`, each ECU read is a `scan` event.
+    pub fn poll(&self, worker: &ElmWorker<'_>, ecus: &Ecus) {
+        let Some(mut scan) = (match &mut *self.state.lock().unwrap() {
+            State::Running { scan, .. } => scan.take(),
+            _ => None,
+        }) else {
+            return;
+        };
+
+        let step = scan.step(worker);
+
+        let mut state = self.state.lock().unwrap();
+        match step {
+            Ok(ScanStep::Found(found)) => {
+                if let Err(err) = ecus.set(found) {
+                    error!("Failed to store the ECUs {err}");
+                }
+            }
+            Ok(ScanStep::Read { ecu, done, total }) => {
+                events::publish(events::SCAN, &ScanProgress { ecu, done, total });
+            }
+            Ok(ScanStep::Finished) => {
+                let report = scan.into_report();
+                info!(
+                    "DTC scan done, {} stored, {} pending, {} permanent, {} ECUs failed",
+                    report.stored, report.pending, report.permanent, report.failed
+                );
+                *state = State::Done(report);
+                return;
+            }
+            Err(err) => {
+                error!("DTC scan failed {err:#}");
+                *state = State::Failed(format!("{err:#}"));
+                return;
+            }
+        }
+
+        if let State::Running {
+            scan: running,
+            done,
+            total,
+            ..
+        } = &mut *state
+        {
+            (*done, *total) = scan.progress();
+            *running = Some(scan);
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        let (state, done, total, error, report) = match &*self.state.lock().unwrap() {
+            State::Idle => ("idle", None, None, None, None),
+            State::Running { done, total, .. } => ("running", Some(*done), *total, None, None),
+            State::Done(report) => ("done", None, None, None, Some(report.clone())),
+            State::Failed(err) => ("failed", None, None, Some(err.clone()), None),
+        };
+
+        ScanStatus {
+            state,
+            done,
+            total,
+            error,
+            report,
+        }
+    }
+}
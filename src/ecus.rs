@@ -0,0 +1,66 @@
+//! The ECUs found by `POST /ecus/scan`, kept in NVS so `?ecu=tcm` works from boot without a
+//! scan. See the core's `ecus` for the enumeration and addressing.
+use std::sync::Mutex;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+
+pub use bt_obd_gw_core::ecus::{enumerate, with_ecu, Ecu};
+
+use crate::config::NVS_ECUS;
+
+/// 8 ECUs at most on 11 bit CAN, more on 29 bit are cut
+const MAX_ECUS: usize = 8;
+const MAX_STORED_LEN: usize = 1024;
+
+pub struct Ecus {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    ecus: Mutex<Vec<Ecu>>,
+}
+
+impl Ecus {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let mut buf = vec![0u8; MAX_STORED_LEN];
+
+        let ecus = nvs
+            .get_raw(NVS_ECUS, &mut buf)?
+            .and_then(|ecus| serde_json::from_slice(ecus).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            ecus: Mutex::new(ecus),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Ecu> {
+        self.ecus.lock().unwrap().clone()
+    }
+
+    /// The ECU called `name`, None if the last scan didn't find one
+    pub fn find(&self, name: &str) -> Option<Ecu> {
+        self.ecus
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|ecu| ecu.name.eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// Replace the ECUs with a scan's
+    pub fn set(&self, mut ecus: Vec<Ecu>) -> Result<()> {
+        if ecus.len() > MAX_ECUS {
+            warn!("{} ECUs answered, keeping {MAX_ECUS}", ecus.len());
+            ecus.truncate(MAX_ECUS);
+        }
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_ECUS, &serde_json::to_vec(&ecus)?)?;
+        *self.ecus.lock().unwrap() = ecus;
+
+        Ok(())
+    }
+}
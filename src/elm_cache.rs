@@ -0,0 +1,168 @@
+//! Response cache for `/post` with a TTL per request, so several clients polling the same slow
+//! changing values (coolant temperature, the VIN) don't keep putting them on the CAN bus.
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::coalesce::Coalescer;
+use crate::config::{self, NVS_CACHE_TTLS};
+use crate::elm327::normalise;
+
+/// `no-cache` skips the cached response, the fresh one is still cached
+pub const CACHE_CONTROL_HEADER: &str = "Cache-Control";
+
+pub const MAX_CONFIG_LEN: usize = 512;
+const MAX_TTLS: usize = 32;
+const MAX_ENTRIES: usize = 32;
+/// Commands that point the adapter at another protocol or ECU. The cache is keyed by request
+/// alone, so the responses cached before are for something else afterwards.
+const RETARGET_COMMANDS: [&str; 5] = ["ATSH", "ATSP", "ATTP", "STP", "ATCRA"];
+/// Resets back to the adapter's own protocol and header, matched whole as `ATD` starts `ATDPN`
+const RESET_COMMANDS: [&str; 3] = ["ATZ", "ATWS", "ATD"];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CacheConfig {
+    /// Request to TTL, e.g. {"0105": 2000}. Null caches the response until a reboot or flush,
+    /// requests not listed are never cached.
+    pub ttl_ms: BTreeMap<String, Option<u32>>,
+}
+
+impl Default for CacheConfig {
+    /// Coolant temperature for 2s, the VIN for good
+    fn default() -> Self {
+        Self {
+            ttl_ms: BTreeMap::from([("0105".to_owned(), Some(2000)), ("0902".to_owned(), None)]),
+        }
+    }
+}
+
+struct Entry {
+    response: String,
+    /// None for good
+    expires: Option<Instant>,
+}
+
+pub struct ElmCache {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<CacheConfig>,
+    /// Keyed the same as the coalescer, see `Coalescer::key`
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl ElmCache {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_CACHE_TTLS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+            entries: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Replace the TTLs and drop everything cached under the old ones
+    pub fn set_config(&self, config: CacheConfig) -> Result<()> {
+        if config.ttl_ms.len() > MAX_TTLS {
+            Err(anyhow!("Too many TTLs, max ({MAX_TTLS})"))?;
+        }
+
+        let mut ttl_ms = BTreeMap::new();
+        for (request, ttl) in config.ttl_ms {
+            let key = Coalescer::key(request.as_bytes())
+                .filter(|key| key.chars().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(|| anyhow!("Only OBD requests can be cached ({request})"))?;
+            ttl_ms.insert(key, ttl);
+        }
+        let config = CacheConfig { ttl_ms };
+
+        let value = config::encode(&config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Cache config too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_CACHE_TTLS, &value)?;
+        *self.config.lock().unwrap() = config;
+        self.flush();
+
+        info!("Response cache TTLs updated");
+
+        Ok(())
+    }
+
+    /// The cached response if it hasn't expired
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(entry) if entry.expires.is_none_or(|at| at > Instant::now()) => {
+                debug!("Cached response for ({key})");
+                Some(entry.response.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache the response if the request has a TTL. Once full only expired entries make room.
+    pub fn insert(&self, key: &str, response: &str) {
+        let expires = match self.config.lock().unwrap().ttl_ms.get(key) {
+            Some(Some(0)) | None => return,
+            Some(Some(ttl_ms)) => Some(Instant::now() + Duration::from_millis(*ttl_ms as u64)),
+            Some(None) => None,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires.is_none_or(|at| at > now));
+
+            if entries.len() >= MAX_ENTRIES {
+                debug!("Response cache full, ({key}) not cached");
+                return;
+            }
+        }
+
+        entries.insert(
+            key.to_owned(),
+            Entry {
+                response: response.to_owned(),
+                expires,
+            },
+        );
+    }
+
+    /// Whether the request changes what later requests are answered by, see `RETARGET_COMMANDS`
+    /// and `RESET_COMMANDS`
+    pub fn retargets(request: &[u8]) -> bool {
+        let request = normalise(request);
+
+        RETARGET_COMMANDS
+            .iter()
+            .any(|command| request.starts_with(command))
+            || RESET_COMMANDS.contains(&request.as_str())
+    }
+
+    /// Drop every cached response, returning how many there were
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+
+        count
+    }
+}
@@ -8,21 +8,179 @@ use std::{
 };
 
 use anyhow::Result;
-use esp_idf_svc::hal::gpio::{self, PinDriver};
+use esp_idf_svc::hal::{
+    gpio::{Level, Output, OutputPin, PinDriver, PinState},
+    peripheral::Peripheral,
+    rmt::{config::TransmitConfig, FixedLengthSignal, Pulse, RmtChannel, TxRmtDriver},
+    sys::EspError,
+};
 use log::error;
 use thiserror::Error;
 
+pub use bt_obd_gw_core::error::{
+    ElmError, ErrorBody, ErrorCode, ReadObdError, UdsError, WorkerError,
+};
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Authorization required")]
+    Missing,
+
+    #[error("Invalid token")]
+    Invalid,
+
+    #[error("Challenge expired or already used")]
+    Expired,
+}
+
 #[derive(Error, Debug)]
-pub enum ReadObdError {
-    #[error("Device IO Error")]
-    IOError(#[from] std::io::Error),
+pub enum SignatureError {
+    #[error("Upload is not signed")]
+    Missing,
+
+    #[error("Upload signature is invalid")]
+    Invalid,
 }
 
 pub enum LedBlink {
     Error(u8),
+    /// Self-test failure, the stage as long blinks then the cause as short ones, forever
+    Fault(u8, u8),
     Times(u8),
     High,
     Low,
+    /// Cycle through the colors once, then back to the current state
+    Test,
+    /// A threshold alert triggered, quick amber flashes
+    Alert,
+}
+
+impl LedBlink {
+    /// The state color shown by an RGB status LED, a plain LED ignores the color.
+    ///
+    /// Blink counts still apply so both backends report the same startup stage.
+    fn color(&self) -> Rgb {
+        match self {
+            LedBlink::Error(_) | LedBlink::Fault(..) => Rgb::RED,
+            LedBlink::Times(1) => Rgb::BLUE,  // BT connecting
+            LedBlink::Times(4) => Rgb::RED,   // BT discovery failed
+            LedBlink::Times(_) => Rgb::GREEN, // ELM ready / WIFI connected
+            LedBlink::High => Rgb::WHITE,     // Request active
+            LedBlink::Low => Rgb::OFF,
+            LedBlink::Test => Rgb::WHITE,
+            LedBlink::Alert => Rgb::AMBER,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+    pub const RED: Rgb = Rgb::new(64, 0, 0);
+    pub const GREEN: Rgb = Rgb::new(0, 64, 0);
+    pub const BLUE: Rgb = Rgb::new(0, 0, 64);
+    pub const WHITE: Rgb = Rgb::new(64, 64, 64);
+    pub const AMBER: Rgb = Rgb::new(64, 32, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    /// Same color at a fraction of the brightness, used as the resting state between blinks
+    const fn dim(self) -> Self {
+        Rgb::new(self.r / 8, self.g / 8, self.b / 8)
+    }
+}
+
+/// A status LED backend driven by the LED blink thread
+pub trait StatusLed: Send {
+    /// Turn the LED on with the color, or off for `Rgb::OFF`
+    fn set(&mut self, color: Rgb);
+
+    /// State to hold once a blink sequence has finished. A single color LED just stays off.
+    fn rest(&mut self, _color: Rgb) {
+        self.set(Rgb::OFF);
+    }
+}
+
+/// Plain single color LED, e.g. the devkit blue LED on GPIO2
+impl<P: OutputPin> StatusLed for PinDriver<'static, P, Output> {
+    fn set(&mut self, color: Rgb) {
+        let _ = self.set_level(if color == Rgb::OFF {
+            Level::Low
+        } else {
+            Level::High
+        });
+    }
+}
+
+/// WS2812 (NeoPixel) RGB LED driven by the RMT peripheral
+pub struct Ws2812Led {
+    tx: TxRmtDriver<'static>,
+}
+
+impl Ws2812Led {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+    ) -> Result<Self> {
+        let config = TransmitConfig::new().clock_divider(1);
+        let tx = TxRmtDriver::new(channel, pin, &config)?;
+
+        let mut led = Ws2812Led { tx };
+        led.write(Rgb::OFF)?;
+
+        Ok(led)
+    }
+
+    fn write(&mut self, color: Rgb) -> Result<(), EspError> {
+        // WS2812 wants GRB, MSB first
+        let grb = ((color.g as u32) << 16) | ((color.r as u32) << 8) | color.b as u32;
+
+        let ticks_hz = self.tx.counter_clock()?;
+        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(350))?;
+        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(800))?;
+        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(700))?;
+        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(600))?;
+
+        let mut signal = FixedLengthSignal::<24>::new();
+        for i in 0..24 {
+            let bit = grb & (1 << (23 - i)) != 0;
+            let pulses = if bit { (t1h, t1l) } else { (t0h, t0l) };
+            signal.set(i, &pulses)?;
+        }
+
+        self.tx.start_blocking(&signal)
+    }
+}
+
+impl StatusLed for Ws2812Led {
+    fn set(&mut self, color: Rgb) {
+        if let Err(err) = self.write(color) {
+            error!("WS2812 write failed {err}");
+        }
+    }
+
+    fn rest(&mut self, color: Rgb) {
+        self.set(color.dim());
+    }
+}
+
+/// Any backend, for when the LED is picked at runtime
+impl StatusLed for Box<dyn StatusLed> {
+    fn set(&mut self, color: Rgb) {
+        (**self).set(color);
+    }
+
+    fn rest(&mut self, color: Rgb) {
+        (**self).rest(color);
+    }
 }
 
 static ERROR_IND_SENDER: OnceLock<SyncSender<LedBlink>> = OnceLock::new();
@@ -42,41 +200,84 @@ impl<T, E> ErrorInd<T, E> for std::result::Result<T, E> {
     }
 }
 
-pub fn start_led_blink(
-    mut led: PinDriver<'static, gpio::Gpio2, gpio::Output>,
-) -> SyncSender<LedBlink> {
+/// Blink a self-test failure, see `LedBlink::Fault`. Doesn't wait if the LED is already busy.
+pub fn fault_ind(stage: u8, cause: u8) {
+    if let Some(sender) = ERROR_IND_SENDER.get() {
+        let _ = sender.try_send(LedBlink::Fault(stage, cause));
+    }
+}
+
+pub fn start_led_blink<L: StatusLed + 'static>(mut led: L) -> SyncSender<LedBlink> {
     let (led_blink_tx, led_blink_rx) = mpsc::sync_channel(1);
 
     let _ = ERROR_IND_SENDER.set(led_blink_tx.clone());
 
-    thread::spawn(move || loop {
-        let mut forever = false;
-        let mut count = 0;
+    thread::spawn(move || {
+        // The last startup stage color, restored once a request or blink sequence is done
+        let mut state = Rgb::OFF;
 
-        if let Ok(blink) = led_blink_rx.recv() {
-            match blink {
-                LedBlink::Error(n) => {
-                    count = n;
-                    forever = true;
+        loop {
+            let mut forever = false;
+            let mut count = 0;
+            let mut color = state;
+
+            if let Ok(blink) = led_blink_rx.recv() {
+                color = blink.color();
+                match blink {
+                    LedBlink::Error(n) => {
+                        count = n;
+                        forever = true;
+                    }
+                    LedBlink::Fault(stage, cause) => loop {
+                        for (n, on) in [(stage, 800), (cause, 200)] {
+                            for _ in 0..n {
+                                led.set(color);
+                                thread::sleep(Duration::from_millis(on));
+                                led.set(Rgb::OFF);
+                                thread::sleep(Duration::from_millis(300));
+                            }
+                            thread::sleep(Duration::from_millis(1000));
+                        }
+                        thread::sleep(Duration::from_millis(1000));
+                    },
+                    LedBlink::Times(n) => count = n,
+                    LedBlink::High => led.set(color),
+                    LedBlink::Low => led.rest(state),
+                    LedBlink::Test => {
+                        for color in [Rgb::RED, Rgb::GREEN, Rgb::BLUE, Rgb::WHITE] {
+                            led.set(color);
+                            thread::sleep(Duration::from_millis(500));
+                        }
+                        led.rest(state);
+                    }
+                    LedBlink::Alert => {
+                        for _ in 0..5 {
+                            led.set(color);
+                            thread::sleep(Duration::from_millis(100));
+                            led.set(Rgb::OFF);
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                        led.rest(state);
+                    }
                 }
-                LedBlink::Times(n) => count = n,
-                LedBlink::High => led.set_high().unwrap_or_default(),
-                LedBlink::Low => led.set_low().unwrap_or_default(),
             }
-        }
 
-        if count > 0 {
-            loop {
-                for _ in 0..count {
-                    let _ = led.set_high();
-                    thread::sleep(Duration::from_millis(500));
-                    let _ = led.set_low();
-                    thread::sleep(Duration::from_millis(250));
+            if count > 0 {
+                loop {
+                    for _ in 0..count {
+                        led.set(color);
+                        thread::sleep(Duration::from_millis(500));
+                        led.set(Rgb::OFF);
+                        thread::sleep(Duration::from_millis(250));
+                    }
+                    if !forever {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(2000));
                 }
-                if !forever {
-                    break;
-                }
-                thread::sleep(Duration::from_millis(2000));
+
+                state = color;
+                led.rest(state);
             }
         }
     });
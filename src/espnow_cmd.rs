@@ -0,0 +1,151 @@
+//! Lightweight ESPNOW commands (ping, status, reboot, LED test) for when the LCD can't make an HTTP
+//! call. ELM traffic stays on HTTP.
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use esp_idf_svc::{
+    espnow::{EspNow, PeerInfo, SendStatus},
+    hal::reset,
+    sys::esp_get_free_heap_size,
+};
+use log::*;
+
+use crate::announce;
+use crate::error::LedBlink;
+use crate::network::{WifiState, WifiSupervisor};
+use crate::peers;
+use crate::persist::Persist;
+use bt_obd_gw_protocol::{Command, MacAddr, Status, StatusReply};
+
+/// Frames sent, a broadcast always counts as delivered
+static SENDS: AtomicU32 = AtomicU32::new(0);
+/// Unicast frames the peer never acked, after the MAC's retries
+static SEND_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Sends and failures since boot, see `coex`
+pub fn send_stats() -> (u32, u32) {
+    (
+        SENDS.load(Ordering::Relaxed),
+        SEND_FAILURES.load(Ordering::Relaxed),
+    )
+}
+
+pub struct CommandRequest {
+    src: MacAddr,
+    command: Command,
+    token: u8,
+}
+
+/// Register the receive callback, announce acks are passed on and commands are queued for `handle` to run outside the WIFI task
+/// (senders are passed to `peers` for pairing), and the send callback that counts failures
+pub fn start(espnow: &EspNow) -> Result<Receiver<CommandRequest>> {
+    let (cmd_tx, cmd_rx) = mpsc::sync_channel(4);
+
+    espnow
+        .register_recv_cb(move |info, data| {
+            peers::heard(info.src_addr);
+
+            if let Some(ip) = bt_obd_gw_protocol::parse_announce_ack(data) {
+                announce::acked(ip);
+                return;
+            }
+
+            let Some((command, token)) = Command::parse(data) else {
+                return;
+            };
+
+            let request = CommandRequest {
+                src: info.src_addr.to_owned(),
+                command,
+                token,
+            };
+
+            if cmd_tx.try_send(request).is_err() {
+                warn!("Command queue full, dropping {command:?}");
+            }
+        })
+        .context("Failed to register ESPNOW recv callback")?;
+
+    espnow
+        .register_send_cb(|_, status| {
+            SENDS.fetch_add(1, Ordering::Relaxed);
+            if let SendStatus::FAIL = status {
+                SEND_FAILURES.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .context("Failed to register ESPNOW send callback")?;
+
+    Ok(cmd_rx)
+}
+
+/// Run a command and reply to the sender
+pub fn handle(
+    espnow: &EspNow,
+    espnow_channel: u8,
+    led_blink: &SyncSender<LedBlink>,
+    persist: &Persist,
+    wifi: &WifiSupervisor,
+    started: Instant,
+    request: CommandRequest,
+) -> Result<()> {
+    let CommandRequest {
+        src,
+        command,
+        token,
+    } = request;
+
+    info!("ESPNOW command {command:?} from {src:02X?}");
+
+    let status;
+    let payload: &[u8] = match command {
+        Command::Ping | Command::Reboot => &[],
+        Command::Status => {
+            status = StatusReply {
+                status: match wifi.status().state {
+                    WifiState::Connected => Status::WifiConnected,
+                    WifiState::Reconnecting => Status::WifiReconnecting,
+                },
+                uptime_s: started.elapsed().as_secs() as u32,
+                free_heap: unsafe { esp_get_free_heap_size() },
+            }
+            .encode();
+            &status
+        }
+        Command::LedTest => {
+            let _ = led_blink.try_send(LedBlink::Test);
+            &[]
+        }
+    };
+
+    if !espnow.peer_exists(src)? {
+        espnow.add_peer(PeerInfo {
+            peer_addr: src,
+            channel: espnow_channel,
+            ifidx: esp_idf_svc::sys::wifi_interface_t_WIFI_IF_STA,
+            encrypt: false,
+            ..Default::default()
+        })?;
+    }
+
+    if let Some(reply) = command.reply(token, payload) {
+        espnow.send(src, &reply)?;
+    }
+
+    if command == Command::Reboot {
+        info!("Rebooting on ESPNOW command");
+        if let Err(err) = persist.flush() {
+            error!("Flush before reboot failed {err}");
+        }
+        thread::sleep(Duration::from_millis(100));
+        reset::restart();
+    }
+
+    Ok(())
+}
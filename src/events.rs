@@ -0,0 +1,160 @@
+//! Server-sent events for `GET /events`, for clients whose HTTP library can't do WebSockets. The
+//! logged PID values, adapter link changes and DTC scan progress are kept in a short ring with
+//! increasing ids, so a client that reconnects with `Last-Event-ID` gets what it missed while it
+//! was away.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+/// Events kept for clients to catch up on, about 30s of samples at the default log interval
+const MAX_EVENTS: usize = 32;
+/// A stream with nothing to send for this long gets a comment line, which keeps proxies from
+/// closing it and finds clients that have gone
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Milliseconds the client waits before reconnecting after the stream drops, the events in
+/// between wait in the ring
+pub const RETRY_MS: u32 = 2000;
+
+/// A logger sample, `PidValues`
+pub const PIDS: &str = "pids";
+/// An adapter link opened or closed, `AdapterLink`
+pub const ADAPTER: &str = "adapter";
+/// An ECU read by a `POST /scan`, `ScanProgress`
+pub const SCAN: &str = "scan";
+
+#[derive(Serialize)]
+pub struct PidValues {
+    pub timestamp_ms: u64,
+    pub time: Option<String>,
+    /// Keyed as `/snapshot`, hex PID or virtual PID name
+    pub values: BTreeMap<String, f32>,
+}
+
+#[derive(Serialize)]
+pub struct AdapterLink {
+    pub address: String,
+    pub connected: bool,
+}
+
+#[derive(Serialize)]
+pub struct ScanProgress {
+    /// The ECU just read
+    pub ecu: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Clone)]
+pub struct Event {
+    pub id: u32,
+    pub name: &'static str,
+    /// JSON
+    pub data: String,
+}
+
+impl Event {
+    /// As sent on the stream
+    pub fn to_sse(&self) -> String {
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.name, self.data
+        )
+    }
+
+    /// The value of a PID in a `pids` event, keyed as `PidValues`
+    pub fn pid_value(&self, key: &str) -> Option<f32> {
+        if self.name != PIDS {
+            return None;
+        }
+
+        let values: SampledValues = serde_json::from_str(&self.data).ok()?;
+        values.values.get(key).copied()
+    }
+}
+
+/// The part of `PidValues` read back
+#[derive(Deserialize)]
+struct SampledValues {
+    values: BTreeMap<String, f32>,
+}
+
+struct EventLog {
+    events: VecDeque<Event>,
+    next_id: u32,
+}
+
+static EVENTS: Mutex<EventLog> = Mutex::new(EventLog {
+    events: VecDeque::new(),
+    next_id: 1,
+});
+static PUBLISHED: Condvar = Condvar::new();
+
+/// Add an event and wake the streams and `/wait`s waiting for one
+pub fn publish<T: Serialize>(name: &'static str, data: &T) {
+    let data = match serde_json::to_string(data) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Event {name} not sent {err}");
+            return;
+        }
+    };
+
+    let mut log = EVENTS.lock().unwrap();
+
+    let id = log.next_id;
+    log.next_id = log.next_id.wrapping_add(1).max(1);
+    if log.events.len() == MAX_EVENTS {
+        log.events.pop_front();
+    }
+    log.events.push_back(Event { id, name, data });
+
+    PUBLISHED.notify_all();
+}
+
+/// Id of the newest event, 0 before any
+pub fn last_id() -> u32 {
+    EVENTS.lock().unwrap().next_id.wrapping_sub(1)
+}
+
+/// The newest value of a PID still in the ring, see `Event::pid_value`
+pub fn latest_value(key: &str) -> Option<f32> {
+    EVENTS
+        .lock()
+        .unwrap()
+        .events
+        .iter()
+        .rev()
+        .find_map(|event| event.pid_value(key))
+}
+
+/// The events after `last_id`, waiting until `deadline` for one if there are none yet. An id the
+/// ring doesn't know, e.g. from before a restart, gets all of them.
+pub fn after(last_id: u32, deadline: Instant) -> Vec<Event> {
+    let mut log = EVENTS.lock().unwrap();
+
+    loop {
+        let known = log.events.iter().any(|event| event.id == last_id);
+        let events: Vec<Event> = if known {
+            log.events
+                .iter()
+                .skip_while(|event| event.id != last_id)
+                .skip(1)
+                .cloned()
+                .collect()
+        } else {
+            log.events.iter().cloned().collect()
+        };
+
+        let now = Instant::now();
+        if !events.is_empty() || now >= deadline {
+            return events;
+        }
+
+        log = PUBLISHED.wait_timeout(log, deadline - now).unwrap().0;
+    }
+}
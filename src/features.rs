@@ -0,0 +1,175 @@
+//! Runtime feature toggles. Each feature is a `Subsystem` registered at startup, `poller` (the
+//! main loop's adapter polls), `logger` and `capture` (session recording). MQTT and TCP emulation
+//! aren't part of this firmware, they're listed as unavailable.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::Serialize;
+
+use crate::session::{Session, SessionMode};
+
+/// Major subsystems that can be switched on and off at runtime
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Feature {
+    Poller,
+    Mqtt,
+    TcpEmulation,
+    Capture,
+    Logger,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 5] = [
+        Feature::Poller,
+        Feature::Mqtt,
+        Feature::TcpEmulation,
+        Feature::Capture,
+        Feature::Logger,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Poller => "poller",
+            Feature::Mqtt => "mqtt",
+            Feature::TcpEmulation => "tcp_emulation",
+            Feature::Capture => "capture",
+            Feature::Logger => "logger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    /// Capture records every exchange to storage, so it's only on when asked for
+    fn default_enabled(&self) -> bool {
+        *self != Feature::Capture
+    }
+
+    fn nvs_key(&self) -> &'static str {
+        match self {
+            Feature::Poller => "ft_poller",
+            Feature::Mqtt => "ft_mqtt",
+            Feature::TcpEmulation => "ft_tcp_emu",
+            Feature::Capture => "ft_capture",
+            Feature::Logger => "ft_logger",
+        }
+    }
+}
+
+/// A subsystem that can be started and stopped without a reboot. `stop` should end any task and
+/// drop its buffers so the memory is given back.
+pub trait Subsystem: Send {
+    fn start(&mut self) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+}
+
+#[derive(Serialize)]
+pub struct FeatureState {
+    pub enabled: bool,
+    /// Subsystem is built in and registered
+    pub available: bool,
+}
+
+/// Runtime feature toggles, persisted in NVS so they survive a reboot
+pub struct Features<'a> {
+    nvs: EspNvs<NvsDefault>,
+    subsystems: Mutex<BTreeMap<Feature, Box<dyn Subsystem + 'a>>>,
+}
+
+impl<'a> Features<'a> {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Self {
+        Self {
+            nvs,
+            subsystems: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Features default to enabled until switched off, except capture
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.nvs
+            .get_u8(feature.nvs_key())
+            .unwrap_or(None)
+            .map_or(feature.default_enabled(), |n| n > 0)
+    }
+
+    /// Built in and registered, so it can be toggled
+    pub fn available(&self, feature: Feature) -> bool {
+        self.subsystems.lock().unwrap().contains_key(&feature)
+    }
+
+    /// Add a subsystem, starting it if the feature is enabled
+    pub fn register(&self, feature: Feature, mut subsystem: Box<dyn Subsystem + 'a>) -> Result<()> {
+        if self.is_enabled(feature) {
+            info!("Starting {}", feature.name());
+            subsystem.start()?;
+        } else {
+            info!("{} disabled, not starting", feature.name());
+        }
+
+        self.subsystems.lock().unwrap().insert(feature, subsystem);
+
+        Ok(())
+    }
+
+    /// Enable or disable a feature, starting or stopping its subsystem
+    pub fn set_enabled(&self, feature: Feature, enabled: bool) -> Result<()> {
+        let mut subsystems = self.subsystems.lock().unwrap();
+
+        let subsystem = subsystems
+            .get_mut(&feature)
+            .ok_or_else(|| anyhow!("Feature ({}) not available", feature.name()))?;
+
+        if self.is_enabled(feature) != enabled {
+            if enabled {
+                info!("Enabling {}", feature.name());
+                subsystem.start()?;
+            } else {
+                info!("Disabling {}", feature.name());
+                subsystem.stop()?;
+            }
+
+            self.nvs.set_u8(feature.nvs_key(), enabled as u8)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn states(&self) -> BTreeMap<&'static str, FeatureState> {
+        let subsystems = self.subsystems.lock().unwrap();
+
+        Feature::ALL
+            .into_iter()
+            .map(|f| {
+                (
+                    f.name(),
+                    FeatureState {
+                        enabled: self.is_enabled(f),
+                        available: subsystems.contains_key(&f),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Toggled by `Feature::Capture`, a new recording while it's on. Replay started from
+/// `/debug/replay` is left alone when it's switched off.
+impl Subsystem for Arc<Session> {
+    fn start(&mut self) -> Result<()> {
+        self.set_mode(SessionMode::Record)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.mode() == SessionMode::Record {
+            self.set_mode(SessionMode::Off)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+//! Multi-frame flow control for long responses, set from `/config/flow-control` and sent by every
+//! setup after the init script. A single UDS request can use its own with `X-Flow-Control`, see
+//! `Elm327::with_flow_control`.
+use std::sync::Mutex;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+
+pub use bt_obd_gw_core::flow_control::{from_header, FlowControlConfig, FLOW_CONTROL_HEADER};
+
+use crate::config::{self, NVS_FLOW_CONTROL};
+
+pub const MAX_CONFIG_LEN: usize = 64;
+/// `PUT /config/flow-control`, the JSON is longer than what's stored
+pub const MAX_REQUEST_LEN: usize = 128;
+
+pub struct FlowControl {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<FlowControlConfig>,
+}
+
+impl FlowControl {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_FLOW_CONTROL, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> FlowControlConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Store the flow control, sent by `Elm327::setup`. Only once the adapter has been set up
+    /// with it, see `http::apply_flow_control`.
+    pub fn set_config(&self, config: FlowControlConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_FLOW_CONTROL, &config::encode(&config)?)?;
+
+        info!("Flow control updated {config:?}");
+        *self.config.lock().unwrap() = config;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Fuel economy worked out on the gateway, so the LCD doesn't need speed and fuel flow samples
+//! taken together. Fuel flow comes from the fuel rate PID (5E) where the vehicle has it, else from
+//! the MAF (PID 10) assuming petrol at stoichiometric. The trip totals are handed to `persist` after
+//! every sample and written with its flushes.
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::config::NVS_FUEL_TRIP;
+use crate::elm327::Elm327;
+use crate::persist::Persist;
+use crate::pid;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+/// A longer gap between samples, e.g. the adapter was busy or asleep, counts as this much
+const MAX_SAMPLE_GAP: Duration = Duration::from_secs(6);
+
+const MAF: u8 = 0x10;
+const FUEL_RATE: u8 = 0x5E;
+/// Petrol, grams of air per gram of fuel and grams per litre
+const STOICHIOMETRIC_AFR: f32 = 14.7;
+const FUEL_DENSITY_G_PER_L: f32 = 740.0;
+/// Below this the L/100km figure means nothing, only L/h is given
+const MIN_SPEED_KMH: f32 = 3.0;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FuelSource {
+    FuelRate,
+    Maf,
+}
+
+/// Totals since the last `POST /trip/reset`
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+struct TripTotals {
+    distance_km: f64,
+    fuel_l: f64,
+    duration_s: f64,
+    /// When the trip was reset, None if the clock wasn't set then
+    started: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct InstantEconomy {
+    pub speed_kmh: f32,
+    pub l_per_h: f32,
+    /// None when stopped or nearly
+    pub l_per_100km: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct TripReport {
+    pub distance_km: f64,
+    pub fuel_l: f64,
+    pub duration_s: u64,
+    pub started: Option<String>,
+    /// None until the trip has covered some distance
+    pub avg_l_per_100km: Option<f64>,
+    pub avg_km_per_l: Option<f64>,
+    /// The last sample, None before the first
+    pub instant: Option<InstantEconomy>,
+    pub source: Option<FuelSource>,
+}
+
+struct State {
+    totals: TripTotals,
+    last_sample: Option<Instant>,
+    instant: Option<InstantEconomy>,
+    source: Option<FuelSource>,
+}
+
+/// Samples speed and fuel flow from the main loop and integrates them into the trip totals
+pub struct FuelEconomy {
+    persist: Arc<Persist>,
+    state: Mutex<State>,
+}
+
+impl FuelEconomy {
+    pub fn new(persist: Arc<Persist>) -> Result<Self> {
+        let totals: TripTotals = persist
+            .load(NVS_FUEL_TRIP)?
+            .and_then(|totals| serde_json::from_slice(&totals).ok())
+            .unwrap_or_default();
+
+        debug!("Fuel trip {totals:?}");
+
+        Ok(Self {
+            persist,
+            state: Mutex::new(State {
+                totals,
+                last_sample: None,
+                instant: None,
+                source: None,
+            }),
+        })
+    }
+
+    pub fn poll_due(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .last_sample
+            .is_none_or(|t| t.elapsed() >= SAMPLE_INTERVAL)
+    }
+
+    /// Take a sample and add it to the trip
+    pub fn poll(&self, elm: &mut Elm327<'_>) -> Result<()> {
+        // The fuel rate is measured, the MAF estimate assumes petrol
+        let source = match pid::supported() {
+            Some(pids) if pids.contains(&FUEL_RATE) => FuelSource::FuelRate,
+            _ => FuelSource::Maf,
+        };
+        let flow_pid = match source {
+            FuelSource::FuelRate => FUEL_RATE,
+            FuelSource::Maf => MAF,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = state
+            .last_sample
+            .map(|t| now.duration_since(t).min(MAX_SAMPLE_GAP));
+        state.last_sample = Some(now);
+
+        let values = pid::request_many(elm, &[pid::SPEED, flow_pid])?;
+        let (Some(&speed_kmh), Some(&flow)) = (values.get(&pid::SPEED), values.get(&flow_pid))
+        else {
+            debug!("No speed or fuel flow, sample skipped");
+            return Ok(());
+        };
+
+        let l_per_h = match source {
+            FuelSource::FuelRate => flow,
+            FuelSource::Maf => flow * 3600.0 / STOICHIOMETRIC_AFR / FUEL_DENSITY_G_PER_L,
+        };
+
+        state.source = Some(source);
+        state.instant = Some(InstantEconomy {
+            speed_kmh,
+            l_per_h,
+            l_per_100km: (speed_kmh >= MIN_SPEED_KMH).then(|| l_per_h / speed_kmh * 100.0),
+        });
+
+        // The first sample of a boot only sets the rates
+        if let Some(elapsed) = elapsed {
+            let hours = elapsed.as_secs_f64() / 3600.0;
+            let totals = &mut state.totals;
+
+            totals.distance_km += speed_kmh as f64 * hours;
+            totals.fuel_l += l_per_h as f64 * hours;
+            totals.duration_s += elapsed.as_secs_f64();
+
+            self.store(totals)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn report(&self) -> TripReport {
+        let state = self.state.lock().unwrap();
+        let totals = &state.totals;
+
+        let driven = totals.distance_km >= 0.1 && totals.fuel_l > 0.0;
+
+        TripReport {
+            distance_km: totals.distance_km,
+            fuel_l: totals.fuel_l,
+            duration_s: totals.duration_s as u64,
+            started: totals.started.clone(),
+            avg_l_per_100km: driven.then(|| totals.fuel_l / totals.distance_km * 100.0),
+            avg_km_per_l: driven.then(|| totals.distance_km / totals.fuel_l),
+            instant: state.instant,
+            source: state.source,
+        }
+    }
+
+    /// Start a new trip
+    pub fn reset(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.totals = TripTotals {
+            started: clock::now(),
+            ..Default::default()
+        };
+        info!("Fuel trip reset");
+
+        self.store(&state.totals)
+    }
+
+    fn store(&self, totals: &TripTotals) -> Result<()> {
+        self.persist
+            .store(NVS_FUEL_TRIP, serde_json::to_vec(totals)?)
+    }
+}
@@ -0,0 +1,1326 @@
+//! Gateway startup. The hardware and board settings are injected through `Gateway::builder()` so
+//! another board only needs a different modem, LED or config rather than a fork of the startup.
+use std::{
+    convert::Infallible,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "wifi-adapter")]
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+#[cfg(feature = "uart")]
+use esp_idf_svc::hal::uart::UartDriver;
+#[cfg(feature = "bt")]
+use esp_idf_svc::{
+    bt::spp::{self, EspSpp, SppConfig},
+    bt::{
+        gap::{DiscoveryMode, EspGap},
+        reduce_bt_memory, BdAddr, BtClassic, BtDriver,
+    },
+    sys::{
+        esp, esp_bt_gap_set_security_param, esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
+        ESP_BT_IO_CAP_NONE,
+    },
+};
+use esp_idf_svc::{
+    espnow::{EspNow, PeerInfo, BROADCAST},
+    eventloop::EspSystemEventLoop,
+    hal::{
+        gpio::{AnyInputPin, PinDriver},
+        modem::Modem,
+        peripheral::Peripheral,
+        reset,
+    },
+    nvs::{EspDefaultNvsPartition, EspNvs},
+    wifi::{self, AuthMethod, BlockingWifi, EspWifi},
+};
+use log::*;
+
+use crate::alerts::Alerts;
+use crate::announce::Announcer;
+use crate::auth::{self, HttpAuth};
+#[cfg(feature = "bt")]
+use crate::bt;
+use crate::clock;
+use crate::coalesce::Coalescer;
+use crate::console;
+use crate::crash::CrashLog;
+use crate::diagnostics;
+#[cfg(feature = "bt")]
+use crate::discovery::{DiscoveryAction, DiscoveryPolicy};
+use crate::drivecycle::DriveCycle;
+use crate::dtc_scan::DtcScanner;
+use crate::ecus::Ecus;
+use crate::elm327::{Elm327, TimingProfile};
+use crate::elm_cache::ElmCache;
+use crate::elm_worker::ElmWorker;
+use crate::error::{start_led_blink, ErrorInd, LedBlink, StatusLed};
+use crate::espnow_cmd;
+use crate::features::{Feature, Features};
+use crate::flow_control::FlowControl;
+use crate::fuel::FuelEconomy;
+use crate::http::{self, Services};
+use crate::http_limits::{HttpLimits, HttpSettings};
+use crate::idle::IdleManager;
+use crate::ignition::{self, Ignition, IgnitionChange};
+use crate::init_script::InitScript;
+use crate::keep_awake::KeepAwake;
+use crate::lifetime::LifetimeStats;
+use crate::link_speed::LinkSpeed;
+use crate::log_level::LogLevels;
+use crate::logger::{self, Logger, TripEnd};
+use crate::maintenance::{DueState, Maintenance};
+use crate::memory::{MemoryMonitor, Pressure};
+#[cfg(feature = "mock-elm")]
+use crate::mock_elm::{MockConfig, MockElm};
+use crate::network::{self, NetConfig, NetEvent, NetSettings, NetWatch, WifiSupervisor};
+use crate::ota;
+use crate::peers::Peers;
+use crate::persist::Persist;
+use crate::policy::Policy;
+use crate::poll_pause;
+use crate::power::{self, SupplySense};
+use crate::selftest::{SelfTest, Stage};
+use crate::session::Session;
+use crate::signing::Signing;
+use crate::smoothing::Smoothing;
+#[cfg(feature = "bt")]
+use crate::spp_handler::{self, SppHandler};
+use crate::tasks::{self, Tasks};
+#[cfg(feature = "wifi-adapter")]
+use crate::tcp_handler::TcpHandler;
+use crate::thresholds::Thresholds;
+use crate::timeouts::ElmTimeouts;
+use crate::tls::TlsStore;
+#[cfg(feature = "bt")]
+use crate::transport::ConnectionStatus;
+#[cfg(feature = "uart")]
+use crate::uart_handler::UartHandler;
+use crate::virtual_pids::VirtualPids;
+use crate::{pid, relay, sleep};
+
+/// Alert name for a weak adapter link, next to the threshold rules'
+#[cfg(feature = "bt")]
+const WEAK_LINK_ALERT: &str = "bt_weak_signal";
+/// Phones and laptops on our own AP, see `NetConfig::access_point`
+const AP_MAX_CONNECTIONS: u16 = 2;
+
+/// Coolant or ambient temperature at startup at or below this is a cold start
+const COLD_START_TEMP_C: f32 = -10.0;
+/// How long the cold start timeouts and poll ramp-up last
+const COLD_PROFILE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Board and network settings, the defaults are for the original ESP32 devkit and OBDLink MX+
+pub struct GatewayConfig {
+    /// BT address of the OBD adapter
+    #[cfg(feature = "bt")]
+    pub obd_addr: BdAddr,
+    /// Our BT device name
+    #[cfg(feature = "bt")]
+    pub device_name: &'static str,
+    #[cfg(feature = "bt")]
+    pub bt_pin: &'static str,
+    /// The adapter link is weak below this RSSI delta, dB under the controller's golden receive
+    /// power range, see `bt::LinkMonitor`
+    #[cfg(feature = "bt")]
+    pub bt_weak_rssi: i8,
+    /// The LCD's AP
+    pub ssid: &'static str,
+    /// Must match the AP channel
+    pub espnow_channel: u8,
+    /// mDNS name, `<hostname>.local`, unless one is set in the network config
+    pub hostname: &'static str,
+    /// HTTP server sizing, overrides from `/config/http` go on top
+    pub http: HttpLimits,
+    /// Supply below this is a power loss, under the cranking dip. Only used with a `SupplySense`.
+    pub power_loss_mv: u32,
+    /// How HTTP requests are authenticated
+    pub http_auth: HttpAuth,
+    /// Serve HTTPS with the certificate in NVS, plain HTTP until one is uploaded
+    pub https: bool,
+    /// Restart cleanly when the heap is nearly gone, see `memory`
+    pub low_memory_restart: bool,
+    /// Put the OBD adapter in low power after this long without HTTP requests, see `idle`
+    pub idle_timeout: Option<Duration>,
+    /// Other adapters connected alongside the OBD adapter, picked with `?device=<name>`
+    #[cfg(feature = "bt")]
+    pub aux_adapters: Vec<AuxAdapter>,
+    /// The WiFi adapter's address, on the network the gateway joins
+    #[cfg(feature = "wifi-adapter")]
+    pub wifi_adapter: SocketAddr,
+    /// What the simulated adapter answers
+    #[cfg(feature = "mock-elm")]
+    pub mock_elm: MockConfig,
+}
+
+/// A second SPP adapter, e.g. an ELM327 on a body bus tap
+#[cfg(feature = "bt")]
+pub struct AuxAdapter {
+    /// `?device=` value for its requests, `obd` is the OBD adapter
+    pub name: &'static str,
+    pub addr: BdAddr,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            // OBDLink MX+ mac
+            #[cfg(feature = "bt")]
+            obd_addr: BdAddr::from_bytes([0x00, 0x04, 0x3E, 0x83, 0xFC, 0x98]),
+            #[cfg(feature = "bt")]
+            device_name: "OBD-ESP32",
+            #[cfg(feature = "bt")]
+            bt_pin: "1234",
+            #[cfg(feature = "bt")]
+            bt_weak_rssi: -10,
+            ssid: "OBD-ESPWIFI",
+            espnow_channel: 1,
+            hostname: "obd-gw",
+            http: HttpLimits::BUILD,
+            power_loss_mv: 6000,
+            http_auth: HttpAuth::Token,
+            https: false,
+            low_memory_restart: true,
+            idle_timeout: None,
+            #[cfg(feature = "bt")]
+            aux_adapters: Vec::new(),
+            // Where most WiFi ELM327 clones listen
+            #[cfg(feature = "wifi-adapter")]
+            wifi_adapter: SocketAddr::from(([192, 168, 0, 10], 35000)),
+            #[cfg(feature = "mock-elm")]
+            mock_elm: MockConfig::default(),
+        }
+    }
+}
+
+/// Builds a `Gateway`. The modem and status LED are required, they come out of the
+/// `Peripherals` the caller takes once along with any other pins the board uses. Anything else
+/// not given defaults to the current board.
+#[derive(Default)]
+pub struct GatewayBuilder {
+    modem: Option<Modem>,
+    led: Option<Box<dyn StatusLed>>,
+    nvs: Option<EspDefaultNvsPartition>,
+    sys_loop: Option<EspSystemEventLoop>,
+    supply: Option<Box<dyn SupplySense>>,
+    ignition_input: Option<AnyInputPin>,
+    #[cfg(feature = "uart")]
+    elm_uart: Option<UartDriver<'static>>,
+    config: GatewayConfig,
+}
+
+impl GatewayBuilder {
+    pub fn modem(mut self, modem: Modem) -> Self {
+        self.modem = Some(modem);
+        self
+    }
+
+    pub fn led(mut self, led: impl StatusLed + 'static) -> Self {
+        self.led = Some(Box::new(led));
+        self
+    }
+
+    pub fn nvs(mut self, nvs: EspDefaultNvsPartition) -> Self {
+        self.nvs = Some(nvs);
+        self
+    }
+
+    pub fn sys_loop(mut self, sys_loop: EspSystemEventLoop) -> Self {
+        self.sys_loop = Some(sys_loop);
+        self
+    }
+
+    /// Watch the supply for a power loss, for boards on an ignition switched OBD port
+    pub fn supply_sense(mut self, supply: impl SupplySense + 'static) -> Self {
+        self.supply = Some(Box::new(supply));
+        self
+    }
+
+    /// Ignition switched input (high with the ignition on), in place of the battery voltage
+    /// heuristic. Must be an RTC GPIO to wake the gateway from deep sleep.
+    pub fn ignition_input(mut self, pin: AnyInputPin) -> Self {
+        self.ignition_input = Some(pin);
+        self
+    }
+
+    /// The wired adapter's UART, needed with the `uart` feature
+    #[cfg(feature = "uart")]
+    pub fn elm_uart(mut self, uart: UartDriver<'static>) -> Self {
+        self.elm_uart = Some(uart);
+        self
+    }
+
+    pub fn config(mut self, config: GatewayConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<Gateway> {
+        let modem = self
+            .modem
+            .ok_or_else(|| anyhow!("Gateway needs the modem, see GatewayBuilder::modem"))?;
+        let led = self
+            .led
+            .ok_or_else(|| anyhow!("Gateway needs a status LED, see GatewayBuilder::led"))?;
+
+        let sys_loop = match self.sys_loop {
+            Some(sys_loop) => sys_loop,
+            None => EspSystemEventLoop::take()?,
+        };
+
+        let nvs = match self.nvs {
+            Some(nvs) => nvs,
+            None => EspDefaultNvsPartition::take()?,
+        };
+
+        Ok(Gateway {
+            modem,
+            led,
+            nvs,
+            sys_loop,
+            supply: self.supply,
+            ignition_input: self.ignition_input,
+            #[cfg(feature = "uart")]
+            elm_uart: self.elm_uart,
+            config: self.config,
+        })
+    }
+}
+
+pub struct Gateway {
+    modem: Modem,
+    led: Box<dyn StatusLed>,
+    nvs: EspDefaultNvsPartition,
+    sys_loop: EspSystemEventLoop,
+    supply: Option<Box<dyn SupplySense>>,
+    ignition_input: Option<AnyInputPin>,
+    #[cfg(feature = "uart")]
+    elm_uart: Option<UartDriver<'static>>,
+    config: GatewayConfig,
+}
+
+impl Gateway {
+    pub fn builder() -> GatewayBuilder {
+        GatewayBuilder::default()
+    }
+
+    /// Establishes the BT connection, sets up the ELM327, and then joins the WIFI AP (LCD). Once
+    /// joined and the HTTP handler is ready an ESPNOW message is broadcast with our IP address and
+    /// we can start servicing ELM327 requests. Never returns unless startup fails.
+    pub fn run(self) -> Result<()> {
+        let Gateway {
+            modem,
+            led,
+            nvs,
+            sys_loop,
+            mut supply,
+            ignition_input,
+            #[cfg(feature = "uart")]
+            elm_uart,
+            config,
+        } = self;
+
+        let started = Instant::now();
+
+        // Named so its priority can be set once the task settings are read
+        let led_blink = tasks::led(|| start_led_blink(led))?;
+
+        // Relay firmware only forwards ESPNOW messages, no BT or ELM
+        if cfg!(feature = "relay") {
+            return relay::run(modem, sys_loop, nvs, config.espnow_channel);
+        }
+
+        let ignition = Mutex::new(Ignition::new(
+            ignition_input.map(PinDriver::input).transpose()?,
+        ));
+
+        // Switched ignition is off, nothing to do until it comes on
+        if ignition.lock().unwrap().input_low() {
+            info!("Ignition input low");
+            sleep::deep_sleep(ignition.lock().unwrap().wake_pin());
+        }
+
+        #[cfg(feature = "bt")]
+        let (wifi_modem, mut bt_modem) = modem.split();
+
+        #[cfg(feature = "bt")]
+        reduce_bt_memory(unsafe { bt_modem.clone_unchecked() })?;
+
+        // No BT, the modem is all WIFI
+        #[cfg(not(feature = "bt"))]
+        let wifi_modem = modem;
+
+        //========
+        // The ordering of peripheral startup and using HTTP instead of ESPNOW is based around
+        // BT/WIFI co-existence. ESPNOW rx proved to be very unreliable due to the modem switching
+        // causing many timeouts. ESPNOW is a non guaranteed delivery which required a lot of
+        // handling and retries to make sure an elm request was replied to. Also, some elm
+        // responses can exceed the ESPNOW message length which makes an even more complicated
+        // protocol on top of guaranteed delivery.
+        //========
+
+        //-----
+        // NVS
+        //-----
+        // Each startup stage is timed and recorded, for GET /selftest
+        let selftest = SelfTest::new();
+        selftest.run(Stage::Nvs, || selftest.open_nvs(nvs.clone()))?;
+
+        // Why this boot happened, and the panic that caused it if there was one
+        let crash_log = CrashLog::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Values that change all the time, written in batches
+        let persist = Arc::new(Persist::new(EspNvs::new(nvs.clone(), "elm_ns", true)?));
+
+        // Request, error and reconnect counts and uptime across reboots
+        let lifetime = LifetimeStats::new(persist.clone())?;
+
+        // Log levels set with POST /config/log, applied before anything else gets going
+        let log_levels = LogLevels::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Task priorities and the ELM worker's core, see `tasks`
+        let tasks = Tasks::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Sometimes discovery fails and a restart finds the adapter, the policy says how often to
+        // try and what to do after
+        #[cfg(feature = "bt")]
+        let discovery = DiscoveryPolicy::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Key for signed uploads, signing is off until a key is set
+        let signing = Signing::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Checked on every HTTP request, token and challenge auth are off until a secret is set
+        let auth = auth::backend(config.http_auth, EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // HTTPS once a certificate and key have been uploaded
+        let tls = TlsStore::new(EspNvs::new(nvs.clone(), "elm_ns", true)?);
+        let server_cert = if config.https {
+            tls.server_cert()
+                .inspect_err(|err| error!("TLS certificate unusable: {err}"))
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+        if config.https && server_cert.is_none() {
+            warn!("HTTPS enabled without a certificate, serving HTTP");
+        }
+
+        // Service intervals and when they were last done
+        let maintenance =
+            Maintenance::new(EspNvs::new(nvs.clone(), "elm_ns", true)?, persist.clone())?;
+
+        // Trip fuel totals
+        let fuel = FuelEconomy::new(persist.clone())?;
+
+        // Per request TTLs for /post responses
+        let elm_cache = ElmCache::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Adapter setup commands for this vehicle, the built in ones until a script is uploaded
+        let init_script = InitScript::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // ATAT and ATST for the normal timing profile
+        let timeouts = ElmTimeouts::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // STBR rate for STN adapters, the power on rate until one is set
+        let link_speed = LinkSpeed::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // ATFCSH, ATFCSD and ATFCSM for long responses, the adapter's own until one is set
+        let flow_control = FlowControl::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Pings an idle adapter before it sleeps, off until an interval is set
+        let keep_awake = KeepAwake::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // The vehicle's ECUs from the last scan, for ?ecu=
+        let ecus = Ecus::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // The displays sent to over ESPNOW, broadcast to until one is paired
+        let peers = Peers::new(
+            EspNvs::new(nvs.clone(), "elm_ns", true)?,
+            config.espnow_channel,
+        )?;
+
+        // Commands HTTP clients need the unlock token for
+        let policy = Arc::new(Policy::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?);
+
+        // Trip logs on the FAT storage partition
+        if let Err(err) = logger::mount_storage() {
+            error!("Failed to mount storage, trip logging unavailable: {err}");
+        } else if let Err(err) = Logger::recover_trip() {
+            error!("Failed to recover the last trip {err}");
+        }
+        let logger = Logger::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // User defined channels over the PIDs, for the logger and snapshots
+        let virtual_pids = VirtualPids::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Clamps, smooths and paces the logged values on their way to /events
+        let smoothing = Smoothing::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Alert rules over the PIDs and battery voltage
+        let thresholds = Thresholds::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+
+        // Static IP and hostname, used when WIFI starts
+        let net_settings = NetSettings::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+        let hostname = net_settings
+            .config()
+            .hostname
+            .unwrap_or_else(|| config.hostname.to_owned());
+
+        // HTTP server sizing over the build's, for the server started below
+        let http_settings = HttpSettings::new(EspNvs::new(nvs.clone(), "elm_ns", true)?)?;
+        let http_limits = config.http.with(&http_settings.overrides());
+
+        // Subsystems register here as they start, and can be toggled at runtime
+        let features = Features::new(EspNvs::new(nvs.clone(), "elm_ns", true)?);
+
+        //--------------------
+        // Start/Connect WIFI
+        //--------------------
+        // After the BT adapter for co-existence, but a WiFi adapter needs it first
+        let start_wifi = || -> Result<(BlockingWifi<EspWifi<'static>>, Ipv4Addr)> {
+            let mut wifi = BlockingWifi::wrap(
+                EspWifi::new(wifi_modem, sys_loop.clone(), Some(nvs.clone()))?,
+                sys_loop.clone(),
+            )?;
+
+            let ip_addr = selftest.run(Stage::Wifi, || {
+                connect_wifi_client(&mut wifi, &config, &net_settings, &hostname)
+            })?;
+
+            led_blink.send(LedBlink::Times(3))?;
+
+            Ok((wifi, ip_addr))
+        };
+
+        //-----------
+        // BLUETOOTH
+        //-----------
+        #[cfg(feature = "bt")]
+        let driver = selftest.run(Stage::BtController, || {
+            let driver = BtDriver::<BtClassic>::new(bt_modem, Some(nvs.clone()))?;
+            driver.set_device_name(config.device_name)?;
+
+            Ok(driver)
+        })?;
+
+        #[cfg(feature = "bt")]
+        let gap = EspGap::new(&driver)?;
+
+        #[cfg(feature = "bt")]
+        let spp = Arc::new(EspSpp::new(
+            &driver,
+            &SppConfig {
+                mode: spp::Mode::Cb,
+                enable_l2cap_ertm: true,
+                tx_buffer_size: 0,
+            },
+        )?);
+
+        #[cfg(feature = "bt")]
+        {
+            info!("Bluetooth initialized, GAP and SPP created");
+
+            unsafe {
+                gap.subscribe_nonstatic(|event| bt::handle_gap(&gap, event))?;
+            }
+
+            // No IO capability
+            // gap.set_ssp_io_cap(IOCapabilities::None)?;
+            esp!(unsafe {
+                esp_bt_gap_set_security_param(
+                    esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
+                    &ESP_BT_IO_CAP_NONE as *const _ as *mut std::ffi::c_void,
+                    1,
+                )
+            })?;
+
+            gap.set_pin(config.bt_pin)?;
+            gap.set_scan_mode(true, DiscoveryMode::Discoverable)?;
+
+            info!("GAP initialized");
+        }
+
+        #[cfg(feature = "bt")]
+        let mut port = SppHandler::new(&spp, config.obd_addr);
+
+        #[cfg(feature = "bt")]
+        let aux_handlers: Vec<_> = config
+            .aux_adapters
+            .iter()
+            .map(|aux| SppHandler::new(&spp, aux.addr))
+            .collect();
+
+        #[cfg(feature = "bt")]
+        {
+            // SPP events are routed to the adapter they're for
+            let links: Vec<_> = std::iter::once(port.link(true))
+                .chain(aux_handlers.iter().map(|handler| handler.link(false)))
+                .collect();
+            let spp_sub = Arc::clone(&spp);
+            let led_blink_2 = led_blink.clone();
+            unsafe {
+                spp.subscribe_nonstatic(move |event| {
+                    spp_handler::handle_spp(&links, &led_blink_2, &spp_sub, event)
+                })?;
+            }
+
+            led_blink.send(LedBlink::Times(1))?;
+
+            let connected = selftest.run(Stage::SppConnect, || {
+                // Seconds sooner than discovery while the adapter's SCN hasn't changed
+                if let Some(scn) = discovery.known_scn(&config.obd_addr) {
+                    match port.connect_direct(scn) {
+                        Ok(()) => return Ok(()),
+                        Err(err) => warn!("{err:#}, discovering the adapter"),
+                    }
+                }
+
+                port.start_discovery()?;
+                port.wait_connected()
+            });
+
+            // Only an SCN that got a connection, one found by a discovery that then failed to
+            // connect may be stale
+            if let Some(scn) = port.scn().filter(|_| connected.is_ok()) {
+                if let Err(err) = discovery.found_scn(&config.obd_addr, scn) {
+                    error!("Failed to keep the adapter SCN {err:#}");
+                }
+            }
+
+            if let Err(err) = connected {
+                match discovery.failed() {
+                    DiscoveryAction::Restart(backoff) => {
+                        info!("Adapter not reached, restarting in {}s", backoff.as_secs());
+                        thread::sleep(backoff);
+                        reset::restart();
+                    }
+                    DiscoveryAction::IdleWithError => {
+                        info!("Adapter not reached, giving up until the next power cycle");
+                        return Err(err);
+                    }
+                    DiscoveryAction::DeepSleep => {
+                        info!("Adapter not reached, sleeping");
+                        sleep::deep_sleep(ignition.lock().unwrap().wake_pin());
+                    }
+                }
+            }
+        }
+
+        //------
+        // UART
+        //------
+        #[cfg(feature = "uart")]
+        let port = UartHandler::new(
+            elm_uart.ok_or_else(|| anyhow!("The uart feature needs GatewayBuilder::elm_uart"))?,
+        )?;
+
+        //--------------
+        // WiFi adapter
+        //--------------
+        #[cfg(feature = "wifi-adapter")]
+        let (mut wifi, mut ip_addr) = start_wifi()?;
+
+        // Connects with the first request of the setup
+        #[cfg(feature = "wifi-adapter")]
+        let port = TcpHandler::new(config.wifi_adapter);
+
+        //-------------
+        // Mock ELM327
+        //-------------
+        #[cfg(feature = "mock-elm")]
+        let port = MockElm::new(config.mock_elm.clone());
+
+        //--------
+        // ELM327
+        //--------
+        let elm327: Mutex<Elm327<'_>> = Mutex::new(Elm327::new(port));
+
+        elm327
+            .lock()
+            .unwrap()
+            .set_init_script(init_script.commands());
+        elm327.lock().unwrap().set_timeouts(timeouts.config());
+        elm327
+            .lock()
+            .unwrap()
+            .set_baud_rate(link_speed.config().baud);
+        elm327
+            .lock()
+            .unwrap()
+            .set_flow_control(flow_control.config());
+
+        // Off until /debug/replay starts recording or replaying
+        let session = Arc::new(Session::new(logger::SESSION_FILE));
+        elm327.lock().unwrap().set_session(Arc::clone(&session));
+
+        // A stored script the adapter now refuses, e.g. after swapping adapters, mustn't keep the
+        // gateway from starting. The built in setup gets it to the HTTP server to fix it from.
+        selftest.run(Stage::Elm, || {
+            let mut elm327 = elm327.lock().unwrap();
+            match elm327.setup() {
+                Err(err) if !init_script.commands().is_empty() => {
+                    error!("Adapter setup failed, using the built in setup {err:#}");
+                    elm327.set_init_script(Vec::new());
+                    elm327.setup()
+                }
+                result => result,
+            }
+        })?;
+
+        led_blink.send(LedBlink::Times(2))?;
+        info!("ELM327 initialized");
+
+        // Woken by the sleep timer, go straight back to sleep unless the engine is running
+        if sleep::woke_by_timer() {
+            let mut elm327 = elm327.lock().unwrap();
+            let volts = elm327.battery_voltage().unwrap_or(0.0);
+            if volts < ignition::RUNNING_VOLTS {
+                info!("Engine not running ({volts:.1}V)");
+                elm327.disconnect();
+                sleep::deep_sleep(None);
+            }
+        }
+
+        // Unknown support (e.g. ignition off) leaves every PID allowed
+        if let Err(err) = pid::discover_supported(&mut elm327.lock().unwrap()) {
+            warn!("Supported PID discovery failed {err}");
+        }
+
+        // Cold ECUs and adapters answer slower, so go easy on them until they've warmed up
+        let mut cold_until = None;
+        if cold_start(&mut elm327.lock().unwrap()) {
+            info!("Cold start, using extended timeouts");
+            elm327.lock().unwrap().set_timing(TimingProfile::Cold)?;
+            logger.ramp_up(COLD_PROFILE_DURATION);
+            cold_until = Some(Instant::now() + COLD_PROFILE_DURATION);
+        }
+
+        features.register(Feature::Logger, Box::new(&logger))?;
+        features.register(Feature::Poller, Box::new(poll_pause::Poller))?;
+        features.register(Feature::Capture, Box::new(Arc::clone(&session)))?;
+
+        // Reached the adapter, the next failure gets all the retries again
+        #[cfg(feature = "bt")]
+        discovery.connected()?;
+
+        // Other adapters one at a time, discovery doesn't say which adapter it found. Not part of
+        // the self-test, one that doesn't connect is kept and its requests fail.
+        let mut aux_elm327: Vec<(&str, Mutex<Elm327<'_>>)> = Vec::new();
+        #[cfg(feature = "bt")]
+        for (aux, mut handler) in config.aux_adapters.iter().zip(aux_handlers) {
+            let result = handler
+                .start_discovery()
+                .and_then(|_| handler.wait_connected());
+
+            let mut elm327 = Elm327::new(handler);
+            match result.and_then(|_| elm327.setup()) {
+                Ok(()) => info!("Adapter ({}) ready", aux.name),
+                Err(err) => error!("Adapter ({}) setup failed {err:#}", aux.name),
+            }
+
+            aux_elm327.push((aux.name, Mutex::new(elm327)));
+        }
+
+        #[cfg(not(feature = "wifi-adapter"))]
+        let (mut wifi, mut ip_addr) = start_wifi()?;
+
+        // From here the HTTP server is the way back in, so a fatal error drops to a diagnostics
+        // only server rather than just blinking the LED
+        let mut serve = || -> Result<Infallible> {
+            // Address changes and AP drops from here on
+            let net_watch = NetWatch::new(&sys_loop)?;
+            let wifi_supervisor = WifiSupervisor::new(ip_addr);
+
+            // Wall clock for the logs and snapshots, without internet a client can set it
+            let _sntp = clock::start_sntp()
+                .inspect_err(|err| error!("Failed to start SNTP {err}"))
+                .ok();
+
+            //--------
+            // ESPNOW
+            //--------
+            let espnow = EspNow::take()?;
+
+            espnow.add_peer(PeerInfo {
+                peer_addr: BROADCAST,
+                channel: config.espnow_channel,
+                ifidx: esp_idf_svc::sys::wifi_interface_t_WIFI_IF_STA,
+                encrypt: false,
+                ..Default::default()
+            })?;
+
+            // Ping, status, reboot and LED test commands from peers
+            let espnow_commands = espnow_cmd::start(&espnow)?;
+
+            // Bench commands typed on the USB serial port
+            let console_lines = console::start()?;
+
+            // Needs ESPNOW up to tell the peers
+            if let Some(supply) = supply.take() {
+                power::start_watch(supply, config.power_loss_mv, persist.clone());
+            }
+
+            //-------------
+            // HTTP Server
+            //-------------
+            info!("Starting service request handler");
+
+            // HTTP adapter requests run on the worker, joined when serve returns
+            let (elm_worker, aux_workers) = tasks.elm_worker(|| {
+                let elm_worker = unsafe { ElmWorker::start(&elm327)? };
+                let aux_workers = aux_elm327
+                    .iter()
+                    .map(|(name, elm327)| Ok((*name, unsafe { ElmWorker::start(elm327)? })))
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok((elm_worker, aux_workers))
+            })?;
+            let adapter = elm327.lock().unwrap().capabilities().clone();
+
+            let coalescer = Coalescer::new();
+            let drive_cycle = DriveCycle::new();
+            let dtc_scanner = DtcScanner::new();
+            let alerts = Alerts::new();
+
+            let services = Services {
+                elm_worker: &elm_worker,
+                aux_workers: &aux_workers,
+                adapter: &adapter,
+                #[cfg(feature = "bt")]
+                adapter_addr: config.obd_addr,
+                #[cfg(feature = "bt")]
+                discovery: &discovery,
+                led_blink: &led_blink,
+                signing: &signing,
+                log_levels: &log_levels,
+                tasks: &tasks,
+                auth: &*auth,
+                features: &features,
+                logger: &logger,
+                coalescer: &coalescer,
+                elm_cache: &elm_cache,
+                drive_cycle: &drive_cycle,
+                dtc_scanner: &dtc_scanner,
+                fuel: &fuel,
+                virtual_pids: &virtual_pids,
+                smoothing: &smoothing,
+                thresholds: &thresholds,
+                alerts: &alerts,
+                ignition: &ignition,
+                maintenance: &maintenance,
+                tls: &tls,
+                init_script: &init_script,
+                timeouts: &timeouts,
+                link_speed: &link_speed,
+                flow_control: &flow_control,
+                keep_awake: &keep_awake,
+                ecus: &ecus,
+                peers: &peers,
+                wifi: &wifi_supervisor,
+                net_settings: &net_settings,
+                selftest: &selftest,
+                crash_log: &crash_log,
+                lifetime: &lifetime,
+                persist: &persist,
+                http_settings: &http_settings,
+                http_limits,
+                session: &session,
+                policy: Arc::clone(&policy),
+            };
+
+            let (mut server, tls) = http::start_server(&http_limits, server_cert)?;
+
+            let mut mdns = network::start_mdns(&hostname, tls)
+                .inspect_err(|err| error!("Failed to start mDNS {err}"))
+                .ok();
+
+            http::register_handlers(&mut server, &services)?;
+            // Reachable, so an OTA image that got here is kept
+            ota::mark_valid();
+
+            // The main loop, HTTP and LED tasks are running now
+            tasks.apply_running();
+
+            //------------------
+            // Off to the races
+            //------------------
+            // Tell the LCD our IP, repeated until it acks
+            let mut announcer = Announcer::new(ip_addr, tls, started)?;
+            announcer
+                .poll(&espnow, &peers, auth.check(None).is_err())
+                .error_ind(2)?;
+
+            let mut memory_monitor = MemoryMonitor::new();
+            let mut idle = IdleManager::new(config.idle_timeout);
+            #[cfg(feature = "bt")]
+            let mut link_monitor = bt::LinkMonitor::new(config.obd_addr, config.bt_weak_rssi);
+
+            loop {
+                while let Ok(request) = espnow_commands.try_recv() {
+                    let result = espnow_cmd::handle(
+                        &espnow,
+                        config.espnow_channel,
+                        &led_blink,
+                        &persist,
+                        &wifi_supervisor,
+                        started,
+                        request,
+                    );
+                    if let Err(err) = result {
+                        error!("ESPNOW command failed {err}");
+                    }
+                }
+
+                while let Ok(line) = console_lines.try_recv() {
+                    console::handle(&services, &line);
+                }
+
+                match net_watch.poll() {
+                    Some(NetEvent::IpAssigned(ip)) => {
+                        if ip != ip_addr {
+                            info!("IP changed from {ip_addr} to {ip}");
+                            ip_addr = ip;
+                        }
+                        wifi_supervisor.connected(ip);
+
+                        // The AP (LCD) may have restarted so always tell it again
+                        announcer.reannounce(ip);
+                        if let Some(mdns) = mdns.as_mut() {
+                            if let Err(err) = network::update_mdns(mdns, &hostname) {
+                                error!("mDNS update failed {err}");
+                            }
+                        }
+                    }
+                    Some(NetEvent::Disconnected) => {
+                        if network::on_home() {
+                            if let Err(err) = leave_home(&mut wifi, &config, &net_settings) {
+                                error!("Failed to join the LCD {err:#}");
+                            }
+                        }
+                        wifi_supervisor.disconnected();
+                    }
+                    None => {}
+                }
+
+                if lifetime.save_due() {
+                    if let Err(err) = lifetime.save() {
+                        error!("Failed to save lifetime counts {err}");
+                    }
+                }
+
+                if persist.flush_due() {
+                    if let Err(err) = persist.flush() {
+                        error!("NVS flush failed {err}");
+                    }
+                }
+
+                // A display heard from in the pairing window, announce again so it hears of us
+                match peers.poll() {
+                    Ok(0) => {}
+                    Ok(_) => announcer.reannounce(ip_addr),
+                    Err(err) => error!("ESPNOW peer registration failed {err}"),
+                }
+
+                if announcer.poll_due() {
+                    if let Err(err) = announcer.poll(&espnow, &peers, auth.check(None).is_err()) {
+                        error!("Announce failed {err}");
+                    }
+                }
+
+                // The new address arrives as an IpAssigned event once connected, without one in
+                // time the association is dropped and tried again
+                if wifi_supervisor.reconnect_due() {
+                    if wifi_supervisor.address_timed_out() {
+                        if let Err(err) = wifi.disconnect() {
+                            warn!("Wifi disconnect failed: {err}");
+                        }
+                    } else {
+                        wifi_supervisor.attempted(wifi.connect());
+                    }
+                }
+
+                // Idle in low power the adapter is left alone, an adapter a request has is awake
+                let (awake, uds_session) = elm327.try_lock().map_or((true, false), |mut elm327| {
+                    (!elm327.asleep(), elm327.keep_alive().next_due().is_some())
+                });
+                // Nor is it polled in a UDS session or while paused, see `poll_pause`
+                let polling = awake && !uds_session && !poll_pause::paused();
+
+                if polling && cold_until.is_some_and(|until| until <= Instant::now()) {
+                    info!("Warmed up, using normal timeouts");
+                    if let Err(err) = elm327.lock().unwrap().set_timing(TimingProfile::Normal) {
+                        error!("Failed to restore timeouts {err}");
+                    }
+                    cold_until = None;
+                }
+
+                if polling && logger.sample_due() {
+                    if let Err(err) =
+                        logger.sample(&mut elm327.lock().unwrap(), &virtual_pids, &smoothing)
+                    {
+                        error!("Log sample failed {err}");
+                    }
+                }
+                smoothing.poll();
+
+                // End the trip while there's still power to write it out
+                let mut ignition = ignition.lock().unwrap();
+                if ignition.poll_due() && (polling || ignition.has_input()) {
+                    match ignition.poll(&mut elm327.lock().unwrap()) {
+                        Ok(Some(IgnitionChange::Off)) => {
+                            if let Err(err) = logger.end_trip(TripEnd::IgnitionOff) {
+                                error!("Failed to end the trip {err}");
+                            }
+                        }
+                        Ok(Some(IgnitionChange::On)) if features.is_enabled(Feature::Logger) => {
+                            if let Err(err) = logger.start_trip() {
+                                error!("Failed to start a trip {err}");
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => debug!("Ignition poll failed {err}"),
+                    }
+                }
+
+                // Only moments left, get the trip onto storage. The watch thread has already flushed
+                // the batched NVS writes.
+                if power::power_lost() {
+                    if let Err(err) = logger.end_trip(TripEnd::PowerLoss) {
+                        error!("Failed to end the trip {err}");
+                    }
+
+                    // Still running, it was a dip rather than a loss
+                    thread::sleep(Duration::from_secs(1));
+                    info!("Supply recovered, restarting");
+                    reset::restart();
+                }
+
+                if memory_monitor.poll_due() {
+                    match memory_monitor.poll() {
+                        Some(Pressure::Shedding) => {
+                            info!("Flushed {} cached responses", elm_cache.flush());
+                        }
+                        Some(Pressure::Critical) if config.low_memory_restart => {
+                            if let Err(err) = logger.end_trip(TripEnd::LowMemory) {
+                                error!("Failed to end the trip {err}");
+                            }
+                            if let Err(err) = persist.flush() {
+                                error!("Flush before restart failed {err}");
+                            }
+
+                            error!("Heap nearly gone, restarting");
+                            reset::restart();
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Close the adapter link and tell the peers before the radio goes quiet
+                if ignition.sleep_due() {
+                    info!(
+                        "Ignition off for {}s, shutting down",
+                        ignition.off_for().unwrap_or_default().as_secs()
+                    );
+
+                    if let Err(err) =
+                        peers.send(&espnow, &bt_obd_gw_protocol::Status::Sleeping.encode())
+                    {
+                        error!("Sleep notify failed {err}");
+                    }
+                    if let Err(err) = lifetime.save().and_then(|_| persist.flush()) {
+                        error!("Flush before sleep failed {err}");
+                    }
+                    elm327.lock().unwrap().disconnect();
+
+                    sleep::deep_sleep(ignition.wake_pin());
+                }
+
+                let ignition_on = ignition.is_on();
+                drop(ignition);
+
+                // A step of a /scan, it holds the polls off while it runs
+                if dtc_scanner.running() {
+                    dtc_scanner.poll(&elm_worker, &ecus);
+                }
+
+                if polling && ignition_on && drive_cycle.poll_due() {
+                    if let Err(err) = drive_cycle.poll(&mut elm327.lock().unwrap()) {
+                        error!("Drive cycle poll failed {err}");
+                    }
+                }
+
+                if polling && ignition_on && fuel.poll_due() {
+                    if let Err(err) = fuel.poll(&mut elm327.lock().unwrap()) {
+                        error!("Fuel economy poll failed {err}");
+                    }
+                }
+
+                // Parked rules, e.g. a low battery, are checked with the ignition off too
+                if polling && thresholds.poll_due() {
+                    match thresholds.poll(&mut elm327.lock().unwrap(), &virtual_pids, ignition_on) {
+                        Ok(events) => {
+                            for event in events {
+                                if event.active {
+                                    let _ = led_blink.try_send(LedBlink::Alert);
+                                }
+                                let alert = bt_obd_gw_protocol::alert(
+                                    event.active,
+                                    event.value,
+                                    &event.name,
+                                );
+                                if let Err(err) = peers.send(&espnow, &alert) {
+                                    error!("Alert send failed {err}");
+                                }
+                            }
+                        }
+                        Err(err) => debug!("Threshold poll failed {err}"),
+                    }
+                }
+
+                // Sent on to the LCD as an alert, marginal range corrupts responses
+                #[cfg(feature = "bt")]
+                if awake && link_monitor.poll_due() {
+                    match link_monitor.poll() {
+                        Ok(Some(change)) => {
+                            if change.weak {
+                                let _ = led_blink.try_send(LedBlink::Alert);
+                            }
+                            let alert = bt_obd_gw_protocol::alert(
+                                change.weak,
+                                change.rssi_delta as f32,
+                                WEAK_LINK_ALERT,
+                            );
+                            if let Err(err) = peers.send(&espnow, &alert) {
+                                error!("Alert send failed {err}");
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => debug!("RSSI read failed {err}"),
+                    }
+                }
+
+                if polling && ignition_on && maintenance.read_due() {
+                    match maintenance.read(&mut elm327.lock().unwrap()) {
+                        Ok(reminders) => {
+                            for (name, state) in reminders {
+                                info!("Maintenance ({name}) {state:?}");
+                                let reminder =
+                                    bt_obd_gw_protocol::reminder(state == DueState::Overdue, &name);
+                                if let Err(err) = peers.send(&espnow, &reminder) {
+                                    error!("Reminder send failed {err}");
+                                }
+                            }
+                        }
+                        Err(err) => error!("Maintenance read failed {err}"),
+                    }
+                }
+
+                // A trip being logged keeps the adapter awake
+                if (polling || !awake) && idle.poll_due() {
+                    let idle_for = elm_worker.idle_for();
+                    if let Err(err) =
+                        idle.poll(&mut elm327.lock().unwrap(), idle_for, logger.trip_active())
+                    {
+                        error!("Idle check failed {err:#}");
+                    }
+                }
+
+                // Not between the steps of a paused operation, and not while a request has the
+                // adapter, it's busy then anyway
+                if polling {
+                    if let Ok(mut elm327) = elm327.try_lock() {
+                        if let Err(err) = keep_awake.poll(&mut elm327) {
+                            error!("Keep awake ping failed {err:#}");
+                        }
+                    }
+                }
+
+                // Alerts demultiplexed from HTTP responses, or sent while idle. Skip if a request
+                // has the adapter.
+                if let Ok(mut elm327) = elm327.try_lock() {
+                    if let Err(err) = elm327.poll_unsolicited() {
+                        error!("Unsolicited read failed {err}");
+                    }
+                    for alert in elm327.take_alerts() {
+                        alerts.push(alert);
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        let Err(err) = serve();
+
+        diagnostics::run(err, &config, &*auth, server_cert)
+    }
+}
+
+/// True if the coolant or ambient temperature says it's very cold. Either PID may be unsupported.
+fn cold_start(elm327: &mut Elm327<'_>) -> bool {
+    [pid::COOLANT_TEMP, pid::AMBIENT_TEMP]
+        .into_iter()
+        .filter_map(|p| pid::request(elm327, p).ok())
+        .any(|temp| temp <= COLD_START_TEMP_C)
+}
+
+fn connect_wifi_client(
+    wifi: &mut BlockingWifi<EspWifi<'_>>,
+    config: &GatewayConfig,
+    net_settings: &NetSettings,
+    hostname: &str,
+) -> Result<Ipv4Addr> {
+    let settings = net_settings.config();
+
+    // One radio, so home WIFI is only joined on the ESPNOW channel, where the LCD link stays
+    let home = match &settings.home_wifi {
+        Some(home) => {
+            let found = find_network(wifi, &home.ssid, config.espnow_channel)
+                .inspect_err(|err| error!("Home WIFI scan failed {err}"))
+                .unwrap_or(false);
+            if !found {
+                info!(
+                    "Home WIFI ({}) not on channel {}, joining the LCD",
+                    home.ssid, config.espnow_channel
+                );
+            }
+            found.then_some(home)
+        }
+        None => None,
+    };
+    let ssid = home.map_or(config.ssid, |home| home.ssid.as_str());
+
+    // Static address or DHCP hostname, set before the interface comes up
+    wifi.wifi_mut()
+        .swap_netif_sta(net_settings.sta_netif(hostname, home.is_some())?)?;
+
+    wifi.set_configuration(&wifi_configuration(config, &settings, home.is_some())?)?;
+
+    wifi.start()?;
+    info!("Wifi started");
+
+    let mut connect_tries = 3;
+    loop {
+        connect_tries -= 1;
+        match wifi.connect() {
+            Ok(_) => {
+                break;
+            }
+            Err(e) => {
+                error!("Wifi connect failed: {e}");
+                if connect_tries == 0 {
+                    Err(e)?;
+                }
+                thread::sleep(Duration::from_millis(1000));
+            }
+        };
+    }
+    info!("Wifi connected");
+
+    wifi.wait_netif_up()?;
+    info!("Wifi netif up");
+
+    network::set_on_home(home.is_some());
+    network::set_ap_addr(match settings.access_point {
+        Some(_) => Some(wifi.wifi().ap_netif().get_ip_info()?.ip),
+        None => None,
+    });
+
+    info!("Connected Wi-Fi with WIFI_SSID `{ssid}`");
+
+    Ok(wifi.wifi().sta_netif().get_ip_info()?.ip)
+}
+
+/// The STA on the LCD's network or home WIFI, with our AP next to it if there's one
+fn wifi_configuration(
+    config: &GatewayConfig,
+    settings: &NetConfig,
+    home: bool,
+) -> Result<wifi::Configuration> {
+    let client = match settings.home_wifi.as_ref().filter(|_| home) {
+        Some(home) => wifi::ClientConfiguration {
+            ssid: home
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("Home WIFI SSID too long"))?,
+            password: home
+                .password
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("Home WIFI password too long"))?,
+            auth_method: AuthMethod::WPA2Personal,
+            channel: Some(config.espnow_channel),
+            ..Default::default()
+        },
+        None => wifi::ClientConfiguration {
+            ssid: config.ssid.try_into().unwrap(),
+            auth_method: AuthMethod::None,
+            channel: Some(config.espnow_channel),
+            ..Default::default()
+        },
+    };
+
+    // One radio, so our AP has to share the LCD's channel, which keeps ESPNOW where it is
+    Ok(match &settings.access_point {
+        Some(ap) => {
+            info!("Also running AP ({})", ap.ssid);
+
+            wifi::Configuration::Mixed(
+                client,
+                wifi::AccessPointConfiguration {
+                    ssid: ap
+                        .ssid
+                        .as_str()
+                        .try_into()
+                        .map_err(|_| anyhow!("AP SSID too long"))?,
+                    password: ap
+                        .password
+                        .as_str()
+                        .try_into()
+                        .map_err(|_| anyhow!("AP password too long"))?,
+                    auth_method: AuthMethod::WPA2Personal,
+                    channel: config.espnow_channel,
+                    max_connections: AP_MAX_CONNECTIONS,
+                    ..Default::default()
+                },
+            )
+        }
+        None => wifi::Configuration::Client(client),
+    })
+}
+
+/// Whether `ssid` is on `channel`, from a scan with the STA alone. Leaves WIFI stopped.
+fn find_network(wifi: &mut BlockingWifi<EspWifi<'_>>, ssid: &str, channel: u8) -> Result<bool> {
+    wifi.set_configuration(&wifi::Configuration::Client(Default::default()))?;
+    wifi.start()?;
+
+    let found = wifi.scan().map(|aps| {
+        aps.iter()
+            .any(|ap| ap.ssid.as_str() == ssid && ap.channel == channel)
+    });
+
+    wifi.stop()?;
+
+    Ok(found?)
+}
+
+/// Back to the LCD's network once home WIFI drops, the van has most likely driven off. Home
+/// WIFI is joined again at the next boot.
+fn leave_home(
+    wifi: &mut BlockingWifi<EspWifi<'_>>,
+    config: &GatewayConfig,
+    net_settings: &NetSettings,
+) -> Result<()> {
+    info!("Home WIFI dropped, joining the LCD ({})", config.ssid);
+
+    wifi.set_configuration(&wifi_configuration(config, &net_settings.config(), false)?)?;
+    network::set_on_home(false);
+
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Requests answered from a thread of their own rather than the httpd task, `/wait` and
+//! `/events`. ESP-IDF's server runs every handler on its one task, so a handler that waits for
+//! something holds up every other client. These are registered with httpd directly, checked on
+//! the httpd task like any other request (see `Router::held_handler`), then handed over with
+//! `httpd_req_async_handler_begin` and the httpd task goes back to its sockets. The thread writes
+//! the response for as long as it needs and completes the request, which gives the socket back.
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    handle::RawHandle,
+    http::server::EspHttpServer,
+    sys::{
+        esp, esp_err_t, http_method_HTTP_GET, httpd_err_code_t_HTTPD_500_INTERNAL_SERVER_ERROR,
+        httpd_register_uri_handler, httpd_req_async_handler_begin,
+        httpd_req_async_handler_complete, httpd_req_get_hdr_value_len, httpd_req_get_hdr_value_str,
+        httpd_req_t, httpd_req_to_sockfd, httpd_resp_send, httpd_resp_send_chunk,
+        httpd_resp_send_err, httpd_resp_set_hdr, httpd_resp_set_status, httpd_resp_set_type,
+        httpd_uri_t, EspError, ESP_FAIL, ESP_OK,
+    },
+};
+use log::*;
+use serde::Serialize;
+
+use crate::request_log;
+
+/// Requests held at once, each has a thread and keeps its socket until it's answered
+const MAX_HELD: usize = 2;
+/// The thread only waits on the events and writes them out
+const STACK_SIZE: usize = 4096;
+/// Longer header values are treated as missing
+const MAX_HEADER_LEN: usize = 256;
+
+static HELD: AtomicUsize = AtomicUsize::new(0);
+static LIMIT: AtomicUsize = AtomicUsize::new(MAX_HELD);
+
+type HeldFn<'a> = Box<dyn Fn(Incoming) -> Result<()> + Send + 'a>;
+
+/// Hold at most one request fewer than the server has sockets, so a client that isn't waiting,
+/// e.g. the LCD, always gets in
+pub fn set_limit(max_open_sockets: usize) {
+    LIMIT.store(
+        max_open_sockets.saturating_sub(1).min(MAX_HELD),
+        Ordering::Relaxed,
+    );
+}
+
+/// Register `f` for GETs of `uri` with httpd itself, so nothing answers the request behind its
+/// back once it's held. The handler is never freed, the server runs until restart.
+///
+/// # Safety
+///
+/// Same as `EspHttpServer::fn_handler_nonstatic`, the handler must not outlive what it borrows.
+pub unsafe fn register<'a>(
+    server: &mut EspHttpServer<'a>,
+    uri: &'static CStr,
+    f: impl Fn(Incoming) -> Result<()> + Send + 'a,
+) -> Result<(), EspError> {
+    let f: HeldFn<'a> = Box::new(f);
+
+    let handler = httpd_uri_t {
+        uri: uri.as_ptr(),
+        method: http_method_HTTP_GET,
+        handler: Some(handle),
+        user_ctx: Box::into_raw(Box::new(f)) as *mut c_void,
+        ..Default::default()
+    };
+
+    esp!(httpd_register_uri_handler(server.handle(), &handler))
+}
+
+unsafe extern "C" fn handle(req: *mut httpd_req_t) -> esp_err_t {
+    let f = &*((*req).user_ctx as *const HeldFn<'static>);
+
+    match f(Incoming { req }) {
+        Ok(()) => ESP_OK,
+        Err(err) => {
+            // httpd closes the socket
+            error!("Held request failed {err:#}");
+            ESP_FAIL
+        }
+    }
+}
+
+/// A request on the httpd task, before it's held or answered
+pub struct Incoming {
+    req: *mut httpd_req_t,
+}
+
+impl Incoming {
+    /// With the query
+    pub fn uri(&self) -> &str {
+        unsafe { CStr::from_ptr((*self.req).uri.as_ptr()) }
+            .to_str()
+            .unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<String> {
+        let name = CString::new(name).ok()?;
+
+        unsafe {
+            let len = httpd_req_get_hdr_value_len(self.req, name.as_ptr());
+            if len == 0 || len > MAX_HEADER_LEN {
+                return None;
+            }
+
+            let mut value = vec![0u8; len + 1];
+            esp!(httpd_req_get_hdr_value_str(
+                self.req,
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut c_char,
+                value.len(),
+            ))
+            .ok()?;
+            value.truncate(len);
+
+            String::from_utf8(value).ok()
+        }
+    }
+
+    /// None if the socket's gone
+    pub fn sockfd(&self) -> Option<i32> {
+        let fd = unsafe { httpd_req_to_sockfd(self.req) };
+        (fd >= 0).then_some(fd)
+    }
+
+    /// Answer on the httpd task, for a request that isn't held
+    pub fn respond(self, status: u16, headers: &[(&str, &str)], body: &str) -> Result<()> {
+        // httpd keeps the pointers until the response is sent
+        let headers = headers
+            .iter()
+            .map(|(name, value)| Ok((CString::new(*name)?, CString::new(*value)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        unsafe {
+            esp!(httpd_resp_set_status(
+                self.req,
+                status_line(status).as_ptr()
+            ))?;
+            esp!(httpd_resp_set_type(self.req, c"text/plain".as_ptr()))?;
+            for (name, value) in &headers {
+                esp!(httpd_resp_set_hdr(self.req, name.as_ptr(), value.as_ptr()))?;
+            }
+            esp!(httpd_resp_send(
+                self.req,
+                body.as_ptr() as *const c_char,
+                body.len() as isize
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Hand the request to a thread of its own, which answers it with `f`. Turned away with a
+    /// 503 while as many as the limit are held.
+    pub fn hold(self, f: impl FnOnce(Held) -> Result<()> + Send + 'static) -> Result<()> {
+        let limit = LIMIT.load(Ordering::Relaxed);
+        let reserved = HELD
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+                (held < limit).then_some(held + 1)
+            })
+            .is_ok();
+        if !reserved {
+            request_log::set_status(503, None);
+            return self.respond(
+                503,
+                &[("Retry-After", "5")],
+                "Too many requests waiting, retry later",
+            );
+        }
+
+        let mut copy = ptr::null_mut();
+        if let Err(err) = esp!(unsafe { httpd_req_async_handler_begin(self.req, &mut copy) }) {
+            HELD.fetch_sub(1, Ordering::Relaxed);
+            return Err(anyhow!("Request not held {err}"));
+        }
+
+        let held = Held {
+            req: copy,
+            streaming: false,
+            sent: false,
+        };
+
+        // A thread that doesn't start drops `held`, which completes the request
+        thread::Builder::new()
+            .name("held".to_owned())
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                if let Err(err) = f(held) {
+                    debug!("Held request ended {err}");
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+/// A request held off the httpd task, completed when it's dropped
+pub struct Held {
+    /// httpd's copy of the request for the thread
+    req: *mut httpd_req_t,
+    /// A chunked response was started, it's ended on drop
+    streaming: bool,
+    sent: bool,
+}
+
+// The copy is the holding thread's alone until it completes it
+unsafe impl Send for Held {}
+
+impl Held {
+    /// Start a chunked response, each `send` goes out as it's written
+    pub fn stream(&mut self, content_type: &'static CStr) -> Result<()> {
+        unsafe {
+            esp!(httpd_resp_set_type(self.req, content_type.as_ptr()))?;
+            esp!(httpd_resp_set_hdr(
+                self.req,
+                c"Cache-Control".as_ptr(),
+                c"no-cache".as_ptr()
+            ))?;
+        }
+        self.streaming = true;
+
+        Ok(())
+    }
+
+    /// Fails once the client has gone
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.sent = true;
+        esp!(unsafe {
+            httpd_resp_send_chunk(
+                self.req,
+                data.as_ptr() as *const c_char,
+                data.len() as isize,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    pub fn json<T: Serialize>(mut self, value: &T) -> Result<()> {
+        let body = serde_json::to_vec(value)?;
+
+        self.sent = true;
+        unsafe {
+            esp!(httpd_resp_set_type(self.req, c"application/json".as_ptr()))?;
+            esp!(httpd_resp_send(
+                self.req,
+                body.as_ptr() as *const c_char,
+                body.len() as isize
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        unsafe {
+            if self.streaming {
+                // The last chunk, which fails harmlessly if the client has gone
+                httpd_resp_send_chunk(self.req, ptr::null(), 0);
+            } else if !self.sent {
+                httpd_resp_send_err(
+                    self.req,
+                    httpd_err_code_t_HTTPD_500_INTERNAL_SERVER_ERROR,
+                    ptr::null(),
+                );
+            }
+
+            httpd_req_async_handler_complete(self.req);
+        }
+
+        HELD.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn status_line(status: u16) -> &'static CStr {
+    match status {
+        400 => c"400 Bad Request",
+        401 => c"401 Unauthorized",
+        403 => c"403 Forbidden",
+        409 => c"409 Conflict",
+        503 => c"503 Service Unavailable",
+        _ => c"500 Internal Server Error",
+    }
+}
@@ -0,0 +1,3269 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CStr,
+    fs::{self, File},
+    path::PathBuf,
+    ptr,
+    sync::{mpsc::SyncSender, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use embedded_svc::http::Headers;
+#[cfg(feature = "bt")]
+use esp_idf_svc::bt::BdAddr;
+use esp_idf_svc::{
+    http::{
+        server::{Configuration, EspHttpConnection, EspHttpServer, Request},
+        Method,
+    },
+    io::{Read, Write},
+    sys::EspError,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+pub use bt_obd_gw_core::clock::uptime_ms;
+
+use crate::alerts::Alerts;
+use crate::api::{self, PostRequest, SnapshotRequest};
+use crate::auth::{AuthBackend, AUTH_HEADER};
+#[cfg(feature = "bt")]
+use crate::bt::{self, Bond};
+use crate::cbor;
+use crate::channels;
+use crate::clock::{self, TimeSource};
+use crate::coalesce::Coalescer;
+#[cfg(feature = "bt")]
+use crate::coex::{self, CoexRequest};
+use crate::config::{self, Settings};
+use crate::crash::CrashLog;
+#[cfg(feature = "bt")]
+use crate::discovery::{self, DiscoveryConfig, DiscoveryPolicy};
+use crate::drivecycle::{self, DriveCycle};
+use crate::dtc_scan::DtcScanner;
+use crate::ecus::{self, Ecu, Ecus};
+use crate::elm327::{Bus, Capabilities, Elm327, StnFilter, MAX_MONITOR_DURATION};
+use crate::elm_cache::{self, CacheConfig, ElmCache, CACHE_CONTROL_HEADER};
+use crate::elm_worker::{ElmWorker, Priority, PRIORITY_HEADER};
+use crate::error::{ElmError, ErrorBody, ErrorCode, LedBlink, UdsError};
+use crate::events;
+use crate::features::{Feature, Features};
+use crate::flow_control::{self, FlowControl, FlowControlConfig, FLOW_CONTROL_HEADER};
+use crate::fuel::FuelEconomy;
+use crate::held::{self, Held, Incoming};
+use crate::http_limits::{self, HttpLimits, HttpOverrides, HttpSettings};
+use crate::ignition::{Ignition, IgnitionState};
+use crate::init_script::{self, InitScript};
+use crate::j1939;
+use crate::keep_awake::{self, KeepAwake, KeepAwakeConfig};
+use crate::lifetime::{LifetimeCounts, LifetimeStats};
+use crate::link_speed::{self, LinkSpeed, LinkSpeedConfig};
+use crate::log_level::{self, LogLevelConfig, LogLevels};
+use crate::logger::{LogConfig, Logger};
+use crate::maintenance::{self, Maintenance, MaintenanceConfig};
+use crate::memory;
+use crate::metrics;
+use crate::monitors;
+use crate::network::{self, NetConfig, NetSettings, Network, WifiStatus, WifiSupervisor};
+use crate::peers::{self, Peers};
+use crate::persist::{Persist, PersistStats};
+use crate::pid;
+use crate::policy::{self, Policy, PolicyConfig, UNLOCK_HEADER};
+use crate::poll_pause;
+use crate::request_log;
+use crate::response_cache::REQUEST_ID_HEADER;
+use crate::sdcard;
+use crate::selftest::SelfTest;
+use crate::session::{Session, SessionMode};
+use crate::signing::{self, Signing, Verifier, SIGNATURE_HEADER};
+use crate::smoothing::{self, Smoothing, SmoothingConfig};
+#[cfg(feature = "bt")]
+use crate::spp_handler::{self, SppStats};
+use crate::system;
+use crate::tasks::{self, TaskConfig, Tasks};
+use crate::thresholds::{self, ThresholdRule, Thresholds};
+use crate::timeouts::{self, ElmTimeouts, TimeoutConfig, TIMEOUT_HEADER};
+use crate::tls::{self, ServerCert, TlsStore};
+use crate::uds;
+use crate::virtual_pids::{self, VirtualPid, VirtualPids};
+
+/// The TLS handshake needs far more stack than plain HTTP
+const HTTPS_STACK_SIZE: usize = 10240;
+/// Most frames returned by a monitoring session
+const MAX_MONITOR_FRAMES: usize = 200;
+/// `/wait` without a timeout
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+/// Longest `/wait`, it holds one of the few sockets meanwhile
+const MAX_WAIT: Duration = Duration::from_secs(300);
+/// Picks the adapter for `/post`, `/raw`, `/monitor` and `/uds`
+const DEVICE_PARAM: &str = "device";
+/// `?device=` name of the OBD adapter, the default
+const OBD_DEVICE: &str = "obd";
+/// Picks the CAN bus for `/post`, `/raw`, `/monitor` and `/uds`, see `Bus`
+const BUS_PARAM: &str = "bus";
+/// Addresses `/post` and `/uds` to one of the ECUs in `/ecus` by name
+const ECU_PARAM: &str = "ecu";
+
+type HttpRequest<'r, 'c> = Request<&'r mut EspHttpConnection<'c>>;
+
+/// Everything the HTTP handlers share. Must outlive the server.
+pub struct Services<'a, 'b, 'd> {
+    /// Adapter work goes through the worker, handlers never hold the adapter lock
+    pub elm_worker: &'a ElmWorker<'d>,
+    /// Workers for the other adapters by `?device=` name, see `GatewayConfig::aux_adapters`
+    pub aux_workers: &'a [(&'static str, ElmWorker<'d>)],
+    /// Read at setup
+    pub adapter: &'a Capabilities,
+    #[cfg(feature = "bt")]
+    pub adapter_addr: BdAddr,
+    /// Restarts and the final action when the adapter can't be reached at startup
+    #[cfg(feature = "bt")]
+    pub discovery: &'a DiscoveryPolicy,
+    pub led_blink: &'a SyncSender<LedBlink>,
+    pub signing: &'a Signing,
+    /// Global and per-target log levels
+    pub log_levels: &'a LogLevels,
+    /// Priorities and cores for the next boot
+    pub tasks: &'a Tasks,
+    pub features: &'a Features<'b>,
+    pub logger: &'a Logger,
+    pub coalescer: &'a Coalescer,
+    pub elm_cache: &'a ElmCache,
+    pub drive_cycle: &'a DriveCycle,
+    pub dtc_scanner: &'a DtcScanner,
+    pub fuel: &'a FuelEconomy,
+    pub virtual_pids: &'a VirtualPids,
+    pub smoothing: &'a Smoothing,
+    pub thresholds: &'a Thresholds,
+    pub alerts: &'a Alerts,
+    pub ignition: &'a Mutex<Ignition>,
+    pub auth: &'a dyn AuthBackend,
+    pub maintenance: &'a Maintenance,
+    pub tls: &'a TlsStore,
+    pub init_script: &'a InitScript,
+    pub timeouts: &'a ElmTimeouts,
+    pub link_speed: &'a LinkSpeed,
+    pub flow_control: &'a FlowControl,
+    pub keep_awake: &'a KeepAwake,
+    /// Found by the last `/ecus/scan`
+    pub ecus: &'a Ecus,
+    /// ESPNOW displays, see `POST /espnow/pairing`
+    pub peers: &'a Peers,
+    pub net_settings: &'a NetSettings,
+    /// The STA connection to the LCD's AP
+    pub wifi: &'a WifiSupervisor,
+    pub selftest: &'a SelfTest,
+    pub crash_log: &'a CrashLog,
+    pub lifetime: &'a LifetimeStats,
+    /// Batched writes of the values that change all the time
+    pub persist: &'a Persist,
+    pub http_settings: &'a HttpSettings,
+    /// The limits the server started with
+    pub http_limits: HttpLimits,
+    /// Records or replays the OBD adapter's requests
+    pub session: &'a Session,
+    /// Shared with the worker jobs, which check it as they write
+    pub policy: Arc<Policy>,
+}
+
+impl<'a, 'b, 'd> Services<'a, 'b, 'd> {
+    /// The worker for the adapter picked with `?device=`, the OBD adapter if none is given. None
+    /// for an unknown device.
+    fn worker(&self, req: &HttpRequest<'_, '_>) -> Option<&'a ElmWorker<'d>> {
+        match query_param(req.uri(), DEVICE_PARAM) {
+            None | Some(OBD_DEVICE) => Some(self.elm_worker),
+            Some(device) => self
+                .aux_workers
+                .iter()
+                .find(|(name, _)| *name == device)
+                .map(|(_, worker)| worker),
+        }
+    }
+
+    /// The policy to check this request's adapter work against, None if it sent the unlock
+    /// token or the policy is off
+    fn guard(&self, req: &HttpRequest<'_, '_>) -> Option<Arc<Policy>> {
+        (!self.policy.unlocked(req.header(UNLOCK_HEADER))).then(|| self.policy.clone())
+    }
+
+    /// The ECU picked with `?ecu=`, None to use the default header. An error for a name the last
+    /// scan didn't find.
+    fn ecu(&self, req: &HttpRequest<'_, '_>) -> Result<Option<Ecu>> {
+        query_param(req.uri(), ECU_PARAM)
+            .map(|name| {
+                self.ecus
+                    .find(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown ecu ({name}), see /ecus"))
+            })
+            .transpose()
+    }
+}
+
+/// Register all the gateway endpoints
+pub fn register_handlers<'a>(
+    server: &mut EspHttpServer<'a>,
+    services: &'a Services<'_, '_, '_>,
+) -> Result<()> {
+    let auth = services.auth;
+    let mut router = Router::new(server, auth).serve_on(services.net_settings.config().serve_on);
+
+    // The nonce for the challenge-response backend, open as it's needed to authenticate
+    unsafe {
+        router
+            .open_handler("/auth/challenge", Method::Get, move |req| {
+                match auth.nonce() {
+                    Some(nonce) => {
+                        req.into_ok_response()?.write_all(nonce.as_bytes())?;
+                        Ok(())
+                    }
+                    None => error_response(req, 404, "No challenge with this auth backend"),
+                }
+            })
+            .context("Register auth challenge handler")
+            .and(Ok(()))?
+    }
+
+    /* Handler to get log 'messages'. Not really using it... */
+    /*
+    server
+        .fn_handler::<anyhow::Error, _>("/log", Method::Get, |req| {
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+                .write_all(MSG_LOGGER.get_messages().as_bytes())
+                .map_err(anyhow::Error::from)
+        })
+        .context("Register log handler")
+        .and(Ok(()))?;
+    */
+
+    unsafe {
+        router
+            .handler("/status", Method::Get, move |req| {
+                json_response(req, &status(services))
+            })
+            .context("Register status handler")
+            .and(Ok(()))?
+    }
+
+    // Startup stages of this boot and the one before, with durations and the error of a failed
+    // stage
+    unsafe {
+        router
+            .handler("/selftest", Method::Get, move |req| {
+                json_response(req, &services.selftest.report())
+            })
+            .context("Register selftest handler")
+            .and(Ok(()))?
+    }
+
+    // This boot's reset reason and the last crash, panic message and backtrace included
+    unsafe {
+        router
+            .handler("/last-crash", Method::Get, move |req| {
+                json_response(req, &services.crash_log.status())
+            })
+            .context("Register last crash handler")
+            .and(Ok(()))?
+    }
+
+    // Forget the last crash
+    unsafe {
+        router
+            .handler("/last-crash", Method::Delete, move |req| {
+                services.crash_log.clear()?;
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register last crash clear handler")
+            .and(Ok(()))?
+    }
+
+    // ELM327 passthrough, the body is the command and the raw response is returned
+    unsafe {
+        router
+            .handler("/post", Method::Post, move |mut req| {
+                let request_id = req.header(REQUEST_ID_HEADER).map(str::to_owned);
+
+                let Some(buf) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+                request_log::set_command(&buf);
+
+                let Some(worker) = services.worker(&req) else {
+                    return error_response(req, 404, "Unknown device");
+                };
+
+                let post = match PostRequest::parse(
+                    buf,
+                    query_param(req.uri(), BUS_PARAM),
+                    req.header(TIMEOUT_HEADER),
+                    req.header(PRIORITY_HEADER),
+                ) {
+                    Ok(post) => post,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let ecu = match services.ecu(&req) {
+                    Ok(ecu) => ecu,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                // Checked here rather than on the worker, the coalescer and cache could otherwise
+                // answer a blocked request with another client's response
+                if let Some(guard) = services.guard(&req) {
+                    if let Err(err) = guard.check(&post.command) {
+                        return adapter_error_response(req, &err.into());
+                    }
+                }
+
+                services.led_blink.send(LedBlink::High)?;
+
+                // A retried request ID is answered from the cache on the worker
+                let transact = || post.transact(worker, request_id.clone(), ecu.clone());
+
+                // The coalescer and cache are keyed by request alone, so only for the OBD adapter
+                // on HS-CAN at the default header
+                let key = Coalescer::key(&post.command).filter(|_| {
+                    ptr::eq(worker, services.elm_worker) && post.bus == Bus::Hs && ecu.is_none()
+                });
+                let no_cache = req
+                    .header(CACHE_CONTROL_HEADER)
+                    .is_some_and(|value| value.contains("no-cache"));
+                let cached = key
+                    .as_deref()
+                    .filter(|_| !no_cache)
+                    .and_then(|key| services.elm_cache.get(key));
+
+                // Another client may already be asking for the same thing
+                let response = match (cached, key) {
+                    (Some(cached), _) => Ok(cached),
+                    (None, Some(key)) => services.coalescer.run(&key, transact, |response| {
+                        services.elm_cache.insert(&key, response)
+                    }),
+                    (None, None) => transact(),
+                }
+                .inspect(|response| {
+                    if let Some(id) = &request_id {
+                        worker.cache_response(id, response);
+                    }
+                });
+
+                // E.g. ATSH to another ECU, the cached responses were for the one before
+                if ptr::eq(worker, services.elm_worker) && ElmCache::retargets(&post.command) {
+                    let flushed = services.elm_cache.flush();
+                    debug!("Adapter retargeted, {flushed} cached responses dropped");
+                }
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                let req_string = match response {
+                    Ok(response) => response,
+                    Err(err) => return adapter_error_response(req, &err),
+                };
+
+                let mut resp = req.into_ok_response()?;
+
+                resp.write_all(req_string.as_bytes())?;
+
+                Ok(())
+            })
+            .context("Register service handler")
+            .and(Ok(()))?
+    }
+
+    // Raw CAN request, e.g. {"header": "DA10F1", "data": "03 22 F1 90"}, returns all frames
+    // received as {"frames": ["18 DA F1 10 ...", ...], "truncated": false}
+    unsafe {
+        router
+            .handler("/raw", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                let Some(worker) = services.worker(&req) else {
+                    return error_response(req, 404, "Unknown device");
+                };
+
+                let raw: RawRequest = match serde_json::from_slice(&body) {
+                    Ok(raw) => raw,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+                request_log::set_command(format!("{} {}", raw.header, raw.data).as_bytes());
+
+                let bus = match Bus::from_param(query_param(req.uri(), BUS_PARAM)) {
+                    Ok(bus) => bus,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let timeout_ms = match timeouts::from_header(req.header(TIMEOUT_HEADER)) {
+                    Ok(timeout_ms) => timeout_ms,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Bulk);
+                let guard = services.guard(&req);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let frames = worker.run(priority, move |elm327| {
+                    elm327.select_bus(bus)?;
+                    elm327.guarded(guard, |elm327| {
+                        elm327.with_timeout(timeout_ms, |elm327| {
+                            let frames = elm327.raw_request(&raw.header, &raw.data)?;
+                            Ok((frames, elm327.truncated()))
+                        })
+                    })
+                });
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                match frames {
+                    Ok((frames, truncated)) => {
+                        json_response(req, &RawResponse { frames, truncated })
+                    }
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register raw handler")
+            .and(Ok(()))?
+    }
+
+    // STN monitoring session, e.g. {"pass": [{"pattern": "18FEF1", "mask": "1FFFFF"}],
+    // "duration_ms": 2000}. Returns the frames received as {"frames": ["18 FE F1 00 ...", ...]}.
+    unsafe {
+        router
+            .handler("/monitor", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                let Some(worker) = services.worker(&req) else {
+                    return error_response(req, 404, "Unknown device");
+                };
+
+                let monitor: MonitorRequest = match serde_json::from_slice(&body) {
+                    Ok(monitor) => monitor,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+                request_log::set_command(b"STM");
+
+                let bus = match Bus::from_param(query_param(req.uri(), BUS_PARAM)) {
+                    Ok(bus) => bus,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let duration = Duration::from_millis(monitor.duration_ms as u64);
+                if duration.is_zero() || duration > MAX_MONITOR_DURATION {
+                    return error_response(
+                        req,
+                        400,
+                        &format!(
+                            "duration_ms must be 1 to {}",
+                            MAX_MONITOR_DURATION.as_millis()
+                        ),
+                    );
+                }
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Bulk);
+                let guard = services.guard(&req);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let frames = worker.run(priority, move |elm327| {
+                    elm327.select_bus(bus)?;
+                    elm327.guarded(guard, |elm327| {
+                        let frames = elm327.monitor(
+                            &monitor.pass,
+                            &monitor.block,
+                            duration,
+                            monitor.max_frames.min(MAX_MONITOR_FRAMES),
+                        )?;
+                        Ok((frames, elm327.truncated()))
+                    })
+                });
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                match frames {
+                    Ok((frames, truncated)) => {
+                        json_response(req, &RawResponse { frames, truncated })
+                    }
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register monitor handler")
+            .and(Ok(()))?
+    }
+
+    // The ECUs found by the last scan, e.g. [{"name": "ecm", "request": "DA10F1", "response":
+    // "18DAF110"}]
+    unsafe {
+        router
+            .handler("/ecus", Method::Get, move |req| {
+                json_response(req, &services.ecus.list())
+            })
+            .context("Register ecus handler")
+            .and(Ok(()))?
+    }
+
+    // Ask which ECUs answer on HS-CAN and keep them for ?ecu=, returns the list as /ecus
+    unsafe {
+        router
+            .handler("/ecus/scan", Method::Post, move |req| {
+                services.led_blink.send(LedBlink::High)?;
+
+                let result = api::find_ecus(services.elm_worker);
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                let found = match result {
+                    Ok(found) => found,
+                    Err(err) => return adapter_error_response(req, &err),
+                };
+                services.ecus.set(found)?;
+
+                json_response(req, &services.ecus.list())
+            })
+            .context("Register ecus scan handler")
+            .and(Ok(()))?
+    }
+
+    // Start reading the stored, pending and permanent DTCs of every ECU in the background, found
+    // first if there's been no /ecus/scan. Answers 202 with the status as GET /scan, 409 while a
+    // scan is running. Each ECU read is a `scan` event on /events.
+    unsafe {
+        router
+            .handler("/scan", Method::Post, move |req| {
+                if let Err(err) = services.dtc_scanner.start(services.ecus.list()) {
+                    return error_response(req, 409, &err.to_string());
+                }
+
+                request_log::set_status(202, None);
+                req.into_response(202, None, &[("Content-Type", "application/json")])?
+                    .write_all(&serde_json::to_vec(&services.dtc_scanner.status())?)?;
+
+                Ok(())
+            })
+            .context("Register DTC scan handler")
+            .and(Ok(()))?
+    }
+
+    // The last DTC scan, {"state": "running", "done": 1, "total": 3} while it runs, then
+    // {"state": "done", "ecus": [{"ecu": "ecm", "stored": [{"code": "P0301", "description":
+    // "Cylinder 1 misfire"}], "pending": [], "permanent": []}], "stored": 1, "pending": 0,
+    // "permanent": 0, "failed": 0}
+    unsafe {
+        router
+            .handler("/scan", Method::Get, move |req| {
+                json_response(req, &services.dtc_scanner.status())
+            })
+            .context("Register DTC scan status handler")
+            .and(Ok(()))?
+    }
+
+    // The registered ESPNOW displays, {"peers": ["24:6F:28:A1:B2:C3"], "pairing_ms": 0}
+    unsafe {
+        router
+            .handler("/espnow/peers", Method::Get, move |req| {
+                json_response(req, &services.peers.list())
+            })
+            .context("Register get ESPNOW peers handler")
+            .and(Ok(()))?
+    }
+
+    // Register the displays heard from in the next minute, e.g. /espnow/pairing?seconds=120.
+    // Returns the list as /espnow/peers.
+    unsafe {
+        router
+            .handler("/espnow/pairing", Method::Post, move |req| {
+                let duration = match query_param(req.uri(), "seconds").map(str::parse) {
+                    None => peers::DEFAULT_PAIRING,
+                    Some(Ok(seconds)) => Duration::from_secs(seconds),
+                    Some(Err(_)) => return error_response(req, 400, "seconds must be a number"),
+                };
+
+                peers::open_pairing(duration);
+
+                json_response(req, &services.peers.list())
+            })
+            .context("Register ESPNOW pairing handler")
+            .and(Ok(()))?
+    }
+
+    // Forget one display, /espnow/peers?mac=24:6F:28:A1:B2:C3, or all of them without an
+    // address. The messages are broadcast again once there are none.
+    unsafe {
+        router
+            .handler("/espnow/peers", Method::Delete, move |req| {
+                let mac = match query_param(req.uri(), "mac").map(peers::parse_mac) {
+                    None => None,
+                    Some(Some(mac)) => Some(mac),
+                    Some(None) => return error_response(req, 400, "Invalid address"),
+                };
+
+                if !services.peers.remove(mac)? {
+                    return error_response(req, 404, "Not registered");
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register delete ESPNOW peers handler")
+            .and(Ok(()))?
+    }
+
+    // Whether the main loop's adapter polls are paused, {"paused": true, "remaining_ms": 42000,
+    // "holds": 0}
+    unsafe {
+        router
+            .handler("/poll", Method::Get, move |req| {
+                json_response(req, &poll_pause::status())
+            })
+            .context("Register poll status handler")
+            .and(Ok(()))?
+    }
+
+    // Pause the polls, e.g. /poll/pause?seconds=120 before a sequence of /raw or /uds requests.
+    // They resume on their own after at most 10 minutes. Returns the status as /poll.
+    unsafe {
+        router
+            .handler("/poll/pause", Method::Post, move |req| {
+                let duration = match query_param(req.uri(), "seconds").map(str::parse) {
+                    None => poll_pause::DEFAULT_PAUSE,
+                    Some(Ok(seconds)) => Duration::from_secs(seconds),
+                    Some(Err(_)) => return error_response(req, 400, "seconds must be a number"),
+                };
+
+                poll_pause::pause(duration);
+
+                json_response(req, &poll_pause::status())
+            })
+            .context("Register poll pause handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/poll/resume", Method::Post, move |req| {
+                poll_pause::resume();
+
+                json_response(req, &poll_pause::status())
+            })
+            .context("Register poll resume handler")
+            .and(Ok(()))?
+    }
+
+    // UDS services, e.g. {"service": "read_did", "did": "F190", "header": "DA10F1"}. The header
+    // is optional and the default restored afterwards, as is the flow control after a request
+    // with `X-Flow-Control`.
+    unsafe {
+        router
+            .handler("/uds", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                let Some(worker) = services.worker(&req) else {
+                    return error_response(req, 404, "Unknown device");
+                };
+
+                let uds_req: UdsRequest = match serde_json::from_slice(&body) {
+                    Ok(uds_req) => uds_req,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+                request_log::set_command(uds_req.command().as_bytes());
+
+                let bus = match Bus::from_param(query_param(req.uri(), BUS_PARAM)) {
+                    Ok(bus) => bus,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let ecu = match services.ecu(&req) {
+                    Ok(ecu) => ecu,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+                if ecu.is_some() && uds_req.header.is_some() {
+                    return error_response(req, 400, "header and ecu can't both be given");
+                }
+
+                let timeout_ms = match timeouts::from_header(req.header(TIMEOUT_HEADER)) {
+                    Ok(timeout_ms) => timeout_ms,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let flow = match flow_control::from_header(req.header(FLOW_CONTROL_HEADER)) {
+                    Ok(flow) => flow,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Bulk);
+                let guard = services.guard(&req);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let result = worker.run(priority, move |elm327| {
+                    elm327.select_bus(bus)?;
+                    elm327.guarded(guard, |elm327| {
+                        elm327.with_flow_control(flow, |elm327| {
+                            elm327.with_timeout(timeout_ms, |elm327| match &ecu {
+                                Some(ecu) => {
+                                    ecus::with_ecu(elm327, ecu, |elm327| uds_req.run(elm327))
+                                }
+                                None => uds_req.run(elm327),
+                            })
+                        })
+                    })
+                });
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                let resp = match result {
+                    Ok(data) => UdsResponse {
+                        positive: true,
+                        data: Some(data.iter().map(|b| format!("{b:02X}")).collect()),
+                        nrc: None,
+                        reason: None,
+                    },
+                    Err(err) => match err.downcast_ref::<UdsError>() {
+                        Some(UdsError::Negative { nrc, .. }) => UdsResponse {
+                            positive: false,
+                            data: None,
+                            nrc: Some(*nrc),
+                            reason: Some(uds::nrc_name(*nrc)),
+                        },
+                        _ => return adapter_error_response(req, &err),
+                    },
+                };
+
+                json_response(req, &resp)
+            })
+            .context("Register uds handler")
+            .and(Ok(()))?
+    }
+
+    // Several mode 01 PIDs read together under one timestamp, e.g. /snapshot?pids=0C,0D,05,42.
+    // Virtual PIDs by name too, e.g. /snapshot?pids=0C,boost. Returns {"timestamp_ms": 12345,
+    // "time": "2025-06-01T14:03:07.250Z", "values": {"0C": 812.5, "boost": 40.0}}, PIDs with no
+    // response are left out. CBOR with `Accept: application/cbor`, see `cbor`.
+    unsafe {
+        router
+            .handler("/snapshot", Method::Get, move |req| {
+                let snapshot = match SnapshotRequest::parse(query_param(req.uri(), "pids"), |p| {
+                    services.virtual_pids.contains(p)
+                }) {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                // The virtual PIDs' inputs are read with the rest
+                let pids = match snapshot.to_read(services.virtual_pids.inputs(&snapshot.names)) {
+                    Ok(pids) => pids,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::High);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let result = SnapshotRequest::read(services.elm_worker, priority, pids, || {
+                    (uptime_ms(), clock::now())
+                });
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                let ((timestamp_ms, time), values) = match result {
+                    Ok(result) => result,
+                    Err(err) => return adapter_error_response(req, &err),
+                };
+
+                let virtual_values = services.virtual_pids.eval(&snapshot.names, &values);
+                let values = values
+                    .into_iter()
+                    .filter(|(pid, _)| snapshot.pids.contains(pid))
+                    .map(|(pid, value)| (format!("{pid:02X}"), value))
+                    .chain(
+                        snapshot
+                            .names
+                            .into_iter()
+                            .zip(virtual_values)
+                            .filter_map(|(name, value)| Some((name, value?))),
+                    )
+                    .collect();
+
+                if cbor::accepted(req.header("Accept")) {
+                    let body = cbor::snapshot(timestamp_ms, time.as_deref(), &values)?;
+                    return cbor_response(req, &body);
+                }
+
+                json_response(
+                    req,
+                    &Snapshot {
+                        timestamp_ms,
+                        time,
+                        values,
+                    },
+                )
+            })
+            .context("Register snapshot handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/time", Method::Get, move |req| {
+                json_response(
+                    req,
+                    &TimeStatus {
+                        time: clock::now(),
+                        source: clock::source(),
+                        uptime_ms: uptime_ms(),
+                    },
+                )
+            })
+            .context("Register get time handler")
+            .and(Ok(()))?
+    }
+
+    // {"unix_ms": 1748786587250}, for when there's no internet for SNTP, e.g. from the LCD.
+    // Ignored once SNTP has synced.
+    unsafe {
+        router
+            .handler("/time", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                let result = serde_json::from_slice::<TimeSet>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|set| clock::set(set.unix_ms));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register set time handler")
+            .and(Ok(()))?
+    }
+
+    // A J1939 PGN by number, e.g. /j1939/pgn/65253 for engine hours. ?bus=j1939_500 for a 500
+    // kbaud bus. Returns {"pgn": 65253, "source": "00", "data": "...", "spns": [{"spn": 247,
+    // "name": "engine_hours", "value": 1234.5, "unit": "h"}]}.
+    unsafe {
+        router
+            .handler("/j1939/pgn/*", Method::Get, move |req| {
+                let pgn = req
+                    .uri()
+                    .trim_start_matches("/j1939/pgn/")
+                    .split('?')
+                    .next()
+                    .and_then(|pgn| pgn.parse::<u32>().ok());
+
+                let Some(pgn) = pgn else {
+                    return error_response(req, 400, "PGN must be a decimal number");
+                };
+
+                let bus = match query_param(req.uri(), BUS_PARAM) {
+                    None => Ok(Bus::J1939),
+                    bus => Bus::from_param(bus),
+                };
+                let bus = match bus {
+                    Ok(bus) => bus,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Normal);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let result = services
+                    .elm_worker
+                    .run(priority, move |elm327| j1939::request_pgn(elm327, bus, pgn));
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                match result {
+                    Ok(response) => json_response(req, &response),
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register J1939 PGN handler")
+            .and(Ok(()))?
+    }
+
+    // Mode 06 test results, every supported OBDMID or ?mid=01 for one. Returns [{"module":
+    // "7E8", "mid": "01", "monitor": "o2_sensor_b1s1", "tid": "01", "test":
+    // "rich_to_lean_threshold", "value": 0.45, "min": 0.4, "max": 0.5, "unit": "V", "passed":
+    // true}].
+    unsafe {
+        router
+            .handler("/monitors", Method::Get, move |req| {
+                let mid = match query_param(req.uri(), "mid")
+                    .map(|mid| u8::from_str_radix(mid, 16))
+                    .transpose()
+                {
+                    Ok(mid) => mid,
+                    Err(_) => return error_response(req, 400, "mid must be a hex OBDMID"),
+                };
+
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Bulk);
+
+                services.led_blink.send(LedBlink::High)?;
+
+                let result = services
+                    .elm_worker
+                    .run(priority, move |elm327| monitors::request(elm327, mid));
+
+                services.led_blink.send(LedBlink::Low)?;
+
+                match result {
+                    Ok(results) => json_response(req, &results),
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register monitors handler")
+            .and(Ok(()))?
+    }
+
+    // Long poll a logged PID, e.g. /wait?pid=0C&timeout=10&delta=50&value=812.5. Answers once
+    // the value on `/events` has moved by at least delta (any change without one) from `value`,
+    // or the latest value, or when the timeout in seconds runs out. Held off the httpd task, see
+    // `held`. Returns {"pid": "0C", "value": 862.5, "changed": true, "timestamp_ms": 12345}.
+    unsafe {
+        router
+            .held_handler(c"/wait", move |req| {
+                let param = |name| query_param(req.uri(), name);
+                let params = (|| {
+                    let pid = param("pid")?;
+                    let timeout = param("timeout")
+                        .map_or(Ok(DEFAULT_WAIT.as_secs()), str::parse)
+                        .ok()?;
+                    let delta = param("delta").map_or(Ok(0.0), str::parse::<f32>).ok()?;
+                    let since = param("value").map(str::parse::<f32>).transpose().ok()?;
+
+                    Some((
+                        pid.to_owned(),
+                        Duration::from_secs(timeout).min(MAX_WAIT),
+                        delta,
+                        since,
+                    ))
+                })();
+
+                let Some((pid, timeout, delta, since)) = params else {
+                    return held_error(
+                        req,
+                        400,
+                        "pid must be a hex PID or virtual PID name, timeout, delta and value numbers",
+                    );
+                };
+
+                // Keyed as on `/events`, the values followed are the logger's
+                let key = match u8::from_str_radix(&pid, 16) {
+                    Ok(p) => format!("{p:02X}"),
+                    Err(_) => pid,
+                };
+                let logged = services.logger.config().pids.iter().any(|logged| {
+                    match u8::from_str_radix(logged, 16) {
+                        Ok(p) => format!("{p:02X}") == key,
+                        Err(_) => *logged == key,
+                    }
+                });
+                if !logged {
+                    return held_error(req, 400, &format!("{key} isn't a logged PID"));
+                }
+                if !services.logger.trip_active() {
+                    return held_error(req, 409, "No trip is being logged");
+                }
+
+                req.hold(move |held| wait_for_change(held, key, timeout, delta, since))
+            })
+            .context("Register wait handler")
+            .and(Ok(()))?
+    }
+
+    // Logged PID values and adapter link changes as server-sent events, see `events`. The stream
+    // stays open, held off the httpd task (see `held`), with a comment line when there's been
+    // nothing to send for a while. A client that reconnects sends Last-Event-ID (or
+    // ?last_event_id=) and gets what it missed. Without one only newer events are sent.
+    unsafe {
+        router
+            .held_handler(c"/events", move |req| {
+                let last_id = req
+                    .header("Last-Event-ID")
+                    .or_else(|| query_param(req.uri(), "last_event_id").map(str::to_owned))
+                    .map(|id| id.trim().parse::<u32>())
+                    .transpose();
+
+                let last_id = match last_id {
+                    Ok(last_id) => last_id.unwrap_or_else(events::last_id),
+                    Err(_) => return held_error(req, 400, "Last-Event-ID must be a number"),
+                };
+
+                req.hold(move |held| stream_events(held, last_id))
+            })
+            .context("Register events handler")
+            .and(Ok(()))?
+    }
+
+    // Restart straight away, the batched NVS values are written first
+    unsafe {
+        router
+            .handler("/system/reboot", Method::Post, move |req| {
+                system::reboot(services.persist)?;
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register reboot handler")
+            .and(Ok(()))?
+    }
+
+    // Erase the settings and bonds, then restart. The first request returns {"confirm":
+    // "9f2c01ab7e3d4410", "expires_s": 30}, the reset happens when that's sent back as
+    // /system/factory-reset?confirm=9f2c01ab7e3d4410 in time. Both need the unlock token, and
+    // once signing is enabled the second is signed over the confirm token.
+    unsafe {
+        router
+            .handler("/system/factory-reset", Method::Post, move |req| {
+                if !services.policy.unlocked(req.header(UNLOCK_HEADER)) {
+                    return error_response(req, 403, "Unlock token required");
+                }
+
+                let Some(token) = query_param(req.uri(), "confirm") else {
+                    let confirm = ResetConfirm {
+                        confirm: system::confirm_token(),
+                        expires_s: system::CONFIRM_LIFETIME.as_secs(),
+                    };
+                    let body = serde_json::to_vec(&confirm)?;
+
+                    request_log::set_status(202, None);
+                    req.into_response(202, None, &[("Content-Type", "application/json")])?
+                        .write_all(&body)?;
+
+                    return Ok(());
+                };
+
+                if let Err(err) = services
+                    .signing
+                    .verify(req.header(SIGNATURE_HEADER), token.as_bytes())
+                {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                if !system::confirmed(token) {
+                    return error_response(req, 403, "Wrong or expired confirm token");
+                }
+
+                system::factory_reset()?;
+                system::restart()?;
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register factory reset handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/tasks", Method::Get, move |req| {
+                json_response(req, &services.tasks.config())
+            })
+            .context("Register get task settings handler")
+            .and(Ok(()))?
+    }
+
+    // {"elm_worker_priority": 6, "elm_worker_core": 0, "http_priority": 5, "main_priority": 3,
+    // "led_priority": 2}, a field left out gets its default. Used from the next boot.
+    unsafe {
+        router
+            .handler("/config/tasks", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, tasks::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<TaskConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.tasks.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put task settings handler")
+            .and(Ok(()))?
+    }
+
+    // Counters, heap and the last PID values in the Prometheus text format
+    unsafe {
+        router
+            .handler("/metrics", Method::Get, move |req| {
+                req.into_response(200, None, &[("Content-Type", "text/plain; version=0.0.4")])?
+                    .write_all(metrics::render().as_bytes())?;
+
+                Ok(())
+            })
+            .context("Register metrics handler")
+            .and(Ok(()))?
+    }
+
+    // Drop every cached response, returns how many there were
+    unsafe {
+        router
+            .handler("/cache/flush", Method::Post, move |req| {
+                let flushed = services.elm_cache.flush();
+
+                req.into_ok_response()?
+                    .write_all(flushed.to_string().as_bytes())?;
+
+                Ok(())
+            })
+            .context("Register cache flush handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/cache", Method::Get, move |req| {
+                json_response(req, &services.elm_cache.config())
+            })
+            .context("Register get cache config handler")
+            .and(Ok(()))?
+    }
+
+    // {"ttl_ms": {"0105": 2000, "010C": 200, "0902": null}}, null caches it for good
+    unsafe {
+        router
+            .handler("/config/cache", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, elm_cache::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<CacheConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.elm_cache.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put cache config handler")
+            .and(Ok(()))?
+    }
+
+    // Every channel the gateway can read with its unit, range, update rate and source
+    unsafe {
+        router
+            .handler("/channels", Method::Get, move |req| {
+                json_response(
+                    req,
+                    &channels::list(
+                        &services.logger.config(),
+                        &services.maintenance.config(),
+                        &services.virtual_pids.config(),
+                    ),
+                )
+            })
+            .context("Register channels handler")
+            .and(Ok(()))?
+    }
+
+    // Mode 01 PIDs the vehicle supports as hex, e.g. ["01", "04", "05", "0C"], from the bitmaps
+    // read at startup
+    unsafe {
+        router
+            .handler(
+                "/pids/supported",
+                Method::Get,
+                move |req| match pid::supported() {
+                    Some(pids) => {
+                        let pids = pids.iter().map(|p| format!("{p:02X}")).collect::<Vec<_>>();
+                        json_response(req, &pids)
+                    }
+                    None => error_response(req, 503, "Supported PIDs not discovered"),
+                },
+            )
+            .context("Register supported pids handler")
+            .and(Ok(()))?
+    }
+
+    // I/M monitor progress for this trip, which monitors are still incomplete and what driving
+    // they're waiting for
+    unsafe {
+        router
+            .handler("/drivecycle", Method::Get, move |req| {
+                match services.drive_cycle.report() {
+                    Some(report) => json_response(req, &report),
+                    None => error_response(req, 503, "Monitor status not read yet"),
+                }
+            })
+            .context("Register drive cycle handler")
+            .and(Ok(()))?
+    }
+
+    // MIL, DTC count and monitor readiness from a fresh read of PID 01, e.g. before an emissions
+    // test. Returns {"mil": false, "dtc_count": 0, "engine": "spark", "ready": false,
+    // "monitors": [{"name": "catalyst", "ready": true}, {"name": "evap", "ready": false}]}.
+    unsafe {
+        router
+            .handler("/readiness", Method::Get, move |req| {
+                let priority = Priority::from_header(req.header(PRIORITY_HEADER), Priority::Normal);
+
+                let result = services.elm_worker.run(priority, |elm327| {
+                    let data = pid::request_data(elm327, pid::MONITOR_STATUS)?;
+                    if data.len() < 4 {
+                        Err(ElmError::NoData("pid 01 too short".to_owned()))?;
+                    }
+                    Ok(data)
+                });
+
+                match result {
+                    Ok(data) => json_response(req, &drivecycle::readiness(&data)),
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register readiness handler")
+            .and(Ok(()))?
+    }
+
+    // Fuel economy, the trip totals and the last sample. Returns {"distance_km": 42.1, "fuel_l":
+    // 3.2, "duration_s": 2400, "started": "2025-06-01T14:03:07.250Z", "avg_l_per_100km": 7.6,
+    // "avg_km_per_l": 13.2, "instant": {"speed_kmh": 88.0, "l_per_h": 5.9, "l_per_100km": 6.7},
+    // "source": "maf"}.
+    unsafe {
+        router
+            .handler("/trip", Method::Get, move |req| {
+                json_response(req, &services.fuel.report())
+            })
+            .context("Register trip handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/trip/reset", Method::Post, move |req| {
+                if let Err(err) = services.fuel.reset() {
+                    error!("Fuel trip reset failed {err}");
+                    return error_response(req, 500, "Trip reset failed");
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register trip reset handler")
+            .and(Ok(()))?
+    }
+
+    // Recent battery voltage alerts from the adapter, oldest first
+    unsafe {
+        router
+            .handler("/alerts", Method::Get, move |req| {
+                json_response(req, &services.alerts.recent())
+            })
+            .context("Register alerts handler")
+            .and(Ok(()))?
+    }
+
+    // Threshold rules triggered and not cleared, and the last changes oldest first. Returns
+    // {"active": ["coolant_hot"], "events": [{"name": "coolant_hot", "active": true, "value": 111.0,
+    // "uptime_ms": 905112, "time": "2025-06-01T14:18:12.004Z"}]}
+    unsafe {
+        router
+            .handler("/thresholds", Method::Get, move |req| {
+                json_response(req, &services.thresholds.report())
+            })
+            .context("Register thresholds handler")
+            .and(Ok(()))?
+    }
+
+    // Bonded BT devices, [{"address": "00:04:3e:83:fc:98", "adapter": true}]
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/bt/bonds", Method::Get, move |req| {
+                let bonds = bt::bonded_devices()?
+                    .into_iter()
+                    .map(|addr| Bond {
+                        address: addr.to_string(),
+                        adapter: addr == services.adapter_addr,
+                    })
+                    .collect::<Vec<_>>();
+
+                json_response(req, &bonds)
+            })
+            .context("Register get bonds handler")
+            .and(Ok(()))?
+    }
+
+    // Remove one bond, /bt/bonds?addr=00:04:3E:83:FC:98, or all of them with /bt/bonds?all=true
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/bt/bonds", Method::Delete, move |req| {
+                let bonds = bt::bonded_devices()?;
+
+                // Every bond only when asked for, a missing addr mustn't unpair everything
+                let all = query_param(req.uri(), "all") == Some("true");
+                let remove = match query_param(req.uri(), "addr").map(bt::parse_addr) {
+                    None if all => bonds,
+                    None => return error_response(req, 400, "addr or all=true required"),
+                    Some(_) if all => {
+                        return error_response(req, 400, "addr and all can't both be given")
+                    }
+                    Some(Some(addr)) if bonds.contains(&addr) => vec![addr],
+                    Some(Some(_)) => return error_response(req, 404, "Not bonded"),
+                    Some(None) => return error_response(req, 400, "Invalid address"),
+                };
+
+                for addr in &remove {
+                    bt::remove_bond(addr)?;
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register delete bonds handler")
+            .and(Ok(()))?
+    }
+
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/config/discovery", Method::Get, move |req| {
+                json_response(req, &services.discovery.config())
+            })
+            .context("Register get discovery policy handler")
+            .and(Ok(()))?
+    }
+
+    // {"max_retries": 3, "backoff_s": [5, 30, 120], "final_action": "idle_with_error"}, final
+    // action "keep_trying" or "deep_sleep" otherwise. Used at the next startup that can't reach
+    // the adapter.
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/config/discovery", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, discovery::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<DiscoveryConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.discovery.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put discovery policy handler")
+            .and(Ok(()))?
+    }
+
+    // Start looking for nearby devices for about /bt/scan?seconds=10 (up to 30), answers 202
+    // straight away. GET /bt/scan has the devices found.
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/bt/scan", Method::Post, move |req| {
+                let seconds = match query_param(req.uri(), "seconds").map(str::parse::<u64>) {
+                    None => 10,
+                    Some(Ok(seconds)) if (1..=bt::MAX_SCAN_SECS).contains(&seconds) => seconds,
+                    _ => {
+                        return error_response(
+                            req,
+                            400,
+                            &format!("seconds must be 1 to {}", bt::MAX_SCAN_SECS),
+                        )
+                    }
+                };
+
+                if bt::scanning() {
+                    return error_response(req, 409, "Scan already running");
+                }
+
+                bt::start_scan(Duration::from_secs(seconds))?;
+
+                request_log::set_status(202, None);
+                req.into_response(202, None, &[("Content-Type", "application/json")])?
+                    .write_all(&serde_json::to_vec(&bt::scan_status())?)?;
+
+                Ok(())
+            })
+            .context("Register scan handler")
+            .and(Ok(()))?
+    }
+
+    // The devices found so far by the scan running, or by the last one, e.g. {"scanning": false,
+    // "devices": [...]}
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/bt/scan", Method::Get, move |req| {
+                json_response(req, &bt::scan_status())
+            })
+            .context("Register scan status handler")
+            .and(Ok(()))?
+    }
+
+    // Forget the adapter's bond and connect again, pairing with the configured PIN. Answers 202
+    // once discovery has started, requests wait for the connection.
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/bt/pair", Method::Post, move |req| {
+                let addr = services.adapter_addr;
+
+                if bt::bonded_devices()?.contains(&addr) {
+                    bt::remove_bond(&addr)?;
+                }
+
+                match api::reconnect(services.elm_worker) {
+                    Ok(()) => {
+                        request_log::set_status(202, None);
+                        req.into_status_response(202)?;
+                        Ok(())
+                    }
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register pair handler")
+            .and(Ok(()))?
+    }
+
+    // Maintenance items with how far off each one is
+    unsafe {
+        router
+            .handler("/maintenance", Method::Get, move |req| {
+                json_response(req, &services.maintenance.report())
+            })
+            .context("Register maintenance handler")
+            .and(Ok(()))?
+    }
+
+    // Body is the item name, records it as done at the current odometer and engine hours
+    unsafe {
+        router
+            .handler("/maintenance/done", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                let name = String::from_utf8_lossy(&body);
+                if let Err(err) = services.maintenance.done(name.trim()) {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register maintenance done handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/maintenance", Method::Get, move |req| {
+                json_response(req, &services.maintenance.config())
+            })
+            .context("Register get maintenance config handler")
+            .and(Ok(()))?
+    }
+
+    // {"items": [{"name": "oil", "every_km": 10000, "last_km": 152000}]}
+    unsafe {
+        router
+            .handler("/config/maintenance", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, maintenance::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<MaintenanceConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.maintenance.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put maintenance config handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/virtual-pids", Method::Get, move |req| {
+                json_response(req, &services.virtual_pids.config())
+            })
+            .context("Register get virtual pids handler")
+            .and(Ok(()))?
+    }
+
+    // [{"name": "boost", "expr": "map - baro", "unit": "kPa", "min": -100, "max": 250}],
+    // replaces them all
+    unsafe {
+        router
+            .handler("/config/virtual-pids", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, virtual_pids::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<Vec<VirtualPid>>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.virtual_pids.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put virtual pids handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/thresholds", Method::Get, move |req| {
+                json_response(req, &services.thresholds.config())
+            })
+            .context("Register get thresholds handler")
+            .and(Ok(()))?
+    }
+
+    // [{"name": "coolant_hot", "channel": "05", "op": ">", "threshold": 110, "hysteresis": 5},
+    // {"name": "battery_low", "channel": "battery", "op": "<", "threshold": 12.0, "when": "parked"}],
+    // replaces them all
+    unsafe {
+        router
+            .handler("/config/thresholds", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, thresholds::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<Vec<ThresholdRule>>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|rules| services.thresholds.set_config(rules, services.virtual_pids));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put thresholds handler")
+            .and(Ok(()))?
+    }
+
+    // Set (hex body) or clear (empty body) the upload signing key. Once enabled the new key must
+    // be signed with the current one.
+    unsafe {
+        router
+            .handler("/config/signing-key", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, 130)? else {
+                    return error_response(req, 413, "Key too long");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let Some(key) = signing::decode_hex(String::from_utf8_lossy(&body).trim()) else {
+                    return error_response(req, 400, "Key must be hex");
+                };
+
+                services.signing.set_key(&key)?;
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register signing key handler")
+            .and(Ok(()))?
+    }
+
+    // Set or clear (empty body) the auth backend secret, plain text for the token backend and hex
+    // for the challenge key. Changing it needs the current credentials, and a signature once
+    // signing is enabled.
+    unsafe {
+        router
+            .handler("/config/auth-token", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, 130)? else {
+                    return error_response(req, 413, "Secret too long");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                if let Err(err) = auth.set_secret(String::from_utf8_lossy(&body).trim()) {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register auth secret handler")
+            .and(Ok(()))?
+    }
+
+    // PEM certificate and private key for HTTPS, empty body removes it. Takes effect at the next
+    // boot.
+    unsafe {
+        router
+            .handler("/config/tls-cert", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, tls::MAX_PEM_LEN)? else {
+                    return error_response(req, 413, "Certificate too long");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                if let Err(err) = services.tls.set_cert(&body) {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register TLS certificate handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/tls-key", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, tls::MAX_PEM_LEN)? else {
+                    return error_response(req, 413, "Key too long");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                if let Err(err) = services.tls.set_key(&body) {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register TLS key handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/init-script", Method::Get, move |req| {
+                json_response(req, &services.init_script.commands())
+            })
+            .context("Register get init script handler")
+            .and(Ok(()))?
+    }
+
+    // Adapter setup commands replacing the built in protocol and formatting ones, e.g.
+    // ["STP 33", "ATH 1", "ATCAF 1", "ATS 1", "ATFCSH 7E0"]. An empty list goes back to the
+    // built in setup. The adapter is set up with it straight away, and it's only stored if that
+    // works.
+    unsafe {
+        router
+            .handler("/config/init-script", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, init_script::MAX_SCRIPT_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let commands = match serde_json::from_slice::<Vec<String>>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|commands| init_script::check(&commands).map(|_| commands))
+                {
+                    Ok(commands) => commands,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                // Stored once the adapter takes it, the old one goes back if it doesn't
+                let script = commands.clone();
+                let result = services.elm_worker.run(Priority::Normal, move |elm327| {
+                    elm327.setup_with(|elm327| elm327.set_init_script(script))
+                });
+
+                // The script may set another protocol or header whether or not it worked
+                services.elm_cache.flush();
+
+                if let Err(err) = result {
+                    return adapter_error_response(req, &err.context("Adapter setup failed"));
+                }
+
+                if let Err(err) = services.init_script.set(commands) {
+                    return error_response(req, 500, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register post init script handler")
+            .and(Ok(()))?
+    }
+
+    // Adapter timeouts outside the cold start profile, e.g. {"adaptive": 1, "timeout_ms": 205}
+    unsafe {
+        router
+            .handler("/config/timeouts", Method::Get, move |req| {
+                json_response(req, &services.timeouts.config())
+            })
+            .context("Register get timeouts handler")
+            .and(Ok(()))?
+    }
+
+    // Stored, then sent to the adapter
+    unsafe {
+        router
+            .handler("/config/timeouts", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, timeouts::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<TimeoutConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.timeouts.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                let config = services.timeouts.config();
+                let result = services.elm_worker.run(Priority::Normal, move |elm327| {
+                    elm327.set_timeouts(config);
+                    elm327.set_timing(elm327.timing())
+                });
+
+                match result {
+                    Ok(()) => {
+                        req.into_ok_response()?;
+                        Ok(())
+                    }
+                    Err(err) => adapter_error_response(req, &err),
+                }
+            })
+            .context("Register put timeouts handler")
+            .and(Ok(()))?
+    }
+
+    // Flow control for long responses, e.g. {"header": "18DA10F1", "block_size": 0,
+    // "separation_time": 10}. All defaults, or {}, is the adapter's own.
+    unsafe {
+        router
+            .handler("/config/flow-control", Method::Get, move |req| {
+                json_response(req, &services.flow_control.config())
+            })
+            .context("Register get flow control handler")
+            .and(Ok(()))?
+    }
+
+    // The adapter is set up again with it, and it's only stored if that works
+    unsafe {
+        router
+            .handler("/config/flow-control", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, flow_control::MAX_REQUEST_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let config = match serde_json::from_slice::<FlowControlConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| config.check().map(|_| config))
+                {
+                    Ok(config) => config,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                if let Err(err) = apply_flow_control(services, config) {
+                    return adapter_error_response(req, &err);
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put flow control handler")
+            .and(Ok(()))?
+    }
+
+    // Seconds without traffic before the adapter is pinged, e.g. {"interval_s": 60}, null for never
+    unsafe {
+        router
+            .handler("/config/keep-awake", Method::Get, move |req| {
+                json_response(req, &services.keep_awake.config())
+            })
+            .context("Register get keep awake handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/keep-awake", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, keep_awake::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<KeepAwakeConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.keep_awake.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                json_response(req, &services.keep_awake.config())
+            })
+            .context("Register put keep awake handler")
+            .and(Ok(()))?
+    }
+
+    // STBR rate for STN adapters, e.g. {"baud": 115200}, null for the power on rate
+    unsafe {
+        router
+            .handler("/config/link-speed", Method::Get, move |req| {
+                json_response(req, &services.link_speed.config())
+            })
+            .context("Register get link speed handler")
+            .and(Ok(()))?
+    }
+
+    // Stored, then the adapter is set up again to switch. Answers with the rate the link ended up
+    // at, null if the adapter stayed at its power on rate.
+    unsafe {
+        router
+            .handler("/config/link-speed", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, link_speed::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<LinkSpeedConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.link_speed.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                let baud = services.link_speed.config().baud;
+                let result = services.elm_worker.run(Priority::Normal, move |elm327| {
+                    elm327.set_baud_rate(baud);
+                    elm327.setup()?;
+                    Ok(elm327.capabilities().baud_rate)
+                });
+
+                match result {
+                    Ok(baud) => json_response(req, &LinkSpeedConfig { baud }),
+                    Err(err) => adapter_error_response(req, &err.context("Adapter setup failed")),
+                }
+            })
+            .context("Register put link speed handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/network", Method::Get, move |req| {
+                let mut config = services.net_settings.config();
+                config.redact();
+
+                json_response(req, &config)
+            })
+            .context("Register get network config handler")
+            .and(Ok(()))?
+    }
+
+    // {"static_ip": {"ip": "192.168.4.2", "gateway": "192.168.4.1", "netmask": "255.255.255.0",
+    // "dns": "192.168.4.1"}, "hostname": "obd-gw", "access_point": {"ssid": "obd-gw",
+    // "password": "..."}, "home_wifi": {"ssid": "home", "password": "..."}, "serve_on":
+    // ["home", "access_point"]}. Leave static_ip out for DHCP, an empty password keeps the
+    // stored one. Used from the next boot.
+    unsafe {
+        router
+            .handler("/config/network", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, network::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<NetConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mut config| {
+                        config.keep_passwords(services.net_settings.config());
+                        services.net_settings.set_config(config)
+                    });
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put network config handler")
+            .and(Ok(()))?
+    }
+
+    // Every setting in one document, the WIFI passwords left out
+    unsafe {
+        router
+            .handler("/config", Method::Get, move |req| {
+                json_response(req, &Settings::redacted(services))
+            })
+            .context("Register get config handler")
+            .and(Ok(()))?
+    }
+
+    // Any sections of `GET /config`, the ones left out are kept. Stored all or none, then the
+    // adapter is set up again if its init script, timeouts, link speed or flow control are among
+    // them, and they all go back if that fails. The policy needs the unlock token.
+    unsafe {
+        router
+            .handler("/config", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, config::MAX_DOCUMENT_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let settings = match serde_json::from_slice::<Settings>(&body) {
+                    Ok(settings) => settings,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                // As `PUT /config/policy`
+                if settings.policy.is_some() && !services.policy.unlocked(req.header(UNLOCK_HEADER))
+                {
+                    return error_response(req, 403, "Unlock token required to change the policy");
+                }
+
+                let changes_adapter = settings.changes_adapter();
+                let earlier = match settings.apply(services) {
+                    Ok(earlier) => earlier,
+                    Err(err) => return error_response(req, 400, &format!("{err:#}")),
+                };
+
+                // A setting the adapter refuses would fail every boot, so it isn't kept
+                if changes_adapter {
+                    let commands = services.init_script.commands();
+                    let timeouts = services.timeouts.config();
+                    let baud = services.link_speed.config().baud;
+                    let flow_control = services.flow_control.config();
+
+                    let result = services.elm_worker.run(Priority::Normal, move |elm327| {
+                        elm327.setup_with(|elm327| {
+                            elm327.set_init_script(commands);
+                            elm327.set_timeouts(timeouts);
+                            elm327.set_baud_rate(baud);
+                            elm327.set_flow_control(flow_control);
+                        })
+                    });
+
+                    if let Err(err) = result {
+                        earlier.restore(services);
+                        return adapter_error_response(req, &err.context("Adapter setup failed"));
+                    }
+                }
+
+                json_response(req, &Settings::redacted(services))
+            })
+            .context("Register put config handler")
+            .and(Ok(()))?
+    }
+
+    // The limits in use and the overrides from NVS, which apply from the next boot
+    unsafe {
+        router
+            .handler("/config/http", Method::Get, move |req| {
+                json_response(
+                    req,
+                    &HttpConfig {
+                        limits: services.http_limits,
+                        overrides: services.http_settings.overrides(),
+                    },
+                )
+            })
+            .context("Register get HTTP config handler")
+            .and(Ok(()))?
+    }
+
+    // {"max_body_len": 4096, "max_open_sockets": 4}, anything left out keeps the build's value.
+    // Used from the next boot.
+    unsafe {
+        router
+            .handler("/config/http", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, http_limits::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<HttpOverrides>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|overrides| services.http_settings.set_overrides(overrides));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put HTTP config handler")
+            .and(Ok(()))?
+    }
+
+    // The last 64 requests as NDJSON, oldest first, e.g. {"uptime_ms": 734000, "method": "POST",
+    // "uri": "/post", "client": "192.168.4.2", "command": "0105", "latency_ms": 84, "status": 504,
+    // "error": "ELM_TIMEOUT"}
+    unsafe {
+        router
+            .handler("/debug/requests", Method::Get, move |req| {
+                req.into_response(200, None, &[("Content-Type", "application/x-ndjson")])?
+                    .write_all(request_log::ndjson().as_bytes())?;
+
+                Ok(())
+            })
+            .context("Register request log handler")
+            .and(Ok(()))?
+    }
+
+    // The BT/WiFi coexistence preference and the SPP and WiFi counters since it was set
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/debug/coex", Method::Get, move |req| {
+                json_response(req, &coex::status(services.wifi))
+            })
+            .context("Register get coex handler")
+            .and(Ok(()))?
+    }
+
+    // {"preference": "bt"}, "wifi" or "balanced", until the next boot
+    #[cfg(feature = "bt")]
+    unsafe {
+        router
+            .handler("/debug/coex", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let request = match serde_json::from_slice::<CoexRequest>(&body) {
+                    Ok(request) => request,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                if let Err(err) = coex::set(request.preference, services.wifi) {
+                    return error_response(req, 500, &format!("{err:#}"));
+                }
+
+                json_response(req, &coex::status(services.wifi))
+            })
+            .context("Register put coex handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/debug/replay", Method::Get, move |req| {
+                json_response(req, &services.session.status())
+            })
+            .context("Register get replay handler")
+            .and(Ok(()))?
+    }
+
+    // {"mode": "record"}, "replay" or "off". Recording starts a new /logs/SESSION.REC.
+    unsafe {
+        router
+            .handler("/debug/replay", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<ReplayMode>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|replay| services.session.set_mode(replay.mode));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                json_response(req, &services.session.status())
+            })
+            .context("Register put replay handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/policy", Method::Get, move |req| {
+                json_response(req, &services.policy.config())
+            })
+            .context("Register get policy handler")
+            .and(Ok(()))?
+    }
+
+    // Commands refused without the unlock token, e.g. {"deny": ["04", "14", "2E"], "allow":
+    // ["2EF190"]}. An allow rule wins over a deny rule. Changing it needs the unlock token.
+    unsafe {
+        router
+            .handler("/config/policy", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, policy::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if !services.policy.unlocked(req.header(UNLOCK_HEADER)) {
+                    return error_response(req, 403, "Unlock token required");
+                }
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<PolicyConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.policy.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put policy handler")
+            .and(Ok(()))?
+    }
+
+    // Set or clear (empty body) the unlock token, the policy is only enforced once one is set.
+    // Changing it needs the unlock token, and a signature once signing is enabled.
+    unsafe {
+        router
+            .handler("/config/unlock-token", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, 130)? else {
+                    return error_response(req, 413, "Token too long");
+                };
+
+                if !services.policy.unlocked(req.header(UNLOCK_HEADER)) {
+                    return error_response(req, 403, "Current unlock token required");
+                }
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                if let Err(err) = services
+                    .policy
+                    .set_unlock_token(String::from_utf8_lossy(&body).trim())
+                {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register unlock token handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/features", Method::Get, move |req| {
+                json_response(req, &services.features.states())
+            })
+            .context("Register get features handler")
+            .and(Ok(()))?
+    }
+
+    // Body is a JSON object of feature name to enabled, e.g. {"poller": false}. An unknown or
+    // unavailable feature is a 400 and nothing is changed, one that fails to start or stop a 500.
+    unsafe {
+        router
+            .handler("/config/features", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let toggles: BTreeMap<String, bool> = match serde_json::from_slice(&body) {
+                    Ok(toggles) => toggles,
+                    Err(err) => return error_response(req, 400, &err.to_string()),
+                };
+
+                let mut features = Vec::with_capacity(toggles.len());
+                for (name, enabled) in toggles {
+                    let feature = match Feature::from_name(&name) {
+                        Some(feature) if services.features.available(feature) => feature,
+                        Some(_) => {
+                            let message = format!("Feature ({name}) not available");
+                            return error_response(req, 400, &message);
+                        }
+                        None => {
+                            return error_response(req, 400, &format!("Unknown feature ({name})"))
+                        }
+                    };
+                    features.push((feature, enabled));
+                }
+
+                for (feature, enabled) in features {
+                    if let Err(err) = services.features.set_enabled(feature, enabled) {
+                        return error_response(req, 500, &format!("{err:#}"));
+                    }
+                }
+
+                json_response(req, &services.features.states())
+            })
+            .context("Register put features handler")
+            .and(Ok(()))?
+    }
+
+    register_log_handlers(&mut router)?;
+
+    unsafe {
+        router
+            .handler("/config/logger", Method::Get, move |req| {
+                json_response(req, &services.logger.config())
+            })
+            .context("Register get logger config handler")
+            .and(Ok(()))?
+    }
+
+    // {"pids": ["05", "0C", "boost"], "interval_ms": 1000}, applies to the next log file
+    unsafe {
+        router
+            .handler("/config/logger", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, services.http_limits.max_body_len)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<LogConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.logger.set_config(&config, services.virtual_pids));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register put logger config handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/smoothing", Method::Get, move |req| {
+                json_response(req, &services.smoothing.config())
+            })
+            .context("Register get smoothing handler")
+            .and(Ok(()))?
+    }
+
+    // Per channel, e.g. {"0C": {"ema": 0.3, "rate_ms": 100}, "boost": {"min": 0.0}}. Channels left
+    // out go to /events as read.
+    unsafe {
+        router
+            .handler("/config/smoothing", Method::Put, move |mut req| {
+                let Some(body) = read_body(&mut req, smoothing::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<SmoothingConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.smoothing.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                json_response(req, &services.smoothing.config())
+            })
+            .context("Register put smoothing handler")
+            .and(Ok(()))?
+    }
+
+    unsafe {
+        router
+            .handler("/config/log", Method::Get, move |req| {
+                json_response(req, &services.log_levels.config())
+            })
+            .context("Register get log levels handler")
+            .and(Ok(()))?
+    }
+
+    // {"level": "info", "targets": {"spp_handler": "warn", "elm327": "debug"}}, applied at once
+    // and at every boot. Targets left out go back to the global level.
+    unsafe {
+        router
+            .handler("/config/log", Method::Post, move |mut req| {
+                let Some(body) = read_body(&mut req, log_level::MAX_CONFIG_LEN)? else {
+                    return error_response(req, 413, "Request too big");
+                };
+
+                if let Err(err) = services.signing.verify(req.header(SIGNATURE_HEADER), &body) {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = serde_json::from_slice::<LogLevelConfig>(&body)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| services.log_levels.set_config(config));
+
+                if let Err(err) = result {
+                    return error_response(req, 400, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register post log levels handler")
+            .and(Ok(()))?
+    }
+
+    // SD card files, GET a file to download it or a directory to list it
+    unsafe {
+        router
+            .handler("/fs/*", Method::Get, move |req| {
+                let Some(path) = fs_path(&req) else {
+                    return error_response(req, 400, "Invalid path");
+                };
+
+                if !sdcard::mounted() {
+                    return error_response(req, 503, "No SD card");
+                }
+
+                match fs::metadata(&path) {
+                    Ok(meta) if meta.is_dir() => {
+                        let mut entries = Vec::new();
+                        for entry in fs::read_dir(&path)? {
+                            let entry = entry?;
+                            let meta = entry.metadata()?;
+                            entries.push(FsEntry {
+                                name: entry.file_name().to_string_lossy().into_owned(),
+                                size: meta.len(),
+                                dir: meta.is_dir(),
+                            });
+                        }
+
+                        json_response(req, &entries)
+                    }
+                    Ok(_) => {
+                        let mut file = File::open(&path)?;
+                        stream_file(req, &mut file, "application/octet-stream")
+                    }
+                    Err(_) => error_response(req, 404, "No such file"),
+                }
+            })
+            .context("Register fs get handler")
+            .and(Ok(()))?
+    }
+
+    // Upload a file, any missing directories are created. Signed uploads are checked as the body
+    // streams to a temporary file which only replaces the original once the signature matches.
+    unsafe {
+        router
+            .handler("/fs/*", Method::Put, move |mut req| {
+                let Some(path) = fs_path(&req) else {
+                    return error_response(req, 400, "Invalid path");
+                };
+
+                if !sdcard::mounted() {
+                    return error_response(req, 503, "No SD card");
+                }
+
+                let mut verifier = match services.signing.verifier(req.header(SIGNATURE_HEADER)) {
+                    Ok(verifier) => verifier,
+                    Err(err) => return error_response(req, 403, &err.to_string()),
+                };
+
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let part = PathBuf::from(format!("{}.part", path.display()));
+                let mut file = File::create(&part)?;
+
+                let mut buf = [0u8; 512];
+                loop {
+                    let n = match req.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) => {
+                            let _ = fs::remove_file(&part);
+                            Err(err)?
+                        }
+                    };
+
+                    if let Some(verifier) = verifier.as_mut() {
+                        verifier.update(&buf[..n]);
+                    }
+                    std::io::Write::write_all(&mut file, &buf[..n])?;
+                }
+                drop(file);
+
+                if let Some(Err(err)) = verifier.map(Verifier::finish) {
+                    let _ = fs::remove_file(&part);
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                // FAT won't rename over an existing file
+                let _ = fs::remove_file(&path);
+                fs::rename(&part, &path)?;
+
+                info!("Uploaded {}", path.display());
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register fs put handler")
+            .and(Ok(()))?
+    }
+
+    // Delete a file or empty directory. There's no body so the signature is over the path, e.g.
+    // "logs/old.csv".
+    unsafe {
+        router
+            .handler("/fs/*", Method::Delete, move |req| {
+                let rel = fs_rel_path(&req).to_owned();
+                let Some(path) = sdcard::path(&rel) else {
+                    return error_response(req, 400, "Invalid path");
+                };
+
+                if !sdcard::mounted() {
+                    return error_response(req, 503, "No SD card");
+                }
+
+                if let Err(err) = services
+                    .signing
+                    .verify(req.header(SIGNATURE_HEADER), rel.as_bytes())
+                {
+                    return error_response(req, 403, &err.to_string());
+                }
+
+                let result = match fs::metadata(&path) {
+                    Ok(meta) if meta.is_dir() => fs::remove_dir(&path),
+                    Ok(_) => fs::remove_file(&path),
+                    Err(_) => return error_response(req, 404, "No such file"),
+                };
+
+                if let Err(err) = result {
+                    return error_response(req, 409, &err.to_string());
+                }
+
+                req.into_ok_response()?;
+
+                Ok(())
+            })
+            .context("Register fs delete handler")
+            .and(Ok(()))?
+    }
+
+    Ok(())
+}
+
+/// Trip log list and download, also served in diagnostics mode
+pub fn register_log_handlers(router: &mut Router<'_, '_>) -> Result<()> {
+    unsafe {
+        router
+            .handler("/logs", Method::Get, move |req| {
+                json_response(req, &Logger::list()?)
+            })
+            .context("Register logs handler")
+            .and(Ok(()))?
+    }
+
+    // Download a log file, /logs/TRIP0001.CSV
+    unsafe {
+        router
+            .handler("/logs/*", Method::Get, move |req| {
+                let name = req.uri().trim_start_matches("/logs/").to_owned();
+
+                let file = Logger::path(&name).and_then(|path| File::open(path).ok());
+                let Some(mut file) = file else {
+                    return error_response(req, 404, "No such log");
+                };
+
+                stream_file(req, &mut file, "text/csv")
+            })
+            .context("Register log download handler")
+            .and(Ok(()))?
+    }
+
+    Ok(())
+}
+
+/// Start the server, HTTPS on 443 with a certificate, otherwise plain HTTP on 80. HTTPS that
+/// won't start falls back to HTTP, so the certificate can be replaced. True if it's HTTPS.
+pub fn start_server<'a>(
+    limits: &HttpLimits,
+    cert: Option<ServerCert>,
+) -> Result<(EspHttpServer<'a>, bool)> {
+    held::set_limit(limits.max_open_sockets);
+
+    let server_configuration = Configuration {
+        stack_size: limits.stack_size,
+        max_sessions: limits.max_sessions,
+        max_open_sockets: limits.max_open_sockets,
+        max_uri_handlers: limits.max_uri_handlers,
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
+
+    if let Some(cert) = cert {
+        let https_configuration = Configuration {
+            stack_size: server_configuration.stack_size.max(HTTPS_STACK_SIZE),
+            server_certificate: Some(cert.cert),
+            private_key: Some(cert.key),
+            ..server_configuration.clone()
+        };
+
+        match EspHttpServer::new(&https_configuration) {
+            Ok(server) => {
+                info!("Serving HTTPS");
+                return Ok((server, true));
+            }
+            Err(err) => error!("HTTPS failed to start, serving HTTP: {err}"),
+        }
+    }
+
+    let server = EspHttpServer::new(&server_configuration).context("Failed to create httpd")?;
+
+    Ok((server, false))
+}
+
+/// Registers handlers behind the auth backend, so every endpoint is checked the same way
+/// without each handler doing it
+pub struct Router<'s, 'a> {
+    server: &'s mut EspHttpServer<'a>,
+    auth: &'a dyn AuthBackend,
+    /// None for every network
+    serve_on: Option<Vec<Network>>,
+}
+
+impl<'s, 'a> Router<'s, 'a> {
+    pub fn new(server: &'s mut EspHttpServer<'a>, auth: &'a dyn AuthBackend) -> Self {
+        Self {
+            server,
+            auth,
+            serve_on: None,
+        }
+    }
+
+    /// Only answer requests that came in on `networks`, others get a 403. ESP-IDF's server
+    /// listens on every interface, so this is the server's bind.
+    pub fn serve_on(mut self, networks: Option<Vec<Network>>) -> Self {
+        self.serve_on = networks;
+        self
+    }
+
+    /// Register a handler that only runs for authorized requests, others get a 401. Turned away
+    /// with a 503 while memory is short, see `memory::shedding`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `EspHttpServer::fn_handler_nonstatic`, the handler must not outlive what it
+    /// borrows.
+    pub unsafe fn handler<F>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: for<'r, 'c> Fn(HttpRequest<'r, 'c>) -> Result<()> + Send + 'a,
+    {
+        let auth = self.auth;
+        let serve_on = self.serve_on.clone();
+        // Kept up while shedding, to see what's going on
+        let sheddable = !["/status", "/metrics"].contains(&uri);
+
+        self.server
+            .fn_handler_nonstatic::<anyhow::Error, _>(uri, method, move |mut req| {
+                metrics::HTTP_REQUESTS.inc();
+
+                let uri = req.uri().to_owned();
+                request_log::begin(method, &uri, req.connection());
+
+                let Some(req) = served(&serve_on, req)? else {
+                    return Ok(());
+                };
+
+                if sheddable && memory::shedding() {
+                    metrics::SHED_REQUESTS.inc();
+                    request_log::set_status(503, None);
+                    request_log::end(false);
+                    req.into_response(503, None, &[("Retry-After", "5")])?
+                        .write_all(b"Low memory, retry later")?;
+                    return Ok(());
+                }
+
+                let result = match authorize(auth, req) {
+                    Ok(Some(req)) => f(req),
+                    Ok(None) => Ok(()),
+                    Err(err) => Err(err),
+                };
+                request_log::end(result.is_err());
+
+                result
+            })?;
+
+        Ok(self)
+    }
+
+    /// Register a handler without auth, only for what's needed to authenticate
+    ///
+    /// # Safety
+    ///
+    /// Same as `handler`
+    pub unsafe fn open_handler<F>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: for<'r, 'c> Fn(HttpRequest<'r, 'c>) -> Result<()> + Send + 'a,
+    {
+        let serve_on = self.serve_on.clone();
+
+        self.server
+            .fn_handler_nonstatic::<anyhow::Error, _>(uri, method, move |mut req| {
+                metrics::HTTP_REQUESTS.inc();
+
+                let uri = req.uri().to_owned();
+                request_log::begin(method, &uri, req.connection());
+
+                let Some(req) = served(&serve_on, req)? else {
+                    return Ok(());
+                };
+
+                let result = f(req);
+                request_log::end(result.is_err());
+
+                result
+            })?;
+
+        Ok(self)
+    }
+
+    /// Register a GET handler that can hold the request off the httpd task, see `held`. It's
+    /// checked as `handler` checks requests, then `f` holds it or answers it straight away.
+    ///
+    /// # Safety
+    ///
+    /// Same as `handler`
+    pub unsafe fn held_handler<F>(
+        &mut self,
+        uri: &'static CStr,
+        f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: Fn(Incoming) -> Result<()> + Send + 'a,
+    {
+        let auth = self.auth;
+        let serve_on = self.serve_on.clone();
+
+        held::register(self.server, uri, move |req| {
+            metrics::HTTP_REQUESTS.inc();
+
+            request_log::begin_on(Method::Get, req.uri(), req.sockfd());
+
+            let served = serve_on.as_ref().is_none_or(|networks| {
+                req.sockfd()
+                    .and_then(request_log::local_addr_on)
+                    .map(network::network_of)
+                    .is_some_and(|network| networks.contains(&network))
+            });
+            let rejected = if !served {
+                Some((403, vec![], "Not served on this network".to_owned()))
+            } else if memory::shedding() {
+                metrics::SHED_REQUESTS.inc();
+                Some((
+                    503,
+                    vec![("Retry-After", "5".to_owned())],
+                    "Low memory, retry later".to_owned(),
+                ))
+            } else if let Err(err) = auth.check(req.header(AUTH_HEADER).as_deref()) {
+                Some((
+                    401,
+                    vec![("WWW-Authenticate", auth.challenge())],
+                    err.to_string(),
+                ))
+            } else {
+                None
+            };
+
+            let result = match rejected {
+                Some((status, headers, body)) => {
+                    request_log::set_status(status, None);
+                    let headers: Vec<(&str, &str)> = headers
+                        .iter()
+                        .map(|(name, value)| (*name, value.as_str()))
+                        .collect();
+                    req.respond(status, &headers, &body)
+                }
+                None => f(req),
+            };
+            request_log::end(result.is_err());
+
+            result
+        })?;
+
+        Ok(self)
+    }
+}
+
+/// The request back if it came in on one of `serve_on`, otherwise it's answered with a 403
+fn served<'r, 'c>(
+    serve_on: &Option<Vec<Network>>,
+    mut req: HttpRequest<'r, 'c>,
+) -> Result<Option<HttpRequest<'r, 'c>>> {
+    let Some(networks) = serve_on else {
+        return Ok(Some(req));
+    };
+
+    let network = request_log::local_addr(req.connection()).map(network::network_of);
+    if network.is_some_and(|network| networks.contains(&network)) {
+        return Ok(Some(req));
+    }
+
+    request_log::set_status(403, None);
+    request_log::end(false);
+    req.into_status_response(403)?
+        .write_all(b"Not served on this network")?;
+
+    Ok(None)
+}
+
+/// The request back if the backend accepts it, otherwise it's answered with a 401
+fn authorize<'r, 'c>(
+    auth: &dyn AuthBackend,
+    req: HttpRequest<'r, 'c>,
+) -> Result<Option<HttpRequest<'r, 'c>>> {
+    match auth.check(req.header(AUTH_HEADER)) {
+        Ok(()) => Ok(Some(req)),
+        Err(err) => {
+            request_log::set_status(401, None);
+            let challenge = auth.challenge();
+            req.into_response(401, None, &[("WWW-Authenticate", &challenge)])?
+                .write_all(err.to_string().as_bytes())?;
+
+            Ok(None)
+        }
+    }
+}
+
+/// Send the flow control, then set the adapter up with it and store it. The setup only warns
+/// about commands the adapter refuses, so they're sent on their own first to be refused here.
+fn apply_flow_control(services: &Services, config: FlowControlConfig) -> Result<()> {
+    let stored = config.clone();
+    services.elm_worker.run(Priority::Normal, move |elm327| {
+        if config != FlowControlConfig::default() {
+            elm327.with_flow_control(Some(config.clone()), |_| Ok(()))?;
+        }
+
+        elm327
+            .setup_with(|elm327| elm327.set_flow_control(config))
+            .context("Adapter setup failed")
+    })?;
+
+    services.flow_control.set_config(stored)
+}
+
+/// Adapter and worker errors as {"code": "ELM_TIMEOUT", "detail": "...", "retryable": true},
+/// with the status for the code and a Retry-After if it's worth sending again
+fn adapter_error_response(req: HttpRequest<'_, '_>, err: &anyhow::Error) -> Result<()> {
+    let body = ErrorBody::of(err);
+    if body.code == ErrorCode::Internal {
+        error!("Adapter request failed {err:#}");
+    }
+    request_log::set_status(body.code.status(), Some(body.code));
+
+    let mut headers = vec![("Content-Type", "application/json")];
+    if body.retryable {
+        headers.push(("Retry-After", "1"));
+    }
+
+    req.into_response(body.code.status(), None, &headers)?
+        .write_all(&serde_json::to_vec(&body)?)?;
+
+    Ok(())
+}
+
+/// Read the whole request body, or None if it is larger than `max_len`
+pub fn read_body(req: &mut HttpRequest<'_, '_>, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let len = req.content_len().unwrap_or(0) as usize;
+
+    if len > max_len {
+        // A client still sending the body may not see a response sent before it's read
+        let mut drain = [0u8; 256];
+        let mut left = len.min(api::MAX_DRAIN_LEN);
+        while left > 0 {
+            match req.read(&mut drain[..left.min(drain.len())])? {
+                0 => break,
+                n => left -= n,
+            }
+        }
+
+        return Ok(None);
+    }
+
+    let mut buf = vec![0; len];
+    let mut pos = 0;
+    while pos < len {
+        match req.read(&mut buf[pos..])? {
+            0 => break,
+            n => pos += n,
+        }
+    }
+    buf.truncate(pos);
+
+    Ok(Some(buf))
+}
+
+pub fn json_response<T: Serialize>(req: HttpRequest<'_, '_>, value: &T) -> Result<()> {
+    let body = serde_json::to_vec(value)?;
+
+    req.into_response(200, None, &[("Content-Type", "application/json")])?
+        .write_all(&body)?;
+
+    Ok(())
+}
+
+/// A CBOR body, see `cbor`
+fn cbor_response(req: HttpRequest<'_, '_>, body: &[u8]) -> Result<()> {
+    req.into_response(
+        200,
+        None,
+        &[("Content-Type", cbor::CONTENT_TYPE), ("Vary", "Accept")],
+    )?
+    .write_all(body)?;
+
+    Ok(())
+}
+
+/// Send a file in chunks, they can be much bigger than the free heap
+fn stream_file(req: HttpRequest<'_, '_>, file: &mut File, content_type: &str) -> Result<()> {
+    let mut resp = req.into_response(200, None, &[("Content-Type", content_type)])?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        match std::io::Read::read(file, &mut buf)? {
+            0 => break,
+            n => resp.write_all(&buf[..n])?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait on the logged values for the PID to move, see `/wait`. The change is measured from
+/// `since`, or the latest value, or the first to arrive.
+fn wait_for_change(
+    held: Held,
+    key: String,
+    timeout: Duration,
+    delta: f32,
+    since: Option<f32>,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let moved = |baseline: f32, value: f32| {
+        if delta > 0.0 {
+            (value - baseline).abs() >= delta
+        } else {
+            value != baseline
+        }
+    };
+
+    let mut last_id = events::last_id();
+    let mut baseline = since.or_else(|| events::latest_value(&key));
+    let mut value = baseline;
+    let mut changed = false;
+
+    while !changed {
+        let sent = events::after(last_id, deadline);
+        let Some(newest) = sent.last() else {
+            break;
+        };
+        last_id = newest.id;
+
+        for sample in sent.iter().filter_map(|event| event.pid_value(&key)) {
+            value = Some(sample);
+            match baseline {
+                Some(baseline) if moved(baseline, sample) => {
+                    changed = true;
+                    break;
+                }
+                Some(_) => {}
+                None => baseline = Some(sample),
+            }
+        }
+    }
+
+    held.json(&WaitResult {
+        pid: key,
+        value,
+        changed,
+        timestamp_ms: uptime_ms(),
+        time: clock::now(),
+    })
+}
+
+/// Send the events after `last_id` as they're published, until the client goes
+fn stream_events(mut held: Held, mut last_id: u32) -> Result<()> {
+    held.stream(c"text/event-stream")?;
+    held.send(format!("retry: {}\n\n", events::RETRY_MS).as_bytes())?;
+
+    loop {
+        let sent = events::after(last_id, Instant::now() + events::KEEP_ALIVE);
+        if sent.is_empty() {
+            // Also finds a client that's gone while nothing's happening
+            held.send(b": keep-alive\n\n")?;
+        }
+
+        for event in sent {
+            held.send(event.to_sse().as_bytes())?;
+            last_id = event.id;
+        }
+    }
+}
+
+/// `error_response` for a request that isn't held after all
+fn held_error(req: Incoming, status: u16, message: &str) -> Result<()> {
+    request_log::set_status(status, None);
+
+    req.respond(status, &[], message)
+}
+
+/// Value of a query parameter, e.g. `pids` in /snapshot?pids=0C,0D
+fn query_param<'u>(uri: &'u str, name: &str) -> Option<&'u str> {
+    let (_, query) = uri.split_once('?')?;
+
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Also printed by the `status` console command
+pub fn status(services: &Services) -> Status {
+    Status {
+        mode: "normal",
+        uptime_ms: uptime_ms(),
+        free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+        adapter: services.adapter.clone(),
+        ignition: services.ignition.lock().unwrap().state(),
+        #[cfg(feature = "bt")]
+        spp: spp_handler::stats(),
+        #[cfg(feature = "bt")]
+        bt_link: bt::link_quality(),
+        shedding: memory::shedding(),
+        lifetime: services.lifetime.counts(),
+        latency: metrics::latencies(),
+        persist: services.persist.stats(),
+        wifi: services.wifi.status(),
+    }
+}
+
+/// Path under /fs/ without any query
+fn fs_rel_path<'r>(req: &'r HttpRequest<'_, '_>) -> &'r str {
+    let uri = req.uri();
+    let uri = uri.split('?').next().unwrap_or(uri);
+
+    uri.trim_start_matches("/fs").trim_start_matches('/')
+}
+
+fn fs_path(req: &HttpRequest<'_, '_>) -> Option<PathBuf> {
+    sdcard::path(fs_rel_path(req))
+}
+
+pub fn error_response(req: HttpRequest<'_, '_>, status: u16, message: &str) -> Result<()> {
+    request_log::set_status(status, None);
+
+    req.into_status_response(status)?
+        .write_all(message.as_bytes())?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct HttpConfig {
+    limits: HttpLimits,
+    overrides: HttpOverrides,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    mode: &'static str,
+    uptime_ms: u64,
+    free_heap: u32,
+    adapter: Capabilities,
+    ignition: IgnitionState,
+    #[cfg(feature = "bt")]
+    spp: SppStats,
+    /// RSSI of the OBD adapter link
+    #[cfg(feature = "bt")]
+    bt_link: bt::LinkQuality,
+    /// HTTP requests are being refused for memory
+    shedding: bool,
+    /// Totals across reboots
+    lifetime: LifetimeCounts,
+    /// Adapter request latency percentiles since boot
+    latency: metrics::Latencies,
+    /// Batched NVS writes since boot
+    persist: PersistStats,
+    wifi: WifiStatus,
+}
+
+#[derive(Serialize)]
+struct ResetConfirm {
+    confirm: String,
+    expires_s: u64,
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    /// Milliseconds since boot, taken as the reads start
+    timestamp_ms: u64,
+    /// RFC 3339 at the same moment, None until the clock is set
+    time: Option<String>,
+    values: BTreeMap<String, f32>,
+}
+
+#[derive(Serialize)]
+struct TimeStatus {
+    time: Option<String>,
+    source: TimeSource,
+    uptime_ms: u64,
+}
+
+#[derive(Deserialize)]
+struct TimeSet {
+    unix_ms: u64,
+}
+
+#[derive(Deserialize)]
+struct ReplayMode {
+    mode: SessionMode,
+}
+
+#[derive(Serialize)]
+struct WaitResult {
+    pid: String,
+    /// None if the PID wasn't sampled in the time
+    value: Option<f32>,
+    /// False if the timeout ran out first
+    changed: bool,
+    timestamp_ms: u64,
+    time: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FsEntry {
+    name: String,
+    size: u64,
+    dir: bool,
+}
+
+#[derive(Deserialize)]
+struct RawRequest {
+    header: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct MonitorRequest {
+    #[serde(default)]
+    pass: Vec<StnFilter>,
+    #[serde(default)]
+    block: Vec<StnFilter>,
+    duration_ms: u32,
+    #[serde(default = "default_monitor_frames")]
+    max_frames: usize,
+}
+
+fn default_monitor_frames() -> usize {
+    MAX_MONITOR_FRAMES
+}
+
+#[derive(Serialize)]
+struct RawResponse {
+    frames: Vec<String>,
+    /// Over the longest response kept, the rest was dropped
+    truncated: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "service", rename_all = "snake_case")]
+enum UdsService {
+    /// ReadDataByIdentifier, DID in hex
+    ReadDid {
+        did: String,
+    },
+    /// DiagnosticSessionControl, e.g. 3 for extended
+    Session {
+        session: u8,
+    },
+    TesterPresent,
+}
+
+#[derive(Deserialize)]
+struct UdsRequest {
+    header: Option<String>,
+    #[serde(flatten)]
+    service: UdsService,
+}
+
+impl UdsRequest {
+    /// The service as sent, for the request log
+    fn command(&self) -> String {
+        match &self.service {
+            UdsService::ReadDid { did } => format!("22 {did}"),
+            UdsService::Session { session } => format!("10 {session:02X}"),
+            UdsService::TesterPresent => "3E 00".to_owned(),
+        }
+    }
+
+    fn run(&self, elm327: &mut Elm327<'_>) -> Result<Vec<u8>> {
+        if let Some(header) = &self.header {
+            elm327.set_header(header)?;
+        }
+
+        let result = match &self.service {
+            UdsService::ReadDid { did } => match u16::from_str_radix(did, 16) {
+                Ok(did) => uds::read_did(elm327, did),
+                Err(_) => Err(ElmError::InvalidRequest("did must be hex".to_owned()).into()),
+            },
+            UdsService::Session { session } => uds::session_control(elm327, *session),
+            UdsService::TesterPresent => uds::tester_present(elm327).map(|_| Vec::new()),
+        };
+
+        if self.header.is_some() {
+            elm327.restore_header()?;
+        }
+
+        result
+    }
+}
+
+#[derive(Serialize)]
+struct UdsResponse {
+    positive: bool,
+    /// Hex bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nrc: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
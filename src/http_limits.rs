@@ -0,0 +1,147 @@
+//! HTTP server sizing. The build picks the defaults, `GatewayConfig::http`, and `/config/http`
+//! can override them in NVS. The server is sized as it starts, so overrides are used from the
+//! next boot.
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_HTTP_LIMITS};
+
+pub const MAX_CONFIG_LEN: usize = 192;
+/// httpd keeps 3 of the LWIP sockets (16) for itself
+const MAX_OPEN_SOCKETS: usize = 13;
+/// The gateway registers 98 handlers, with one spare
+const MIN_URI_HANDLERS: usize = 99;
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct HttpLimits {
+    pub stack_size: usize,
+    /// Largest request body for the ELM handlers and the configs without their own limit
+    pub max_body_len: usize,
+    pub max_sessions: usize,
+    pub max_open_sockets: usize,
+    /// Registering more handlers than this fails at startup
+    pub max_uri_handlers: usize,
+}
+
+impl HttpLimits {
+    /// Without BT there's the RAM for more and bigger sessions, `http-large` asks for more still
+    pub const BUILD: Self = if cfg!(feature = "http-large") {
+        Self {
+            stack_size: 12288,
+            max_body_len: 4096,
+            max_sessions: 8,
+            max_open_sockets: 6,
+            max_uri_handlers: 105,
+        }
+    } else if cfg!(not(feature = "bt")) {
+        Self {
+            stack_size: 8192,
+            max_body_len: 1024,
+            max_sessions: 8,
+            max_open_sockets: 4,
+            max_uri_handlers: 105,
+        }
+    } else {
+        Self {
+            stack_size: 4096,
+            max_body_len: 1024,
+            max_sessions: 4,
+            max_open_sockets: 3,
+            max_uri_handlers: 105,
+        }
+    };
+
+    /// These limits with the overrides that are set. Never fewer URI handlers than the gateway
+    /// registers, whatever `GatewayConfig::http` or an older override asks for.
+    pub fn with(self, overrides: &HttpOverrides) -> Self {
+        Self {
+            stack_size: overrides.stack_size.unwrap_or(self.stack_size),
+            max_body_len: overrides.max_body_len.unwrap_or(self.max_body_len),
+            max_sessions: overrides.max_sessions.unwrap_or(self.max_sessions),
+            max_open_sockets: overrides.max_open_sockets.unwrap_or(self.max_open_sockets),
+            max_uri_handlers: overrides
+                .max_uri_handlers
+                .unwrap_or(self.max_uri_handlers)
+                .max(MIN_URI_HANDLERS),
+        }
+    }
+}
+
+/// Set from `/config/http`, a field left out keeps the build's value
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct HttpOverrides {
+    pub stack_size: Option<usize>,
+    pub max_body_len: Option<usize>,
+    pub max_sessions: Option<usize>,
+    pub max_open_sockets: Option<usize>,
+    pub max_uri_handlers: Option<usize>,
+}
+
+impl HttpOverrides {
+    fn check(&self) -> Result<()> {
+        let limits = [
+            ("stack_size", self.stack_size, 4096, 32768),
+            ("max_body_len", self.max_body_len, 128, 16384),
+            ("max_sessions", self.max_sessions, 1, 16),
+            (
+                "max_open_sockets",
+                self.max_open_sockets,
+                1,
+                MAX_OPEN_SOCKETS,
+            ),
+            (
+                "max_uri_handlers",
+                self.max_uri_handlers,
+                MIN_URI_HANDLERS,
+                128,
+            ),
+        ];
+
+        for (name, value, min, max) in limits {
+            if value.is_some_and(|value| !(min..=max).contains(&value)) {
+                Err(anyhow!("{name} must be {min} to {max}"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct HttpSettings {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    overrides: Mutex<HttpOverrides>,
+}
+
+impl HttpSettings {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let overrides = config::load(&nvs, NVS_HTTP_LIMITS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            overrides: Mutex::new(overrides),
+        })
+    }
+
+    pub fn overrides(&self) -> HttpOverrides {
+        *self.overrides.lock().unwrap()
+    }
+
+    pub fn set_overrides(&self, overrides: HttpOverrides) -> Result<()> {
+        overrides.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_HTTP_LIMITS, &config::encode(&overrides)?)?;
+        *self.overrides.lock().unwrap() = overrides;
+
+        info!("HTTP limits updated, used from the next boot {overrides:?}");
+
+        Ok(())
+    }
+}
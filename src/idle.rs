@@ -0,0 +1,51 @@
+//! Idle manager. With no HTTP requests for a while the adapter goes to low power (ATLP) and the
+//! SPP link is closed, so it doesn't drain the battery. The ELM worker wakes it for the next
+//! request, see `Elm327::wake`, and the background polls leave it alone meanwhile.
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::elm327::Elm327;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct IdleManager {
+    /// None leaves the adapter connected
+    timeout: Option<Duration>,
+    last_poll: Option<Instant>,
+}
+
+impl IdleManager {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            last_poll: None,
+        }
+    }
+
+    pub fn poll_due(&self) -> bool {
+        self.timeout.is_some() && self.last_poll.is_none_or(|t| t.elapsed() >= POLL_INTERVAL)
+    }
+
+    /// Put the adapter in low power if nothing has asked for it within the timeout, or wake it
+    /// if it's `in_use` again, e.g. a trip started. `idle_for` is the time since the last HTTP
+    /// request, see `ElmWorker::idle_for`.
+    pub fn poll(
+        &mut self,
+        elm327: &mut Elm327<'_>,
+        idle_for: Duration,
+        in_use: bool,
+    ) -> Result<()> {
+        self.last_poll = Some(Instant::now());
+
+        if in_use {
+            return elm327.wake();
+        }
+
+        if elm327.asleep() || self.timeout.is_none_or(|timeout| idle_for < timeout) {
+            return Ok(());
+        }
+
+        elm327.low_power()
+    }
+}
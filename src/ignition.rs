@@ -0,0 +1,174 @@
+//! Ignition on/off, from an ignition switched input pin where the install has one, otherwise from
+//! the battery voltage at the OBD port. The gateway stays powered with the ignition off (until the
+//! battery saver cuts the port), so trips are ended on the voltage dropping from charging to
+//! resting rather than on power loss.
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver};
+use log::*;
+use serde::Serialize;
+
+use crate::elm327::Elm327;
+use crate::sleep;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The alternator is charging, the engine is running
+pub const RUNNING_VOLTS: f32 = 13.2;
+/// Resting battery, with a margin below `RUNNING_VOLTS` so a dip at idle doesn't end the trip
+const OFF_VOLTS: f32 = 12.9;
+/// Consecutive low reads before deciding the ignition is off
+const OFF_READS: u8 = 3;
+/// The input level must hold this long to count
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IgnitionChange {
+    On,
+    Off,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IgnitionSource {
+    /// Ignition switched 12V through a divider
+    Input,
+    /// `ATRV` heuristic
+    Voltage,
+}
+
+/// Reported at /status
+#[derive(Serialize)]
+pub struct IgnitionState {
+    pub source: IgnitionSource,
+    pub on: bool,
+}
+
+pub struct Ignition {
+    input: Option<PinDriver<'static, AnyInputPin, Input>>,
+    on: bool,
+    low_reads: u8,
+    /// Input level and since when, for the debounce
+    level: Option<(bool, Instant)>,
+    last_poll: Option<Instant>,
+    off_since: Option<Instant>,
+}
+
+impl Ignition {
+    /// Starts out on, the gateway usually boots with the engine
+    pub fn new(input: Option<PinDriver<'static, AnyInputPin, Input>>) -> Self {
+        Self {
+            input,
+            on: true,
+            low_reads: 0,
+            level: None,
+            last_poll: None,
+            off_since: None,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn state(&self) -> IgnitionState {
+        IgnitionState {
+            source: if self.input.is_some() {
+                IgnitionSource::Input
+            } else {
+                IgnitionSource::Voltage
+            },
+            on: self.on,
+        }
+    }
+
+    /// True if there's an ignition input and it's low, undebounced for the boot check
+    pub fn input_low(&self) -> bool {
+        self.input.as_ref().is_some_and(|input| input.is_low())
+    }
+
+    /// The input pin, which also wakes the gateway from deep sleep
+    pub fn wake_pin(&self) -> Option<i32> {
+        self.input.as_ref().map(|input| input.pin())
+    }
+
+    /// How long the ignition has been off, None while on
+    pub fn off_for(&self) -> Option<Duration> {
+        self.off_since.map(|t| t.elapsed())
+    }
+
+    /// A switched input is trusted straight away, the voltage heuristic waits `SLEEP_AFTER`
+    pub fn sleep_due(&self) -> bool {
+        match self.input {
+            Some(_) => self.off_since.is_some(),
+            None => self.off_for().is_some_and(|off| off >= sleep::SLEEP_AFTER),
+        }
+    }
+
+    /// True with a switched input, the adapter isn't needed to poll
+    pub fn has_input(&self) -> bool {
+        self.input.is_some()
+    }
+
+    /// The input is cheap to read and checked every loop for the debounce
+    pub fn poll_due(&self) -> bool {
+        self.input.is_some() || self.last_poll.is_none_or(|t| t.elapsed() >= POLL_INTERVAL)
+    }
+
+    /// Read the input or battery voltage, returning the change if the ignition just went on or
+    /// off
+    pub fn poll(&mut self, elm: &mut Elm327<'_>) -> Result<Option<IgnitionChange>> {
+        self.last_poll = Some(Instant::now());
+
+        let change = match self.input.as_ref().map(|input| input.is_high()) {
+            Some(high) => self.input_change(high),
+            None => self.voltage_change(elm.battery_voltage()?),
+        };
+
+        if let Some(change) = change {
+            info!("Ignition {change:?}");
+            self.on = change == IgnitionChange::On;
+            self.off_since = (!self.on).then(Instant::now);
+        }
+
+        Ok(change)
+    }
+
+    fn input_change(&mut self, high: bool) -> Option<IgnitionChange> {
+        match self.level {
+            Some((level, since)) if level == high => {
+                if since.elapsed() < DEBOUNCE || high == self.on {
+                    return None;
+                }
+            }
+            _ => {
+                self.level = Some((high, Instant::now()));
+                return None;
+            }
+        }
+
+        Some(if high {
+            IgnitionChange::On
+        } else {
+            IgnitionChange::Off
+        })
+    }
+
+    fn voltage_change(&mut self, volts: f32) -> Option<IgnitionChange> {
+        debug!("Battery ({volts:.1}V)");
+
+        if volts < OFF_VOLTS {
+            self.low_reads = self.low_reads.saturating_add(1);
+        } else {
+            self.low_reads = 0;
+        }
+
+        if self.on && self.low_reads >= OFF_READS {
+            Some(IgnitionChange::Off)
+        } else if !self.on && volts >= RUNNING_VOLTS {
+            Some(IgnitionChange::On)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Adapter init script, the protocol and formatting commands `Elm327::setup` sends after the
+//! reset. Different vehicles and adapters need different ATSP/STP/ATFC setups, so it can be
+//! replaced from `/config/init-script`.
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+
+use crate::config::{self, NVS_INIT_SCRIPT};
+
+pub const MAX_SCRIPT_LEN: usize = 1024;
+const MAX_COMMANDS: usize = 32;
+const MAX_COMMAND_LEN: usize = 32;
+
+pub struct InitScript {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    /// Empty for the built in setup
+    commands: Mutex<Vec<String>>,
+}
+
+impl InitScript {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let commands: Vec<String> =
+            config::load(&nvs, NVS_INIT_SCRIPT, MAX_SCRIPT_LEN)?.unwrap_or_default();
+
+        if !commands.is_empty() {
+            info!("Adapter init script, {} commands", commands.len());
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            commands: Mutex::new(commands),
+        })
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// Store the commands, empty goes back to the built in setup. Only once the adapter has been
+    /// set up with them, a script it refuses would fail every boot.
+    pub fn set(&self, commands: Vec<String>) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if commands.is_empty() {
+            nvs.remove(NVS_INIT_SCRIPT)?;
+            self.commands.lock().unwrap().clear();
+            info!("Adapter init script removed");
+            return Ok(());
+        }
+
+        check(&commands)?;
+
+        let value = config::encode(&commands)?;
+        if value.len() > MAX_SCRIPT_LEN {
+            Err(anyhow!("Init script too big, max ({MAX_SCRIPT_LEN})"))?;
+        }
+
+        nvs.set_raw(NVS_INIT_SCRIPT, &value)?;
+        info!("Adapter init script updated, {} commands", commands.len());
+
+        *self.commands.lock().unwrap() = commands;
+
+        Ok(())
+    }
+}
+
+/// Whether the commands can be stored, before the adapter is set up with them
+pub fn check(commands: &[String]) -> Result<()> {
+    if commands.len() > MAX_COMMANDS {
+        Err(anyhow!("Too many commands, max ({MAX_COMMANDS})"))?;
+    }
+
+    for command in commands {
+        check_command(command)?;
+    }
+
+    Ok(())
+}
+
+/// Only AT and ST commands, an OBD request in the script would go to the vehicle on every reset
+fn check_command(command: &str) -> Result<()> {
+    let upper = command.trim().to_ascii_uppercase();
+
+    if upper.is_empty() || upper.len() > MAX_COMMAND_LEN {
+        Err(anyhow!(
+            "Commands must be 1 to {MAX_COMMAND_LEN} characters"
+        ))?;
+    }
+    if !upper.starts_with("AT") && !upper.starts_with("ST") {
+        Err(anyhow!("Only AT and ST commands are allowed ({command})"))?;
+    }
+    if !upper.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        Err(anyhow!("Invalid characters in ({command})"))?;
+    }
+    // Echo and linefeeds change the responses the gateway parses
+    if ["ATE1", "ATL1"].contains(&upper.replace(' ', "").as_str()) {
+        Err(anyhow!("({command}) would break response parsing"))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Keeps an idle adapter awake. The OBDLink MX+ and other STN adapters go to sleep after a while
+//! without traffic (see STSLCS), and the next request times out while they wake. With an
+//! interval set, an adapter nothing has written to for that long is sent `Elm327::ping`.
+//! Idle low power still puts the adapter to sleep, the pings only count as traffic for the
+//! adapter, not as requests.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_KEEP_AWAKE};
+use crate::elm327::Elm327;
+
+pub const MAX_CONFIG_LEN: usize = 64;
+const MIN_INTERVAL_S: u32 = 5;
+const MAX_INTERVAL_S: u32 = 3600;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct KeepAwakeConfig {
+    /// Seconds without traffic before a ping, None never pings
+    pub interval_s: Option<u32>,
+}
+
+impl KeepAwakeConfig {
+    fn check(&self) -> Result<()> {
+        match self.interval_s {
+            Some(interval) if !(MIN_INTERVAL_S..=MAX_INTERVAL_S).contains(&interval) => Err(
+                anyhow!("interval_s must be {MIN_INTERVAL_S} to {MAX_INTERVAL_S} or null"),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct KeepAwake {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<KeepAwakeConfig>,
+    /// A ping that failed isn't tried again until the next interval
+    last_ping: Mutex<Option<Instant>>,
+}
+
+impl KeepAwake {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_KEEP_AWAKE, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+            last_ping: Mutex::new(None),
+        })
+    }
+
+    pub fn config(&self) -> KeepAwakeConfig {
+        *self.config.lock().unwrap()
+    }
+
+    pub fn set_config(&self, config: KeepAwakeConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_KEEP_AWAKE, &config::encode(&config)?)?;
+        *self.config.lock().unwrap() = config;
+
+        info!("Keep awake updated {config:?}");
+
+        Ok(())
+    }
+
+    /// Ping the adapter if nothing has been written to it for the interval. Left alone while
+    /// it's in idle low power.
+    pub fn poll(&self, elm327: &mut Elm327<'_>) -> Result<()> {
+        let Some(interval) = self.config().interval_s else {
+            return Ok(());
+        };
+        let interval = Duration::from_secs(interval.into());
+
+        let mut last_ping = self.last_ping.lock().unwrap();
+        if elm327.asleep()
+            || elm327.quiet_for() < interval
+            || last_ping.is_some_and(|t| t.elapsed() < interval)
+        {
+            return Ok(());
+        }
+        *last_ping = Some(Instant::now());
+
+        debug!("Adapter quiet for {}s, pinging", interval.as_secs());
+        elm327.ping()
+    }
+}
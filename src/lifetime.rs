@@ -0,0 +1,88 @@
+//! Counts kept across reboots, for the reliability of an install over weeks rather than since
+//! the last boot. This boot's counts from `metrics` are added to the stored totals, handed to
+//! `persist` every `SAVE_INTERVAL` and written with its next flush.
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NVS_LIFETIME;
+use crate::http::uptime_ms;
+use crate::metrics;
+use crate::persist::Persist;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct LifetimeCounts {
+    /// Requests written to the adapter
+    pub elm_requests: u64,
+    /// Adapter responses that didn't arrive
+    pub elm_errors: u64,
+    /// SPP connections after the first one of each boot
+    pub bt_reconnects: u64,
+    /// Boots, this one included
+    pub boots: u32,
+    pub uptime_s: u64,
+}
+
+pub struct LifetimeStats {
+    persist: Arc<Persist>,
+    /// The totals before this boot
+    stored: LifetimeCounts,
+    last_save: Mutex<Instant>,
+}
+
+impl LifetimeStats {
+    /// Load the totals and count this boot, written straight away so a boot loop is counted
+    pub fn new(persist: Arc<Persist>) -> Result<Self> {
+        let mut stored: LifetimeCounts = persist
+            .load(NVS_LIFETIME)?
+            .and_then(|counts| serde_json::from_slice(&counts).ok())
+            .unwrap_or_default();
+        stored.boots += 1;
+
+        let stats = Self {
+            persist,
+            stored,
+            last_save: Mutex::new(Instant::now()),
+        };
+        stats.save()?;
+        stats.persist.flush()?;
+
+        info!("Lifetime counts {stored:?}");
+
+        Ok(stats)
+    }
+
+    /// The stored totals with this boot's counts added
+    pub fn counts(&self) -> LifetimeCounts {
+        let stored = &self.stored;
+
+        LifetimeCounts {
+            elm_requests: stored.elm_requests + metrics::ELM_REQUESTS.get() as u64,
+            elm_errors: stored.elm_errors + metrics::ELM_ERRORS.get() as u64,
+            bt_reconnects: stored.bt_reconnects
+                + metrics::SPP_CONNECTS.get().saturating_sub(1) as u64,
+            boots: stored.boots,
+            uptime_s: stored.uptime_s + uptime_ms() / 1000,
+        }
+    }
+
+    pub fn save_due(&self) -> bool {
+        self.last_save.lock().unwrap().elapsed() >= SAVE_INTERVAL
+    }
+
+    /// Hand the totals to `persist` for its next flush
+    pub fn save(&self) -> Result<()> {
+        *self.last_save.lock().unwrap() = Instant::now();
+
+        self.persist
+            .store(NVS_LIFETIME, serde_json::to_vec(&self.counts())?)
+    }
+}
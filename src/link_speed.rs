@@ -0,0 +1,68 @@
+//! UART rate between an STN adapter and its link, set from `/config/link-speed`. Every setup
+//! switches to it with STBR, so `/monitor` on a busy bus isn't held back by the power on rate,
+//! and falls back to the power on rate if the adapter doesn't answer at the new one. It's never
+//! stored in the adapter (STWBR), a wired link always starts at the power on rate.
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_LINK_SPEED};
+
+pub const MAX_CONFIG_LEN: usize = 64;
+/// Rates the STN chips take with STBR
+const BAUD_RATES: [u32; 7] = [57600, 115200, 230400, 460800, 500000, 921600, 1000000];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct LinkSpeedConfig {
+    /// None stays at the power on rate
+    pub baud: Option<u32>,
+}
+
+impl LinkSpeedConfig {
+    fn check(&self) -> Result<()> {
+        match self.baud {
+            Some(baud) if !BAUD_RATES.contains(&baud) => {
+                Err(anyhow!("baud must be one of {BAUD_RATES:?} or null"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct LinkSpeed {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<LinkSpeedConfig>,
+}
+
+impl LinkSpeed {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_LINK_SPEED, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> LinkSpeedConfig {
+        *self.config.lock().unwrap()
+    }
+
+    /// Store the rate, switched to by `Elm327::setup`
+    pub fn set_config(&self, config: LinkSpeedConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_LINK_SPEED, &config::encode(&config)?)?;
+        *self.config.lock().unwrap() = config;
+
+        info!("Link speed updated {config:?}");
+
+        Ok(())
+    }
+}
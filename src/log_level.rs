@@ -0,0 +1,174 @@
+//! Log levels changed at runtime with `POST /config/log` and kept in NVS, so a noisy module can be
+//! quietened (or a quiet one opened up) without a rebuild. Short names cover the modules that are
+//! usually asked about, any other target is given in full, e.g. `bt_obd_gw::http`.
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_LOG_LEVELS};
+
+pub const MAX_CONFIG_LEN: usize = 512;
+const MAX_TARGETS: usize = 12;
+/// Every target without its own level
+const ALL_TARGETS: &str = "*";
+
+/// Short names and the log target they stand for
+const TARGETS: &[(&str, &str)] = &[
+    ("spp_handler", "bt_obd_gw::spp_handler"),
+    ("elm327", "bt_obd_gw_core::elm327"),
+    ("elm_worker", "bt_obd_gw_core::elm_worker"),
+    ("espnow", "bt_obd_gw::espnow_cmd"),
+    ("bt", "bt_obd_gw::bt"),
+    ("http", "bt_obd_gw::http"),
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<Level> for LevelFilter {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Off => LevelFilter::Off,
+            Level::Error => LevelFilter::Error,
+            Level::Warn => LevelFilter::Warn,
+            Level::Info => LevelFilter::Info,
+            Level::Debug => LevelFilter::Debug,
+            Level::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LogLevelConfig {
+    /// For every target without its own level
+    pub level: Level,
+    /// Short name or full target, and its level
+    #[serde(default)]
+    pub targets: BTreeMap<String, Level>,
+}
+
+impl Default for LogLevelConfig {
+    fn default() -> Self {
+        Self {
+            level: Level::Debug,
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+impl LogLevelConfig {
+    fn check(&self) -> Result<()> {
+        if self.targets.len() > MAX_TARGETS {
+            Err(anyhow!(
+                "Only {MAX_TARGETS} targets can have their own level"
+            ))?;
+        }
+
+        for name in self.targets.keys() {
+            target(name)?;
+        }
+
+        Ok(())
+    }
+
+    /// The most verbose level of any target, records past it aren't formatted at all
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .values()
+            .map(|level| LevelFilter::from(*level))
+            .fold(self.level.into(), LevelFilter::max)
+    }
+}
+
+/// The log target for a short name, or the name itself if it's a full target
+fn target(name: &str) -> Result<&str> {
+    if let Some((_, target)) = TARGETS.iter().find(|(short, _)| *short == name) {
+        return Ok(target);
+    }
+
+    if name.contains("::") {
+        Ok(name)
+    } else {
+        Err(anyhow!(
+            "Unknown target ({name}), use a full target or one of {:?}",
+            TARGETS.iter().map(|(short, _)| *short).collect::<Vec<_>>()
+        ))
+    }
+}
+
+pub struct LogLevels {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<LogLevelConfig>,
+}
+
+impl LogLevels {
+    /// Load the stored levels and apply them
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config: LogLevelConfig =
+            config::load(&nvs, NVS_LOG_LEVELS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        if config != LogLevelConfig::default() {
+            info!("Log levels {config:?}");
+            apply(&config, &BTreeMap::new())?;
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> LogLevelConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: LogLevelConfig) -> Result<()> {
+        config.check()?;
+
+        let value = config::encode(&config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Log levels too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        let mut current = self.config.lock().unwrap();
+
+        self.nvs.lock().unwrap().set_raw(NVS_LOG_LEVELS, &value)?;
+
+        apply(&config, &current.targets)?;
+        info!("Log levels updated {config:?}");
+        *current = config;
+
+        Ok(())
+    }
+}
+
+/// Set the levels, targets that had their own level in `previous` and don't any more go back to
+/// the global one
+fn apply(config: &LogLevelConfig, previous: &BTreeMap<String, Level>) -> Result<()> {
+    set_max_level(config.max_level());
+    esp_idf_svc::log::set_target_level(ALL_TARGETS, config.level.into())?;
+
+    for name in previous
+        .keys()
+        .filter(|name| !config.targets.contains_key(*name))
+    {
+        esp_idf_svc::log::set_target_level(target(name)?, config.level.into())?;
+    }
+
+    for (name, level) in &config.targets {
+        esp_idf_svc::log::set_target_level(target(name)?, (*level).into())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,526 @@
+//! Trip data logger, samples a set of mode 01 PIDs and appends CSV records to the FAT storage
+//! partition, or the SD card when one is mounted
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::{esp, esp_vfs_fat_mount_config_t, esp_vfs_fat_spiflash_mount_rw_wl, wl_handle_t},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::config::{self, NVS_LOG_INTERVAL, NVS_LOG_PIDS, NVS_LOG_VIRTUAL};
+use crate::elm327::Elm327;
+use crate::events::{self, PidValues};
+use crate::features::Subsystem;
+use crate::http::uptime_ms;
+use crate::pid;
+use crate::sdcard;
+use crate::smoothing::Smoothing;
+use crate::virtual_pids::VirtualPids;
+
+pub const LOG_DIR: &str = "/logs";
+/// Trips go here while an SD card is mounted, under `sdcard::SD_DIR`
+const SD_LOG_DIR: &str = "/sd/logs";
+
+const STORAGE_PARTITION: &str = "storage";
+const DEFAULT_PIDS: [u8; 3] = [0x05, 0x0C, 0x0D];
+const DEFAULT_INTERVAL_MS: u32 = 1000;
+const MAX_PIDS: usize = 16;
+const MAX_VIRTUAL: usize = 8;
+const MAX_VIRTUAL_LEN: usize = 256;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Holds the CSV path of the trip being logged, left behind if the power goes
+const OPEN_TRIP_MARKER: &str = "/logs/OPEN.TRP";
+/// The `/debug/replay` recording, downloadable with the logs
+pub const SESSION_FILE: &str = "/logs/SESSION.REC";
+
+/// Mount the FAT `storage` partition at `LOG_DIR`, formatting it if needed
+pub fn mount_storage() -> Result<()> {
+    let base_path = CString::new(LOG_DIR)?;
+    let label = CString::new(STORAGE_PARTITION)?;
+
+    let mount_config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: true,
+        max_files: 4,
+        allocation_unit_size: 4096,
+        ..Default::default()
+    };
+
+    let mut wl_handle: wl_handle_t = 0;
+
+    esp!(unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(
+            base_path.as_ptr(),
+            label.as_ptr(),
+            &mount_config,
+            &mut wl_handle,
+        )
+    })?;
+
+    info!("Storage mounted at {LOG_DIR}");
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogConfig {
+    /// Mode 01 PIDs in hex or virtual PID names, e.g. ["05", "0C", "boost"]
+    pub pids: Vec<String>,
+    pub interval_ms: u32,
+}
+
+#[derive(Serialize)]
+pub struct LogFileInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// How a trip was closed out
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TripEnd {
+    IgnitionOff,
+    /// Logger feature switched off
+    Stopped,
+    /// Supply lost, or found open at boot with a best effort summary from the CSV
+    PowerLoss,
+    /// Restarted before running out of heap
+    LowMemory,
+}
+
+/// Written next to the CSV as `TRIPnnnn.SUM` when the trip ends
+#[derive(Serialize)]
+struct TripSummary<'a> {
+    file: &'a str,
+    /// RFC 3339, None if the clock wasn't set when the trip started or it was recovered
+    started: Option<&'a str>,
+    duration_ms: u64,
+    samples: u32,
+    end: TripEnd,
+}
+
+struct LogFile {
+    name: String,
+    writer: BufWriter<File>,
+    samples: u32,
+    started: Instant,
+    started_at: Option<String>,
+    last_sample: Option<Instant>,
+    last_flush: Instant,
+}
+
+/// Samples the configured PIDs at an interval while running. Each start opens a new
+/// `TRIPnnnn.CSV` file.
+pub struct Logger {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    pids: Mutex<heapless::Vec<u8, MAX_PIDS>>,
+    /// Logged after the PIDs
+    virtuals: Mutex<Vec<String>>,
+    interval: Mutex<Duration>,
+    file: Mutex<Option<LogFile>>,
+    /// Sample at half the rate until then
+    ramp_up_until: Mutex<Option<Instant>>,
+}
+
+impl Logger {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let mut buf = [0u8; MAX_PIDS];
+        let pids = match nvs.get_raw(NVS_LOG_PIDS, &mut buf)? {
+            Some(pids) => heapless::Vec::from_slice(pids).unwrap_or_default(),
+            None => heapless::Vec::from_slice(&DEFAULT_PIDS).unwrap_or_default(),
+        };
+
+        let virtuals = config::load(&nvs, NVS_LOG_VIRTUAL, MAX_VIRTUAL_LEN)?.unwrap_or_default();
+
+        let interval = nvs
+            .get_u32(NVS_LOG_INTERVAL)?
+            .unwrap_or(DEFAULT_INTERVAL_MS);
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            pids: Mutex::new(pids),
+            virtuals: Mutex::new(virtuals),
+            interval: Mutex::new(Duration::from_millis(interval as u64)),
+            file: Mutex::new(None),
+            ramp_up_until: Mutex::new(None),
+        })
+    }
+
+    pub fn config(&self) -> LogConfig {
+        LogConfig {
+            pids: self
+                .pids
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| format!("{p:02X}"))
+                .chain(self.virtuals.lock().unwrap().iter().cloned())
+                .collect(),
+            interval_ms: self.interval.lock().unwrap().as_millis() as u32,
+        }
+    }
+
+    /// Change the sampled PIDs and interval, takes effect with the next log file
+    pub fn set_config(&self, config: &LogConfig, virtual_pids: &VirtualPids) -> Result<()> {
+        let mut pids = Vec::new();
+        let mut virtuals = Vec::new();
+        for p in &config.pids {
+            match u8::from_str_radix(p, 16) {
+                Ok(p) => pids.push(p),
+                Err(_) if virtual_pids.contains(p) => virtuals.push(p.clone()),
+                Err(_) => Err(anyhow!("Invalid pid ({p})"))?,
+            }
+        }
+
+        if let Some(p) = pids.iter().find(|p| pid::name(**p).is_none()) {
+            Err(anyhow!("Unsupported pid ({p:02X})"))?;
+        }
+
+        pid::check_supported(&pids)?;
+
+        let pids = heapless::Vec::from_slice(&pids)
+            .map_err(|_| anyhow!("Too many pids, max ({MAX_PIDS})"))?;
+
+        if virtuals.len() > MAX_VIRTUAL {
+            Err(anyhow!("Too many virtual pids, max ({MAX_VIRTUAL})"))?;
+        }
+
+        if config.interval_ms < 100 {
+            Err(anyhow!("Interval too short, min (100ms)"))?;
+        }
+
+        let mut nvs = self.nvs.lock().unwrap();
+        nvs.set_raw(NVS_LOG_PIDS, &pids)?;
+        nvs.set_raw(NVS_LOG_VIRTUAL, &config::encode(&virtuals)?)?;
+        nvs.set_u32(NVS_LOG_INTERVAL, config.interval_ms)?;
+
+        *self.pids.lock().unwrap() = pids;
+        *self.virtuals.lock().unwrap() = virtuals;
+        *self.interval.lock().unwrap() = Duration::from_millis(config.interval_ms as u64);
+
+        Ok(())
+    }
+
+    /// Sample at half the configured rate for a while, e.g. to go easy on a cold ECU
+    pub fn ramp_up(&self, period: Duration) {
+        *self.ramp_up_until.lock().unwrap() = Some(Instant::now() + period);
+    }
+
+    /// True while a trip is being logged
+    pub fn trip_active(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    /// True when logging and the next sample is due
+    pub fn sample_due(&self) -> bool {
+        let mut interval = *self.interval.lock().unwrap();
+
+        let mut ramp_up_until = self.ramp_up_until.lock().unwrap();
+        if let Some(until) = *ramp_up_until {
+            if until > Instant::now() {
+                interval *= 2;
+            } else {
+                *ramp_up_until = None;
+            }
+        }
+
+        self.file
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|f| f.last_sample.is_none_or(|t| t.elapsed() >= interval))
+    }
+
+    /// Read the PIDs and append a record, a PID with no data is logged as an empty field. The
+    /// virtual PIDs follow, worked out from the same read. The values go to `/events` through
+    /// `smoothing`, the record keeps them as read.
+    pub fn sample(
+        &self,
+        elm: &mut Elm327<'_>,
+        virtual_pids: &VirtualPids,
+        smoothing: &Smoothing,
+    ) -> Result<()> {
+        let pids = self.pids.lock().unwrap().clone();
+        let virtuals = self.virtuals.lock().unwrap().clone();
+
+        // A PID the vehicle doesn't support would only time out, e.g. one saved for another car
+        let mut requested = pids.to_vec();
+        requested.extend(virtual_pids.inputs(&virtuals));
+        requested.sort_unstable();
+        requested.dedup();
+        requested.retain(|p| pid::is_supported(*p));
+
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+
+        file.last_sample = Some(Instant::now());
+
+        // Milliseconds into the trip, then the time if the clock has been set
+        let mut record = file.started.elapsed().as_millis().to_string();
+        record.push(',');
+        record.push_str(&clock::now().unwrap_or_default());
+
+        // Up to 6 PIDs per request rather than a round trip each
+        let values = pid::request_many(elm, &requested).unwrap_or_else(|err| {
+            debug!("Log sample failed {err}");
+            Default::default()
+        });
+
+        // The same values go to `/events`
+        let mut sample = BTreeMap::new();
+
+        for p in pids {
+            record.push(',');
+            match values.get(&p) {
+                Some(value) => {
+                    record.push_str(&format!("{value:.2}"));
+                    sample.insert(format!("{p:02X}"), *value);
+                }
+                None => debug!("Log sample pid {p:02X} no data"),
+            }
+        }
+
+        for (name, value) in virtuals.iter().zip(virtual_pids.eval(&virtuals, &values)) {
+            record.push(',');
+            if let Some(value) = value {
+                record.push_str(&format!("{value:.2}"));
+                sample.insert(name.clone(), value);
+            }
+        }
+
+        writeln!(file.writer, "{record}")?;
+
+        events::publish(
+            events::PIDS,
+            &PidValues {
+                timestamp_ms: uptime_ms(),
+                time: clock::now(),
+                values: smoothing.process(sample),
+            },
+        );
+        file.samples += 1;
+
+        if file.last_flush.elapsed() > FLUSH_INTERVAL {
+            file.writer.flush()?;
+            file.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Open a new `TRIPnnnn.CSV` and mark it as the open trip
+    pub fn start_trip(&self) -> Result<()> {
+        let name = Logger::next_file_name()?;
+        let mut writer = BufWriter::new(File::create(&name)?);
+
+        let mut header = "ms,time".to_owned();
+        for p in self.pids.lock().unwrap().iter() {
+            header.push(',');
+            header.push_str(pid::name(*p).unwrap_or("?"));
+
+            if !pid::is_supported(*p) {
+                warn!("Logged pid {p:02X} not supported by the vehicle, it will be empty");
+            }
+        }
+        for name in self.virtuals.lock().unwrap().iter() {
+            header.push(',');
+            header.push_str(name);
+        }
+        writeln!(writer, "{header}")?;
+
+        let mut marker = File::create(OPEN_TRIP_MARKER)?;
+        marker.write_all(name.as_bytes())?;
+        marker.sync_all()?;
+
+        info!("Logging to {name}");
+
+        *self.file.lock().unwrap() = Some(LogFile {
+            name,
+            writer,
+            samples: 0,
+            started: Instant::now(),
+            started_at: clock::now(),
+            last_sample: None,
+            last_flush: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Flush the CSV to storage, write the trip summary and clear the open trip marker. Done as
+    /// soon as the trip ends, the power may not last much longer.
+    pub fn end_trip(&self, end: TripEnd) -> Result<()> {
+        let Some(mut file) = self.file.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        file.writer.flush()?;
+        file.writer.get_ref().sync_all()?;
+
+        Self::write_summary(
+            &file.name,
+            &TripSummary {
+                file: &file.name,
+                started: file.started_at.as_deref(),
+                duration_ms: file.started.elapsed().as_millis() as u64,
+                samples: file.samples,
+                end,
+            },
+        )?;
+
+        fs::remove_file(OPEN_TRIP_MARKER)?;
+
+        info!("Trip {} ended ({end:?})", file.name);
+
+        Ok(())
+    }
+
+    /// Close out a trip left open by a sudden power loss, the duration and sample count come
+    /// from the last complete CSV record
+    pub fn recover_trip() -> Result<()> {
+        let name = match fs::read_to_string(OPEN_TRIP_MARKER) {
+            Ok(name) => name,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => Err(err)?,
+        };
+
+        warn!("Trip {name} was not finalized, recovering");
+
+        let csv = fs::read_to_string(&name).unwrap_or_default();
+
+        // A torn last line is skipped, it won't parse
+        let records = csv
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split(',').next()?.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+
+        Self::write_summary(
+            &name,
+            &TripSummary {
+                file: &name,
+                started: None,
+                duration_ms: records.last().copied().unwrap_or(0),
+                samples: records.len() as u32,
+                end: TripEnd::PowerLoss,
+            },
+        )?;
+
+        fs::remove_file(OPEN_TRIP_MARKER)?;
+
+        Ok(())
+    }
+
+    fn write_summary(csv_name: &str, summary: &TripSummary) -> Result<()> {
+        let name = csv_name.replace(".CSV", ".SUM");
+
+        let mut file = File::create(&name)?;
+        serde_json::to_writer(&mut file, summary)?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// All log files with their size, on the card and in flash
+    pub fn list() -> Result<Vec<LogFileInfo>> {
+        let mut files = Vec::new();
+
+        for dir in Self::dirs() {
+            // No logs directory on a card that hasn't been logged to yet
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+
+            for entry in entries {
+                let entry = entry?;
+                files.push(LogFileInfo {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(files)
+    }
+
+    /// Path of a log file, on the card if it's there, None if the name isn't a plain file name
+    pub fn path(name: &str) -> Option<String> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return None;
+        }
+
+        let path = Self::dirs()
+            .map(|dir| format!("{dir}/{name}"))
+            .find(|path| fs::metadata(path).is_ok());
+
+        Some(path.unwrap_or_else(|| format!("{LOG_DIR}/{name}")))
+    }
+
+    /// Where the logs are, the card first
+    fn dirs() -> impl Iterator<Item = &'static str> {
+        sdcard::mounted()
+            .then_some(SD_LOG_DIR)
+            .into_iter()
+            .chain([LOG_DIR])
+    }
+
+    /// The card if one is mounted, otherwise the storage partition. A card that won't take the
+    /// directory falls back to the partition rather than losing the trip.
+    fn trip_dir() -> &'static str {
+        if !sdcard::mounted() {
+            return LOG_DIR;
+        }
+
+        match fs::create_dir_all(SD_LOG_DIR) {
+            Ok(()) => SD_LOG_DIR,
+            Err(err) => {
+                error!("Failed to create {SD_LOG_DIR}, logging to flash {err}");
+                LOG_DIR
+            }
+        }
+    }
+
+    fn next_file_name() -> Result<String> {
+        let last = Self::list()?
+            .iter()
+            .filter_map(|f| {
+                f.name
+                    .strip_prefix("TRIP")?
+                    .strip_suffix(".CSV")?
+                    .parse()
+                    .ok()
+            })
+            .max()
+            .unwrap_or(0u32);
+
+        Ok(format!("{}/TRIP{:04}.CSV", Self::trip_dir(), last + 1))
+    }
+}
+
+/// Toggled by `Feature::Logger`
+impl Subsystem for &Logger {
+    fn start(&mut self) -> Result<()> {
+        self.start_trip()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.end_trip(TripEnd::Stopped)?;
+        info!("Logging stopped");
+
+        Ok(())
+    }
+}
@@ -1,69 +1,123 @@
-use std::{
-    net::Ipv4Addr,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
-};
-
-use anyhow::{Context, Result};
+use anyhow::Result;
+use esp_idf_svc::hal::peripherals::Peripherals;
+use log::*;
 
-use elm327::Elm327;
+use gateway::{Gateway, GatewayConfig};
 
-use embedded_svc::http::Headers;
+//use crate::error::MSG_LOGGER;
 
-use esp_idf_svc::{
-    bt::spp::{self, EspSpp, SppConfig},
-    bt::{
-        gap::{DiscoveryMode, EspGap},
-        reduce_bt_memory, BdAddr, BtClassic, BtDriver,
-    },
-    espnow::{EspNow, PeerInfo},
-    eventloop::EspSystemEventLoop,
-    hal::gpio::PinDriver,
-    http::{server::EspHttpServer, Method},
-    io::Write,
-    nvs::{EspDefaultNvsPartition, EspNvs},
-    sys::{
-        esp, esp_bt_gap_set_security_param, esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
-        ESP_BT_IO_CAP_NONE,
-    },
-    wifi::{self, BlockingWifi, EspWifi},
+#[cfg(all(feature = "sd-spi", feature = "rgb-led"))]
+compile_error!("sd-spi and rgb-led both use GPIO18");
+#[cfg(all(feature = "sd-mmc", not(feature = "rgb-led")))]
+compile_error!("sd-mmc uses the devkit LED pin GPIO2, enable rgb-led");
+#[cfg(not(any(
+    feature = "bt",
+    feature = "uart",
+    feature = "wifi-adapter",
+    feature = "mock-elm"
+)))]
+compile_error!("No adapter transport, enable bt, uart, wifi-adapter or mock-elm");
+#[cfg(any(
+    all(
+        feature = "bt",
+        any(feature = "uart", feature = "wifi-adapter", feature = "mock-elm")
+    ),
+    all(feature = "uart", any(feature = "wifi-adapter", feature = "mock-elm")),
+    all(feature = "wifi-adapter", feature = "mock-elm")
+))]
+compile_error!(
+    "One adapter transport only, build uart, wifi-adapter and mock-elm with --no-default-features"
+);
+
+// The adapter side is bt-obd-gw-core, imported at the root so it stays `crate::elm327` etc.
+use bt_obd_gw_core::{
+    api, dtc, elm327, elm_worker, expr, j1939, monitors, pid, response_cache, session, transport,
+    uds,
 };
-use esp_idf_svc::{hal::peripheral::Peripheral, wifi::AuthMethod};
-use esp_idf_svc::{hal::peripherals::Peripherals, http::server::Configuration};
-
-use log::*;
-use spp_handler::SppHandler;
-
-use error::{start_led_blink, ErrorInd, LedBlink};
 
-//use crate::error::MSG_LOGGER;
+#[cfg(feature = "mock-elm")]
+use bt_obd_gw_core::mock_elm;
 
+mod alerts;
+mod announce;
+mod auth;
+#[cfg(feature = "bt")]
 mod bt;
-mod elm327;
+mod cbor;
+mod channels;
+mod clock;
+mod coalesce;
+#[cfg(feature = "bt")]
+mod coex;
+mod config;
+mod console;
+mod crash;
+mod diagnostics;
+#[cfg(feature = "bt")]
+mod discovery;
+mod drivecycle;
+mod dtc_scan;
+mod ecus;
+mod elm_cache;
 mod error;
+mod espnow_cmd;
+mod events;
 // mod espidf;
+mod features;
+mod flow_control;
+mod fuel;
+mod gateway;
+mod held;
+mod http;
+mod http_limits;
+mod idle;
+mod ignition;
+mod init_script;
+mod keep_awake;
+mod lifetime;
+mod link_speed;
+mod log_level;
+mod logger;
+mod maintenance;
+mod memory;
+mod metrics;
+mod network;
+mod ota;
+mod peers;
+mod persist;
+mod policy;
+mod poll_pause;
+mod power;
+mod relay;
+mod request_log;
+mod sdcard;
+mod selftest;
+mod signing;
+mod sleep;
+mod smoothing;
+#[cfg(feature = "bt")]
 mod spp_handler;
-
-// OBDLink MX+ mac
-static BD_ADDR: BdAddr = BdAddr::from_bytes([0x00, 0x04, 0x3E, 0x83, 0xFC, 0x98]);
-
-const ESPNOW_CHANNEL: u8 = 1;
-const NVS_DISC_FAIL_COUNT: &str = "dsc_fail_cnt";
-const SSID: &str = "OBD-ESPWIFI";
-// const PASSWORD: &str = "123456789";
+mod system;
+mod tasks;
+#[cfg(feature = "wifi-adapter")]
+mod tcp_handler;
+mod thresholds;
+mod timeouts;
+mod tls;
+#[cfg(feature = "uart")]
+mod uart_handler;
+mod virtual_pids;
 
 /// OBDLink MX+ BT Classic to HTTP interface. Takes simple HTTP requests for ELM327 commands and
-/// returns the result.
-///
-/// Establishes the BT connection, sets up the ELM327, and then joins the WIFI AP (LCD). Once joined
-/// and the HTTP handler is ready an ESPNOW message is broadcast with our IP address and we can
-/// start servicing ELM327 requests.
+/// returns the result. See `Gateway::run` for the startup sequence.
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
     // esp_idf_svc::log::EspLogger::initialize_default();
     esp_idf_svc::log::init(LevelFilter::Debug);
 
+    // Record panics for the next boot, see GET /last-crash
+    crash::install_panic_hook();
+
     // esp_idf_svc::log::set_target_level("esp_dev", LevelFilter::Debug)?;
     // esp_idf_svc::log::set_target_level("esp_dev::espidf::spp", LevelFilter::Debug)?;
     // esp_idf_svc::log::set_target_level("esp_dev::spp_handler", LevelFilter::Debug)?;
@@ -77,257 +131,62 @@ fn main() -> Result<()> {
     // }
 
     let peripherals = Peripherals::take()?;
-    let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
-    let led = PinDriver::output(peripherals.pins.gpio2)?;
-
-    let led_blink = start_led_blink(led);
-
-    let (wifi_modem, mut bt_modem) = peripherals.modem.split();
-
-    reduce_bt_memory(unsafe { bt_modem.clone_unchecked() })?;
-
-    // unsafe {
-    //     heap_caps_print_heap_info(MALLOC_CAP_DEFAULT);
-    // }
-
-    //========
-    // The ordering of peripheral startup and using HTTP instead of ESPNOW is based around
-    // BT/WIFI co-existence. ESPNOW rx proved to be very unreliable due to the modem switching
-    // causing many timeouts. ESPNOW is a non guaranteed delivery which required a lot of
-    // handling and retries to make sure an elm request was replied to. Also, some elm responses
-    // can exceed the ESPNOW message length which makes an even more complicated protocol on top of
-    // guaranteed delivery.
-    //========
 
-    //-----
-    // NVS
-    //-----
-    // Store the BT discovery failure count, sometimes discovery will fail so we should
-    // try again but don't continually reboot and discover
-    let elm_nvs = Arc::new(EspNvs::new(nvs.clone(), "elm_ns", true)?);
-
-    //-----------
-    // BLUETOOTH
-    //-----------
-    let driver = BtDriver::<BtClassic>::new(bt_modem, Some(nvs.clone()))?;
-
-    driver.set_device_name("OBD-ESP32")?;
-
-    info!("Bluetooth initialized");
-
-    let gap = EspGap::new(&driver)?;
-
-    info!("GAP created");
-
-    let spp_config = SppConfig {
-        mode: spp::Mode::Cb,
-        enable_l2cap_ertm: true,
-        tx_buffer_size: 0,
-    };
-
-    let spp = Arc::new(EspSpp::new(&driver, &spp_config)?);
-
-    info!("SPP created");
-
-    unsafe {
-        gap.subscribe_nonstatic(|event| bt::handle_gap(&gap, event))?;
+    // RGB status LED on an external WS2812, otherwise the devkit LED on GPIO2
+    #[cfg(feature = "rgb-led")]
+    let led = error::Ws2812Led::new(peripherals.rmt.channel0, peripherals.pins.gpio18)?;
+    #[cfg(not(feature = "rgb-led"))]
+    let led = esp_idf_svc::hal::gpio::PinDriver::output(peripherals.pins.gpio2)?;
+
+    #[cfg(feature = "sd-spi")]
+    let sd_mount = sdcard::mount_spi(
+        peripherals.spi3,
+        peripherals.pins.gpio18,
+        peripherals.pins.gpio23,
+        peripherals.pins.gpio19,
+        peripherals.pins.gpio5,
+    );
+    #[cfg(feature = "sd-mmc")]
+    let sd_mount = sdcard::mount_mmc(
+        peripherals.sdmmc1,
+        peripherals.pins.gpio15,
+        peripherals.pins.gpio14,
+        peripherals.pins.gpio2,
+    );
+    #[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+    if let Err(err) = sd_mount {
+        error!("Failed to mount SD card: {err}");
     }
 
-    // No IO capability
-    // gap.set_ssp_io_cap(IOCapabilities::None)?;
-    esp!(unsafe {
-        esp_bt_gap_set_security_param(
-            esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
-            &ESP_BT_IO_CAP_NONE as *const _ as *mut std::ffi::c_void,
-            1,
-        )
-    })?;
-
-    gap.set_pin("1234")?;
-    gap.set_scan_mode(true, DiscoveryMode::Discoverable)?;
-
-    info!("GAP initialized");
-
-    let spp_handler = SppHandler::new(&spp);
-
-    let spp_rem_handle = Arc::clone(&spp_handler.handle);
-    let write_buf = Arc::clone(&spp_handler.write_buf);
-    let read_buf = Arc::clone(&spp_handler.read_buf);
-    let spp_sub = Arc::clone(&spp);
-    let elm_nvs_2 = Arc::clone(&elm_nvs);
-    let led_blink_2 = led_blink.clone();
-    unsafe {
-        spp.subscribe_nonstatic(move |event| {
-            spp_handler::handle_spp(
-                &elm_nvs_2,
-                &led_blink_2,
-                &spp_sub,
-                &spp_rem_handle,
-                &write_buf,
-                &read_buf,
-                event,
-            )
-        })?;
-    }
-
-    spp.start_discovery(&BD_ADDR).error_ind(1)?;
-
-    led_blink.send(LedBlink::Times(1))?;
-
-    //--------
-    // ELM327
-    //--------
-    let elm327: Arc<Mutex<Elm327<'_, BtClassic, &BtDriver<'_, BtClassic>>>> =
-        Arc::new(Mutex::new(Elm327::new(spp_handler)));
-
-    elm327.lock().unwrap().setup().error_ind(2)?;
-
-    led_blink.send(LedBlink::Times(2))?;
-    info!("ELM327 initialized");
-
-    // Reset the discovery fail count if needed
-    if elm_nvs.get_u8(NVS_DISC_FAIL_COUNT)?.is_some_and(|n| n > 0) {
-        info!("Resetting discovery fail count");
-        let _ = elm_nvs.set_u8(NVS_DISC_FAIL_COUNT, 0);
-    }
-
-    //--------------------
-    // Start/Connect WIFI
-    //--------------------
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(wifi_modem, sys_loop.clone(), Some(nvs.clone()))?,
-        sys_loop,
-    )?;
-
-    let ip_addr = connect_wifi_client(&mut wifi).error_ind(3)?;
-
-    led_blink.send(LedBlink::Times(3))?;
-
-    //--------
-    // ESPNOW
-    //--------
-    pub const BROADCAST: [u8; 6] = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
-    let espnow = EspNow::take()?;
-
-    espnow.add_peer(PeerInfo {
-        peer_addr: BROADCAST,
-        channel: ESPNOW_CHANNEL,
-        ifidx: esp_idf_svc::sys::wifi_interface_t_WIFI_IF_STA,
-        encrypt: false,
-        ..Default::default()
-    })?;
-
-    //-------------
-    // HTTP Server
-    //-------------
-    info!("Starting service request handler");
-
-    let server_configuration = Configuration {
-        stack_size: 4096,
-        max_sessions: 4,
-        max_open_sockets: 2,
-        ..Default::default()
-    };
-
-    let mut server = EspHttpServer::new(&server_configuration).context("Failed to create httpd")?;
-
-    /* Handler to get log 'messages'. Not really using it... */
-    /*
-    server
-        .fn_handler::<anyhow::Error, _>("/log", Method::Get, |req| {
-            req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
-                .write_all(MSG_LOGGER.get_messages().as_bytes())
-                .map_err(anyhow::Error::from)
-        })
-        .context("Register log handler")
-        .and(Ok(()))?;
-    */
-
-    unsafe {
-        server
-            .fn_handler_nonstatic::<anyhow::Error, _>("/post", Method::Post, move |mut req| {
-                let len = req.content_len().unwrap_or(0) as usize;
-
-                if len > 250 {
-                    req.into_status_response(413)?
-                        .write_all("Request too big".as_bytes())?;
-                    return Ok(());
-                }
-
-                led_blink.send(LedBlink::High)?;
-
-                let mut buf = vec![0; len];
-                req.read(&mut buf)?;
-
-                let mut elm327 = elm327.lock().unwrap();
-                elm327.write_request(&buf)?;
-
-                let req_string = elm327.read_response()?;
-
-                led_blink.send(LedBlink::Low)?;
-
-                let mut resp = req.into_ok_response()?;
-
-                resp.write_all(req_string.as_bytes())?;
-
-                Ok(())
-            })
-            .context("Register service handler")
-            .and(Ok(()))?
-    }
-
-    //------------------
-    // Off to the races
-    //------------------
-    // Tell the LCD our IP
-    let mut data = heapless::Vec::<u8, 5>::new();
-    let _ = data.push(0x01); // simple ID to identify the espnow packet as ready/IP addr
-    let _ = data.extend_from_slice(&ip_addr.octets());
-
-    espnow.send(BROADCAST, &data).error_ind(2)?;
-
-    loop {
-        thread::sleep(Duration::from_millis(10));
-    }
-}
-
-fn connect_wifi_client(wifi: &mut BlockingWifi<EspWifi<'_>>) -> Result<Ipv4Addr> {
-    let wifi_configuration: wifi::Configuration =
-        wifi::Configuration::Client(wifi::ClientConfiguration {
-            ssid: SSID.try_into().unwrap(),
-            auth_method: AuthMethod::None,
-            channel: Some(ESPNOW_CHANNEL),
-            ..Default::default()
-        });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    wifi.start()?;
-    info!("Wifi started");
-
-    let mut connect_tries = 3;
-    loop {
-        connect_tries -= 1;
-        match wifi.connect() {
-            Ok(_) => {
-                break;
-            }
-            Err(e) => {
-                error!("Wifi connect failed: {e}");
-                if connect_tries == 0 {
-                    Err(e)?;
-                }
-                thread::sleep(Duration::from_millis(1000));
-            }
-        };
-    }
-    info!("Wifi connected");
-
-    wifi.wait_netif_up()?;
-    info!("Wifi netif up");
-
-    info!("Connected Wi-Fi with WIFI_SSID `{SSID}`");
-
-    Ok(wifi.wifi().sta_netif().get_ip_info()?.ip)
+    // Another board is a different modem, LED or `GatewayConfig` here
+    let builder = Gateway::builder()
+        .modem(peripherals.modem)
+        .led(led)
+        .config(GatewayConfig::default());
+
+    // OBD port supply through a 100k/10k divider
+    #[cfg(feature = "power-sense")]
+    let builder = builder.supply_sense(power::AdcSupply::new(
+        peripherals.adc1,
+        peripherals.pins.gpio34,
+        11.0,
+    )?);
+
+    // Ignition switched 12V through a divider on GPIO35
+    #[cfg(feature = "ignition-input")]
+    let builder = builder.ignition_input(peripherals.pins.gpio35.into());
+
+    // Wired adapter on UART1 (TX GPIO17, RX GPIO16) in place of BT, 38400 baud for an ELM327
+    #[cfg(feature = "uart")]
+    let builder = builder.elm_uart(esp_idf_svc::hal::uart::UartDriver::new(
+        peripherals.uart1,
+        peripherals.pins.gpio17,
+        peripherals.pins.gpio16,
+        Option::<esp_idf_svc::hal::gpio::AnyIOPin>::None,
+        Option::<esp_idf_svc::hal::gpio::AnyIOPin>::None,
+        &esp_idf_svc::hal::uart::config::Config::new()
+            .baudrate(esp_idf_svc::hal::units::Hertz(38_400)),
+    )?);
+
+    builder.build()?.run()
 }
@@ -0,0 +1,302 @@
+//! Maintenance reminders from the odometer and engine hours the gateway already reads. Items are
+//! user defined intervals, e.g. an oil change every 10000 km or a generator service every 200
+//! engine hours.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_MAINT_CONFIG, NVS_MAINT_READING};
+use crate::elm327::{Bus, Elm327};
+use crate::persist::Persist;
+use crate::{pid, uds};
+
+pub const MAX_CONFIG_LEN: usize = 1024;
+const MAX_ITEMS: usize = 16;
+pub const READ_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// An item is due within this fraction of its interval
+const DUE_FRACTION: f32 = 0.05;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MaintenanceItem {
+    pub name: String,
+    #[serde(default)]
+    pub every_km: Option<u32>,
+    #[serde(default)]
+    pub every_hours: Option<u32>,
+    /// Odometer when last done
+    #[serde(default)]
+    pub last_km: Option<u32>,
+    /// Engine hours when last done
+    #[serde(default)]
+    pub last_hours: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MaintenanceConfig {
+    /// Read the odometer from this DID (hex, big endian km) rather than mode 01 PID A6
+    #[serde(default)]
+    pub odometer_did: Option<String>,
+    pub items: Vec<MaintenanceItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+struct Reading {
+    odometer_km: Option<u32>,
+    engine_hours: Option<u32>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DueState {
+    Ok,
+    Due,
+    Overdue,
+    /// No reading or no last done value yet
+    Unknown,
+}
+
+#[derive(Serialize)]
+pub struct ItemStatus {
+    pub name: String,
+    pub state: DueState,
+    /// Negative once overdue
+    pub due_in_km: Option<i64>,
+    pub due_in_hours: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct MaintenanceReport {
+    pub odometer_km: Option<u32>,
+    pub engine_hours: Option<u32>,
+    pub items: Vec<ItemStatus>,
+}
+
+/// Reads the odometer and engine hours every few minutes and works out what's due
+pub struct Maintenance {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    persist: Arc<Persist>,
+    config: Mutex<MaintenanceConfig>,
+    reading: Mutex<Reading>,
+    last_read: Mutex<Option<Instant>>,
+    /// Last state reminded about per item, so a reminder goes out once per change
+    reminded: Mutex<BTreeMap<String, DueState>>,
+}
+
+impl Maintenance {
+    pub fn new(nvs: EspNvs<NvsDefault>, persist: Arc<Persist>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_MAINT_CONFIG, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        let reading = persist
+            .load(NVS_MAINT_READING)?
+            .and_then(|reading| serde_json::from_slice(&reading).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            persist,
+            config: Mutex::new(config),
+            reading: Mutex::new(reading),
+            last_read: Mutex::new(None),
+            reminded: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn config(&self) -> MaintenanceConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Replace the items, keeping the last done values of items that are kept
+    pub fn set_config(&self, mut config: MaintenanceConfig) -> Result<()> {
+        if config.items.len() > MAX_ITEMS {
+            Err(anyhow!("Too many items, max ({MAX_ITEMS})"))?;
+        }
+
+        for (i, item) in config.items.iter().enumerate() {
+            if item.name.is_empty()
+                || config.items[..i]
+                    .iter()
+                    .any(|other| other.name == item.name)
+            {
+                Err(anyhow!("Item names must be unique and not empty"))?;
+            }
+            if item.every_km.is_none() && item.every_hours.is_none() {
+                Err(anyhow!("Item ({}) has no interval", item.name))?;
+            }
+        }
+
+        if let Some(did) = &config.odometer_did {
+            u16::from_str_radix(did, 16).map_err(|_| anyhow!("Invalid odometer DID ({did})"))?;
+        }
+
+        let current = self.config.lock().unwrap().clone();
+        for item in config.items.iter_mut() {
+            if let Some(old) = current.items.iter().find(|old| old.name == item.name) {
+                item.last_km = item.last_km.or(old.last_km);
+                item.last_hours = item.last_hours.or(old.last_hours);
+            }
+        }
+
+        self.store(&config)?;
+        self.reminded.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    /// Record an item as done at the current odometer and engine hours
+    pub fn done(&self, name: &str) -> Result<()> {
+        let reading = *self.reading.lock().unwrap();
+        if reading.odometer_km.is_none() && reading.engine_hours.is_none() {
+            Err(anyhow!("No odometer or engine hours read yet"))?;
+        }
+
+        let mut config = self.config();
+        let item = config
+            .items
+            .iter_mut()
+            .find(|item| item.name == name)
+            .ok_or_else(|| anyhow!("No item ({name})"))?;
+
+        item.last_km = reading.odometer_km.or(item.last_km);
+        item.last_hours = reading.engine_hours.or(item.last_hours);
+
+        info!("Maintenance ({name}) done at {reading:?}");
+
+        self.store(&config)?;
+        self.reminded.lock().unwrap().remove(name);
+
+        Ok(())
+    }
+
+    pub fn read_due(&self) -> bool {
+        !self.config.lock().unwrap().items.is_empty()
+            && self
+                .last_read
+                .lock()
+                .unwrap()
+                .is_none_or(|t| t.elapsed() >= READ_INTERVAL)
+    }
+
+    /// Read the odometer and engine hours, returning the items that have just come due or
+    /// overdue for a reminder
+    pub fn read(&self, elm: &mut Elm327<'_>) -> Result<Vec<(String, DueState)>> {
+        *self.last_read.lock().unwrap() = Some(Instant::now());
+
+        let odometer_did = self.config.lock().unwrap().odometer_did.clone();
+        let odometer_km = match odometer_did.and_then(|did| u16::from_str_radix(&did, 16).ok()) {
+            Some(did) => elm
+                .select_bus(Bus::Hs)
+                .and_then(|_| uds::read_did(elm, did))
+                .ok()
+                .and_then(|data| {
+                    let bytes = data.get(..data.len().min(4))?;
+                    Some(bytes.iter().fold(0u32, |km, b| (km << 8) | *b as u32))
+                }),
+            None => pid::request(elm, pid::ODOMETER).ok().map(|km| km as u32),
+        };
+
+        // Total run time in seconds, bytes B to E after the support byte
+        let engine_hours = pid::request_data(elm, pid::ENGINE_RUN_TIME)
+            .ok()
+            .and_then(|data| Some(u32::from_be_bytes(data.get(1..5)?.try_into().ok()?) / 3600));
+
+        {
+            let mut reading = self.reading.lock().unwrap();
+            reading.odometer_km = odometer_km.or(reading.odometer_km);
+            reading.engine_hours = engine_hours.or(reading.engine_hours);
+
+            self.persist
+                .store(NVS_MAINT_READING, serde_json::to_vec(&*reading)?)?;
+        }
+
+        let mut reminded = self.reminded.lock().unwrap();
+        let reminders = self
+            .report()
+            .items
+            .into_iter()
+            .filter(|item| matches!(item.state, DueState::Due | DueState::Overdue))
+            .filter(|item| reminded.insert(item.name.clone(), item.state) != Some(item.state))
+            .map(|item| (item.name, item.state))
+            .collect();
+
+        Ok(reminders)
+    }
+
+    pub fn report(&self) -> MaintenanceReport {
+        let reading = *self.reading.lock().unwrap();
+        let config = self.config.lock().unwrap();
+
+        let items = config
+            .items
+            .iter()
+            .map(|item| {
+                let due_in_km = remaining(item.every_km, item.last_km, reading.odometer_km);
+                let due_in_hours =
+                    remaining(item.every_hours, item.last_hours, reading.engine_hours);
+
+                let states = [
+                    state(due_in_km, item.every_km),
+                    state(due_in_hours, item.every_hours),
+                ];
+
+                ItemStatus {
+                    name: item.name.clone(),
+                    state: states
+                        .into_iter()
+                        .flatten()
+                        .max_by_key(|state| match state {
+                            DueState::Overdue => 2,
+                            DueState::Due => 1,
+                            _ => 0,
+                        })
+                        .unwrap_or(DueState::Unknown),
+                    due_in_km,
+                    due_in_hours,
+                }
+            })
+            .collect();
+
+        MaintenanceReport {
+            odometer_km: reading.odometer_km,
+            engine_hours: reading.engine_hours,
+            items,
+        }
+    }
+
+    fn store(&self, config: &MaintenanceConfig) -> Result<()> {
+        let value = config::encode(config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!(
+                "Maintenance config too big, max ({MAX_CONFIG_LEN})"
+            ))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_MAINT_CONFIG, &value)?;
+        *self.config.lock().unwrap() = config.clone();
+
+        Ok(())
+    }
+}
+
+/// Distance or hours left until the next service, None if it can't be worked out
+fn remaining(every: Option<u32>, last: Option<u32>, now: Option<u32>) -> Option<i64> {
+    Some(last? as i64 + every? as i64 - now? as i64)
+}
+
+fn state(remaining: Option<i64>, every: Option<u32>) -> Option<DueState> {
+    let remaining = remaining?;
+
+    Some(if remaining < 0 {
+        DueState::Overdue
+    } else if (remaining as f32) <= every? as f32 * DUE_FRACTION {
+        DueState::Due
+    } else {
+        DueState::Ok
+    })
+}
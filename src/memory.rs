@@ -0,0 +1,136 @@
+//! Heap and stack watch. BT, WIFI and the HTTP server leave little room on a WROOM module, and
+//! running out shows up as an allocation failure somewhere that can't recover. Below the
+//! thresholds the gateway warns, then sheds load, then restarts cleanly.
+use std::{
+    ffi::CString,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::sys::{
+    esp_get_free_heap_size, heap_caps_get_largest_free_block, uxTaskGetStackHighWaterMark,
+    xTaskGetHandle, MALLOC_CAP_8BIT,
+};
+use log::*;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const LOW_FREE: u32 = 24 * 1024;
+const SHED_FREE: u32 = 16 * 1024;
+const CRITICAL_FREE: u32 = 8 * 1024;
+/// Fragmentation fails the big allocations (TLS, JSON bodies) first, whatever the total
+const SHED_LARGEST_BLOCK: u32 = 4 * 1024;
+/// Unused stack, in bytes, below which a task is logged
+const STACK_WARN: u32 = 512;
+/// Tasks checked for stack use, those not running in this build are skipped
+const TASKS: [&str; 7] = [
+    "main",
+    "elm_worker",
+    "httpd",
+    "BTC_TASK",
+    "BTU_TASK",
+    "wifi",
+    "tiT",
+];
+
+/// Set while new HTTP requests are being turned away
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+pub fn shedding() -> bool {
+    SHEDDING.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pressure {
+    Ok,
+    /// Logged, nothing else
+    Low,
+    /// New HTTP requests are refused and the response cache flushed
+    Shedding,
+    /// Restart before an allocation fails
+    Critical,
+}
+
+pub struct MemoryMonitor {
+    last_poll: Option<Instant>,
+    pressure: Pressure,
+    /// Tasks already logged for stack use, so each is logged once
+    warned_tasks: Vec<&'static str>,
+}
+
+impl MemoryMonitor {
+    pub fn new() -> Self {
+        Self {
+            last_poll: None,
+            pressure: Pressure::Ok,
+            warned_tasks: Vec::new(),
+        }
+    }
+
+    pub fn poll_due(&self) -> bool {
+        self.last_poll.is_none_or(|t| t.elapsed() >= POLL_INTERVAL)
+    }
+
+    /// Sample the heap and task stacks, returning the pressure if it changed
+    pub fn poll(&mut self) -> Option<Pressure> {
+        self.last_poll = Some(Instant::now());
+
+        self.check_stacks();
+
+        let (free, largest) = unsafe {
+            (
+                esp_get_free_heap_size(),
+                heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) as u32,
+            )
+        };
+
+        let pressure = if free < CRITICAL_FREE {
+            Pressure::Critical
+        } else if free < SHED_FREE || largest < SHED_LARGEST_BLOCK {
+            Pressure::Shedding
+        } else if free < LOW_FREE {
+            Pressure::Low
+        } else {
+            Pressure::Ok
+        };
+
+        // Keep shedding until the heap is back to normal, not just above the line
+        match pressure {
+            Pressure::Shedding | Pressure::Critical => SHEDDING.store(true, Ordering::Relaxed),
+            Pressure::Ok => SHEDDING.store(false, Ordering::Relaxed),
+            Pressure::Low => {}
+        }
+
+        if pressure == self.pressure {
+            return None;
+        }
+
+        match pressure {
+            Pressure::Ok => info!("Heap back to normal, free ({free}) largest block ({largest})"),
+            _ => warn!("Heap pressure {pressure:?}, free ({free}) largest block ({largest})"),
+        }
+        self.pressure = pressure;
+
+        Some(pressure)
+    }
+
+    fn check_stacks(&mut self) {
+        for task in TASKS {
+            if self.warned_tasks.contains(&task) {
+                continue;
+            }
+
+            let name = CString::new(task).unwrap();
+            let handle = unsafe { xTaskGetHandle(name.as_ptr()) };
+            if handle.is_null() {
+                continue;
+            }
+
+            // In bytes on ESP-IDF
+            let unused = unsafe { uxTaskGetStackHighWaterMark(handle) };
+            if unused < STACK_WARN {
+                warn!("Task ({task}) close to its stack size, ({unused}) bytes never used");
+                self.warned_tasks.push(task);
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Counters for `GET /metrics`, in the Prometheus text format so the gateway can be scraped
+//! straight into Grafana.
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use esp_idf_svc::sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, heap_caps_get_largest_free_block,
+    MALLOC_CAP_8BIT,
+};
+
+use crate::http::uptime_ms;
+#[cfg(feature = "bt")]
+use crate::{bt, spp_handler};
+use crate::{memory, pid};
+
+pub use bt_obd_gw_core::metrics::{
+    Counter, Histogram, LatencySummary, ADAPTER_WAKES, ELM_ERRORS, ELM_FIRST_BYTE_LATENCY,
+    ELM_READ_TIMEOUTS, ELM_REQUESTS, ELM_RESETS, ELM_RESPONSE_LATENCY, ELM_WRITE_LATENCY,
+    KEEP_AWAKE_PINGS, LATENCY_BUCKETS_MS, WORKER_LATENCY, WORKER_TIMEOUTS,
+};
+
+/// Requests to any endpoint, rejected ones included
+pub static HTTP_REQUESTS: Counter = Counter::new();
+/// SPP connections opened, the first one included
+pub static SPP_CONNECTS: Counter = Counter::new();
+/// SPP writes sent again for data left over after a partial write or congestion
+pub static BT_WRITE_RETRIES: Counter = Counter::new();
+/// HTTP requests turned away while memory was short
+pub static SHED_REQUESTS: Counter = Counter::new();
+
+/// Where the time goes in an adapter request, for `GET /status`
+#[derive(Serialize)]
+pub struct Latencies {
+    /// Writing the request to the transport
+    pub write: LatencySummary,
+    /// Request written to the first byte back
+    pub first_byte: LatencySummary,
+    /// Request written to the prompt
+    pub response: LatencySummary,
+    /// A handler's wait on the worker, queueing included
+    pub worker: LatencySummary,
+}
+
+pub fn latencies() -> Latencies {
+    Latencies {
+        write: ELM_WRITE_LATENCY.summary(),
+        first_byte: ELM_FIRST_BYTE_LATENCY.summary(),
+        response: ELM_RESPONSE_LATENCY.summary(),
+        worker: WORKER_LATENCY.summary(),
+    }
+}
+
+/// Everything in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    #[cfg(feature = "bt")]
+    let spp = spp_handler::stats();
+
+    let counters = [
+        ("http_requests", "HTTP requests", HTTP_REQUESTS.get()),
+        (
+            "elm_requests",
+            "Requests sent to the adapter",
+            ELM_REQUESTS.get(),
+        ),
+        (
+            "elm_errors",
+            "Adapter responses that didn't arrive",
+            ELM_ERRORS.get(),
+        ),
+        ("elm_resets", "Adapter resets", ELM_RESETS.get()),
+        (
+            "spp_reconnects",
+            "SPP reconnects to the adapter",
+            SPP_CONNECTS.get().saturating_sub(1),
+        ),
+        (
+            "bt_write_retries",
+            "SPP writes retried",
+            BT_WRITE_RETRIES.get(),
+        ),
+        (
+            "shed_requests",
+            "HTTP requests refused while memory was short",
+            SHED_REQUESTS.get(),
+        ),
+        (
+            "adapter_wakes",
+            "Adapter woken from idle low power",
+            ADAPTER_WAKES.get(),
+        ),
+        (
+            "keep_awake_pings",
+            "Requests sent to keep an idle adapter awake",
+            KEEP_AWAKE_PINGS.get(),
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "spp_write_waits",
+            "Writes that waited for the write buffer",
+            spp.write_waits,
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "spp_write_full",
+            "Writes refused with the write buffer full",
+            spp.write_full,
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "spp_read_overflow_bytes",
+            "Received bytes dropped with the read buffer full",
+            spp.read_overflow_bytes,
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "spp_congestions",
+            "Times the SPP link reported congestion",
+            spp.congestions,
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "spp_congested_ms",
+            "Time the SPP link was congested",
+            spp.congested_ms,
+        ),
+    ];
+
+    for (name, help, value) in counters {
+        metric(&mut out, &format!("{name}_total"), help, "counter");
+        let _ = writeln!(out, "obdgw_{name}_total {value}");
+    }
+
+    metric(&mut out, "timeouts_total", "Timeouts by where", "counter");
+    let _ = writeln!(
+        out,
+        "obdgw_timeouts_total{{kind=\"adapter_read\"}} {}",
+        ELM_READ_TIMEOUTS.get()
+    );
+    let _ = writeln!(
+        out,
+        "obdgw_timeouts_total{{kind=\"worker_reply\"}} {}",
+        WORKER_TIMEOUTS.get()
+    );
+
+    metric(
+        &mut out,
+        "elm_latency_seconds",
+        "Adapter request latency by stage",
+        "histogram",
+    );
+    for (stage, histogram) in [
+        ("write", &ELM_WRITE_LATENCY),
+        ("first_byte", &ELM_FIRST_BYTE_LATENCY),
+        ("response", &ELM_RESPONSE_LATENCY),
+        ("worker", &WORKER_LATENCY),
+    ] {
+        render_histogram(&mut out, "elm_latency_seconds", stage, histogram);
+    }
+
+    let (free, min_free, largest) = unsafe {
+        (
+            esp_get_free_heap_size(),
+            esp_get_minimum_free_heap_size(),
+            heap_caps_get_largest_free_block(MALLOC_CAP_8BIT),
+        )
+    };
+
+    let gauges = [
+        (
+            "uptime_seconds",
+            "Time since boot",
+            uptime_ms() as f32 / 1000.0,
+        ),
+        ("heap_free_bytes", "Free heap", free as f32),
+        (
+            "heap_min_free_bytes",
+            "Lowest free heap since boot",
+            min_free as f32,
+        ),
+        (
+            "heap_largest_free_block_bytes",
+            "Largest heap block that can be allocated",
+            largest as f32,
+        ),
+    ];
+
+    for (name, help, value) in gauges {
+        metric(&mut out, name, help, "gauge");
+        let _ = writeln!(out, "obdgw_{name} {value}");
+    }
+
+    metric(
+        &mut out,
+        "load_shedding",
+        "1 while HTTP requests are refused for memory",
+        "gauge",
+    );
+    let _ = writeln!(out, "obdgw_load_shedding {}", memory::shedding() as u8);
+
+    #[cfg(feature = "bt")]
+    {
+        metric(
+            &mut out,
+            "spp_write_high_water_bytes",
+            "Most bytes queued for the adapter at once",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "obdgw_spp_write_high_water_bytes {}",
+            spp.write_high_water
+        );
+
+        let link = bt::link_quality();
+        if let Some(rssi_delta) = link.rssi_delta {
+            metric(
+                &mut out,
+                "bt_rssi_delta_db",
+                "Adapter link RSSI outside the golden receive power range",
+                "gauge",
+            );
+            let _ = writeln!(out, "obdgw_bt_rssi_delta_db {rssi_delta}");
+        }
+        metric(
+            &mut out,
+            "bt_weak_signal",
+            "1 while the adapter link is weak",
+            "gauge",
+        );
+        let _ = writeln!(out, "obdgw_bt_weak_signal {}", link.weak as u8);
+    }
+
+    metric(
+        &mut out,
+        "pid_value",
+        "Last value read for each PID",
+        "gauge",
+    );
+    for (p, value) in pid::last_values() {
+        let (name, unit) = (pid::name(p), pid::unit_range(p));
+        let _ = writeln!(
+            out,
+            "obdgw_pid_value{{pid=\"{p:02X}\",name=\"{}\",unit=\"{}\"}} {value}",
+            name.unwrap_or_default(),
+            unit.map(|(unit, _, _)| unit).unwrap_or_default(),
+        );
+    }
+
+    out
+}
+
+/// Cumulative buckets in seconds, as Prometheus expects
+fn render_histogram(out: &mut String, name: &str, stage: &str, histogram: &Histogram) {
+    let mut count = 0;
+    for (i, n) in histogram.counts().into_iter().enumerate() {
+        count += n;
+        let le = match LATENCY_BUCKETS_MS.get(i) {
+            Some(bound) => (*bound as f32 / 1000.0).to_string(),
+            None => "+Inf".to_owned(),
+        };
+        let _ = writeln!(
+            out,
+            "obdgw_{name}_bucket{{stage=\"{stage}\",le=\"{le}\"}} {count}"
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "obdgw_{name}_sum{{stage=\"{stage}\"}} {}",
+        histogram.sum_ms() as f32 / 1000.0
+    );
+    let _ = writeln!(out, "obdgw_{name}_count{{stage=\"{stage}\"}} {count}");
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP obdgw_{name} {help}");
+    let _ = writeln!(out, "# TYPE obdgw_{name} {kind}");
+}
@@ -0,0 +1,524 @@
+//! Watches the STA connection so the gateway can re-announce itself when its address changes
+//! (lease renewal, AP restart) and reconnect, backing off, when the AP goes away. Also the
+//! network settings from NVS, a static address saves waiting on the LCD's DHCP at boot, and
+//! which network a request came in on, for the networks the HTTP server is served on.
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
+    ipv4::{self, ClientSettings, DHCPClientSettings, Mask, Subnet},
+    mdns::EspMdns,
+    netif::{EspNetif, IpEvent, NetifConfiguration},
+    nvs::{EspNvs, NvsDefault},
+    sys::EspError,
+    wifi::WifiEvent,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_NET_CONFIG};
+
+pub const MAX_CONFIG_LEN: usize = 384;
+/// DHCP hostname limit
+const MAX_HOSTNAME_LEN: usize = 30;
+/// Wait before the first reconnect attempt, doubled after each one that fails
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// An association without an address by then is dropped and tried again
+const ADDRESS_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct NetConfig {
+    /// Fixed address on the LCD's AP, DHCP when left out
+    #[serde(default)]
+    pub static_ip: Option<StaticIp>,
+    /// Replaces `GatewayConfig::hostname` for DHCP and mDNS
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Our own AP next to the LCD link, on the LCD's channel
+    #[serde(default)]
+    pub access_point: Option<AccessPoint>,
+    /// Joined in place of the LCD's network when it's in range at boot on the ESPNOW channel
+    #[serde(default)]
+    pub home_wifi: Option<HomeWifi>,
+    /// The networks the HTTP server answers on, all of them when left out
+    #[serde(default)]
+    pub serve_on: Option<Vec<Network>>,
+}
+
+impl NetConfig {
+    /// Without the passwords, as `GET /config/network` returns it
+    pub fn redact(&mut self) {
+        if let Some(ap) = self.access_point.as_mut() {
+            ap.password.clear();
+        }
+        if let Some(home) = self.home_wifi.as_mut() {
+            home.password.clear();
+        }
+    }
+
+    /// Empty passwords, as `redact` leaves them, keep the ones in `stored`
+    pub fn keep_passwords(&mut self, stored: NetConfig) {
+        if let (Some(ap), Some(stored)) = (self.access_point.as_mut(), stored.access_point) {
+            if ap.password.is_empty() {
+                ap.password = stored.password;
+            }
+        }
+        if let (Some(home), Some(stored)) = (self.home_wifi.as_mut(), stored.home_wifi) {
+            if home.password.is_empty() {
+                home.password = stored.password;
+            }
+        }
+    }
+}
+
+/// WPA2 AP run alongside the STA connection to the LCD
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessPoint {
+    pub ssid: String,
+    pub password: String,
+}
+
+/// WPA2 network the STA joins when parked, e.g. at home
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HomeWifi {
+    pub ssid: String,
+    pub password: String,
+}
+
+/// Where a request came in
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    /// The STA on the LCD's network
+    Lcd,
+    /// The STA on `home_wifi`
+    Home,
+    /// Our own AP
+    AccessPoint,
+}
+
+/// The STA joined `home_wifi`, see `Network`
+static ON_HOME: AtomicBool = AtomicBool::new(false);
+/// Our AP's address, None without one
+static AP_ADDR: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+
+/// The STA has joined `home_wifi`, or the LCD's network with false
+pub fn set_on_home(on_home: bool) {
+    ON_HOME.store(on_home, Ordering::Relaxed);
+}
+
+pub fn on_home() -> bool {
+    ON_HOME.load(Ordering::Relaxed)
+}
+
+pub fn set_ap_addr(addr: Option<Ipv4Addr>) {
+    *AP_ADDR.lock().unwrap() = addr;
+}
+
+/// The network a connection to `local`, the gateway's end, came in on
+pub fn network_of(local: Ipv4Addr) -> Network {
+    if *AP_ADDR.lock().unwrap() == Some(local) {
+        Network::AccessPoint
+    } else if on_home() {
+        Network::Home
+    } else {
+        Network::Lcd
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    #[serde(default)]
+    pub dns: Option<Ipv4Addr>,
+}
+
+/// Network settings in NVS, used from the next boot
+pub struct NetSettings {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<NetConfig>,
+}
+
+impl NetSettings {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_NET_CONFIG, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> NetConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: NetConfig) -> Result<()> {
+        if let Some(static_ip) = &config.static_ip {
+            let prefix = prefix_len(static_ip.netmask)
+                .ok_or_else(|| anyhow!("Invalid netmask ({})", static_ip.netmask))?;
+
+            let net = |addr: Ipv4Addr| u32::from(addr) & u32::from(static_ip.netmask);
+            if prefix == 0 || net(static_ip.ip) != net(static_ip.gateway) {
+                Err(anyhow!("Gateway must be on the same subnet as the address"))?;
+            }
+        }
+
+        if let Some(hostname) = &config.hostname {
+            if hostname.is_empty()
+                || hostname.len() > MAX_HOSTNAME_LEN
+                || hostname.starts_with('-')
+                || !hostname
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                Err(anyhow!(
+                    "Hostname must be 1 to {MAX_HOSTNAME_LEN} letters, digits or '-'"
+                ))?;
+            }
+        }
+
+        if let Some(ap) = &config.access_point {
+            if ap.ssid.is_empty() || ap.ssid.len() > 32 {
+                Err(anyhow!("AP SSID must be 1 to 32 characters"))?;
+            }
+            if !(8..=63).contains(&ap.password.len()) {
+                Err(anyhow!("AP password must be 8 to 63 characters"))?;
+            }
+        }
+
+        if let Some(home) = &config.home_wifi {
+            if home.ssid.is_empty() || home.ssid.len() > 32 {
+                Err(anyhow!("Home WIFI SSID must be 1 to 32 characters"))?;
+            }
+            if !(8..=63).contains(&home.password.len()) {
+                Err(anyhow!("Home WIFI password must be 8 to 63 characters"))?;
+            }
+        }
+
+        if config
+            .serve_on
+            .as_ref()
+            .is_some_and(|networks| networks.is_empty())
+        {
+            Err(anyhow!(
+                "serve_on needs a network, leave it out for all of them"
+            ))?;
+        }
+
+        let value = config::encode(&config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Network config too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_NET_CONFIG, &value)?;
+        info!(
+            "Network config updated, used from the next boot, static IP ({:?}), hostname ({:?}), AP ({:?}), home WIFI ({:?})",
+            config.static_ip.map(|static_ip| static_ip.ip),
+            config.hostname,
+            config.access_point.as_ref().map(|ap| &ap.ssid),
+            config.home_wifi.as_ref().map(|home| &home.ssid)
+        );
+        *self.config.lock().unwrap() = config;
+
+        Ok(())
+    }
+
+    /// STA interface with the static address, or DHCP asking for `hostname`. The static address
+    /// is on the LCD's network, `home` is always DHCP.
+    pub fn sta_netif(&self, hostname: &str, home: bool) -> Result<EspNetif> {
+        let config = self.config();
+
+        let ip_configuration = match config.static_ip.filter(|_| !home) {
+            Some(static_ip) => {
+                info!("Static IP {}", static_ip.ip);
+
+                ipv4::ClientConfiguration::Fixed(ClientSettings {
+                    ip: static_ip.ip,
+                    subnet: Subnet {
+                        gateway: static_ip.gateway,
+                        mask: Mask(prefix_len(static_ip.netmask).unwrap_or(24)),
+                    },
+                    dns: static_ip.dns,
+                    secondary_dns: None,
+                })
+            }
+            None => ipv4::ClientConfiguration::DHCP(DHCPClientSettings {
+                hostname: hostname.try_into().ok(),
+            }),
+        };
+
+        let netif = EspNetif::new_with_conf(&NetifConfiguration {
+            ip_configuration: Some(ipv4::Configuration::Client(ip_configuration)),
+            ..NetifConfiguration::wifi_default_client()
+        })?;
+
+        Ok(netif)
+    }
+}
+
+/// Prefix length of a netmask, None if the bits aren't contiguous
+fn prefix_len(netmask: Ipv4Addr) -> Option<u8> {
+    let bits = u32::from(netmask);
+
+    (bits.leading_ones() == bits.count_ones()).then_some(bits.count_ones() as u8)
+}
+
+pub enum NetEvent {
+    IpAssigned(Ipv4Addr),
+    Disconnected,
+}
+
+pub struct NetWatch {
+    events: Receiver<NetEvent>,
+    _ip_sub: EspSubscription<'static, System>,
+    _wifi_sub: EspSubscription<'static, System>,
+}
+
+impl NetWatch {
+    pub fn new(sys_loop: &EspSystemEventLoop) -> Result<Self> {
+        let (ip_tx, events) = mpsc::channel();
+        let wifi_tx = ip_tx.clone();
+
+        let ip_sub = sys_loop.subscribe::<IpEvent, _>(move |event| {
+            if let IpEvent::DhcpIpAssigned(assignment) = event {
+                let _ = ip_tx.send(NetEvent::IpAssigned(assignment.ip()));
+            }
+        })?;
+
+        let wifi_sub = sys_loop.subscribe::<WifiEvent, _>(move |event| {
+            if let WifiEvent::StaDisconnected(_) = event {
+                let _ = wifi_tx.send(NetEvent::Disconnected);
+            }
+        })?;
+
+        Ok(Self {
+            events,
+            _ip_sub: ip_sub,
+            _wifi_sub: wifi_sub,
+        })
+    }
+
+    pub fn poll(&self) -> Option<NetEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiState {
+    Connected,
+    /// The AP went away, see `WifiStatus::attempts`
+    Reconnecting,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct WifiStatus {
+    pub state: WifiState,
+    /// The last address DHCP assigned, or the static one
+    pub ip: Ipv4Addr,
+    /// Times the AP went away since boot
+    pub disconnects: u32,
+    /// Failed attempts since it went away
+    pub attempts: u32,
+    /// Time in the current state
+    pub for_ms: u64,
+}
+
+struct Supervised {
+    state: WifiState,
+    ip: Ipv4Addr,
+    disconnects: u32,
+    attempts: u32,
+    since: Instant,
+    /// None while connected
+    reconnect_at: Option<Instant>,
+    /// Associated, waiting on DHCP until `reconnect_at`
+    associated: bool,
+}
+
+/// Reconnects the STA after the AP goes away, e.g. the LCD rebooting. The first attempt is
+/// straight away, then the wait doubles from 2s up to a minute so a missing AP doesn't keep the
+/// radio busy scanning. The main loop feeds it the `NetWatch` events and runs the attempts,
+/// HTTP reads its state for `/status`.
+pub struct WifiSupervisor {
+    state: Mutex<Supervised>,
+}
+
+impl Supervised {
+    fn backoff(&self) -> Duration {
+        RECONNECT_BACKOFF
+            .saturating_mul(1 << self.attempts.min(8))
+            .min(MAX_RECONNECT_BACKOFF)
+    }
+}
+
+impl WifiSupervisor {
+    pub fn new(ip: Ipv4Addr) -> Self {
+        Self {
+            state: Mutex::new(Supervised {
+                state: WifiState::Connected,
+                ip,
+                disconnects: 0,
+                attempts: 0,
+                since: Instant::now(),
+                reconnect_at: None,
+                associated: false,
+            }),
+        }
+    }
+
+    /// The AP went away. Failed attempts disconnect again, only the first one counts. Dropped
+    /// after associating, before the address, the next attempt is scheduled with the backoff.
+    pub fn disconnected(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        match state.state {
+            WifiState::Connected => {
+                warn!("Wifi disconnected");
+                state.state = WifiState::Reconnecting;
+                state.disconnects += 1;
+                state.attempts = 0;
+                state.since = Instant::now();
+                state.reconnect_at = Some(Instant::now());
+            }
+            WifiState::Reconnecting if state.associated => {
+                state.associated = false;
+                state.attempts += 1;
+                let backoff = state.backoff();
+
+                warn!(
+                    "Wifi dropped before an address, next attempt in {}s",
+                    backoff.as_secs()
+                );
+                state.reconnect_at = Some(Instant::now() + backoff);
+            }
+            _ => {}
+        }
+    }
+
+    /// Still without an address `ADDRESS_TIMEOUT` after associating. Schedules the next attempt
+    /// with the backoff, the main loop disconnects so it starts over.
+    pub fn address_timed_out(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.associated {
+            return false;
+        }
+
+        state.associated = false;
+        state.attempts += 1;
+        let backoff = state.backoff();
+
+        warn!(
+            "Wifi got no address in {}s, next attempt in {}s",
+            ADDRESS_TIMEOUT.as_secs(),
+            backoff.as_secs()
+        );
+        state.reconnect_at = Some(Instant::now() + backoff);
+
+        true
+    }
+
+    /// DHCP assigned an address, connected again if it had gone away
+    pub fn connected(&self, ip: Ipv4Addr) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.state == WifiState::Reconnecting {
+            info!(
+                "Wifi reconnected after {}s and {} attempts",
+                state.since.elapsed().as_secs(),
+                state.attempts
+            );
+            state.state = WifiState::Connected;
+            state.since = Instant::now();
+        }
+
+        state.ip = ip;
+        state.attempts = 0;
+        state.reconnect_at = None;
+        state.associated = false;
+    }
+
+    pub fn reconnect_due(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .reconnect_at
+            .is_some_and(|at| at <= Instant::now())
+    }
+
+    /// Record a reconnect attempt. A connection waits up to `ADDRESS_TIMEOUT` for its address,
+    /// a failure backs off.
+    pub fn attempted(&self, result: Result<(), EspError>) {
+        let mut state = self.state.lock().unwrap();
+
+        match result {
+            Ok(()) => {
+                info!("Wifi associated, waiting for an address");
+                state.associated = true;
+                state.reconnect_at = Some(Instant::now() + ADDRESS_TIMEOUT);
+            }
+            Err(err) => {
+                let backoff = state.backoff();
+                state.associated = false;
+                state.attempts += 1;
+
+                error!(
+                    "Wifi reconnect attempt {} failed, next in {}s: {err}",
+                    state.attempts,
+                    backoff.as_secs()
+                );
+                state.reconnect_at = Some(Instant::now() + backoff);
+            }
+        }
+    }
+
+    pub fn status(&self) -> WifiStatus {
+        let state = self.state.lock().unwrap();
+
+        WifiStatus {
+            state: state.state,
+            ip: state.ip,
+            disconnects: state.disconnects,
+            attempts: state.attempts,
+            for_ms: state.since.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+/// Advertise the HTTP service as `<hostname>.local`
+pub fn start_mdns(hostname: &str, https: bool) -> Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name("OBD gateway")?;
+    if https {
+        mdns.add_service(None, "_https", "_tcp", 443, &[])?;
+    } else {
+        mdns.add_service(None, "_http", "_tcp", 80, &[])?;
+    }
+
+    info!("mDNS started as {hostname}.local");
+
+    Ok(mdns)
+}
+
+/// Re-announce the mDNS records after an address change
+pub fn update_mdns(mdns: &mut EspMdns, hostname: &str) -> Result<()> {
+    mdns.set_hostname(hostname)?;
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Firmware updates over HTTP, `POST /ota` with the app image as the body, served in diagnostics
+//! mode so a build that fails at startup can be replaced without physical access. The image goes
+//! to the OTA slot that isn't running and boots from the restart after it. With rollback on
+//! (`sdkconfig.defaults`) a new image that doesn't get as far as `mark_valid` is rolled back to
+//! the earlier one on the next boot.
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use esp_idf_svc::{
+    hal::reset,
+    http::Method,
+    io::{Read, Write},
+    ota::EspOta,
+};
+use log::*;
+
+use crate::http::{error_response, Router};
+
+const CHUNK_LEN: usize = 4096;
+
+/// The running image got as far as its HTTP server, keep booting it
+pub fn mark_valid() {
+    if let Err(err) = EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        error!("Failed to mark the firmware valid {err}");
+    }
+}
+
+pub fn register_handler(router: &mut Router<'_, '_>) -> Result<()> {
+    unsafe {
+        router
+            .handler("/ota", Method::Post, |mut req| {
+                let len = req.content_len().unwrap_or(0) as usize;
+                if len == 0 {
+                    return error_response(req, 400, "Firmware image required");
+                }
+
+                info!("Firmware update, {len} bytes");
+
+                let mut flash = || -> Result<()> {
+                    let mut ota = EspOta::new()?;
+                    let mut update = ota.initiate_update()?;
+
+                    match write_image(&mut req, len, &mut update) {
+                        Ok(()) => update.complete().context("Image rejected")?,
+                        Err(err) => {
+                            if let Err(err) = update.abort() {
+                                error!("Failed to abort the update {err}");
+                            }
+                            return Err(err);
+                        }
+                    }
+
+                    Ok(())
+                };
+
+                if let Err(err) = flash() {
+                    error!("Firmware update failed {err:#}");
+                    return error_response(req, 500, &format!("{err:#}"));
+                }
+
+                req.into_ok_response()?.write_all(b"Updated, restarting")?;
+
+                info!("Firmware updated, restarting");
+                thread::sleep(Duration::from_millis(100));
+                reset::restart();
+            })
+            .context("Register OTA handler")
+            .and(Ok(()))
+    }
+}
+
+fn write_image(req: &mut impl Read, len: usize, update: &mut impl Write) -> Result<()> {
+    let mut buf = vec![0u8; CHUNK_LEN];
+    let mut written = 0;
+
+    while written < len {
+        let n = req
+            .read(&mut buf[..(len - written).min(CHUNK_LEN)])
+            .map_err(|err| anyhow!("Read failed {err:?}"))?;
+        if n == 0 {
+            Err(anyhow!("Image cut short, {written} of {len} bytes"))?;
+        }
+
+        update
+            .write_all(&buf[..n])
+            .map_err(|err| anyhow!("Write failed {err:?}"))?;
+        written += n;
+    }
+
+    Ok(())
+}
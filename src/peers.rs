@@ -0,0 +1,242 @@
+//! ESPNOW peers the gateway sends to, e.g. the LCD cluster and a gauge pod. Peers are learned in
+//! a pairing window opened with `POST /espnow/pairing`: any peer heard from in it, by an announce
+//! ack or a command, is registered and kept in NVS. The announce, heartbeat, alerts and reminders
+//! go to every registered peer, or are broadcast while there are none, as before pairing. The
+//! list is also the `peers` section of `/config`, so a replaced gateway can take it over.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bt_obd_gw_protocol::MacAddr;
+use esp_idf_svc::{
+    espnow::{EspNow, PeerInfo, BROADCAST},
+    nvs::{EspNvs, NvsDefault},
+    sys::wifi_interface_t_WIFI_IF_STA,
+};
+use log::*;
+use serde::Serialize;
+
+use crate::config::{self, NVS_ESPNOW_PEERS};
+
+/// ESPNOW takes 20 unencrypted peers, the broadcast peer and command senders need some too
+const MAX_PEERS: usize = 8;
+/// Room for the JSON earlier firmware stored, the encoded list is much smaller
+const MAX_CONFIG_LEN: usize = 256;
+/// `POST /espnow/pairing` without `seconds`
+pub const DEFAULT_PAIRING: Duration = Duration::from_secs(60);
+pub const MAX_PAIRING: Duration = Duration::from_secs(300);
+
+/// End of the pairing window, read from the ESPNOW receive callback
+static PAIRING_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+/// Heard from in the window, waiting for `Peers::poll` to register them outside the WIFI task
+static HEARD: Mutex<Vec<MacAddr>> = Mutex::new(Vec::new());
+
+/// A message from `mac`, called from the receive callback. Only kept in a pairing window.
+pub fn heard(mac: &MacAddr) {
+    if pairing_left().is_zero() {
+        return;
+    }
+
+    let mut heard = HEARD.lock().unwrap();
+    if !heard.contains(mac) && heard.len() < MAX_PEERS {
+        heard.push(*mac);
+    }
+}
+
+/// Register the peers heard from for `duration`, at most `MAX_PAIRING`
+pub fn open_pairing(duration: Duration) {
+    let duration = duration.min(MAX_PAIRING);
+    *PAIRING_UNTIL.lock().unwrap() = Some(Instant::now() + duration);
+
+    info!("ESPNOW pairing open for {}s", duration.as_secs());
+}
+
+fn pairing_left() -> Duration {
+    PAIRING_UNTIL
+        .lock()
+        .unwrap()
+        .map(|until| until.saturating_duration_since(Instant::now()))
+        .unwrap_or_default()
+}
+
+/// `AA:BB:CC:DD:EE:FF`
+pub fn format_mac(mac: &MacAddr) -> String {
+    mac.iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub fn parse_mac(mac: &str) -> Option<MacAddr> {
+    let mut parsed = MacAddr::default();
+    let mut parts = mac.split(':');
+
+    for b in parsed.iter_mut() {
+        *b = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(parsed)
+}
+
+#[derive(Serialize)]
+pub struct PeerList {
+    pub peers: Vec<String>,
+    /// Left of the pairing window, 0 when it's closed
+    pub pairing_ms: u64,
+}
+
+pub struct Peers {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    peers: Mutex<Vec<MacAddr>>,
+    channel: u8,
+}
+
+impl Peers {
+    /// `channel` is the ESPNOW channel the peers are added on
+    pub fn new(nvs: EspNvs<NvsDefault>, channel: u8) -> Result<Self> {
+        let peers: Vec<MacAddr> =
+            config::load(&nvs, NVS_ESPNOW_PEERS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        if !peers.is_empty() {
+            info!("{} ESPNOW peers registered", peers.len());
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            peers: Mutex::new(peers),
+            channel,
+        })
+    }
+
+    pub fn list(&self) -> PeerList {
+        PeerList {
+            peers: self.config(),
+            pairing_ms: pairing_left().as_millis() as u64,
+        }
+    }
+
+    /// The registered peers as `AA:BB:CC:DD:EE:FF`, the `/config` section
+    pub fn config(&self) -> Vec<String> {
+        self.peers.lock().unwrap().iter().map(format_mac).collect()
+    }
+
+    /// Replace the registered peers, as `/config` sets them
+    pub fn set_config(&self, config: Vec<String>) -> Result<()> {
+        if config.len() > MAX_PEERS {
+            Err(anyhow!("Too many peers, max ({MAX_PEERS})"))?;
+        }
+
+        let mut macs = Vec::with_capacity(config.len());
+        for mac in &config {
+            let parsed = parse_mac(mac).ok_or_else(|| anyhow!("Invalid peer mac ({mac})"))?;
+            if !macs.contains(&parsed) {
+                macs.push(parsed);
+            }
+        }
+
+        let mut peers = self.peers.lock().unwrap();
+        self.store(&macs)?;
+        *peers = macs;
+
+        info!("ESPNOW peers now {}", peers.len());
+
+        Ok(())
+    }
+
+    /// Register the peers heard from in the pairing window, how many are new
+    pub fn poll(&self) -> Result<usize> {
+        let heard = std::mem::take(&mut *HEARD.lock().unwrap());
+
+        let mut peers = self.peers.lock().unwrap();
+        let mut added = 0;
+        for mac in heard {
+            if peers.contains(&mac) {
+                continue;
+            }
+            if peers.len() >= MAX_PEERS {
+                warn!(
+                    "{MAX_PEERS} ESPNOW peers registered, not adding {}",
+                    format_mac(&mac)
+                );
+                continue;
+            }
+
+            info!("ESPNOW peer {} registered", format_mac(&mac));
+            peers.push(mac);
+            added += 1;
+        }
+
+        if added > 0 {
+            self.store(&peers)?;
+        }
+
+        Ok(added)
+    }
+
+    /// Forget `mac`, or every peer with None. False if `mac` isn't registered.
+    pub fn remove(&self, mac: Option<MacAddr>) -> Result<bool> {
+        let mut peers = self.peers.lock().unwrap();
+
+        match mac {
+            Some(mac) if !peers.contains(&mac) => return Ok(false),
+            Some(mac) => peers.retain(|peer| *peer != mac),
+            None => peers.clear(),
+        }
+        self.store(&peers)?;
+
+        info!("ESPNOW peers now {}", peers.len());
+
+        Ok(true)
+    }
+
+    fn store(&self, peers: &[MacAddr]) -> Result<()> {
+        let value = config::encode(&peers)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Peers too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_ESPNOW_PEERS, &value)?;
+
+        Ok(())
+    }
+
+    /// Send to every registered peer, or broadcast while there are none. A peer that fails
+    /// doesn't stop the others getting it.
+    pub fn send(&self, espnow: &EspNow, data: &[u8]) -> Result<()> {
+        let peers = self.peers.lock().unwrap().clone();
+        if peers.is_empty() {
+            espnow.send(BROADCAST, data)?;
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        for mac in peers {
+            let sent = self
+                .add_peer(espnow, mac)
+                .and_then(|_| Ok(espnow.send(mac, data)?))
+                .with_context(|| format!("Peer {}", format_mac(&mac)));
+
+            if sent.is_err() {
+                result = sent;
+            }
+        }
+
+        result
+    }
+
+    fn add_peer(&self, espnow: &EspNow, mac: MacAddr) -> Result<()> {
+        if !espnow.peer_exists(mac)? {
+            espnow.add_peer(PeerInfo {
+                peer_addr: mac,
+                channel: self.channel,
+                ifidx: wifi_interface_t_WIFI_IF_STA,
+                encrypt: false,
+                ..Default::default()
+            })?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Batched NVS writes for the values that change all the time: lifetime counts, the fuel trip and
+//! the maintenance reading. Each write wears the flash, so they're kept in RAM and written together
+//! every `FLUSH_INTERVAL`, on a supply drop (from the power watch thread, inside the hold-up time),
+//! and before deep sleep or a restart.
+//!
+//! Each record is stored with a CRC and read back after it's written. On boot a record that fails
+//! its CRC, e.g. cut off by a power loss mid-write, is dropped and its owner starts from defaults
+//! rather than from garbage.
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::esp_rom_crc32_le,
+};
+use log::*;
+use serde::Serialize;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Largest record, value and header
+const MAX_RECORD_LEN: usize = 256;
+/// Marks a record with a CRC, older records are plain JSON and start with `{`
+const RECORD_MAGIC: u8 = 0xB7;
+const HEADER_LEN: usize = 5;
+
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct PersistStats {
+    /// Records written since boot
+    pub writes: u32,
+    pub flushes: u32,
+    /// Records dropped on boot for a bad CRC
+    pub corrupt: u32,
+    /// Writes that didn't read back the same
+    pub verify_failures: u32,
+}
+
+struct State {
+    /// Values waiting for the next flush, by key
+    dirty: BTreeMap<&'static str, Vec<u8>>,
+    last_flush: Instant,
+    stats: PersistStats,
+}
+
+pub struct Persist {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    state: Mutex<State>,
+}
+
+impl Persist {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Self {
+        Self {
+            nvs: Mutex::new(nvs),
+            state: Mutex::new(State {
+                dirty: BTreeMap::new(),
+                last_flush: Instant::now(),
+                stats: PersistStats::default(),
+            }),
+        }
+    }
+
+    /// The value for `key`, the unflushed one if there is one. None if it was never written or
+    /// failed its CRC.
+    pub fn load(&self, key: &'static str) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.state.lock().unwrap().dirty.get(key) {
+            return Ok(Some(value.clone()));
+        }
+
+        let mut buf = vec![0u8; MAX_RECORD_LEN];
+        let Some(record) = self
+            .nvs
+            .lock()
+            .unwrap()
+            .get_raw(key, &mut buf)?
+            .map(<[u8]>::to_vec)
+        else {
+            return Ok(None);
+        };
+
+        match decode(&record) {
+            Some(value) => Ok(Some(value.to_vec())),
+            None => {
+                warn!("Stored ({key}) failed its CRC, dropped");
+                self.state.lock().unwrap().stats.corrupt += 1;
+                self.nvs.lock().unwrap().remove(key)?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keep `value` for the next flush, replacing any unflushed one
+    pub fn store(&self, key: &'static str, value: Vec<u8>) -> Result<()> {
+        if value.len() + HEADER_LEN > MAX_RECORD_LEN {
+            Err(anyhow!("({key}) over ({MAX_RECORD_LEN}) bytes"))?;
+        }
+
+        self.state.lock().unwrap().dirty.insert(key, value);
+
+        Ok(())
+    }
+
+    pub fn flush_due(&self) -> bool {
+        let state = self.state.lock().unwrap();
+
+        !state.dirty.is_empty() && state.last_flush.elapsed() >= FLUSH_INTERVAL
+    }
+
+    /// Write out everything waiting. A record that fails is kept for the next flush.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.last_flush = Instant::now();
+        state.stats.flushes += 1;
+
+        let mut nvs = self.nvs.lock().unwrap();
+        let mut buf = vec![0u8; MAX_RECORD_LEN];
+        let mut failed = None;
+
+        let dirty = std::mem::take(&mut state.dirty);
+        for (key, value) in dirty {
+            let record = encode(&value);
+
+            let verified = nvs
+                .set_raw(key, &record)
+                .and_then(|_| nvs.get_raw(key, &mut buf));
+            match verified {
+                Ok(Some(read)) if read == record.as_slice() => {
+                    state.stats.writes += 1;
+                    continue;
+                }
+                Ok(_) => {
+                    state.stats.verify_failures += 1;
+                    failed = Some(anyhow!("({key}) didn't read back the same"));
+                }
+                Err(err) => failed = Some(anyhow!("({key}) write failed {err}")),
+            }
+
+            // Keep it unless something newer came in meanwhile
+            state.dirty.entry(key).or_insert(value);
+        }
+
+        failed.map_or(Ok(()), Err)
+    }
+
+    pub fn stats(&self) -> PersistStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// `| RECORD_MAGIC | crc32 (4, little endian) | value... |`
+fn encode(value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + value.len());
+    record.push(RECORD_MAGIC);
+    record.extend_from_slice(&crc32(value).to_le_bytes());
+    record.extend_from_slice(value);
+
+    record
+}
+
+/// The value of a record, None if it fails its CRC. Records from before the CRC are taken as is.
+fn decode(record: &[u8]) -> Option<&[u8]> {
+    match record {
+        [RECORD_MAGIC, a, b, c, d, value @ ..] => {
+            (crc32(value) == u32::from_le_bytes([*a, *b, *c, *d])).then_some(value)
+        }
+        [RECORD_MAGIC, ..] => None,
+        _ => Some(record),
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    unsafe { esp_rom_crc32_le(0, data.as_ptr(), data.len() as u32) }
+}
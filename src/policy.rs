@@ -0,0 +1,171 @@
+//! Command policy for requests from HTTP clients. Commands on the deny list (DTC clears, UDS
+//! writes and routines, protocol close) are refused unless the request carries the unlock token,
+//! so the dashboard URL can be shared without handing out DTC clears.
+//!
+//! Checked by `Elm327::write_request` for work queued with a guard, see `Elm327::guarded`.
+//! Nothing is refused until an unlock token has been stored.
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::constant_time_eq;
+use crate::config::{self, NVS_POLICY, NVS_UNLOCK_TOKEN};
+use crate::elm327::{normalise, RequestGuard};
+use crate::error::ElmError;
+
+/// The unlock token, lets a request through the deny list
+pub const UNLOCK_HEADER: &str = "X-Unlock";
+
+pub const MAX_CONFIG_LEN: usize = 512;
+const MAX_RULES: usize = 32;
+const MAX_TOKEN_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PolicyConfig {
+    /// Request prefixes refused without the unlock token, e.g. "04" or "ATPC". Spaces and case
+    /// are ignored.
+    pub deny: Vec<String>,
+    /// Prefixes let through even if a deny rule matches, e.g. "3101FF00" for one routine
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl Default for PolicyConfig {
+    /// Clearing DTCs, UDS ECU reset, DTC clear, security access, writes, routines and downloads,
+    /// and closing the protocol
+    fn default() -> Self {
+        Self {
+            deny: [
+                "04", "11", "14", "27", "2E", "2F", "31", "34", "3B", "3D", "ATPC",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+pub struct Policy {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<PolicyConfig>,
+    /// None leaves the policy off
+    unlock_token: Mutex<Option<String>>,
+}
+
+impl Policy {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_POLICY, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        let mut buf = [0u8; MAX_TOKEN_LEN + 1];
+        let unlock_token = nvs
+            .get_str(NVS_UNLOCK_TOKEN, &mut buf)?
+            .filter(|token| !token.is_empty())
+            .map(str::to_owned);
+
+        if unlock_token.is_some() {
+            info!("Command policy enabled");
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+            unlock_token: Mutex::new(unlock_token),
+        })
+    }
+
+    pub fn config(&self) -> PolicyConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: PolicyConfig) -> Result<()> {
+        if config.deny.len() + config.allow.len() > MAX_RULES {
+            Err(anyhow!("Too many rules, max ({MAX_RULES})"))?;
+        }
+
+        let clean = |rules: Vec<String>| -> Result<Vec<String>> {
+            rules
+                .iter()
+                .map(|rule| match normalise(rule.as_bytes()) {
+                    rule if rule.is_empty() => Err(anyhow!("Empty rule")),
+                    rule => Ok(rule),
+                })
+                .collect()
+        };
+        let config = PolicyConfig {
+            deny: clean(config.deny)?,
+            allow: clean(config.allow)?,
+        };
+
+        let value = config::encode(&config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Policy too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_POLICY, &value)?;
+        *self.config.lock().unwrap() = config;
+
+        info!("Command policy updated");
+
+        Ok(())
+    }
+
+    /// Store the unlock token, empty turns the policy off
+    pub fn set_unlock_token(&self, token: &str) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if token.is_empty() {
+            nvs.remove(NVS_UNLOCK_TOKEN)?;
+            *self.unlock_token.lock().unwrap() = None;
+            info!("Command policy disabled");
+        } else {
+            if token.len() > MAX_TOKEN_LEN {
+                Err(anyhow!("Token too long, max ({MAX_TOKEN_LEN})"))?;
+            }
+            if token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                Err(anyhow!("Token can't contain whitespace"))?;
+            }
+
+            nvs.set_str(NVS_UNLOCK_TOKEN, token)?;
+            *self.unlock_token.lock().unwrap() = Some(token.to_owned());
+            info!("Command policy unlock token updated");
+        }
+
+        Ok(())
+    }
+
+    /// True if nothing needs checking for a request with this `X-Unlock` value, either the
+    /// token matches or the policy is off
+    pub fn unlocked(&self, unlock: Option<&str>) -> bool {
+        match self.unlock_token.lock().unwrap().as_ref() {
+            Some(token) => unlock
+                .is_some_and(|unlock| constant_time_eq(unlock.trim().as_bytes(), token.as_bytes())),
+            None => true,
+        }
+    }
+
+    /// Refuse the request if a deny rule matches and no allow rule does
+    pub fn check(&self, request: &[u8]) -> Result<(), ElmError> {
+        let request = normalise(request);
+        let config = self.config.lock().unwrap();
+
+        let matches =
+            |rules: &[String]| rules.iter().any(|rule| request.starts_with(rule.as_str()));
+
+        if matches(&config.deny) && !matches(&config.allow) {
+            warn!("Request ({request}) refused by the command policy");
+            Err(ElmError::Blocked(request))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checked by `Elm327` as each request of a guarded job is written
+impl RequestGuard for Policy {
+    fn check(&self, request: &[u8]) -> Result<(), ElmError> {
+        Policy::check(self, request)
+    }
+}
@@ -0,0 +1,122 @@
+//! Pauses the main loop's adapter polls (logger, ignition, drive cycle, fuel economy, thresholds,
+//! maintenance and idle), so they don't land between the steps of a longer operation and mix their
+//! frames into its responses. `POST /poll/pause` pauses them for a while, and a DTC scan holds them
+//! off with `hold` until it's done. The `poller` feature switches them off until it's enabled
+//! again. The main loop also leaves the adapter alone while a UDS session is open, see
+//! `uds::session_control`.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::*;
+use serde::Serialize;
+
+use crate::features::Subsystem;
+
+/// `POST /poll/pause` without `seconds`
+pub const DEFAULT_PAUSE: Duration = Duration::from_secs(60);
+/// The polls resume on their own after this, a client that forgets can't stop them for good
+pub const MAX_PAUSE: Duration = Duration::from_secs(600);
+
+struct PauseState {
+    /// Set by `POST /poll/pause`
+    until: Option<Instant>,
+    /// Operations in progress, see `hold`
+    holds: usize,
+}
+
+static STATE: Mutex<PauseState> = Mutex::new(PauseState {
+    until: None,
+    holds: 0,
+});
+
+/// The `poller` feature is off
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Toggled by `Feature::Poller`
+pub struct Poller;
+
+impl Subsystem for Poller {
+    fn start(&mut self) -> Result<()> {
+        DISABLED.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        DISABLED.store(true, Ordering::Relaxed);
+        info!("Polls stopped");
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    /// Left of a `POST /poll/pause`, 0 without one
+    pub remaining_ms: u64,
+    /// Operations holding the polls off
+    pub holds: usize,
+}
+
+/// Keeps the polls paused until it's dropped
+pub struct PauseHold(());
+
+impl Drop for PauseHold {
+    fn drop(&mut self) {
+        let mut state = STATE.lock().unwrap();
+        state.holds -= 1;
+        if state.holds == 0 {
+            debug!("Polls no longer held");
+        }
+    }
+}
+
+/// Pause the polls for `duration`, at most `MAX_PAUSE`. A pause already running is replaced.
+pub fn pause(duration: Duration) -> Duration {
+    let duration = duration.min(MAX_PAUSE);
+    STATE.lock().unwrap().until = Some(Instant::now() + duration);
+    info!("Polls paused for {}s", duration.as_secs());
+
+    duration
+}
+
+/// End a `pause` early, operations holding the polls off still do
+pub fn resume() {
+    if STATE.lock().unwrap().until.take().is_some() {
+        info!("Polls resumed");
+    }
+}
+
+/// Hold the polls off for an operation of several jobs
+pub fn hold() -> PauseHold {
+    STATE.lock().unwrap().holds += 1;
+
+    PauseHold(())
+}
+
+pub fn paused() -> bool {
+    let state = STATE.lock().unwrap();
+
+    DISABLED.load(Ordering::Relaxed)
+        || state.holds > 0
+        || state.until.is_some_and(|until| until > Instant::now())
+}
+
+pub fn status() -> PauseStatus {
+    let state = STATE.lock().unwrap();
+    let remaining = state
+        .until
+        .map(|until| until.saturating_duration_since(Instant::now()))
+        .unwrap_or_default();
+
+    PauseStatus {
+        paused: DISABLED.load(Ordering::Relaxed) || state.holds > 0 || !remaining.is_zero(),
+        remaining_ms: remaining.as_millis() as u64,
+        holds: state.holds,
+    }
+}
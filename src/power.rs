@@ -0,0 +1,108 @@
+//! OBD port power loss. Where the port is switched with the ignition the supply collapses with no
+//! warning, so a watch thread samples it and runs the fast shutdown within the hold-up time of
+//! the board's capacitors.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use esp_idf_svc::{
+    espnow::BROADCAST,
+    hal::{
+        adc::{
+            attenuation::DB_11,
+            oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+            ADCPin,
+        },
+        peripheral::Peripheral,
+    },
+    sys::esp_now_send,
+};
+use log::*;
+
+use bt_obd_gw_protocol::Status;
+
+use crate::persist::Persist;
+
+/// Shortest sleep at the default FreeRTOS tick
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+static POWER_LOST: AtomicBool = AtomicBool::new(false);
+
+/// Supply voltage source for the power loss watch
+pub trait SupplySense: Send {
+    /// Supply voltage in mV, before any divider
+    fn millivolts(&mut self) -> Result<u32>;
+}
+
+/// Supply through a resistor divider into an ADC1 pin
+pub struct AdcSupply<'d, P: ADCPin> {
+    channel: AdcChannelDriver<'d, P, AdcDriver<'d, P::Adc>>,
+    /// Supply volts per volt at the pin
+    divider: f32,
+}
+
+impl<'d, P: ADCPin> AdcSupply<'d, P> {
+    pub fn new(
+        adc: impl Peripheral<P = P::Adc> + 'd,
+        pin: impl Peripheral<P = P> + 'd,
+        divider: f32,
+    ) -> Result<Self> {
+        let config = AdcChannelConfig {
+            attenuation: DB_11,
+            ..Default::default()
+        };
+
+        let channel = AdcChannelDriver::new(AdcDriver::new(adc)?, pin, &config)?;
+
+        Ok(Self { channel, divider })
+    }
+}
+
+impl<P: ADCPin> SupplySense for AdcSupply<'static, P>
+where
+    AdcChannelDriver<'static, P, AdcDriver<'static, P::Adc>>: Send,
+{
+    fn millivolts(&mut self) -> Result<u32> {
+        Ok((self.channel.read()? as f32 * self.divider) as u32)
+    }
+}
+
+/// True once the supply has collapsed, the main loop then flushes what it can
+pub fn power_lost() -> bool {
+    POWER_LOST.load(Ordering::Relaxed)
+}
+
+/// Sample the supply and on a drop below `threshold_mv` broadcast a powering down status to the
+/// ESPNOW peers and flush the batched NVS writes straight from the watch thread, the main loop may
+/// be busy with the adapter. ESPNOW must already be initialized.
+pub fn start_watch(mut sense: Box<dyn SupplySense>, threshold_mv: u32, persist: Arc<Persist>) {
+    thread::spawn(move || loop {
+        match sense.millivolts() {
+            Ok(mv) if mv < threshold_mv => {
+                let frame = Status::PoweringDown.encode();
+                unsafe {
+                    esp_now_send(BROADCAST.as_ptr(), frame.as_ptr(), frame.len());
+                }
+
+                POWER_LOST.store(true, Ordering::Relaxed);
+                warn!("Supply lost ({mv}mV), shutting down");
+
+                if let Err(err) = persist.flush() {
+                    error!("Flush on supply loss failed {err}");
+                }
+
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => error!("Supply read failed {err}"),
+        }
+
+        thread::sleep(SAMPLE_INTERVAL);
+    });
+}
@@ -0,0 +1,106 @@
+use std::{sync::mpsc, time::Instant};
+
+use anyhow::{Context, Result};
+use esp_idf_svc::{
+    espnow::{EspNow, PeerInfo, BROADCAST},
+    eventloop::EspSystemEventLoop,
+    hal::{modem::WifiModemPeripheral, peripheral::Peripheral},
+    nvs::EspDefaultNvsPartition,
+    wifi::{self, BlockingWifi, EspWifi},
+};
+use log::*;
+
+use bt_obd_gw_protocol::{Dedup, EspNowData, MacAddr, RelayFrame, MAX_HOPS, MSG_RELAY};
+
+const DEDUP_WINDOW_MS: u64 = 2000;
+
+/// ESPNOW relay for displays out of range of the gateway.
+///
+/// Every message heard directly is wrapped in a relay frame and re-broadcast, and relay frames
+/// from other relays are forwarded until they reach `MAX_HOPS`. Duplicates are dropped so
+/// several relays, or a message heard both directly and relayed, don't flood the channel. Never
+/// returns.
+pub fn run(
+    modem: impl Peripheral<P = impl WifiModemPeripheral> + 'static,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    espnow_channel: u8,
+) -> Result<()> {
+    info!("Starting ESPNOW relay");
+
+    // ESPNOW only needs the radio started on the channel, no AP connection
+    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), Some(nvs))?, sys_loop)?;
+
+    wifi.set_configuration(&wifi::Configuration::Client(wifi::ClientConfiguration {
+        channel: Some(espnow_channel),
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+
+    let espnow = EspNow::take()?;
+
+    espnow.add_peer(PeerInfo {
+        peer_addr: BROADCAST,
+        channel: espnow_channel,
+        ifidx: esp_idf_svc::sys::wifi_interface_t_WIFI_IF_STA,
+        encrypt: false,
+        ..Default::default()
+    })?;
+
+    let (espnow_tx, espnow_rx) = mpsc::sync_channel::<(MacAddr, EspNowData)>(10);
+
+    espnow
+        .register_recv_cb(move |info, data| match EspNowData::from_slice(data) {
+            Ok(data) => {
+                if espnow_tx
+                    .try_send((info.src_addr.to_owned(), data))
+                    .is_err()
+                {
+                    warn!("Relay queue full, dropping message");
+                }
+            }
+            Err(_) => error!("Relay message too long ({})", data.len()),
+        })
+        .context("Failed to register ESPNOW recv callback")?;
+
+    let start = Instant::now();
+    let mut dedup = Dedup::new(DEDUP_WINDOW_MS);
+
+    for (src, data) in espnow_rx.iter() {
+        let frame = if data.first() == Some(&MSG_RELAY) {
+            match RelayFrame::parse(&data) {
+                Some(frame) => frame,
+                None => {
+                    warn!("Invalid relay frame from {src:02X?}");
+                    continue;
+                }
+            }
+        } else {
+            RelayFrame::wrap(src, &data)
+        };
+
+        if !dedup.is_new(&frame.origin, frame.id, start.elapsed().as_millis() as u64) {
+            debug!(
+                "Duplicate frame {:04X} from {:02X?}",
+                frame.id, frame.origin
+            );
+            continue;
+        }
+
+        if frame.hops >= MAX_HOPS {
+            debug!("Frame {:04X} reached max hops", frame.id);
+            continue;
+        }
+
+        let Some(next) = frame.encode_next_hop() else {
+            warn!("Frame from {:02X?} too long to relay", frame.origin);
+            continue;
+        };
+
+        if let Err(err) = espnow.send(BROADCAST, &next) {
+            error!("Relay send failed {err}");
+        }
+    }
+
+    Ok(())
+}
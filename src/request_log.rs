@@ -0,0 +1,187 @@
+//! The last HTTP requests, who sent them and how they went, for `GET /debug/requests`. Finds the
+//! client sending whatever wedges the adapter. The router opens an entry for each request and
+//! closes it when the handler returns; the handler adds the ELM command and error code as it
+//! goes, through a thread local as the httpd task runs one request at a time.
+use std::{cell::RefCell, fmt::Write as _, mem, net::Ipv4Addr, sync::Mutex, time::Instant};
+
+use circular_buffer::CircularBuffer;
+use esp_idf_svc::{
+    http::{server::EspHttpConnection, Method},
+    sys::{
+        httpd_req_to_sockfd, lwip_getpeername, lwip_getsockname, sockaddr, sockaddr_in,
+        sockaddr_in6, socklen_t, AF_INET, AF_INET6,
+    },
+};
+use serde::Serialize;
+
+use crate::error::ErrorCode;
+use crate::http::uptime_ms;
+
+const MAX_ENTRIES: usize = 64;
+/// Longer URIs and commands are cut short
+const MAX_URI_LEN: usize = 64;
+const MAX_COMMAND_LEN: usize = 32;
+
+static ENTRIES: Mutex<CircularBuffer<MAX_ENTRIES, RequestEntry>> =
+    Mutex::new(CircularBuffer::new());
+
+thread_local! {
+    static CURRENT: RefCell<Option<(RequestEntry, Instant)>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RequestEntry {
+    /// When the request arrived
+    pub uptime_ms: u64,
+    pub method: &'static str,
+    /// With the query
+    pub uri: String,
+    /// None if the socket's gone
+    pub client: Option<Ipv4Addr>,
+    /// The adapter command of a `/post`, the header and data of a `/raw`, the service of a
+    /// `/uds`, `STM` for a `/monitor`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub latency_ms: u32,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+}
+
+/// Open the entry for a request, from the router before the handler runs
+pub fn begin(method: Method, uri: &str, conn: &mut EspHttpConnection<'_>) {
+    begin_on(method, uri, sockfd(conn));
+}
+
+/// `begin` for a request httpd hands over as it is, see `held`
+pub fn begin_on(method: Method, uri: &str, fd: Option<i32>) {
+    let entry = RequestEntry {
+        uptime_ms: uptime_ms(),
+        method: method_name(method),
+        uri: truncate(uri, MAX_URI_LEN).to_owned(),
+        client: fd.and_then(|fd| socket_addr(fd, lwip_getpeername)),
+        command: None,
+        latency_ms: 0,
+        status: 200,
+        error: None,
+    };
+
+    CURRENT.with_borrow_mut(|current| *current = Some((entry, Instant::now())));
+}
+
+/// The adapter command the request carries
+pub fn set_command(command: &[u8]) {
+    let command = String::from_utf8_lossy(command);
+    let command = truncate(command.trim(), MAX_COMMAND_LEN).to_owned();
+
+    with_current(|entry| entry.command = Some(command));
+}
+
+/// A response other than a 200, from every handler that builds a response of its own
+pub fn set_status(status: u16, error: Option<ErrorCode>) {
+    with_current(|entry| {
+        entry.status = status;
+        entry.error = error;
+    });
+}
+
+/// Close the entry, a handler that failed is recorded as a 500
+pub fn end(failed: bool) {
+    let Some((mut entry, start)) = CURRENT.with_borrow_mut(Option::take) else {
+        return;
+    };
+
+    entry.latency_ms = start.elapsed().as_millis().min(u32::MAX as u128) as u32;
+    if failed {
+        entry.status = 500;
+    }
+
+    ENTRIES.lock().unwrap().push_back(entry);
+}
+
+/// Oldest first, one JSON object per line
+pub fn ndjson() -> String {
+    let entries = ENTRIES.lock().unwrap();
+
+    let mut out = String::with_capacity(entries.len() * 128);
+    for entry in entries.iter() {
+        if let Ok(line) = serde_json::to_string(entry) {
+            let _ = writeln!(out, "{line}");
+        }
+    }
+
+    out
+}
+
+fn with_current(f: impl FnOnce(&mut RequestEntry)) {
+    CURRENT.with_borrow_mut(|current| {
+        if let Some((entry, _)) = current {
+            f(entry);
+        }
+    });
+}
+
+/// The gateway's end of the connection, which says the interface it came in on
+pub fn local_addr(conn: &mut EspHttpConnection<'_>) -> Option<Ipv4Addr> {
+    local_addr_on(sockfd(conn)?)
+}
+
+/// `local_addr` of a socket
+pub fn local_addr_on(fd: i32) -> Option<Ipv4Addr> {
+    socket_addr(fd, lwip_getsockname)
+}
+
+/// None if the socket's gone
+fn sockfd(conn: &mut EspHttpConnection<'_>) -> Option<i32> {
+    let raw = conn.raw_connection().ok()?;
+    let fd = unsafe { httpd_req_to_sockfd(raw.handle()) };
+
+    (fd >= 0).then_some(fd)
+}
+
+/// The IPv4 address of an end of the socket. The server listens on IPv6 so it's usually a mapped
+/// one.
+fn socket_addr(
+    fd: i32,
+    name: unsafe extern "C" fn(i32, *mut sockaddr, *mut socklen_t) -> i32,
+) -> Option<Ipv4Addr> {
+    unsafe {
+        let mut addr: sockaddr_in6 = mem::zeroed();
+        let mut len = mem::size_of::<sockaddr_in6>() as socklen_t;
+        if name(fd, &mut addr as *mut _ as *mut sockaddr, &mut len) != 0 {
+            return None;
+        }
+
+        match addr.sin6_family as u32 {
+            AF_INET6 => {
+                let bytes = addr.sin6_addr.un.u8_addr;
+                Some(Ipv4Addr::new(bytes[12], bytes[13], bytes[14], bytes[15]))
+            }
+            AF_INET => {
+                let addr = &*(&addr as *const _ as *const sockaddr_in);
+                // Network order in memory
+                Some(Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        _ => "OTHER",
+    }
+}
+
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut len = text.len().min(max_len);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    &text[..len]
+}
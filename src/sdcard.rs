@@ -0,0 +1,127 @@
+//! SD card storage for data too big for the flash partition (drive logs, firmware images, config
+//! files). The card is on SPI (`sd-spi` feature) or the SDMMC slot (`sd-mmc` feature) and mounted
+//! at `SD_DIR`, the `/fs/*` endpoints serve files from it.
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+use anyhow::Result;
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+use esp_idf_svc::{
+    fs::fatfs::Fatfs,
+    hal::sd::{SdCardConfiguration, SdCardDriver},
+    io::vfs::MountedFatfs,
+};
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+use log::*;
+
+pub const SD_DIR: &str = "/sd";
+
+/// FatFs drive number, the flash storage partition takes drive 0
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+const SD_DRIVE: u8 = 1;
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+const MAX_FILES: usize = 4;
+
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Mount a card on SPI. The card stays mounted for the life of the firmware.
+#[cfg(feature = "sd-spi")]
+pub fn mount_spi(
+    spi: impl esp_idf_svc::hal::peripheral::Peripheral<P = impl esp_idf_svc::hal::spi::SpiAnyPins>
+        + 'static,
+    sclk: impl esp_idf_svc::hal::peripheral::Peripheral<P = impl esp_idf_svc::hal::gpio::OutputPin>
+        + 'static,
+    mosi: impl esp_idf_svc::hal::peripheral::Peripheral<P = impl esp_idf_svc::hal::gpio::OutputPin>
+        + 'static,
+    miso: impl esp_idf_svc::hal::peripheral::Peripheral<P = impl esp_idf_svc::hal::gpio::InputPin>
+        + 'static,
+    cs: impl esp_idf_svc::hal::peripheral::Peripheral<P = impl esp_idf_svc::hal::gpio::OutputPin>
+        + 'static,
+) -> Result<()> {
+    use esp_idf_svc::hal::{
+        gpio::AnyIOPin,
+        sd::spi::SdSpiHostDriver,
+        spi::{config::DriverConfig, Dma, SpiDriver},
+    };
+
+    let spi = SpiDriver::new(
+        spi,
+        sclk,
+        mosi,
+        Some(miso),
+        &DriverConfig::default().dma(Dma::Auto(4096)),
+    )?;
+
+    let host = SdSpiHostDriver::new(
+        spi,
+        Some(cs),
+        AnyIOPin::none(),
+        AnyIOPin::none(),
+        AnyIOPin::none(),
+        None,
+    )?;
+
+    let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+
+    mount(Fatfs::new_sdcard(SD_DRIVE, card)?)
+}
+
+/// Mount a card in the SDMMC slot, 1-bit mode (CMD GPIO15, CLK GPIO14, D0 GPIO2). The card stays
+/// mounted for the life of the firmware.
+#[cfg(feature = "sd-mmc")]
+pub fn mount_mmc(
+    slot: esp_idf_svc::hal::sd::mmc::SDMMC1,
+    cmd: esp_idf_svc::hal::gpio::Gpio15,
+    clk: esp_idf_svc::hal::gpio::Gpio14,
+    d0: esp_idf_svc::hal::gpio::Gpio2,
+) -> Result<()> {
+    use esp_idf_svc::hal::{
+        gpio::AnyIOPin,
+        sd::mmc::{SdMmcHostDriver, SlotConfiguration},
+    };
+
+    let host = SdMmcHostDriver::new_1bit(
+        slot,
+        cmd,
+        clk,
+        d0,
+        None::<AnyIOPin>,
+        None::<AnyIOPin>,
+        &SlotConfiguration::default(),
+    )?;
+
+    let card = SdCardDriver::new_mmc(host, &SdCardConfiguration::new())?;
+
+    mount(Fatfs::new_sdcard(SD_DRIVE, card)?)
+}
+
+#[cfg(any(feature = "sd-spi", feature = "sd-mmc"))]
+fn mount<T>(fatfs: Fatfs<T>) -> Result<()> {
+    let mounted = MountedFatfs::mount(fatfs, SD_DIR, MAX_FILES)?;
+
+    // Never unmounted
+    std::mem::forget(mounted);
+    MOUNTED.store(true, Ordering::Relaxed);
+
+    info!("SD card mounted at {SD_DIR}");
+
+    Ok(())
+}
+
+pub fn mounted() -> bool {
+    MOUNTED.load(Ordering::Relaxed)
+}
+
+/// Full path on the card for a path relative to `SD_DIR`, None if it tries to leave the card
+pub fn path(rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel.trim_start_matches('/'));
+
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    Some(Path::new(SD_DIR).join(rel))
+}
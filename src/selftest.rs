@@ -0,0 +1,181 @@
+//! Boot self-test. Each startup stage is timed and its result kept in NVS as it goes, so
+//! `GET /selftest` can say why the last boot failed, and a failed stage blinks its number and a
+//! cause rather than just the stage.
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::EspError,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{NVS_SELFTEST, NVS_SELFTEST_PROBE};
+use crate::error::{self, ErrorCode};
+
+const MAX_REPORT_LEN: usize = 1024;
+/// Longest error kept per stage
+const MAX_ERROR_LEN: usize = 80;
+
+/// In startup order, the number is the long blink count of a failure
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Nvs = 1,
+    BtController = 2,
+    SppConnect = 3,
+    /// ATZ, ATI and the protocol setup
+    Elm = 4,
+    Wifi = 5,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Never finished, the previous boot reset or hung during it
+    Running,
+    Passed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StageResult {
+    pub stage: Stage,
+    pub outcome: Outcome,
+    pub duration_ms: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    pub current: Vec<StageResult>,
+    /// The boot before this one, as it was left
+    pub previous: Vec<StageResult>,
+}
+
+pub struct SelfTest {
+    /// None until the NVS stage has passed
+    nvs: Mutex<Option<EspNvs<NvsDefault>>>,
+    current: Mutex<Vec<StageResult>>,
+    previous: Mutex<Vec<StageResult>>,
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        Self {
+            nvs: Mutex::new(None),
+            current: Mutex::new(Vec::new()),
+            previous: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Run a startup stage, recording the result and blinking the failure code if it fails
+    pub fn run<R>(&self, stage: Stage, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        let index = {
+            let mut current = self.current.lock().unwrap();
+            current.push(StageResult {
+                stage,
+                outcome: Outcome::Running,
+                duration_ms: 0,
+                error: None,
+            });
+            current.len() - 1
+        };
+        self.store();
+
+        let start = Instant::now();
+        let result = f();
+        let duration_ms = start.elapsed().as_millis() as u32;
+
+        {
+            let mut current = self.current.lock().unwrap();
+            let entry = &mut current[index];
+            entry.duration_ms = duration_ms;
+
+            match &result {
+                Ok(_) => {
+                    entry.outcome = Outcome::Passed;
+                    info!("Self-test {stage:?} passed in {duration_ms}ms");
+                }
+                Err(err) => {
+                    entry.outcome = Outcome::Failed;
+                    entry.error = Some(format!("{err:#}").chars().take(MAX_ERROR_LEN).collect());
+
+                    let cause = cause(err);
+                    error!("Self-test {stage:?} failed in {duration_ms}ms, cause {cause}: {err:#}");
+                    error::fault_ind(stage as u8, cause);
+                }
+            }
+        }
+        self.store();
+
+        result
+    }
+
+    /// The NVS stage, opens the namespace and checks a write reads back. Also picks up the
+    /// previous boot's results before they're replaced.
+    pub fn open_nvs(&self, partition: EspDefaultNvsPartition) -> Result<()> {
+        let nvs = EspNvs::new(partition, "elm_ns", true)?;
+
+        let mut buf = vec![0u8; MAX_REPORT_LEN];
+        if let Some(previous) = nvs
+            .get_raw(NVS_SELFTEST, &mut buf)?
+            .and_then(|report| serde_json::from_slice(report).ok())
+        {
+            *self.previous.lock().unwrap() = previous;
+        }
+
+        // A different value every boot, so a stale read can't pass
+        let probe = nvs.get_u8(NVS_SELFTEST_PROBE)?.unwrap_or(0).wrapping_add(1);
+        nvs.set_u8(NVS_SELFTEST_PROBE, probe)?;
+        if nvs.get_u8(NVS_SELFTEST_PROBE)? != Some(probe) {
+            Err(anyhow!("NVS write didn't read back"))?;
+        }
+
+        *self.nvs.lock().unwrap() = Some(nvs);
+
+        Ok(())
+    }
+
+    pub fn report(&self) -> SelfTestReport {
+        SelfTestReport {
+            current: self.current.lock().unwrap().clone(),
+            previous: self.previous.lock().unwrap().clone(),
+        }
+    }
+
+    /// Best effort, the results so far are still logged and blinked without NVS
+    fn store(&self) {
+        let nvs = self.nvs.lock().unwrap();
+        let Some(nvs) = nvs.as_ref() else {
+            return;
+        };
+
+        let result = serde_json::to_vec(&*self.current.lock().unwrap())
+            .map_err(anyhow::Error::from)
+            .and_then(|value| Ok(nvs.set_raw(NVS_SELFTEST, &value)?));
+
+        if let Err(err) = result {
+            warn!("Failed to store the self-test results {err}");
+        }
+    }
+}
+
+/// The short blink count after the stage: 1 timeout, 2 link down, 3 unexpected response, 4 ESP-IDF
+/// error, 5 anything else
+fn cause(err: &anyhow::Error) -> u8 {
+    match ErrorCode::of(err) {
+        ErrorCode::ElmTimeout | ErrorCode::ResponseTimeout | ErrorCode::WorkerTimeout => 1,
+        ErrorCode::AdapterDisconnected | ErrorCode::BtLinkLost => 2,
+        ErrorCode::BadResponse
+        | ErrorCode::InvalidRequest
+        | ErrorCode::NoData
+        | ErrorCode::CanError
+        | ErrorCode::AdapterReset
+        | ErrorCode::AdapterProgramming => 3,
+        _ if err.chain().any(|cause| cause.is::<EspError>()) => 4,
+        _ => 5,
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use hmac::{Hmac, Mac};
+use log::*;
+use sha2::Sha256;
+
+use crate::config::NVS_SIGNING_KEY;
+use crate::error::SignatureError;
+
+/// Header carrying the hex HMAC-SHA256 of the request body
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+const MAX_KEY_LEN: usize = 64;
+
+type SigningKey = heapless::Vec<u8, MAX_KEY_LEN>;
+
+/// Optional HMAC signing of uploads (config, macros, scripts).
+///
+/// Signing is enabled once a key has been stored in NVS, after which any upload without a valid
+/// signature is rejected. With no key everything is accepted, which is fine on the LCD network.
+pub struct Signing {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    key: Mutex<Option<SigningKey>>,
+}
+
+impl Signing {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let mut buf = [0u8; MAX_KEY_LEN];
+
+        let key = nvs
+            .get_raw(NVS_SIGNING_KEY, &mut buf)?
+            .filter(|key| !key.is_empty())
+            .and_then(|key| SigningKey::from_slice(key).ok());
+
+        if key.is_some() {
+            info!("Upload signing enabled");
+        }
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            key: Mutex::new(key),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.key.lock().unwrap().is_some()
+    }
+
+    /// Check the signature header value against the body. Always passes when signing is disabled.
+    pub fn verify(&self, signature: Option<&str>, body: &[u8]) -> Result<(), SignatureError> {
+        match self.verifier(signature)? {
+            Some(mut verifier) => {
+                verifier.update(body);
+                verifier.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Verifier for a body too big to hold in memory, None when signing is disabled
+    pub fn verifier(&self, signature: Option<&str>) -> Result<Option<Verifier>, SignatureError> {
+        let key = self.key.lock().unwrap();
+
+        let Some(key) = key.as_ref() else {
+            return Ok(None);
+        };
+
+        let signature = signature.ok_or(SignatureError::Missing)?;
+        let signature = decode_hex(signature.trim()).ok_or(SignatureError::Invalid)?;
+
+        let mac = Hmac::<Sha256>::new_from_slice(key).map_err(|_| SignatureError::Invalid)?;
+
+        Ok(Some(Verifier { mac, signature }))
+    }
+
+    /// Store a new key, an empty key disables signing
+    pub fn set_key(&self, key: &[u8]) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if key.is_empty() {
+            nvs.remove(NVS_SIGNING_KEY)?;
+            *self.key.lock().unwrap() = None;
+            info!("Upload signing disabled");
+        } else {
+            let key = SigningKey::from_slice(key)
+                .map_err(|_| anyhow::anyhow!("Signing key too long, max ({MAX_KEY_LEN})"))?;
+            nvs.set_raw(NVS_SIGNING_KEY, &key)?;
+            *self.key.lock().unwrap() = Some(key);
+            info!("Upload signing key updated");
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks a signature over a body fed in chunks
+pub struct Verifier {
+    mac: Hmac<Sha256>,
+    signature: Vec<u8>,
+}
+
+impl Verifier {
+    pub fn update(&mut self, data: &[u8]) {
+        self.mac.update(data);
+    }
+
+    pub fn finish(self) -> Result<(), SignatureError> {
+        self.mac.verify_slice(&self.signature).map_err(|_| {
+            warn!("Upload signature mismatch");
+            SignatureError::Invalid
+        })
+    }
+}
+
+/// Decode a hex string, e.g. "0a1B", into bytes
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
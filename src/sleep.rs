@@ -0,0 +1,38 @@
+//! Deep sleep once the ignition has been off a while, so the gateway doesn't keep the vehicle
+//! battery draining through the OBD port. A timer wakes it to check the voltage again.
+use std::time::Duration;
+
+use esp_idf_svc::sys::{
+    esp_deep_sleep_start, esp_sleep_enable_ext0_wakeup, esp_sleep_enable_timer_wakeup,
+    esp_sleep_get_wakeup_cause, esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER,
+};
+use log::*;
+
+/// Ignition off this long before going to sleep
+pub const SLEEP_AFTER: Duration = Duration::from_secs(10 * 60);
+/// Wake to check the battery voltage
+const WAKE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// True if this boot is the sleep timer checking whether the engine is running
+pub fn woke_by_timer() -> bool {
+    unsafe { esp_sleep_get_wakeup_cause() == esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER }
+}
+
+/// Enter deep sleep, the next wakeup is a fresh boot. With an ignition input (an RTC GPIO) it
+/// going high wakes the gateway, otherwise the timer does.
+pub fn deep_sleep(wake_pin: Option<i32>) -> ! {
+    unsafe {
+        match wake_pin {
+            Some(pin) => {
+                info!("Deep sleep until GPIO{pin} goes high");
+                esp_sleep_enable_ext0_wakeup(pin, 1);
+            }
+            None => {
+                info!("Deep sleep, checking again in {}s", WAKE_INTERVAL.as_secs());
+                esp_sleep_enable_timer_wakeup(WAKE_INTERVAL.as_micros() as u64);
+            }
+        }
+
+        esp_deep_sleep_start()
+    }
+}
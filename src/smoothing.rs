@@ -0,0 +1,237 @@
+//! Per channel processing of the logger samples before they go out on `/events`, so a jittery
+//! sensor doesn't make a gauge flicker. A channel's value is clamped to `min` and `max`, then
+//! smoothed with an exponential moving average, and with `rate_ms` sent on its own at that rate
+//! rather than with each sample. Faster than the logger it's interpolated between the last two
+//! samples, a sample behind, and slower it's decimated to the latest value. The trip log and
+//! `/snapshot` keep the values as read.
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::config::{self, NVS_SMOOTHING};
+use crate::events::{self, PidValues};
+use crate::http::uptime_ms;
+
+pub const MAX_CONFIG_LEN: usize = 1024;
+/// As many as the logger samples
+const MAX_CHANNELS: usize = 24;
+/// A virtual PID name's limit, hex PIDs are 2
+const MAX_CHANNEL_LEN: usize = 24;
+const MIN_RATE_MS: u32 = 50;
+const MAX_RATE_MS: u32 = 60_000;
+/// A channel not sampled for this long, e.g. between trips, starts over
+const STALE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(default)]
+pub struct ChannelProcessing {
+    /// Weight of the new value, 0 to 1. None or 1 for no smoothing.
+    pub ema: Option<f32>,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// Sent on its own this often, None to go with each sample
+    pub rate_ms: Option<u32>,
+}
+
+impl ChannelProcessing {
+    fn check(&self) -> Result<()> {
+        if self.ema.is_some_and(|ema| !(ema > 0.0 && ema <= 1.0)) {
+            Err(anyhow!("ema must be above 0 and at most 1"))?;
+        }
+
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                Err(anyhow!("min must not be above max"))?;
+            }
+        }
+
+        if self
+            .rate_ms
+            .is_some_and(|rate| !(MIN_RATE_MS..=MAX_RATE_MS).contains(&rate))
+        {
+            Err(anyhow!("rate_ms must be {MIN_RATE_MS} to {MAX_RATE_MS}"))?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&self, value: f32, smoothed: Option<f32>) -> f32 {
+        let value = value.max(self.min.unwrap_or(f32::MIN));
+        let value = value.min(self.max.unwrap_or(f32::MAX));
+
+        match (self.ema, smoothed) {
+            (Some(ema), Some(smoothed)) => smoothed + ema * (value - smoothed),
+            _ => value,
+        }
+    }
+}
+
+/// Keyed as `/snapshot`, hex PID or virtual PID name
+pub type SmoothingConfig = BTreeMap<String, ChannelProcessing>;
+
+#[derive(Default)]
+struct ChannelState {
+    /// The two latest samples after smoothing, `last` is the newer
+    prev: Option<(Instant, f32)>,
+    last: Option<(Instant, f32)>,
+    next_output: Option<Instant>,
+}
+
+impl ChannelState {
+    /// Between `prev` and `last`, as far past `prev` as `now` is past `last`
+    fn interpolated(&self, now: Instant) -> Option<f32> {
+        let (t1, v1) = self.last?;
+        let Some((t0, v0)) = self.prev else {
+            return Some(v1);
+        };
+
+        let span = t1.duration_since(t0).as_secs_f32();
+        if span <= 0.0 {
+            return Some(v1);
+        }
+        let t = (now.duration_since(t1).as_secs_f32() / span).min(1.0);
+
+        Some(v0 + (v1 - v0) * t)
+    }
+}
+
+pub struct Smoothing {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<SmoothingConfig>,
+    state: Mutex<BTreeMap<String, ChannelState>>,
+}
+
+impl Smoothing {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_SMOOTHING, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+            state: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn config(&self) -> SmoothingConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// PIDs are stored upper case, as the samples are keyed
+    pub fn set_config(&self, config: SmoothingConfig) -> Result<()> {
+        if config.len() > MAX_CHANNELS {
+            Err(anyhow!("Too many channels, max ({MAX_CHANNELS})"))?;
+        }
+
+        let mut normalised = SmoothingConfig::new();
+        for (channel, processing) in config {
+            if channel.is_empty() || channel.len() > MAX_CHANNEL_LEN {
+                Err(anyhow!("Channel names must be 1 to {MAX_CHANNEL_LEN} long"))?;
+            }
+
+            processing
+                .check()
+                .map_err(|err| anyhow!("{err} ({channel})"))?;
+
+            let channel = match u8::from_str_radix(&channel, 16) {
+                Ok(p) if channel.len() <= 2 => format!("{p:02X}"),
+                _ => channel,
+            };
+            normalised.insert(channel, processing);
+        }
+
+        let value = config::encode(&normalised)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Smoothing too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_SMOOTHING, &value)?;
+        *self.config.lock().unwrap() = normalised;
+        self.state.lock().unwrap().clear();
+
+        info!("Smoothing updated for {:?}", self.config().keys());
+
+        Ok(())
+    }
+
+    /// Process a logger sample. What goes out with it comes back, channels with a rate are kept
+    /// for `poll`.
+    pub fn process(&self, sample: BTreeMap<String, f32>) -> BTreeMap<String, f32> {
+        let config = self.config.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let mut values = BTreeMap::new();
+        for (channel, value) in sample {
+            let Some(processing) = config.get(&channel) else {
+                values.insert(channel, value);
+                continue;
+            };
+
+            let channel_state = state.entry(channel.clone()).or_default();
+            let smoothed = channel_state
+                .last
+                .filter(|(t, _)| now.duration_since(*t) < STALE)
+                .map(|(_, v)| v);
+            if smoothed.is_none() {
+                *channel_state = ChannelState::default();
+            }
+
+            let value = processing.apply(value, smoothed);
+            channel_state.prev = channel_state.last;
+            channel_state.last = Some((now, value));
+
+            if processing.rate_ms.is_none() {
+                values.insert(channel, value);
+            }
+        }
+
+        values
+    }
+
+    /// Publish the channels with a rate that are due, in one `pids` event
+    pub fn poll(&self) {
+        let config = self.config.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let mut values = BTreeMap::new();
+        for (channel, channel_state) in state.iter_mut() {
+            let Some(rate) = config.get(channel).and_then(|p| p.rate_ms) else {
+                continue;
+            };
+
+            let sampled = channel_state
+                .last
+                .is_some_and(|(t, _)| now.duration_since(t) < STALE);
+            if !sampled || channel_state.next_output.is_some_and(|t| t > now) {
+                continue;
+            }
+            channel_state.next_output = Some(now + Duration::from_millis(rate.into()));
+
+            if let Some(value) = channel_state.interpolated(now) {
+                values.insert(channel.clone(), value);
+            }
+        }
+
+        if values.is_empty() {
+            return;
+        }
+
+        events::publish(
+            events::PIDS,
+            &PidValues {
+                timestamp_ms: uptime_ms(),
+                time: clock::now(),
+                values,
+            },
+        );
+    }
+}
@@ -1,48 +1,219 @@
 use circular_buffer::CircularBuffer;
 use esp_idf_svc::{
     bt::spp::{self, EspSpp, SppEvent},
-    bt::{BtClassicEnabled, BtDriver},
-    nvs::{EspNvs, NvsDefault},
+    bt::{BdAddr, BtClassicEnabled, BtDriver},
     sys::EspError,
 };
+use serde::Serialize;
 use std::{
     borrow::Borrow,
+    collections::VecDeque,
     io::{self, Read, Write},
     sync::{
-        atomic::{self, AtomicU32},
+        atomic::{self, AtomicBool, AtomicU32, AtomicU8},
         mpsc::SyncSender,
         Arc, Condvar, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
 
 use crate::error::LedBlink;
-use crate::{BD_ADDR, NVS_DISC_FAIL_COUNT};
+use crate::events::{self, AdapterLink};
+use crate::metrics;
+use crate::transport::{ConnectionStatus, ElmTransport};
 use log::*;
 
 const WRITE_BUF_SIZE: usize = 250;
 const READ_BUF_SIZE: usize = 500;
-
-type WriteBuffer = Arc<Mutex<Box<CircularBuffer<WRITE_BUF_SIZE, u8>>>>;
+/// The read buffer after an STBR switch holds this much of the adapter's output, up to
+/// `MAX_READ_BUF_SIZE`
+const READ_BUF_WINDOW_MS: u32 = 50;
+const MAX_READ_BUF_SIZE: usize = 4096;
+/// Longest a read waits for the adapter, so a dropped link can't hang the ELM worker
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a write waits for the adapter to take what's queued when the buffer is full
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Discovery and the SPP connection, a slow adapter takes a few seconds
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// A connection on a known SCN opens in a second or two, discovery is the fallback after this
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes that had to wait for room in the write buffer
+static WRITE_WAITS: AtomicU32 = AtomicU32::new(0);
+/// Writes refused as the write buffer stayed full
+static WRITE_FULL: AtomicU32 = AtomicU32::new(0);
+/// Received bytes dropped as the read buffer was full
+static READ_OVERFLOW_BYTES: AtomicU32 = AtomicU32::new(0);
+/// Times the stack reported the link congested
+static CONGESTIONS: AtomicU32 = AtomicU32::new(0);
+/// Time spent congested, writes wait in the queue meanwhile
+static CONGESTED_MS: AtomicU32 = AtomicU32::new(0);
+/// Most bytes queued for the adapter at once
+static WRITE_HIGH_WATER: AtomicU32 = AtomicU32::new(0);
+
+type WriteBuffer = Arc<(Mutex<WriteQueue>, Condvar)>;
 type ReadBuffer = Arc<(Mutex<DataBuffer>, Condvar)>;
 
 pub struct DataBuffer {
-    data: Box<CircularBuffer<READ_BUF_SIZE, u8>>,
+    data: VecDeque<u8>,
+    /// Bytes kept before the oldest are dropped, see `set_baud_rate`
+    size: usize,
     available: bool,
 }
 
+impl DataBuffer {
+    /// Keep the newest `size` bytes
+    fn trim(&mut self) {
+        let excess = self.data.len().saturating_sub(self.size);
+        self.data.drain(..excess);
+    }
+}
+
+/// Data waiting for the adapter. One write is handed to the stack at a time and the next goes
+/// once its Write event says how much was taken, none while the link is congested. Bytes are
+/// only dropped from the queue once the stack has taken them.
+pub struct WriteQueue {
+    data: Box<CircularBuffer<WRITE_BUF_SIZE, u8>>,
+    /// Handed to the stack, waiting for its Write event
+    in_flight: bool,
+    /// Set while the stack reports congestion, see `SppEvent::Cong`
+    congested_since: Option<Instant>,
+}
+
+impl WriteQueue {
+    fn new() -> Self {
+        Self {
+            data: CircularBuffer::boxed(),
+            in_flight: false,
+            congested_since: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn extend(&mut self, buf: &[u8]) {
+        self.data.extend_from_slice(buf);
+        WRITE_HIGH_WATER.fetch_max(self.data.len() as u32, atomic::Ordering::Relaxed);
+    }
+
+    /// Hand what's queued to the stack, unless a write is already in flight or the link is
+    /// congested. The Write or Cong event that ends either sends the rest.
+    fn send<'d, M, T>(&mut self, spp: &EspSpp<'d, M, T>, handle: u32) -> Result<(), EspError>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        if self.in_flight || self.congested_since.is_some() || self.data.is_empty() {
+            return Ok(());
+        }
+
+        spp.write(handle, self.data.make_contiguous())?;
+        self.in_flight = true;
+
+        Ok(())
+    }
+
+    /// The stack took `length` bytes from the front of the write in flight
+    fn written(&mut self, length: usize) {
+        if length > self.data.len() {
+            warn!(
+                "Write length more than buffer. buffer_len ({}), written ({length})",
+                self.data.len()
+            );
+        }
+
+        let written = length.min(self.data.len());
+        self.data.truncate_front(self.data.len() - written);
+        self.in_flight = false;
+    }
+
+    fn set_congested(&mut self, cong: bool) {
+        match (cong, self.congested_since) {
+            (true, None) => {
+                CONGESTIONS.fetch_add(1, atomic::Ordering::Relaxed);
+                self.congested_since = Some(Instant::now());
+            }
+            (false, Some(since)) => {
+                let ms = since.elapsed().as_millis().min(u32::MAX as u128) as u32;
+                CONGESTED_MS.fetch_add(ms, atomic::Ordering::Relaxed);
+                self.congested_since = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// The connection opened or closed, nothing is in flight on it. The queued data stays for
+    /// the next connection.
+    fn reset_link(&mut self) {
+        self.in_flight = false;
+        self.set_congested(false);
+    }
+}
+
+/// Buffer pressure counters since boot, reported at /status
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct SppStats {
+    pub write_waits: u32,
+    pub write_full: u32,
+    pub read_overflow_bytes: u32,
+    pub congestions: u32,
+    pub congested_ms: u32,
+    pub write_high_water: u32,
+}
+
+pub fn stats() -> SppStats {
+    SppStats {
+        write_waits: WRITE_WAITS.load(atomic::Ordering::Relaxed),
+        write_full: WRITE_FULL.load(atomic::Ordering::Relaxed),
+        read_overflow_bytes: READ_OVERFLOW_BYTES.load(atomic::Ordering::Relaxed),
+        congestions: CONGESTIONS.load(atomic::Ordering::Relaxed),
+        congested_ms: CONGESTED_MS.load(atomic::Ordering::Relaxed),
+        write_high_water: WRITE_HIGH_WATER.load(atomic::Ordering::Relaxed),
+    }
+}
+
 pub struct SppHandler<'d, M, T>
 where
     M: BtClassicEnabled,
     T: Borrow<BtDriver<'d, M>>,
 {
     spp: &'d EspSpp<'d, M, T>,
+    /// The adapter this handler talks to, SPP events are routed to it by address then handle
+    pub addr: BdAddr,
     pub handle: Arc<AtomicU32>,
     pub write_buf: WriteBuffer,
     pub read_buf: ReadBuffer,
+    /// Set while discovery runs for this adapter, see `start_discovery`
+    discovering: Arc<AtomicBool>,
+    /// Set when discovery didn't find the adapter's SPP service
+    discovery_failed: Arc<AtomicBool>,
+    /// The SPP server channel discovery found, 0 until it has
+    scn: Arc<AtomicU8>,
+    /// Set when the stack couldn't open the connection, see `connect_direct`
+    open_failed: Arc<AtomicBool>,
+}
+
+/// One adapter's share of the SPP callback, see `SppHandler::link`
+pub struct SppLink {
+    addr: BdAddr,
+    handle: Arc<AtomicU32>,
+    write_buf: WriteBuffer,
+    read_buf: ReadBuffer,
+    discovering: Arc<AtomicBool>,
+    discovery_failed: Arc<AtomicBool>,
+    scn: Arc<AtomicU8>,
+    open_failed: Arc<AtomicBool>,
+    /// A failed discovery is shown on the LED for the OBD adapter only
+    primary: bool,
 }
 
 impl<'d, M, T> Write for SppHandler<'d, M, T>
@@ -58,14 +229,15 @@ where
         Ok(buf.len())
     }
 
+    /// Send what's queued, or leave it for the write in flight to pick up
     fn flush(&mut self) -> io::Result<()> {
         let handle = self.handle.load(atomic::Ordering::Relaxed);
         if handle > 0 {
-            let mut write_buf = self.write_buf.lock().unwrap();
+            let mut write_buf = self.write_buf.0.lock().unwrap();
 
-            if let Err(err) = self.spp.write(handle, write_buf.make_contiguous()) {
+            if let Err(err) = write_buf.send(self.spp, handle) {
                 error!("Failed to write: {err}");
-                write_buf.clear();
+                write_buf.data.clear();
 
                 return Err::<(), io::Error>(io::Error::new::<EspError>(
                     io::ErrorKind::ConnectionReset,
@@ -83,7 +255,8 @@ where
     M: BtClassicEnabled,
     T: Borrow<BtDriver<'d, M>>,
 {
-    /// Read a response from the OBDLink. Will BLOCK until there is some data available
+    /// Read a response from the OBDLink. Will BLOCK until there is some data available, or
+    /// fail with `TimedOut` after `READ_TIMEOUT`
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let (read_buf, cvar) = &*self.read_buf;
 
@@ -91,9 +264,26 @@ where
         let mut read_buf = read_buf.lock().unwrap();
 
         while read_buf.data.is_empty() {
-            read_buf = cvar
-                .wait_while(read_buf, |data| !data.available)
+            let (guard, wait) = cvar
+                .wait_timeout_while(read_buf, READ_TIMEOUT, |data| !data.available)
                 .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Poisoned"))?;
+            read_buf = guard;
+
+            if wait.timed_out() {
+                // Requests queue while the link is down, so no answer is expected
+                if self.handle.load(atomic::Ordering::Relaxed) == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "Adapter not connected",
+                    ));
+                }
+
+                metrics::ELM_READ_TIMEOUTS.inc();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No response from the adapter",
+                ));
+            }
 
             read_buf.available = false; // might be false wake up
 
@@ -113,29 +303,97 @@ where
     M: BtClassicEnabled,
     T: Borrow<BtDriver<'d, M>>,
 {
-    pub fn new(spp: &'d EspSpp<'d, M, T>) -> Self {
+    pub fn new(spp: &'d EspSpp<'d, M, T>, addr: BdAddr) -> Self {
         Self {
             spp,
+            addr,
             handle: Arc::new(AtomicU32::new(0)),
-            write_buf: Arc::new(Mutex::new(CircularBuffer::boxed())),
+            write_buf: Arc::new((Mutex::new(WriteQueue::new()), Condvar::new())),
             read_buf: Arc::new((
                 Mutex::new(DataBuffer {
-                    data: CircularBuffer::boxed(),
+                    data: VecDeque::with_capacity(READ_BUF_SIZE),
+                    size: READ_BUF_SIZE,
                     available: false,
                 }),
                 Condvar::new(),
             )),
+            discovering: Arc::new(AtomicBool::new(false)),
+            discovery_failed: Arc::new(AtomicBool::new(false)),
+            scn: Arc::new(AtomicU8::new(0)),
+            open_failed: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
-        self.extend_write_buf(request)?;
+    /// The shared state the SPP callback needs to route events to this adapter
+    pub fn link(&self, primary: bool) -> SppLink {
+        SppLink {
+            addr: self.addr,
+            handle: Arc::clone(&self.handle),
+            write_buf: Arc::clone(&self.write_buf),
+            read_buf: Arc::clone(&self.read_buf),
+            discovering: Arc::clone(&self.discovering),
+            discovery_failed: Arc::clone(&self.discovery_failed),
+            scn: Arc::clone(&self.scn),
+            open_failed: Arc::clone(&self.open_failed),
+            primary,
+        }
+    }
 
-        self.write_all(b"\r")?;
+    /// Look for the adapter's SPP service, the connection opens once it's found. One discovery
+    /// at a time, the result doesn't say which adapter it was for.
+    pub fn start_discovery(&self) -> Result<()> {
+        self.discovery_failed
+            .store(false, atomic::Ordering::Relaxed);
+        self.discovering.store(true, atomic::Ordering::Relaxed);
+        self.spp.start_discovery(&self.addr)?;
+
+        Ok(())
+    }
+
+    /// The SPP server channel the last discovery found, to skip it next time
+    pub fn scn(&self) -> Option<u8> {
+        match self.scn.load(atomic::Ordering::Relaxed) {
+            0 => None,
+            scn => Some(scn),
+        }
+    }
+
+    /// Open the connection on a known server channel without discovering it first. Fails if
+    /// it isn't open within `DIRECT_CONNECT_TIMEOUT`, e.g. the adapter moved its SPP service.
+    pub fn connect_direct(&mut self, scn: u8) -> Result<()> {
+        info!("Connecting to the adapter on SCN ({scn})");
+
+        self.open_failed.store(false, atomic::Ordering::Relaxed);
+        self.spp.connect(
+            spp::Security::Authenticate,
+            spp::Role::Master,
+            scn,
+            &self.addr,
+        )?;
+
+        let start = Instant::now();
+        while self.handle.load(atomic::Ordering::Relaxed) == 0 {
+            if self.open_failed.load(atomic::Ordering::Relaxed) {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("SPP connection on SCN ({scn}) refused"),
+                ))?;
+            }
+            if start.elapsed() >= DIRECT_CONNECT_TIMEOUT {
+                Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("No SPP connection on SCN ({scn})"),
+                ))?;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
 
         Ok(())
     }
 
+    /// Queue data for the adapter. Waits for the adapter to take what's already queued rather
+    /// than overwrite it, and fails with `WouldBlock` if there's still no room after
+    /// `WRITE_TIMEOUT`.
     fn extend_write_buf(&self, buf: &[u8]) -> Result<()> {
         if buf.len() > WRITE_BUF_SIZE {
             Err(io::Error::new(
@@ -144,9 +402,133 @@ where
             ))?;
         };
 
-        let mut write_buf = self.write_buf.lock().unwrap();
+        let (write_buf, drained) = &*self.write_buf;
+        let room = |queued: usize| queued + buf.len() <= WRITE_BUF_SIZE;
+
+        let mut write_buf = write_buf.lock().unwrap();
+
+        if !room(write_buf.len()) {
+            WRITE_WAITS.fetch_add(1, atomic::Ordering::Relaxed);
+            debug!("Write buffer full ({}), waiting", write_buf.len());
+
+            let (guard, wait) = drained
+                .wait_timeout_while(write_buf, WRITE_TIMEOUT, |write_buf| !room(write_buf.len()))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Poisoned"))?;
+            write_buf = guard;
 
-        write_buf.extend_from_slice(buf);
+            if wait.timed_out() {
+                WRITE_FULL.fetch_add(1, atomic::Ordering::Relaxed);
+                error!(
+                    "Write buffer still full ({}), write refused",
+                    write_buf.len()
+                );
+
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Write buffer full",
+                ))?;
+            }
+        }
+
+        write_buf.extend(buf);
+
+        Ok(())
+    }
+}
+
+impl<'d, M, T> ConnectionStatus for SppHandler<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    /// Wait for discovery to open the SPP connection, failing early if discovery didn't find
+    /// the adapter
+    fn wait_connected(&mut self) -> Result<()> {
+        let start = Instant::now();
+
+        while self.handle.load(atomic::Ordering::Relaxed) == 0 {
+            if self.discovery_failed.load(atomic::Ordering::Relaxed) {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "SPP discovery didn't find the adapter",
+                ))?;
+            }
+            if start.elapsed() >= CONNECT_TIMEOUT {
+                Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("No SPP connection within {}s", CONNECT_TIMEOUT.as_secs()),
+                ))?;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    /// Close the SPP connection to the adapter, e.g. before sleeping
+    fn disconnect(&mut self) {
+        let handle = self.handle.swap(0, atomic::Ordering::Relaxed);
+        if handle > 0 {
+            if let Err(err) = self.spp.disconnect(handle) {
+                error!("Failed to disconnect: {err}");
+            }
+            self.write_buf.0.lock().unwrap().reset_link();
+        }
+    }
+
+    /// Drop the connection and discover the adapter again, pairing if the bond was removed.
+    /// Requests queue in the write buffer until it's open.
+    fn reconnect(&mut self) -> Result<()> {
+        self.disconnect();
+        self.start_discovery()
+    }
+
+    fn name(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl<'d, M, T> ElmTransport for SppHandler<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+    Self: Send,
+{
+    /// Read whatever has been received without waiting, e.g. unsolicited adapter messages
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (read_buf, _) = &*self.read_buf;
+
+        let mut read_buf = read_buf.lock().unwrap();
+
+        let nread = read_buf.data.read(buf)?;
+
+        read_buf.available = !read_buf.data.is_empty();
+
+        Ok(nread)
+    }
+
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
+        self.extend_write_buf(request)?;
+
+        self.write_all(b"\r")?;
+
+        Ok(())
+    }
+
+    /// SPP has no rate of its own, but a faster adapter UART fills the read buffer faster
+    fn set_baud_rate(&mut self, baud: Option<u32>) -> Result<()> {
+        // 10 bits a byte on the UART
+        let size = baud.map_or(READ_BUF_SIZE, |baud| {
+            ((baud / 10 * READ_BUF_WINDOW_MS / 1000) as usize)
+                .clamp(READ_BUF_SIZE, MAX_READ_BUF_SIZE)
+        });
+
+        let mut read_buf = self.read_buf.0.lock().unwrap();
+        read_buf.size = size;
+        read_buf.trim();
+        read_buf.data.shrink_to(size);
+
+        info!("SPP read buffer ({size}) bytes");
 
         Ok(())
     }
@@ -165,19 +547,26 @@ where
     }
 }
 
-/// BT Serial Port Profile callback handler
+/// BT Serial Port Profile callback handler, `links` has an entry per adapter
 pub fn handle_spp<'d, M, T>(
-    elm_nvs: &EspNvs<NvsDefault>,
+    links: &[SppLink],
     led_blink: &SyncSender<LedBlink>,
     spp: &EspSpp<'d, M, T>,
-    rem_handle: &AtomicU32,
-    write_buf: &Mutex<Box<CircularBuffer<WRITE_BUF_SIZE, u8>>>,
-    read_buf: &(Mutex<DataBuffer>, Condvar),
     event: SppEvent<'_>,
 ) where
     M: BtClassicEnabled,
     T: Borrow<BtDriver<'d, M>>,
 {
+    let by_handle = |handle: u32| {
+        let link = links
+            .iter()
+            .find(|link| link.handle.load(atomic::Ordering::Relaxed) == handle);
+        if link.is_none() {
+            warn!("Event for unknown handle ({handle})");
+        }
+        link
+    };
+
     match event {
         SppEvent::DiscoveryComp {
             status,
@@ -185,40 +574,37 @@ pub fn handle_spp<'d, M, T>(
             scn,
             service_name,
         } => {
+            let Some(link) = links
+                .iter()
+                .find(|link| link.discovering.swap(false, atomic::Ordering::Relaxed))
+            else {
+                warn!("Event: DisComp, no discovery in progress");
+                return;
+            };
+
             if status == spp::Status::Success {
                 debug!(
-                    "Event: DisComp, scn_num ({scn_num}), scn ({scn:?}), service_name ({service_name:?})"
+                    "Event: DisComp ({}), scn_num ({scn_num}), scn ({scn:?}), service_name ({service_name:?})",
+                    link.addr
                 );
+                link.scn.store(scn[0], atomic::Ordering::Relaxed);
 
                 if let Err(err) = spp.connect(
                     spp::Security::Authenticate,
                     spp::Role::Master,
                     scn[0],
-                    &BD_ADDR,
+                    &link.addr,
                 ) {
                     error!("Event: DisComp failed to dispatch spp.connect, {err}")
                 }
             } else {
-                error!("Event: DisComp FAILED, status {status:?}");
-
-                // Panic so we can try discover again, but only do this a few times so we don't go into a
-                // boot loop
-                let _ = led_blink.send(LedBlink::Times(4));
-                thread::sleep(Duration::from_millis(3500)); // wait for the leds...
-
-                if let Some(n) = elm_nvs
-                    .get_u8(NVS_DISC_FAIL_COUNT)
-                    .unwrap_or(Some(0))
-                    .or(Some(0))
-                    .filter(|n| n <= &2)
-                {
-                    info!("Fail count {n}");
-                    let _ = elm_nvs.set_u8(NVS_DISC_FAIL_COUNT, n + 1);
-                    panic!("Failed to discover OBDLink, rebooting...");
-                }
+                error!("Event: DisComp ({}) FAILED, status {status:?}", link.addr);
 
-                info!("Rebooted too many times, not rebooting again");
-                let _ = led_blink.send(LedBlink::Error(4));
+                // The waiting startup applies the discovery policy, see `discovery`
+                link.discovery_failed.store(true, atomic::Ordering::Relaxed);
+                if link.primary {
+                    let _ = led_blink.send(LedBlink::Times(4));
+                }
             }
         }
         SppEvent::Open {
@@ -230,10 +616,23 @@ pub fn handle_spp<'d, M, T>(
             if status == spp::Status::Success {
                 debug!("Event: Open, handle ({handle}), fd ({fd}), rem_bda ({rem_bda})");
 
-                rem_handle.store(handle, atomic::Ordering::Relaxed);
+                let Some(link) = links.iter().find(|link| link.addr == rem_bda) else {
+                    warn!("Event: Open, unknown device ({rem_bda})");
+                    return;
+                };
+
+                link.handle.store(handle, atomic::Ordering::Relaxed);
+                metrics::SPP_CONNECTS.inc();
+                events::publish(
+                    events::ADAPTER,
+                    &AdapterLink {
+                        address: link.addr.to_string(),
+                        connected: true,
+                    },
+                );
 
                 // If we have data, write now...
-                let mut write_buf = match write_buf.lock() {
+                let mut write_buf = match link.write_buf.0.lock() {
                     Ok(guard) => guard,
                     Err(poisoned) => {
                         error!("Event: Open, write buf poisoned, acquiring anyway...");
@@ -241,14 +640,19 @@ pub fn handle_spp<'d, M, T>(
                     }
                 };
 
+                write_buf.reset_link();
                 if !write_buf.is_empty() {
                     debug!("writing... {} bytes", write_buf.len());
-                    if let Err(err) = spp.write(handle, write_buf.make_contiguous()) {
+                    if let Err(err) = write_buf.send(spp, handle) {
                         error!("Event: Open write failed {err}");
                     }
                 }
             } else {
                 error!("Event: Open FAILED, status {status:?}");
+
+                if let Some(link) = links.iter().find(|link| link.addr == rem_bda) {
+                    link.open_failed.store(true, atomic::Ordering::Relaxed);
+                }
             }
         }
         SppEvent::DataInd {
@@ -260,7 +664,10 @@ pub fn handle_spp<'d, M, T>(
             if status == spp::Status::Success {
                 debug!("Event: DataInd, handle ({handle}), data ({data:?})");
 
-                let (read_buf, cvar) = read_buf;
+                let Some(link) = by_handle(handle) else {
+                    return;
+                };
+                let (read_buf, cvar) = &*link.read_buf;
 
                 // get read lock
                 let mut read_buf = match read_buf.lock() {
@@ -271,7 +678,7 @@ pub fn handle_spp<'d, M, T>(
                     }
                 };
 
-                let max_length = READ_BUF_SIZE - read_buf.data.len();
+                let max_length = read_buf.size.saturating_sub(read_buf.data.len());
                 let read_length: usize = length as _;
 
                 if read_length > max_length {
@@ -279,11 +686,14 @@ pub fn handle_spp<'d, M, T>(
                         "Read buffer overflow, total bytes would be ({})",
                         read_buf.data.len() + read_length
                     );
+                    READ_OVERFLOW_BYTES
+                        .fetch_add((read_length - max_length) as u32, atomic::Ordering::Relaxed);
                 };
 
                 read_buf
                     .data
-                    .extend_from_slice(unsafe { core::slice::from_raw_parts(data, read_length) });
+                    .extend(unsafe { core::slice::from_raw_parts(data, read_length) });
+                read_buf.trim();
 
                 read_buf.available = true;
                 cvar.notify_all();
@@ -297,6 +707,11 @@ pub fn handle_spp<'d, M, T>(
             length,
             cong,
         } => {
+            let Some(link) = by_handle(handle) else {
+                return;
+            };
+            let (write_buf, write_drained) = &*link.write_buf;
+
             let mut write_buf = match write_buf.lock() {
                 Ok(guard) => guard,
                 Err(poisoned) => {
@@ -311,32 +726,26 @@ pub fn handle_spp<'d, M, T>(
                     write_buf.len()
                 );
 
-                let length: usize = length as _;
-                let mut write_buf_length = write_buf.len();
-                if length > write_buf_length {
-                    warn!(
-                        "Write length more than buffer. buffer_len ({write_buf_length}), written ({length})"
-                    );
-                    write_buf_length = length;
-                }
-                if length < write_buf_length {
-                    warn!(
-                        "Write not fully written. buffer_len ({write_buf_length}), written ({length})"  
-                    );
-                }
-                write_buf.truncate_front(write_buf_length - length);
+                write_buf.written(length as _);
+                write_drained.notify_all();
             } else {
                 error!(
                     "Event: Write FAILED, status {:?} write buf {}",
                     status,
                     write_buf.len()
                 );
+                // Sent again from the front
+                write_buf.in_flight = false;
             }
+            write_buf.set_congested(cong);
 
-            // If not congested and there is more data to write...
-            if !cong && !write_buf.is_empty() {
-                if let Err(err) = spp.write(handle, write_buf.make_contiguous()) {
-                    error!("Event: Write, not cong but write again failed {err}");
+            // The rest, or what was queued meanwhile, once the link can take it
+            if !write_buf.is_empty() {
+                if !cong {
+                    metrics::BT_WRITE_RETRIES.inc();
+                }
+                if let Err(err) = write_buf.send(spp, handle) {
+                    error!("Event: Write, write again failed {err}");
                 }
             }
         }
@@ -348,7 +757,11 @@ pub fn handle_spp<'d, M, T>(
             if status == spp::Status::Success {
                 debug!("Event: Cong, handle {handle}, cong {cong}");
 
-                let mut write_buf = match write_buf.lock() {
+                let Some(link) = by_handle(handle) else {
+                    return;
+                };
+
+                let mut write_buf = match link.write_buf.0.lock() {
                     Ok(guard) => guard,
                     Err(poisoned) => {
                         error!("Event: Cong, write buf poisoned, acquiring anyway...");
@@ -356,8 +769,11 @@ pub fn handle_spp<'d, M, T>(
                     }
                 };
 
+                write_buf.set_congested(cong);
+
                 if !cong && !write_buf.is_empty() {
-                    if let Err(err) = spp.write(handle, write_buf.make_contiguous()) {
+                    metrics::BT_WRITE_RETRIES.inc();
+                    if let Err(err) = write_buf.send(spp, handle) {
                         error!("Event: Cong write failed {err}");
                     }
                 }
@@ -377,7 +793,17 @@ pub fn handle_spp<'d, M, T>(
                 error!("Event: Close FAILED, status {status:?}");
             }
 
-            rem_handle.store(0, atomic::Ordering::Relaxed);
+            if let Some(link) = by_handle(handle) {
+                link.handle.store(0, atomic::Ordering::Relaxed);
+                link.write_buf.0.lock().unwrap().reset_link();
+                events::publish(
+                    events::ADAPTER,
+                    &AdapterLink {
+                        address: link.addr.to_string(),
+                        connected: false,
+                    },
+                );
+            }
         }
         _ => (),
     }
@@ -0,0 +1,111 @@
+//! Reboot and factory reset from `/system`, for a gateway that's out of reach behind the dash.
+//! A factory reset is asked for twice: the first request gets a token, the second has to bring
+//! it back within `CONFIRM_LIFETIME`, so a stray or replayed request can't wipe the settings.
+//! As it also erases the signing key and unlock token, both are checked first.
+use std::{
+    ffi::CString,
+    fmt::Write,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use esp_idf_svc::{
+    hal::reset,
+    sys::{
+        esp, esp_fill_random, nvs_close, nvs_commit, nvs_erase_all, nvs_handle_t, nvs_open,
+        nvs_open_mode_t_NVS_READWRITE,
+    },
+};
+use log::*;
+
+#[cfg(feature = "bt")]
+use crate::bt;
+use crate::persist::Persist;
+
+/// The gateway's settings
+const NVS_NAMESPACE: &str = "elm_ns";
+pub const CONFIRM_LIFETIME: Duration = Duration::from_secs(30);
+const TOKEN_LEN: usize = 8;
+/// Time for the response to go out before the restart
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+const RESTART_STACK_SIZE: usize = 2048;
+
+/// The outstanding factory reset token and when it was issued
+static CONFIRM: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+/// A new factory reset token, replacing any earlier one
+pub fn confirm_token() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    unsafe { esp_fill_random(bytes.as_mut_ptr().cast(), bytes.len()) };
+
+    let token = bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+
+    *CONFIRM.lock().unwrap() = Some((token.clone(), Instant::now()));
+
+    token
+}
+
+/// Whether `token` is the outstanding one and still good, it can only be used once
+pub fn confirmed(token: &str) -> bool {
+    match CONFIRM.lock().unwrap().take() {
+        Some((expected, issued)) => expected == token && issued.elapsed() < CONFIRM_LIFETIME,
+        None => false,
+    }
+}
+
+/// Write out the batched NVS values and restart, see `restart`
+pub fn reboot(persist: &Persist) -> Result<()> {
+    info!("Rebooting on request");
+    if let Err(err) = persist.flush() {
+        error!("Flush before reboot failed {err}");
+    }
+
+    restart()
+}
+
+/// Forget the adapter bonds, erase every setting and restart with the defaults. The batched
+/// values aren't flushed, they'd only write some of it back.
+pub fn factory_reset() -> Result<()> {
+    warn!("Factory reset");
+
+    #[cfg(feature = "bt")]
+    for addr in bt::bonded_devices()? {
+        bt::remove_bond(&addr)?;
+    }
+
+    let namespace = CString::new(NVS_NAMESPACE)?;
+    let mut handle: nvs_handle_t = 0;
+    esp!(unsafe {
+        nvs_open(
+            namespace.as_ptr(),
+            nvs_open_mode_t_NVS_READWRITE,
+            &mut handle,
+        )
+    })?;
+
+    let erased =
+        esp!(unsafe { nvs_erase_all(handle) }).and_then(|_| esp!(unsafe { nvs_commit(handle) }));
+    unsafe { nvs_close(handle) };
+    erased?;
+
+    Ok(())
+}
+
+/// Restart from a thread of its own once `RESTART_DELAY` is up. The handler asking for it returns
+/// first, and its response only goes out once it has.
+pub fn restart() -> Result<()> {
+    thread::Builder::new()
+        .name("restart".to_owned())
+        .stack_size(RESTART_STACK_SIZE)
+        .spawn(|| {
+            thread::sleep(RESTART_DELAY);
+            reset::restart();
+        })?;
+
+    Ok(())
+}
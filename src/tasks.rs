@@ -0,0 +1,165 @@
+//! FreeRTOS priorities and core for the gateway's tasks, from `/config/tasks` in NVS. The BT
+//! controller and Bluedroid are pinned to core 1 in sdkconfig (WiFi stays on core 0 for coexist),
+//! so by default the ELM worker is pinned to core 0 with the HTTP server, keeping the SPP
+//! callbacks clear of request handling. The main loop, HTTP server and LED tasks already exist by
+//! the time the settings are read, they only get their priority set. Used from the next boot.
+use std::{ffi::CStr, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    hal::{cpu::Core, task::thread::ThreadSpawnConfiguration},
+    nvs::{EspNvs, NvsDefault},
+    sys::{vTaskPrioritySet, xTaskGetHandle},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_TASKS};
+
+pub const MAX_CONFIG_LEN: usize = 192;
+/// Below lwIP (18), Bluedroid (19, 20) and WiFi (23), which can't be starved
+const MAX_PRIORITY: u8 = 15;
+/// FreeRTOS task names, the LED's and the worker's are given as they're spawned
+const LED_TASK: &CStr = c"led";
+const ELM_WORKER_TASK: &CStr = c"elm_worker";
+const HTTP_TASK: &CStr = c"httpd";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct TaskConfig {
+    pub elm_worker_priority: u8,
+    /// 0 or 1, None for either core
+    pub elm_worker_core: Option<u8>,
+    pub http_priority: u8,
+    /// The main loop, polls the monitors, ignition, logger and the like
+    pub main_priority: u8,
+    pub led_priority: u8,
+}
+
+impl Default for TaskConfig {
+    /// Adapter work ahead of HTTP, the LED behind everything
+    fn default() -> Self {
+        Self {
+            elm_worker_priority: 6,
+            elm_worker_core: Some(0),
+            http_priority: 5,
+            main_priority: 3,
+            led_priority: 2,
+        }
+    }
+}
+
+impl TaskConfig {
+    fn check(&self) -> Result<()> {
+        let priorities = [
+            self.elm_worker_priority,
+            self.http_priority,
+            self.main_priority,
+            self.led_priority,
+        ];
+        if priorities.iter().any(|p| !(1..=MAX_PRIORITY).contains(p)) {
+            Err(anyhow!("Priorities must be 1 to {MAX_PRIORITY}"))?;
+        }
+
+        if self.elm_worker_core.is_some_and(|core| core > 1) {
+            Err(anyhow!("elm_worker_core must be 0, 1 or null"))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Tasks {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<TaskConfig>,
+}
+
+impl Tasks {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_TASKS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> TaskConfig {
+        *self.config.lock().unwrap()
+    }
+
+    pub fn set_config(&self, config: TaskConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_TASKS, &config::encode(&config)?)?;
+
+        info!("Task settings updated, used from the next boot {config:?}");
+        *self.config.lock().unwrap() = config;
+
+        Ok(())
+    }
+
+    /// Run `start` with the threads it spawns getting the ELM worker's priority and core
+    pub fn elm_worker<R>(&self, start: impl FnOnce() -> Result<R>) -> Result<R> {
+        let config = self.config();
+
+        spawn_with(
+            ThreadSpawnConfiguration {
+                name: Some(ELM_WORKER_TASK.to_bytes_with_nul()),
+                priority: config.elm_worker_priority,
+                pin_to_core: config.elm_worker_core.map(|core| match core {
+                    0 => Core::Core0,
+                    _ => Core::Core1,
+                }),
+                ..Default::default()
+            },
+            start,
+        )
+    }
+
+    /// Set the priorities of the tasks that were running before the settings were read. Called
+    /// from the main task once the HTTP server has started.
+    pub fn apply_running(&self) {
+        let config = self.config();
+
+        // A null handle is the calling task
+        unsafe { vTaskPrioritySet(std::ptr::null_mut(), config.main_priority as u32) };
+        set_priority(HTTP_TASK, config.http_priority);
+        set_priority(LED_TASK, config.led_priority);
+
+        info!("Task priorities {config:?}");
+    }
+}
+
+/// Run `start` with the LED thread it spawns named, so its priority can be set later
+pub fn led<R>(start: impl FnOnce() -> R) -> Result<R> {
+    spawn_with(
+        ThreadSpawnConfiguration {
+            name: Some(LED_TASK.to_bytes_with_nul()),
+            ..Default::default()
+        },
+        || Ok(start()),
+    )
+}
+
+/// Run `start` with `spawn` for the threads it starts, then back to the defaults
+fn spawn_with<R>(spawn: ThreadSpawnConfiguration, start: impl FnOnce() -> Result<R>) -> Result<R> {
+    spawn.set()?;
+    let result = start();
+    ThreadSpawnConfiguration::default().set()?;
+
+    result
+}
+
+fn set_priority(task: &CStr, priority: u8) {
+    let handle = unsafe { xTaskGetHandle(task.as_ptr()) };
+    if handle.is_null() {
+        warn!("No {task:?} task to set the priority of");
+        return;
+    }
+
+    unsafe { vTaskPrioritySet(handle, priority as u32) };
+}
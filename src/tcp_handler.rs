@@ -0,0 +1,162 @@
+//! ELM327 WiFi adapter (the "WiFi clone" kind) over TCP, e.g. 192.168.0.10:35000, in place of the
+//! BT SPP adapter. Built with the `wifi-adapter` feature, the adapter has to be reachable from the
+//! network the gateway joins.
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::*;
+
+use crate::metrics;
+use crate::transport::{ConnectionStatus, ElmTransport};
+
+/// Longest a read waits for the adapter, as for SPP
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a write waits for the adapter to take the request
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// The adapter is on the local network, it answers quickly or not at all
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TcpHandler {
+    addr: SocketAddr,
+    /// None until connected, and again once the adapter drops the connection
+    stream: Option<TcpStream>,
+}
+
+impl TcpHandler {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, stream: None }
+    }
+
+    /// The open connection, connecting first if there isn't one
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream =
+                TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("Adapter ({}) not connected, {err}", self.addr),
+                    )
+                })?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            stream.set_nodelay(true)?;
+
+            info!("Connected to the adapter ({})", self.addr);
+
+            self.stream = Some(stream);
+        }
+
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Drop a failed connection, the next request connects again
+    fn closed(&mut self, err: io::Error) -> io::Error {
+        warn!("Adapter connection lost {err}");
+        self.stream = None;
+
+        io::Error::new(io::ErrorKind::ConnectionReset, err)
+    }
+}
+
+impl Read for TcpHandler {
+    /// Read a response from the adapter. Will BLOCK until there is some data available, or fail
+    /// with `TimedOut` after `READ_TIMEOUT`
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Adapter not connected",
+            ));
+        };
+
+        match stream.read(buf) {
+            Ok(0) => Err(self.closed(io::ErrorKind::UnexpectedEof.into())),
+            Ok(nread) => Ok(nread),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                metrics::ELM_READ_TIMEOUTS.inc();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No response from the adapter",
+                ))
+            }
+            Err(err) => Err(self.closed(err)),
+        }
+    }
+}
+
+impl Write for TcpHandler {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stream()?.write(buf) {
+            Ok(nwritten) => Ok(nwritten),
+            Err(err) => Err(self.closed(err)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream.as_mut().map(TcpStream::flush) {
+            Some(Err(err)) => Err(self.closed(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ConnectionStatus for TcpHandler {
+    fn wait_connected(&mut self) -> Result<()> {
+        self.stream()?;
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.disconnect();
+        self.wait_connected()
+    }
+
+    fn name(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl ElmTransport for TcpHandler {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(0);
+        };
+
+        stream.set_nonblocking(true)?;
+        let result = stream.read(buf);
+        stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(self.closed(io::ErrorKind::UnexpectedEof.into())),
+            Ok(nread) => Ok(nread),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(self.closed(err)),
+        }
+    }
+
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
+        let mut line = request.to_vec();
+        line.push(b'\r');
+
+        // One segment, some adapters take a request split over two as two requests
+        self.write_all(&line)?;
+
+        Ok(())
+    }
+}
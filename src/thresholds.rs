@@ -0,0 +1,321 @@
+//! Threshold alerts on the values the gateway reads, e.g. coolant over 110°C or the battery under
+//! 12.0V while parked. The main loop polls them; a rule triggers when its value crosses the
+//! threshold and clears once it's back past the hysteresis, each change is kept for
+//! `GET /thresholds` and sent on to the LCD.
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use circular_buffer::CircularBuffer;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::config::{self, NVS_THRESHOLDS};
+use crate::elm327::Elm327;
+use crate::http::uptime_ms;
+use crate::pid;
+use crate::virtual_pids::VirtualPids;
+
+pub const MAX_CONFIG_LEN: usize = 2048;
+const MAX_RULES: usize = 16;
+/// Goes out whole in the ESPNOW alert
+const MAX_NAME_LEN: usize = 32;
+const MAX_EVENTS: usize = 16;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The adapter's own voltage reading (ATRV), there with the ignition off too
+const BATTERY_CHANNEL: &str = "battery";
+/// Slower than this is parked
+const PARKED_KMH: f32 = 1.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = "<")]
+    Below,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum When {
+    #[default]
+    Always,
+    /// Stopped, or the ignition off
+    Parked,
+    Moving,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThresholdRule {
+    pub name: String,
+    /// Mode 01 PID in hex, a virtual PID name, or `battery` for the adapter's voltage reading
+    pub channel: String,
+    pub op: Comparison,
+    pub threshold: f32,
+    /// How far back past the threshold the value has to go to clear
+    #[serde(default)]
+    pub hysteresis: f32,
+    #[serde(default)]
+    pub when: When,
+}
+
+impl ThresholdRule {
+    fn triggers(&self, value: f32) -> bool {
+        match self.op {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    fn clears(&self, value: f32) -> bool {
+        match self.op {
+            Comparison::Above => value < self.threshold - self.hysteresis,
+            Comparison::Below => value > self.threshold + self.hysteresis,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ThresholdEvent {
+    pub name: String,
+    /// True when triggered, false when cleared
+    pub active: bool,
+    pub value: f32,
+    pub uptime_ms: u64,
+    /// RFC 3339, once the clock has been set
+    pub time: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ThresholdReport {
+    /// Rules triggered and not cleared yet
+    pub active: Vec<String>,
+    /// The last changes, oldest first
+    pub events: Vec<ThresholdEvent>,
+}
+
+struct Rule {
+    rule: ThresholdRule,
+    active: bool,
+}
+
+pub struct Thresholds {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    rules: Mutex<Vec<Rule>>,
+    events: Mutex<CircularBuffer<MAX_EVENTS, ThresholdEvent>>,
+    last_poll: Mutex<Option<Instant>>,
+}
+
+impl Thresholds {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let rules: Vec<ThresholdRule> =
+            config::load(&nvs, NVS_THRESHOLDS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            rules: Mutex::new(
+                rules
+                    .into_iter()
+                    .map(|rule| Rule {
+                        rule,
+                        active: false,
+                    })
+                    .collect(),
+            ),
+            events: Mutex::new(CircularBuffer::new()),
+            last_poll: Mutex::new(None),
+        })
+    }
+
+    pub fn config(&self) -> Vec<ThresholdRule> {
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.rule.clone())
+            .collect()
+    }
+
+    /// Replace the rules, all start out clear
+    pub fn set_config(&self, rules: Vec<ThresholdRule>, virtual_pids: &VirtualPids) -> Result<()> {
+        if rules.len() > MAX_RULES {
+            Err(anyhow!("Too many rules, max ({MAX_RULES})"))?;
+        }
+
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.name.is_empty() || rules[..i].iter().any(|other| other.name == rule.name) {
+                Err(anyhow!("Rule names must be unique and not empty"))?;
+            }
+
+            if rule.name.len() > MAX_NAME_LEN {
+                Err(anyhow!(
+                    "Rule ({}) name too long, max ({MAX_NAME_LEN})",
+                    rule.name
+                ))?;
+            }
+
+            let known = match u8::from_str_radix(&rule.channel, 16) {
+                Ok(p) => pid::name(p).is_some(),
+                Err(_) => rule.channel == BATTERY_CHANNEL || virtual_pids.contains(&rule.channel),
+            };
+            if !known {
+                Err(anyhow!(
+                    "Rule ({}) has an unknown channel ({})",
+                    rule.name,
+                    rule.channel
+                ))?;
+            }
+
+            if rule.hysteresis < 0.0 {
+                Err(anyhow!("Rule ({}) hysteresis is negative", rule.name))?;
+            }
+        }
+
+        let value = config::encode(&rules)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Rules too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_THRESHOLDS, &value)?;
+
+        *self.rules.lock().unwrap() = rules
+            .into_iter()
+            .map(|rule| Rule {
+                rule,
+                active: false,
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    pub fn poll_due(&self) -> bool {
+        !self.rules.lock().unwrap().is_empty()
+            && self
+                .last_poll
+                .lock()
+                .unwrap()
+                .is_none_or(|t| t.elapsed() >= POLL_INTERVAL)
+    }
+
+    /// Read the channels and check the rules, returning the ones that triggered or cleared. With
+    /// the ignition off only the battery is read, the ECUs are asleep.
+    pub fn poll(
+        &self,
+        elm: &mut Elm327<'_>,
+        virtual_pids: &VirtualPids,
+        ignition_on: bool,
+    ) -> Result<Vec<ThresholdEvent>> {
+        *self.last_poll.lock().unwrap() = Some(Instant::now());
+
+        let mut rules = self.rules.lock().unwrap();
+
+        let virtuals: Vec<String> = rules
+            .iter()
+            .map(|r| r.rule.channel.clone())
+            .filter(|channel| virtual_pids.contains(channel))
+            .collect();
+
+        let values = if ignition_on {
+            let mut pids: Vec<u8> = rules
+                .iter()
+                .filter_map(|r| u8::from_str_radix(&r.rule.channel, 16).ok())
+                .collect();
+            pids.extend(virtual_pids.inputs(&virtuals));
+            if rules.iter().any(|r| r.rule.when != When::Always) {
+                pids.push(pid::SPEED);
+            }
+            pids.sort_unstable();
+            pids.dedup();
+
+            pid::request_many(elm, &pids)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let battery = if rules.iter().any(|r| r.rule.channel == BATTERY_CHANNEL) {
+            elm.battery_voltage()
+                .inspect_err(|err| debug!("Battery read failed {err}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let speed = values.get(&pid::SPEED).copied();
+        let parked = !ignition_on || speed.is_some_and(|s| s < PARKED_KMH);
+        let moving = speed.is_some_and(|s| s >= PARKED_KMH);
+
+        let virtual_values: BTreeMap<&String, Option<f32>> = virtuals
+            .iter()
+            .zip(virtual_pids.eval(&virtuals, &values))
+            .collect();
+
+        let mut changed = Vec::new();
+
+        for r in rules.iter_mut() {
+            let applies = match r.rule.when {
+                When::Always => true,
+                When::Parked => parked,
+                When::Moving => moving,
+            };
+            if !applies {
+                continue;
+            }
+
+            let value = match u8::from_str_radix(&r.rule.channel, 16) {
+                Ok(p) => values.get(&p).copied(),
+                Err(_) if r.rule.channel == BATTERY_CHANNEL => battery,
+                Err(_) => virtual_values.get(&r.rule.channel).copied().flatten(),
+            };
+            let Some(value) = value else {
+                continue;
+            };
+
+            let active = match r.active {
+                false => r.rule.triggers(value),
+                true => !r.rule.clears(value),
+            };
+            if active == r.active {
+                continue;
+            }
+            r.active = active;
+
+            let event = ThresholdEvent {
+                name: r.rule.name.clone(),
+                active,
+                value,
+                uptime_ms: uptime_ms(),
+                time: clock::now(),
+            };
+
+            match active {
+                true => warn!("Threshold ({}) triggered at {value:.2}", event.name),
+                false => info!("Threshold ({}) cleared at {value:.2}", event.name),
+            }
+
+            self.events.lock().unwrap().push_back(event.clone());
+            changed.push(event);
+        }
+
+        Ok(changed)
+    }
+
+    pub fn report(&self) -> ThresholdReport {
+        ThresholdReport {
+            active: self
+                .rules
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.active)
+                .map(|r| r.rule.name.clone())
+                .collect(),
+            events: self.events.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
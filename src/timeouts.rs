@@ -0,0 +1,49 @@
+//! Adapter timeouts outside the cold start profile, the adaptive timing mode (ATAT) and the
+//! response timeout (ATST), set from `/config/timeouts`. A slow request can raise the timeout for
+//! itself with `X-Elm-Timeout`, see `Elm327::with_timeout`.
+use std::sync::Mutex;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+
+pub use bt_obd_gw_core::timeouts::{from_header, TimeoutConfig, TIMEOUT_HEADER};
+
+use crate::config::{self, NVS_TIMEOUTS};
+
+pub const MAX_CONFIG_LEN: usize = 128;
+
+pub struct ElmTimeouts {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    config: Mutex<TimeoutConfig>,
+}
+
+impl ElmTimeouts {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config = config::load(&nvs, NVS_TIMEOUTS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> TimeoutConfig {
+        *self.config.lock().unwrap()
+    }
+
+    /// Store the defaults, sent to the adapter by `Elm327::set_timeouts`
+    pub fn set_config(&self, config: TimeoutConfig) -> Result<()> {
+        config.check()?;
+
+        self.nvs
+            .lock()
+            .unwrap()
+            .set_raw(NVS_TIMEOUTS, &config::encode(&config)?)?;
+        *self.config.lock().unwrap() = config;
+
+        info!("Adapter timeouts updated {config:?}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Certificate and key for serving HTTPS, PEM in NVS. Either self-signed with
+//! `scripts/gen-cert.sh` or issued by your own CA, uploaded to `/config/tls-cert` and
+//! `/config/tls-key`. Each is parsed with mbedTLS when it's uploaded, and the pair is checked
+//! at boot, so a bad one leaves the gateway on plain HTTP rather than unreachable.
+use std::{ffi::c_void, ptr, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::{
+        esp_fill_random, mbedtls_pk_check_pair, mbedtls_pk_context, mbedtls_pk_free,
+        mbedtls_pk_init, mbedtls_pk_parse_key, mbedtls_x509_crt, mbedtls_x509_crt_free,
+        mbedtls_x509_crt_init, mbedtls_x509_crt_parse,
+    },
+    tls::X509,
+};
+use log::*;
+
+use crate::config::{NVS_TLS_CERT, NVS_TLS_KEY};
+
+/// Room for an RSA 2048 key, EC P-256 is much smaller
+pub const MAX_PEM_LEN: usize = 2048;
+
+/// Certificate and private key handed to the HTTPS server
+#[derive(Clone, Copy)]
+pub struct ServerCert {
+    pub cert: X509<'static>,
+    pub key: X509<'static>,
+}
+
+pub struct TlsStore {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+impl TlsStore {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Self {
+        Self {
+            nvs: Mutex::new(nvs),
+        }
+    }
+
+    /// The stored certificate and key, None until both have been uploaded. An error if the key
+    /// isn't the certificate's. The server keeps them for good, so they're leaked.
+    pub fn server_cert(&self) -> Result<Option<ServerCert>> {
+        let (Some(cert), Some(key)) = (self.load(NVS_TLS_CERT)?, self.load(NVS_TLS_KEY)?) else {
+            return Ok(None);
+        };
+
+        check_pair(&cert, &key)?;
+
+        Ok(Some(ServerCert {
+            cert: X509::pem_until_nul(cert.leak()),
+            key: X509::pem_until_nul(key.leak()),
+        }))
+    }
+
+    /// Store the PEM certificate, empty removes it. Used from the next boot.
+    pub fn set_cert(&self, pem: &[u8]) -> Result<()> {
+        self.store(NVS_TLS_CERT, pem, "CERTIFICATE")
+    }
+
+    /// Store the PEM private key, empty removes it. Used from the next boot.
+    pub fn set_key(&self, pem: &[u8]) -> Result<()> {
+        self.store(NVS_TLS_KEY, pem, "PRIVATE KEY")
+    }
+
+    /// PEM with the nul terminator the server wants
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; MAX_PEM_LEN];
+
+        let pem = self
+            .nvs
+            .lock()
+            .unwrap()
+            .get_raw(name, &mut buf)?
+            .filter(|pem| !pem.is_empty())
+            .map(|pem| [pem, &[0]].concat());
+
+        Ok(pem)
+    }
+
+    fn store(&self, name: &str, pem: &[u8], label: &str) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+
+        if pem.is_empty() {
+            nvs.remove(name)?;
+            info!("TLS {} removed", label.to_lowercase());
+            return Ok(());
+        }
+
+        if pem.len() > MAX_PEM_LEN {
+            Err(anyhow!("PEM too long, max ({MAX_PEM_LEN})"))?;
+        }
+
+        // "BEGIN PRIVATE KEY", "BEGIN EC PRIVATE KEY" and "BEGIN RSA PRIVATE KEY" are all fine
+        let text = std::str::from_utf8(pem).map_err(|_| anyhow!("PEM must be text"))?;
+        let begin = text
+            .trim_start()
+            .strip_prefix("-----BEGIN ")
+            .and_then(|rest| rest.split_once("-----"))
+            .map(|(begin, _)| begin);
+        if !begin.is_some_and(|begin| begin.ends_with(label)) {
+            Err(anyhow!("Expected a PEM {label}"))?;
+        }
+
+        let pem_nul = [pem, &[0]].concat();
+        if label == "CERTIFICATE" {
+            Certificate::parse(&pem_nul)?;
+        } else {
+            PrivateKey::parse(&pem_nul)?;
+        }
+
+        nvs.set_raw(name, pem)?;
+        info!(
+            "TLS {} updated, used from the next boot",
+            label.to_lowercase()
+        );
+
+        Ok(())
+    }
+}
+
+/// Parsed X.509 certificate, freed on drop
+struct Certificate(Box<mbedtls_x509_crt>);
+
+impl Certificate {
+    /// PEM with its nul terminator
+    fn parse(pem: &[u8]) -> Result<Self> {
+        let mut crt = Self(Box::new(unsafe { std::mem::zeroed() }));
+        unsafe { mbedtls_x509_crt_init(&mut *crt.0) };
+
+        let ret = unsafe { mbedtls_x509_crt_parse(&mut *crt.0, pem.as_ptr(), pem.len()) };
+        if ret != 0 {
+            Err(anyhow!("Certificate doesn't parse (-0x{:04X})", -ret))?;
+        }
+
+        Ok(crt)
+    }
+}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        unsafe { mbedtls_x509_crt_free(&mut *self.0) };
+    }
+}
+
+/// Parsed private key, freed on drop
+struct PrivateKey(Box<mbedtls_pk_context>);
+
+impl PrivateKey {
+    /// PEM with its nul terminator. Encrypted keys aren't supported, the server has no password.
+    fn parse(pem: &[u8]) -> Result<Self> {
+        let mut pk = Self(Box::new(unsafe { std::mem::zeroed() }));
+        unsafe { mbedtls_pk_init(&mut *pk.0) };
+
+        let ret = unsafe {
+            mbedtls_pk_parse_key(
+                &mut *pk.0,
+                pem.as_ptr(),
+                pem.len(),
+                ptr::null(),
+                0,
+                Some(random),
+                ptr::null_mut(),
+            )
+        };
+        if ret != 0 {
+            Err(anyhow!("Private key doesn't parse (-0x{:04X})", -ret))?;
+        }
+
+        Ok(pk)
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        unsafe { mbedtls_pk_free(&mut *self.0) };
+    }
+}
+
+/// Both PEMs with their nul terminators
+fn check_pair(cert: &[u8], key: &[u8]) -> Result<()> {
+    let cert = Certificate::parse(cert)?;
+    let key = PrivateKey::parse(key)?;
+
+    // The certificate's public key is private to mbedTLS 3, bindgen names it `private_pk`
+    let ret = unsafe {
+        mbedtls_pk_check_pair(&cert.0.private_pk, &*key.0, Some(random), ptr::null_mut())
+    };
+    if ret != 0 {
+        Err(anyhow!(
+            "Private key doesn't match the certificate (-0x{:04X})",
+            -ret
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// RNG for mbedTLS, key parsing and the pair check blind with it
+unsafe extern "C" fn random(_: *mut c_void, out: *mut u8, len: usize) -> i32 {
+    esp_fill_random(out.cast(), len);
+
+    0
+}
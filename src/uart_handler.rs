@@ -0,0 +1,118 @@
+//! Wired ELM327 on a UART, e.g. an STN1110 or OBD UART board, in place of the BT SPP adapter.
+//! Built with the `uart` feature, which leaves the whole BT stack out.
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use anyhow::Result;
+use esp_idf_svc::hal::{
+    delay::{TickType, NON_BLOCK},
+    uart::UartDriver,
+    units::Hertz,
+};
+use log::*;
+
+use crate::metrics;
+use crate::transport::{ConnectionStatus, ElmTransport};
+
+/// Longest a read waits for the adapter, as for SPP
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a write waits for the UART to send what's queued
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct UartHandler {
+    uart: UartDriver<'static>,
+    /// The rate the UART was set up at, the adapter's power on rate
+    power_on_baud: Hertz,
+}
+
+impl UartHandler {
+    pub fn new(uart: UartDriver<'static>) -> Result<Self> {
+        let power_on_baud = uart.baudrate()?;
+
+        Ok(Self {
+            uart,
+            power_on_baud,
+        })
+    }
+}
+
+impl Read for UartHandler {
+    /// Read a response from the adapter. Will BLOCK until there is some data available, or fail
+    /// with `TimedOut` after `READ_TIMEOUT`
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nread = self
+            .uart
+            .read(buf, TickType::from(READ_TIMEOUT).ticks())
+            .map_err(io::Error::other)?;
+
+        if nread == 0 {
+            metrics::ELM_READ_TIMEOUTS.inc();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No response from the adapter",
+            ));
+        }
+
+        Ok(nread)
+    }
+}
+
+impl Write for UartHandler {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.uart
+            .write(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::ConnectionReset, err))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.uart
+            .wait_tx_done(TickType::from(WRITE_TIMEOUT).ticks())
+            .map_err(|err| io::Error::new(io::ErrorKind::WouldBlock, err))
+    }
+}
+
+impl ConnectionStatus for UartHandler {
+    /// A wire is always connected
+    fn wait_connected(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Nothing to close, ATLP is what stops the adapter drawing power
+    fn disconnect(&mut self) {}
+
+    /// Drop anything half received, the adapter answers from the next request
+    fn reconnect(&mut self) -> Result<()> {
+        self.uart.clear_rx()?;
+
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "UART".to_owned()
+    }
+}
+
+impl ElmTransport for UartHandler {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.uart.read(buf, NON_BLOCK).map_err(io::Error::other)
+    }
+
+    fn write_elm_request(&mut self, request: &[u8]) -> Result<()> {
+        self.write_all(request)?;
+        self.write_all(b"\r")?;
+        self.flush()?;
+
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud: Option<u32>) -> Result<()> {
+        let baud = self
+            .uart
+            .change_baudrate(baud.map_or(self.power_on_baud, Hertz))?;
+        info!("UART at ({baud})");
+
+        Ok(())
+    }
+}
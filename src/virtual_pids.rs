@@ -0,0 +1,169 @@
+//! Virtual channels, user defined arithmetic over mode 01 PIDs (see `expr`), e.g. boost as
+//! `map - baro`. They're read wherever a PID can be, `/snapshot` and the trip logger, by reading
+//! the PIDs they use and working out the value, so vehicle specific math stays out of clients.
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, NVS_VIRTUAL_PIDS};
+use crate::expr::Expr;
+use crate::pid;
+
+pub const MAX_CONFIG_LEN: usize = 2048;
+const MAX_VIRTUAL_PIDS: usize = 16;
+const MAX_NAME_LEN: usize = 24;
+const MAX_UNIT_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VirtualPid {
+    /// Channel name, e.g. `boost`, letters, digits and `_`
+    pub name: String,
+    /// e.g. `map - baro`
+    pub expr: String,
+    #[serde(default)]
+    pub unit: String,
+    /// Gauge range for `/channels`
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default)]
+    pub max: f32,
+}
+
+pub struct VirtualPids {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+    /// Each with its parsed expression
+    defs: Mutex<Vec<(VirtualPid, Expr)>>,
+}
+
+impl VirtualPids {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Result<Self> {
+        let config: Vec<VirtualPid> =
+            config::load(&nvs, NVS_VIRTUAL_PIDS, MAX_CONFIG_LEN)?.unwrap_or_default();
+
+        // Stored configs were checked when set, a PID dropped from `pid::name` since would fail
+        let defs = config
+            .into_iter()
+            .filter_map(|def| match Expr::parse(&def.expr) {
+                Ok(expr) => Some((def, expr)),
+                Err(err) => {
+                    warn!("Virtual pid ({}) dropped, {err}", def.name);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            nvs: Mutex::new(nvs),
+            defs: Mutex::new(defs),
+        })
+    }
+
+    pub fn config(&self) -> Vec<VirtualPid> {
+        self.defs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(def, _)| def.clone())
+            .collect()
+    }
+
+    /// Replace the virtual PIDs, every expression has to parse
+    pub fn set_config(&self, config: Vec<VirtualPid>) -> Result<()> {
+        if config.len() > MAX_VIRTUAL_PIDS {
+            Err(anyhow!("Too many virtual pids, max ({MAX_VIRTUAL_PIDS})"))?;
+        }
+
+        let mut defs: Vec<(VirtualPid, Expr)> = Vec::with_capacity(config.len());
+        for def in config {
+            check_name(&def.name)?;
+            if defs.iter().any(|(other, _)| other.name == def.name) {
+                Err(anyhow!("Virtual pid ({}) is defined twice", def.name))?;
+            }
+
+            if def.unit.len() > MAX_UNIT_LEN {
+                Err(anyhow!(
+                    "Virtual pid ({}) unit too long, max ({MAX_UNIT_LEN})",
+                    def.name
+                ))?;
+            }
+
+            let expr = Expr::parse(&def.expr)
+                .map_err(|err| anyhow!("Virtual pid ({}) {err}", def.name))?;
+            defs.push((def, expr));
+        }
+
+        let config: Vec<&VirtualPid> = defs.iter().map(|(def, _)| def).collect();
+        let value = config::encode(&config)?;
+        if value.len() > MAX_CONFIG_LEN {
+            Err(anyhow!("Virtual pids too big, max ({MAX_CONFIG_LEN})"))?;
+        }
+
+        self.nvs.lock().unwrap().set_raw(NVS_VIRTUAL_PIDS, &value)?;
+
+        info!("{} virtual pids", defs.len());
+        *self.defs.lock().unwrap() = defs;
+
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.defs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(def, _)| def.name == name)
+    }
+
+    /// The PIDs to read for the virtual PIDs in `names`, unknown names are skipped
+    pub fn inputs(&self, names: &[String]) -> Vec<u8> {
+        let mut pids: Vec<u8> = self
+            .defs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(def, _)| names.contains(&def.name))
+            .flat_map(|(_, expr)| expr.pids())
+            .collect();
+        pids.sort_unstable();
+        pids.dedup();
+
+        pids
+    }
+
+    /// The value of each virtual PID in `names` from the PIDs read, None where one of its PIDs
+    /// wasn't read or the name isn't defined (any more)
+    pub fn eval(&self, names: &[String], values: &BTreeMap<u8, f32>) -> Vec<Option<f32>> {
+        let defs = self.defs.lock().unwrap();
+
+        names
+            .iter()
+            .map(|name| {
+                defs.iter()
+                    .find(|(def, _)| def.name == *name)
+                    .and_then(|(_, expr)| expr.eval(values))
+            })
+            .collect()
+    }
+}
+
+/// Not empty, not a hex PID or a PID's channel name so it can't be mistaken for one
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        Err(anyhow!(
+            "Virtual pid name ({name}) must be up to {MAX_NAME_LEN} letters, digits and _"
+        ))?;
+    }
+
+    if u8::from_str_radix(name, 16).is_ok() || (0..=0xFFu8).any(|p| pid::name(p) == Some(name)) {
+        Err(anyhow!("Virtual pid name ({name}) is taken by a PID"))?;
+    }
+
+    Ok(())
+}